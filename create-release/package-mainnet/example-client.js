@@ -13,7 +13,7 @@ const crypto = require('crypto');
 if (process.argv.length != 6) {
     console.log(`Usage: node example-client.js <public mirror host> <public mirror port> <key file> <request>`);
     console.log(`For example: node example-client.js 127.0.0.1 9091 mirror-client.pem '{"method": "get_block", "params": {"block_index": "0"}, "jsonrpc": "2.0", "id": 1}'`);
-    console.log('To generate keys please run the generate-rsa-keypair binary. See README.md for more details')
+    console.log('To generate keys please run the mirror-keytool binary (mirror-keytool generate). See README.md for more details')
     return;
 }
 
@@ -10,7 +10,7 @@ if [ -z "$PUBLIC_HOST" ]; then
 fi
 
 if [ ! -f "mirror-private.pem" ]; then
-    echo "mirror-private.pem does not exist. Did you run generate-rsa-keypair?"
+    echo "mirror-private.pem does not exist. Did you run mirror-keytool generate?"
     exit 1
 fi
 
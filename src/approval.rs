@@ -0,0 +1,382 @@
+// Copyright (c) 2018-2022 MobileCoin Inc.
+
+//! The approval spool: a local directory shared between the private side of the mirror and the
+//! `mirror-approve` tool, holding requests that need to be approved before they are forwarded to
+//! full-service.
+//!
+//! Layout:
+//! - `pending/<approval id>.json` - requests waiting for approval.
+//! - `signatures/<approval id>/<approver>.sig` - hex-encoded approver signatures.
+//! - `processing/<approval id>.json` - approved requests currently being forwarded.
+//! - `completed/<approval id>.json` - the outcome of requests that are no longer pending.
+//!
+//! Approvers have to see the requests they sign, so requests and full-service responses are
+//! stored in plaintext. The spool is only accessible to its owner and group, and should be kept on
+//! storage that nobody but the mirror and the approvers can read.
+
+use rand::RngCore;
+use serde_derive::{Deserialize, Serialize};
+use std::{
+    fs::{self, DirBuilder, OpenOptions, Permissions},
+    io::{ErrorKind, Write},
+    os::unix::fs::{DirBuilderExt, OpenOptionsExt, PermissionsExt},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use zeroize::Zeroizing;
+
+/// Length (in bytes) of the randomly generated approval ids.
+const APPROVAL_ID_LEN: usize = 16;
+
+/// Domain separator for approval signatures.
+const APPROVAL_SIGNATURE_CONTEXT: &str = "wallet-service-mirror-approval";
+
+/// Permissions of the spool directories and files, which are shared with approvers through the
+/// group of the spool.
+const SPOOL_DIR_MODE: u32 = 0o770;
+const SPOOL_FILE_MODE: u32 = 0o660;
+
+/// A request waiting for approval.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct PendingApproval {
+    pub approval_id: String,
+    pub method: String,
+    pub json_request: String,
+    /// Unix timestamp (seconds).
+    pub submitted_at: u64,
+    /// How the request reached the private side (`unencrypted` or `encrypted`).
+    #[serde(default)]
+    pub transport: String,
+    /// The client that signed the request, if it was signed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signer: Option<String>,
+}
+
+/// The final status of a request that required approval.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ApprovalStatus {
+    /// The request was approved and forwarded to full-service.
+    Approved,
+
+    /// The request was not approved in time and was never forwarded.
+    Expired,
+
+    /// The request was approved, but it would have exceeded a spending limit or forwarding it to
+    /// full-service failed.
+    Failed,
+
+    /// The mirror stopped while forwarding the request, so it is unknown whether full-service
+    /// received it. It will not be retried automatically.
+    Interrupted,
+}
+
+/// The outcome of a request that required approval.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct CompletedApproval {
+    pub approval_id: String,
+    pub method: String,
+    pub status: ApprovalStatus,
+    /// The approvers whose signatures were accepted.
+    pub approvers: Vec<String>,
+    pub json_response: Option<String>,
+    pub error: Option<String>,
+    /// Unix timestamp (seconds).
+    pub completed_at: u64,
+}
+
+/// The message approvers sign in order to approve a request.
+pub fn approval_message(approval_id: &str, json_request: &str) -> Vec<u8> {
+    format!(
+        "{}\n{}\n{}",
+        APPROVAL_SIGNATURE_CONTEXT, approval_id, json_request
+    )
+    .into_bytes()
+}
+
+/// Generate a random approval id.
+pub fn generate_approval_id() -> String {
+    let mut bytes = [0u8; APPROVAL_ID_LEN];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    hex::encode(bytes)
+}
+
+/// Whether a string could be an approval id, i.e. is safe to use as a file name in the spool.
+pub fn is_valid_approval_id(approval_id: &str) -> bool {
+    check_name(approval_id).is_ok()
+}
+
+/// Current time as a Unix timestamp (seconds).
+pub fn now_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+/// Access to the approval spool directory.
+#[derive(Clone, Debug)]
+pub struct ApprovalSpool {
+    dir: PathBuf,
+}
+
+impl ApprovalSpool {
+    /// Open a spool directory, creating its layout if needed.
+    pub fn new(dir: impl AsRef<Path>) -> Result<Self, String> {
+        let spool = Self {
+            dir: dir.as_ref().to_path_buf(),
+        };
+        for subdir in &["pending", "signatures", "processing", "completed"] {
+            create_dir(&spool.dir.join(subdir))?;
+        }
+        Ok(spool)
+    }
+
+    /// Queue a request for approval.
+    pub fn add_pending(&self, pending: &PendingApproval) -> Result<(), String> {
+        write_json(&self.pending_path(&pending.approval_id)?, pending)
+    }
+
+    /// Returns all requests waiting for approval.
+    pub fn pending(&self) -> Result<Vec<PendingApproval>, String> {
+        read_json_dir(&self.dir.join("pending"))
+    }
+
+    /// Returns the number of requests waiting for approval, without reading them.
+    pub fn pending_count(&self) -> Result<usize, String> {
+        let dir = self.dir.join("pending");
+        let mut count = 0;
+        for entry in
+            fs::read_dir(&dir).map_err(|err| format!("failed reading {:?}: {}", dir, err))?
+        {
+            let path = entry
+                .map_err(|err| format!("failed reading {:?}: {}", dir, err))?
+                .path();
+            if path.extension().and_then(|ext| ext.to_str()) == Some("json") {
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    /// Returns a request waiting for approval, if there is one with the given id.
+    pub fn get_pending(&self, approval_id: &str) -> Result<Option<PendingApproval>, String> {
+        read_json_if_exists(&self.pending_path(approval_id)?)
+    }
+
+    /// Move a pending request to processing. Returns false if the request was no longer pending.
+    pub fn claim(&self, approval_id: &str) -> Result<bool, String> {
+        let pending_path = self.pending_path(approval_id)?;
+        if !pending_path.exists() {
+            return Ok(false);
+        }
+        fs::rename(&pending_path, self.processing_path(approval_id)?)
+            .map_err(|err| format!("failed claiming {}: {}", approval_id, err))?;
+        Ok(true)
+    }
+
+    /// Returns requests that were being forwarded when the mirror last stopped.
+    pub fn processing(&self) -> Result<Vec<PendingApproval>, String> {
+        read_json_dir(&self.dir.join("processing"))
+    }
+
+    /// Record the outcome of a request, removing it from the pending/processing state.
+    pub fn complete(&self, completed: &CompletedApproval) -> Result<(), String> {
+        let approval_id = &completed.approval_id;
+        write_json(&self.completed_path(approval_id)?, completed)?;
+
+        for path in &[
+            self.pending_path(approval_id)?,
+            self.processing_path(approval_id)?,
+        ] {
+            if path.exists() {
+                fs::remove_file(path)
+                    .map_err(|err| format!("failed removing {:?}: {}", path, err))?;
+            }
+        }
+
+        let signatures_dir = self.signatures_dir(approval_id)?;
+        if signatures_dir.exists() {
+            fs::remove_dir_all(&signatures_dir)
+                .map_err(|err| format!("failed removing {:?}: {}", signatures_dir, err))?;
+        }
+        Ok(())
+    }
+
+    /// Remove the outcomes of requests that completed longer ago than the given age.
+    pub fn prune_completed(&self, max_age: Duration) -> Result<(), String> {
+        let dir = self.dir.join("completed");
+        for entry in
+            fs::read_dir(&dir).map_err(|err| format!("failed reading {:?}: {}", dir, err))?
+        {
+            let entry = entry.map_err(|err| format!("failed reading {:?}: {}", dir, err))?;
+            let expired = entry
+                .metadata()
+                .and_then(|metadata| metadata.modified())
+                .ok()
+                .and_then(|modified| modified.elapsed().ok())
+                .map_or(false, |age| age > max_age);
+            // Another instance sharing the spool may be removing the same files.
+            if expired {
+                let _ = fs::remove_file(entry.path());
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the outcome of a request, if it is no longer pending.
+    pub fn get_completed(&self, approval_id: &str) -> Result<Option<CompletedApproval>, String> {
+        read_json_if_exists(&self.completed_path(approval_id)?)
+    }
+
+    /// Store an approver's signature for a request.
+    pub fn add_signature(
+        &self,
+        approval_id: &str,
+        approver: &str,
+        signature: &[u8],
+    ) -> Result<(), String> {
+        check_name(approver)?;
+        let signatures_dir = self.signatures_dir(approval_id)?;
+        create_dir(&signatures_dir)?;
+        write_atomic(
+            &signatures_dir.join(format!("{}.sig", approver)),
+            hex::encode(signature).as_bytes(),
+        )
+    }
+
+    /// Returns the (approver, signature) pairs stored for a request.
+    pub fn signatures(&self, approval_id: &str) -> Result<Vec<(String, Vec<u8>)>, String> {
+        let signatures_dir = self.signatures_dir(approval_id)?;
+        if !signatures_dir.exists() {
+            return Ok(vec![]);
+        }
+
+        let mut signatures = Vec::new();
+        for entry in fs::read_dir(&signatures_dir)
+            .map_err(|err| format!("failed reading {:?}: {}", signatures_dir, err))?
+        {
+            let path = entry
+                .map_err(|err| format!("failed reading {:?}: {}", signatures_dir, err))?
+                .path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some("sig") {
+                continue;
+            }
+            let approver = match path.file_stem().and_then(|stem| stem.to_str()) {
+                Some(approver) => approver.to_owned(),
+                None => continue,
+            };
+            let signature_hex = fs::read_to_string(&path)
+                .map_err(|err| format!("failed reading {:?}: {}", path, err))?;
+            let signature = hex::decode(signature_hex.trim())
+                .map_err(|err| format!("failed decoding {:?}: {}", path, err))?;
+            signatures.push((approver, signature));
+        }
+        Ok(signatures)
+    }
+
+    fn pending_path(&self, approval_id: &str) -> Result<PathBuf, String> {
+        check_name(approval_id)?;
+        Ok(self
+            .dir
+            .join("pending")
+            .join(format!("{}.json", approval_id)))
+    }
+
+    fn processing_path(&self, approval_id: &str) -> Result<PathBuf, String> {
+        check_name(approval_id)?;
+        Ok(self
+            .dir
+            .join("processing")
+            .join(format!("{}.json", approval_id)))
+    }
+
+    fn completed_path(&self, approval_id: &str) -> Result<PathBuf, String> {
+        check_name(approval_id)?;
+        Ok(self
+            .dir
+            .join("completed")
+            .join(format!("{}.json", approval_id)))
+    }
+
+    fn signatures_dir(&self, approval_id: &str) -> Result<PathBuf, String> {
+        check_name(approval_id)?;
+        Ok(self.dir.join("signatures").join(approval_id))
+    }
+}
+
+/// Approval ids and approver names are used as file names, so they are restricted to a safe
+/// character set.
+fn check_name(name: &str) -> Result<(), String> {
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err(format!("invalid name \"{}\"", name));
+    }
+    Ok(())
+}
+
+fn create_dir(path: &Path) -> Result<(), String> {
+    DirBuilder::new()
+        .recursive(true)
+        .mode(SPOOL_DIR_MODE)
+        .create(path)
+        .map_err(|err| format!("failed creating {:?}: {}", path, err))
+}
+
+/// Write a file by writing a temporary file and renaming it, so that readers never see a
+/// partially written file.
+fn write_atomic(path: &Path, contents: &[u8]) -> Result<(), String> {
+    let tmp_path = path.with_extension("tmp");
+    let write_err = |err: std::io::Error| format!("failed writing {:?}: {}", tmp_path, err);
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(SPOOL_FILE_MODE)
+        .open(&tmp_path)
+        .map_err(write_err)?;
+    // The mode is only used for new files, and a temporary file may be left from a crash.
+    file.set_permissions(Permissions::from_mode(SPOOL_FILE_MODE))
+        .map_err(write_err)?;
+    file.write_all(contents).map_err(write_err)?;
+    fs::rename(&tmp_path, path).map_err(|err| format!("failed renaming {:?}: {}", tmp_path, err))
+}
+
+fn write_json<T: serde::Serialize>(path: &Path, value: &T) -> Result<(), String> {
+    let json = Zeroizing::new(
+        serde_json::to_vec_pretty(value)
+            .map_err(|err| format!("failed serializing {:?}: {}", path, err))?,
+    );
+    write_atomic(path, &json)
+}
+
+fn read_json_if_exists<T: serde::de::DeserializeOwned>(path: &Path) -> Result<Option<T>, String> {
+    // Files may be moved by another process at any time, so a missing file is not an error.
+    let json = match fs::read(path) {
+        Ok(json) => Zeroizing::new(json),
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(format!("failed reading {:?}: {}", path, err)),
+    };
+    serde_json::from_slice(&json)
+        .map(Some)
+        .map_err(|err| format!("failed parsing {:?}: {}", path, err))
+}
+
+fn read_json_dir<T: serde::de::DeserializeOwned>(dir: &Path) -> Result<Vec<T>, String> {
+    let mut values = Vec::new();
+    for entry in fs::read_dir(dir).map_err(|err| format!("failed reading {:?}: {}", dir, err))? {
+        let path = entry
+            .map_err(|err| format!("failed reading {:?}: {}", dir, err))?
+            .path();
+        if path.extension().and_then(|ext| ext.to_str()) != Some("json") {
+            continue;
+        }
+        if let Some(value) = read_json_if_exists(&path)? {
+            values.push(value);
+        }
+    }
+    Ok(values)
+}
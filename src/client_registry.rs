@@ -0,0 +1,114 @@
+// Copyright (c) 2018-2022 MobileCoin Inc.
+
+//! The client key registry: a JSON file holding the public keys of the clients allowed to submit
+//! encrypted requests, read by the private side of the mirror (`--client-keys`) and maintained
+//! with `mirror-keytool register` and `mirror-keytool revoke`.
+//!
+//! ```json
+//! {
+//!     "clients": {
+//!         "acme-app": { "type": "ed25519", "public_key": "<hex-encoded Ed25519 public key>" },
+//!         "ops": { "type": "ecdsa-p256", "public_key": "<hex-encoded SEC1 public key>" }
+//!     }
+//! }
+//! ```
+
+use boring::{
+    bn::BigNumContext,
+    ec::{EcGroup, EcKey, EcPoint},
+    ecdsa::EcdsaSig,
+    nid::Nid,
+    pkey::Public,
+};
+use mc_crypto_keys::{Ed25519Public, Ed25519Signature, Verifier};
+use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{collections::BTreeMap, convert::TryFrom, fs, path::Path};
+
+/// A client public key, as it appears in the registry.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum ClientKeyConfig {
+    Ed25519 { public_key: String },
+    EcdsaP256 { public_key: String },
+}
+
+impl ClientKeyConfig {
+    /// Parse the public key.
+    pub fn parse(&self) -> Result<ClientKey, String> {
+        match self {
+            ClientKeyConfig::Ed25519 { public_key } => {
+                let key_bytes = hex::decode(public_key).map_err(|err| err.to_string())?;
+                let key =
+                    Ed25519Public::try_from(&key_bytes[..]).map_err(|err| format!("{:?}", err))?;
+                Ok(ClientKey::Ed25519(key))
+            }
+            ClientKeyConfig::EcdsaP256 { public_key } => {
+                let key_bytes = hex::decode(public_key).map_err(|err| err.to_string())?;
+                let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)
+                    .map_err(|err| err.to_string())?;
+                let mut ctx = BigNumContext::new().map_err(|err| err.to_string())?;
+                let point = EcPoint::from_bytes(&group, &key_bytes, &mut ctx)
+                    .map_err(|err| err.to_string())?;
+                let key = EcKey::from_public_key(&group, &point).map_err(|err| err.to_string())?;
+                key.check_key().map_err(|err| err.to_string())?;
+                Ok(ClientKey::EcdsaP256(key))
+            }
+        }
+    }
+}
+
+/// A parsed client public key.
+pub enum ClientKey {
+    Ed25519(Ed25519Public),
+    EcdsaP256(EcKey<Public>),
+}
+
+impl ClientKey {
+    /// Verify a signature over a message. Ed25519 signatures are the raw 64 bytes, ECDSA P-256
+    /// signatures are DER-encoded over the SHA-256 digest of the message.
+    pub fn verify(&self, message: &[u8], signature: &[u8]) -> bool {
+        match self {
+            ClientKey::Ed25519(key) => Ed25519Signature::try_from(signature)
+                .map(|signature| key.verify(message, &signature).is_ok())
+                .unwrap_or(false),
+            ClientKey::EcdsaP256(key) => EcdsaSig::from_der(signature)
+                .and_then(|signature| signature.verify(&Sha256::digest(message), key))
+                .unwrap_or(false),
+        }
+    }
+}
+
+/// The contents of a client key registry file.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct ClientRegistry {
+    /// Map of client name -> public key.
+    pub clients: BTreeMap<String, ClientKeyConfig>,
+}
+
+impl ClientRegistry {
+    pub fn from_json(registry_str: &str) -> Result<Self, String> {
+        serde_json::from_str(registry_str).map_err(|err| err.to_string())
+    }
+
+    /// Load a registry file.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let registry_str = fs::read_to_string(path)
+            .map_err(|err| format!("failed reading client registry {:?}: {}", path, err))?;
+        Self::from_json(&registry_str)
+            .map_err(|err| format!("failed parsing client registry {:?}: {}", path, err))
+    }
+
+    /// Write the registry to a file, replacing it atomically so that it is never read partially
+    /// written. The private side only reads its `--client-keys` registry on startup, and has to be
+    /// restarted to pick up changes.
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let registry_str = serde_json::to_string_pretty(self).map_err(|err| err.to_string())?;
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        fs::write(&tmp_path, registry_str + "\n")
+            .map_err(|err| format!("failed writing client registry {:?}: {}", tmp_path, err))?;
+        fs::rename(&tmp_path, path)
+            .map_err(|err| format!("failed writing client registry {:?}: {}", path, err))
+    }
+}
@@ -0,0 +1,129 @@
+// Copyright (c) 2018-2022 MobileCoin Inc.
+
+//! Reading requests on, and limiting the connections to, the minimal HTTP listeners that the mirror
+//! serves outside of rocket (the public side's notification subscriptions and the private side's
+//! health checks).
+
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::TcpStream,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+/// How long a client has to send its request after connecting.
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The maximum size of a request (request line and headers).
+pub const MAX_REQUEST_LEN: u64 = 8192;
+
+/// The request line and headers of a request. Request bodies are not supported.
+pub struct RequestHead {
+    pub request_line: String,
+    pub headers: Vec<(String, String)>,
+}
+
+impl RequestHead {
+    /// The value of a header, matched case-insensitively.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header_name, _)| header_name.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+/// Limits the number of connections that are handled at the same time, so that a thread is only
+/// spawned for a connection when there is room for it.
+#[derive(Clone)]
+pub struct ConnectionLimit {
+    active: Arc<AtomicUsize>,
+    max: usize,
+}
+
+impl ConnectionLimit {
+    pub fn new(max: usize) -> Self {
+        Self {
+            active: Arc::new(AtomicUsize::new(0)),
+            max,
+        }
+    }
+
+    /// Count a connection until the returned guard is dropped, or returns None if the limit is
+    /// reached.
+    pub fn acquire(&self) -> Option<ConnectionGuard> {
+        self.active
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |active| {
+                if active < self.max {
+                    Some(active + 1)
+                } else {
+                    None
+                }
+            })
+            .ok()
+            .map(|_| ConnectionGuard(self.active.clone()))
+    }
+}
+
+/// A connection counted by a `ConnectionLimit`.
+pub struct ConnectionGuard(Arc<AtomicUsize>);
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Turn away a connection over the limit without reading its request.
+pub fn reject_connection(mut stream: TcpStream) -> Result<(), String> {
+    stream
+        .set_write_timeout(Some(REQUEST_TIMEOUT))
+        .and_then(|_| {
+            stream.write_all(
+                b"HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+            )
+        })
+        .map_err(|err| err.to_string())
+}
+
+/// Read the request line and headers of a request, which have to be read before responding.
+pub fn read_request_head(stream: &TcpStream) -> Result<RequestHead, String> {
+    stream
+        .set_read_timeout(Some(REQUEST_TIMEOUT))
+        .map_err(|err| err.to_string())?;
+
+    let mut reader = BufReader::new(
+        stream
+            .try_clone()
+            .map_err(|err| err.to_string())?
+            .take(MAX_REQUEST_LEN),
+    );
+    let mut request_line = String::new();
+    reader
+        .read_line(&mut request_line)
+        .map_err(|err| err.to_string())?;
+
+    let mut headers = Vec::new();
+    loop {
+        let mut line = String::new();
+        let len = reader.read_line(&mut line).map_err(|err| err.to_string())?;
+        let line = line.trim_end();
+        if len == 0 || line.is_empty() {
+            break;
+        }
+        if let Some(index) = line.find(':') {
+            headers.push((
+                line[..index].trim().to_owned(),
+                line[index + 1..].trim().to_owned(),
+            ));
+        }
+    }
+
+    Ok(RequestHead {
+        request_line,
+        headers,
+    })
+}
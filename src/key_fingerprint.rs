@@ -0,0 +1,18 @@
+// Copyright (c) 2018-2022 MobileCoin Inc.
+
+//! Fingerprints identifying mirror keys.
+//!
+//! The fingerprint of a mirror key is the hex-encoded SHA-256 digest of the DER encoding of its
+//! public key (as a SubjectPublicKeyInfo), which can also be computed with
+//! `openssl pkey -pubin -in mirror-client.pem -outform der | sha256sum`.
+
+use boring::{pkey::HasPublic, rsa::Rsa};
+use sha2::{Digest, Sha256};
+
+/// The fingerprint of a mirror key.
+pub fn key_fingerprint<T: HasPublic>(key: &Rsa<T>) -> Result<String, String> {
+    let der = key
+        .public_key_to_der()
+        .map_err(|err| format!("failed encoding public key: {}", err))?;
+    Ok(hex::encode(Sha256::digest(&der)))
+}
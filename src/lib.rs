@@ -16,4 +16,10 @@ mod autogenerated_code {
 
 pub use autogenerated_code::{wallet_service_mirror_api::*, *};
 
+pub mod approval;
+pub mod client_registry;
+pub mod http_request;
+pub mod key_fingerprint;
+pub mod passphrase;
+pub mod response_signature;
 pub mod uri;
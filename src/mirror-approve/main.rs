@@ -0,0 +1,162 @@
+// Copyright (c) 2018-2022 MobileCoin Inc.
+
+//! Utility for approving requests queued by the private side of the mirror, when it is configured
+//! with `--approval-config`. Approvers run this on the private host, against the approval spool
+//! directory.
+
+use mc_crypto_keys::{Ed25519Pair, Ed25519Private, Signer};
+use mc_wallet_service_mirror::approval::{approval_message, ApprovalSpool};
+use rand::RngCore;
+use std::{
+    convert::TryFrom,
+    fs::{self, OpenOptions},
+    io::Write,
+    os::unix::fs::OpenOptionsExt,
+    path::PathBuf,
+};
+use structopt::StructOpt;
+
+/// Command line config
+#[derive(Clone, Debug, StructOpt)]
+#[structopt(
+    name = "mirror-approve",
+    about = "Approve requests that the private side of wallet-service-mirror queued for approval"
+)]
+pub enum Command {
+    /// Generate an approver keypair. The private key is written to the given file, and the public
+    /// key is printed so that it can be added to the approval config.
+    GenerateKey {
+        /// Output file for the hex-encoded private key.
+        #[structopt(long, parse(from_os_str))]
+        out: PathBuf,
+    },
+
+    /// List requests waiting for approval.
+    List {
+        /// The approval spool directory.
+        #[structopt(long, parse(from_os_str))]
+        spool_dir: PathBuf,
+    },
+
+    /// Show a request waiting for approval.
+    Show {
+        /// The approval spool directory.
+        #[structopt(long, parse(from_os_str))]
+        spool_dir: PathBuf,
+
+        /// The approval id of the request.
+        approval_id: String,
+    },
+
+    /// Sign a request waiting for approval.
+    Approve {
+        /// The approval spool directory.
+        #[structopt(long, parse(from_os_str))]
+        spool_dir: PathBuf,
+
+        /// The approver name, as it appears in the approval config.
+        #[structopt(long)]
+        approver: String,
+
+        /// File holding the approver's hex-encoded private key.
+        #[structopt(long, parse(from_os_str))]
+        key: PathBuf,
+
+        /// The approval id of the request.
+        approval_id: String,
+    },
+}
+
+fn main() {
+    match Command::from_args() {
+        Command::GenerateKey { out } => {
+            if out.exists() {
+                panic!("{:?} already exists", out);
+            }
+
+            let mut seed = [0u8; 32];
+            rand::thread_rng().fill_bytes(&mut seed);
+            let private_key =
+                Ed25519Private::try_from(&seed[..]).expect("Failed creating private key");
+            let pair = Ed25519Pair::from(private_key);
+
+            let mut file = OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .mode(0o600)
+                .open(&out)
+                .expect("Failed creating private key file");
+            file.write_all(hex::encode(seed).as_bytes())
+                .expect("Failed writing private key to file");
+            println!("Wrote {:?}", out);
+            println!(
+                "Public key (add this to the approval config): {}",
+                hex::encode(pair.public_key())
+            );
+        }
+
+        Command::List { spool_dir } => {
+            let spool = ApprovalSpool::new(spool_dir).expect("Failed opening approval spool");
+            let mut pending = spool.pending().expect("Failed reading pending requests");
+            pending.sort_by_key(|pending| pending.submitted_at);
+
+            for pending in pending {
+                let signatures = spool
+                    .signatures(&pending.approval_id)
+                    .expect("Failed reading signatures");
+                let approvers: Vec<String> = signatures
+                    .into_iter()
+                    .map(|(approver, _)| approver)
+                    .collect();
+                println!(
+                    "{} {} submitted_at={} signed_by={:?}",
+                    pending.approval_id, pending.method, pending.submitted_at, approvers
+                );
+            }
+        }
+
+        Command::Show {
+            spool_dir,
+            approval_id,
+        } => {
+            let spool = ApprovalSpool::new(spool_dir).expect("Failed opening approval spool");
+            let pending = spool
+                .get_pending(&approval_id)
+                .expect("Failed reading request")
+                .unwrap_or_else(|| panic!("No pending request with id {}", approval_id));
+            println!("{}", pending.json_request);
+        }
+
+        Command::Approve {
+            spool_dir,
+            approver,
+            key,
+            approval_id,
+        } => {
+            let spool = ApprovalSpool::new(spool_dir).expect("Failed opening approval spool");
+            let pending = spool
+                .get_pending(&approval_id)
+                .expect("Failed reading request")
+                .unwrap_or_else(|| panic!("No pending request with id {}", approval_id));
+
+            let key_hex = fs::read_to_string(&key)
+                .unwrap_or_else(|err| panic!("failed reading key file {:?}: {}", key, err));
+            let seed = hex::decode(key_hex.trim())
+                .unwrap_or_else(|err| panic!("failed decoding key file {:?}: {}", key, err));
+            let private_key = Ed25519Private::try_from(&seed[..])
+                .unwrap_or_else(|err| panic!("failed parsing key file {:?}: {:?}", key, err));
+            let pair = Ed25519Pair::from(private_key);
+
+            let message = approval_message(&pending.approval_id, &pending.json_request);
+            let signature = pair.sign(&message);
+
+            spool
+                .add_signature(&approval_id, &approver, signature.as_ref())
+                .expect("Failed writing signature");
+            println!(
+                "Approved {} ({}) as {}",
+                pending.approval_id, pending.method, approver
+            );
+        }
+    }
+}
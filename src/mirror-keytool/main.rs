@@ -0,0 +1,671 @@
+// Copyright (c) 2018-2022 MobileCoin Inc.
+
+//! Utility for managing the keys used with wallet-service-mirror: generating mirror keys (used by
+//! clients to encrypt requests to the private side) and client signing keys, inspecting and
+//! exporting mirror public keys, and maintaining the client key registry (see
+//! `client_registry.rs`).
+
+use boring::{
+    bn::BigNumContext,
+    ec::{EcGroup, EcKey, PointConversionForm},
+    nid::Nid,
+    pkey::{PKey, Private, Public},
+    rsa::Rsa,
+    symm::Cipher,
+};
+use mc_crypto_keys::{Ed25519Pair, Ed25519Private};
+use mc_wallet_service_mirror::{
+    client_registry::{ClientKeyConfig, ClientRegistry},
+    key_fingerprint::key_fingerprint,
+    passphrase::PassphraseSource,
+};
+use rand::RngCore;
+use serde_json::json;
+use std::{
+    convert::TryFrom,
+    fs::{self, OpenOptions, Permissions},
+    io::{ErrorKind, Write},
+    os::unix::fs::{OpenOptionsExt, PermissionsExt},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+use structopt::StructOpt;
+use zeroize::Zeroizing;
+
+/// The smallest RSA key size accepted for mirror keys.
+const MIN_RSA_BITS: u32 = 2048;
+
+/// Key algorithms `generate` supports.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum KeyAlgorithm {
+    /// Mirror keys.
+    Rsa,
+    /// Client signing keys, written as a hex-encoded seed.
+    Ed25519,
+    /// Client signing keys, written as PEM.
+    EcdsaP256,
+}
+
+impl FromStr for KeyAlgorithm {
+    type Err = String;
+
+    fn from_str(src: &str) -> Result<Self, Self::Err> {
+        match src {
+            "rsa" => Ok(KeyAlgorithm::Rsa),
+            "ed25519" => Ok(KeyAlgorithm::Ed25519),
+            "ecdsa-p256" => Ok(KeyAlgorithm::EcdsaP256),
+            _ => Err(format!(
+                "unknown algorithm {}, expected rsa, ed25519 or ecdsa-p256",
+                src
+            )),
+        }
+    }
+}
+
+impl KeyAlgorithm {
+    /// The default private and public key output files.
+    fn default_paths(self) -> (&'static str, &'static str) {
+        match self {
+            KeyAlgorithm::Rsa => ("mirror-private.pem", "mirror-client.pem"),
+            KeyAlgorithm::Ed25519 => ("client-private.key", "client-public.key"),
+            KeyAlgorithm::EcdsaP256 => ("client-private.pem", "client-public.key"),
+        }
+    }
+}
+
+/// Formats `export` writes public keys in.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ExportFormat {
+    Pem,
+    Jwk,
+    SpkiHex,
+}
+
+impl FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(src: &str) -> Result<Self, Self::Err> {
+        match src {
+            "pem" => Ok(ExportFormat::Pem),
+            "jwk" => Ok(ExportFormat::Jwk),
+            "spki-hex" => Ok(ExportFormat::SpkiHex),
+            _ => Err(format!(
+                "unknown format {}, expected pem, jwk or spki-hex",
+                src
+            )),
+        }
+    }
+}
+
+/// Command line config
+#[derive(Clone, Debug, StructOpt)]
+#[structopt(
+    name = "mirror-keytool",
+    about = "Manage the keys used with wallet-service-mirror"
+)]
+pub enum Command {
+    /// Generate a keypair: an RSA mirror key (for `--mirror-key`, and for clients to encrypt
+    /// requests with), or an Ed25519 or ECDSA P-256 client signing key (see `register`).
+    Generate {
+        /// The key algorithm: `rsa`, `ed25519` or `ecdsa-p256`.
+        #[structopt(long, default_value = "rsa")]
+        algorithm: KeyAlgorithm,
+
+        /// The size of RSA keys, in bits.
+        #[structopt(long, default_value = "4096")]
+        bits: u32,
+
+        /// Output file for the private key. Defaults to `mirror-private.pem` for RSA keys, and
+        /// `client-private.key` or `client-private.pem` for client keys.
+        #[structopt(long, parse(from_os_str))]
+        out: Option<PathBuf>,
+
+        /// Output file for the public key. Defaults to `mirror-client.pem` for RSA keys (a PEM
+        /// file to hand to clients), and `client-public.key` for client keys (hex-encoded, as
+        /// `register` expects).
+        #[structopt(long, parse(from_os_str))]
+        public_out: Option<PathBuf>,
+
+        /// Encrypt the private key (as PKCS#8, with AES-256-CBC) with a passphrase read from
+        /// `file:<path>`, `env:<variable>` or `prompt`. Not supported for Ed25519 keys.
+        #[structopt(long)]
+        passphrase: Option<PassphraseSource>,
+
+        /// Overwrite existing output files.
+        #[structopt(long)]
+        force: bool,
+    },
+
+    /// Print the fingerprint clients pass in the `X-Mirror-Key-Fingerprint` header, of a mirror
+    /// private or public key PEM file.
+    Fingerprint {
+        /// The mirror key file.
+        #[structopt(parse(from_os_str))]
+        key: PathBuf,
+
+        /// Where to read the passphrase of an encrypted private key from.
+        #[structopt(long)]
+        passphrase: Option<PassphraseSource>,
+    },
+
+    /// Export the public half of a mirror key, from a private or public key PEM file.
+    Export {
+        /// The mirror key file.
+        #[structopt(parse(from_os_str))]
+        key: PathBuf,
+
+        /// The output format: `pem` (SubjectPublicKeyInfo), `jwk` or `spki-hex` (hex-encoded
+        /// SubjectPublicKeyInfo DER).
+        #[structopt(long, default_value = "pem")]
+        format: ExportFormat,
+
+        /// Output file. The public key is printed if not provided.
+        #[structopt(long, parse(from_os_str))]
+        out: Option<PathBuf>,
+
+        /// Where to read the passphrase of an encrypted private key from.
+        #[structopt(long)]
+        passphrase: Option<PassphraseSource>,
+    },
+
+    /// Check that the mirror public key given to clients belongs to a mirror private key.
+    Verify {
+        /// The mirror private key file.
+        #[structopt(long, parse(from_os_str))]
+        private_key: PathBuf,
+
+        /// The mirror public key file given to clients.
+        #[structopt(long, parse(from_os_str))]
+        public_key: PathBuf,
+
+        /// Where to read the passphrase of an encrypted private key from.
+        #[structopt(long)]
+        passphrase: Option<PassphraseSource>,
+    },
+
+    /// Add a client signing key to a client key registry (as used with `--client-keys`),
+    /// creating the registry if it does not exist. The private side has to be restarted to pick
+    /// up the new key.
+    Register {
+        /// The client key registry file.
+        #[structopt(long, parse(from_os_str))]
+        registry: PathBuf,
+
+        /// The client name, which clients put in the `signer` field of signed requests.
+        #[structopt(long)]
+        name: String,
+
+        /// The client key type: `ed25519` or `ecdsa-p256`.
+        #[structopt(long = "type")]
+        key_type: String,
+
+        /// The hex-encoded public key.
+        #[structopt(long)]
+        public_key: Option<String>,
+
+        /// A file holding the hex-encoded public key (such as one written by `generate`), instead
+        /// of `--public-key`.
+        #[structopt(long, parse(from_os_str))]
+        public_key_file: Option<PathBuf>,
+
+        /// Replace the key of a client that is already registered.
+        #[structopt(long)]
+        force: bool,
+    },
+
+    /// Remove a client from a client key registry. The private side keeps accepting the client
+    /// until it is restarted.
+    Revoke {
+        /// The client key registry file.
+        #[structopt(long, parse(from_os_str))]
+        registry: PathBuf,
+
+        /// The client name.
+        #[structopt(long)]
+        name: String,
+    },
+}
+
+fn main() {
+    if let Err(err) = run(Command::from_args()) {
+        eprintln!("Error: {}", err);
+        std::process::exit(1);
+    }
+}
+
+fn run(command: Command) -> Result<(), String> {
+    match command {
+        Command::Generate {
+            algorithm,
+            bits,
+            out,
+            public_out,
+            passphrase,
+            force,
+        } => {
+            let (default_out, default_public_out) = algorithm.default_paths();
+            let out = out.unwrap_or_else(|| PathBuf::from(default_out));
+            let public_out = public_out.unwrap_or_else(|| PathBuf::from(default_public_out));
+            if !force {
+                for path in &[&out, &public_out] {
+                    if path.exists() {
+                        return Err(format!(
+                            "{:?} already exists, use --force to overwrite it",
+                            path
+                        ));
+                    }
+                }
+            }
+            if algorithm == KeyAlgorithm::Ed25519 && passphrase.is_some() {
+                return Err("Ed25519 keys cannot be encrypted with a passphrase".into());
+            }
+            let passphrase = passphrase.as_ref().map(read_new_passphrase).transpose()?;
+            let passphrase = passphrase.as_ref().map(|passphrase| passphrase.as_str());
+
+            let (private_key, public_key) = match algorithm {
+                KeyAlgorithm::Rsa => generate_rsa(bits, passphrase)?,
+                KeyAlgorithm::Ed25519 => generate_ed25519()?,
+                KeyAlgorithm::EcdsaP256 => generate_ecdsa_p256(passphrase)?,
+            };
+
+            write_file(&out, &private_key, true, force)?;
+            println!("Wrote private key to {:?}", out);
+            write_file(&public_out, &public_key, false, force)?;
+            println!("Wrote public key to {:?}", public_out);
+
+            if algorithm == KeyAlgorithm::Rsa {
+                let public_key = Rsa::public_key_from_pem(&public_key)
+                    .map_err(|err| format!("failed parsing public key: {}", err))?;
+                println!(
+                    "Key fingerprint: {} - clients using this key pass it in the X-Mirror-Key-Fingerprint header",
+                    key_fingerprint(&public_key)?
+                );
+            }
+            Ok(())
+        }
+
+        Command::Fingerprint { key, passphrase } => {
+            let public_key = load_public_key(&key, passphrase.as_ref())?;
+            println!("{}", key_fingerprint(&public_key)?);
+            Ok(())
+        }
+
+        Command::Export {
+            key,
+            format,
+            out,
+            passphrase,
+        } => {
+            let public_key = load_public_key(&key, passphrase.as_ref())?;
+            let exported = export_public_key(&public_key, format)?;
+            match out {
+                Some(out) => {
+                    fs::write(&out, exported)
+                        .map_err(|err| format!("failed writing {:?}: {}", out, err))?;
+                    println!("Wrote public key to {:?}", out);
+                }
+                None => println!("{}", exported.trim_end()),
+            }
+            Ok(())
+        }
+
+        Command::Verify {
+            private_key,
+            public_key,
+            passphrase,
+        } => {
+            let private_key = load_private_key(&private_key, passphrase.as_ref())?;
+            let client_key = load_public_key(&public_key, None)?;
+            if !keys_match(&private_key, &client_key) {
+                return Err(format!(
+                    "{:?} does not belong to the private key",
+                    public_key
+                ));
+            }
+            println!(
+                "{:?} belongs to the private key (fingerprint {})",
+                public_key,
+                key_fingerprint(&client_key)?
+            );
+            Ok(())
+        }
+
+        Command::Register {
+            registry,
+            name,
+            key_type,
+            public_key,
+            public_key_file,
+            force,
+        } => {
+            let public_key = match (public_key, public_key_file) {
+                (Some(public_key), None) => public_key,
+                (None, Some(path)) => fs::read_to_string(&path)
+                    .map_err(|err| format!("failed reading {:?}: {}", path, err))?
+                    .trim()
+                    .to_owned(),
+                _ => {
+                    return Err(
+                        "exactly one of --public-key and --public-key-file is required".into(),
+                    )
+                }
+            };
+            let key_config = match key_type.as_str() {
+                "ed25519" => ClientKeyConfig::Ed25519 { public_key },
+                "ecdsa-p256" => ClientKeyConfig::EcdsaP256 { public_key },
+                _ => {
+                    return Err(format!(
+                        "unknown key type {}, expected ed25519 or ecdsa-p256",
+                        key_type
+                    ))
+                }
+            };
+            register_client(&registry, &name, key_config, force)?;
+            println!(
+                "Registered {} in {:?}, restart the private side to use it",
+                name, registry
+            );
+            Ok(())
+        }
+
+        Command::Revoke { registry, name } => {
+            revoke_client(&registry, &name)?;
+            println!(
+                "Revoked {} from {:?}, restart the private side to apply it",
+                name, registry
+            );
+            Ok(())
+        }
+    }
+}
+
+/// Read the passphrase a new private key is encrypted with, asking for it twice when prompting.
+fn read_new_passphrase(source: &PassphraseSource) -> Result<Zeroizing<String>, String> {
+    let passphrase = source.read("Private key passphrase")?;
+    if *source == PassphraseSource::Prompt
+        && source.read("Confirm private key passphrase")? != passphrase
+    {
+        return Err("passphrases do not match".into());
+    }
+    Ok(passphrase)
+}
+
+/// Generate an RSA mirror key, returning the private and public keys as PEM.
+fn generate_rsa(bits: u32, passphrase: Option<&str>) -> Result<(Vec<u8>, Vec<u8>), String> {
+    if bits < MIN_RSA_BITS {
+        return Err(format!(
+            "RSA keys must be at least {} bits long",
+            MIN_RSA_BITS
+        ));
+    }
+    println!("Generating private key, this might take a few seconds...");
+    let key = Rsa::generate(bits).map_err(|err| format!("failed generating key: {}", err))?;
+    let public_key = key
+        .public_key_to_pem()
+        .map_err(|err| format!("failed encoding public key: {}", err))?;
+    let key = PKey::from_rsa(key).map_err(|err| err.to_string())?;
+    Ok((private_key_to_pem(&key, passphrase)?, public_key))
+}
+
+/// Generate an Ed25519 client key, returning the private key seed and the public key, hex-encoded.
+fn generate_ed25519() -> Result<(Vec<u8>, Vec<u8>), String> {
+    let mut seed = Zeroizing::new([0u8; 32]);
+    rand::thread_rng().fill_bytes(&mut seed[..]);
+    let private_key = Ed25519Private::try_from(&seed[..]).map_err(|err| format!("{:?}", err))?;
+    let pair = Ed25519Pair::from(private_key);
+    Ok((
+        hex::encode(&seed[..]).into_bytes(),
+        hex::encode(pair.public_key()).into_bytes(),
+    ))
+}
+
+/// Generate an ECDSA P-256 client key, returning the private key as PEM and the public key as
+/// hex-encoded SEC1.
+fn generate_ecdsa_p256(passphrase: Option<&str>) -> Result<(Vec<u8>, Vec<u8>), String> {
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).map_err(|err| err.to_string())?;
+    let key = EcKey::generate(&group).map_err(|err| format!("failed generating key: {}", err))?;
+    let mut ctx = BigNumContext::new().map_err(|err| err.to_string())?;
+    let public_key = key
+        .public_key()
+        .to_bytes(&group, PointConversionForm::UNCOMPRESSED, &mut ctx)
+        .map_err(|err| format!("failed encoding public key: {}", err))?;
+    let key = PKey::from_ec_key(key).map_err(|err| err.to_string())?;
+    Ok((
+        private_key_to_pem(&key, passphrase)?,
+        hex::encode(public_key).into_bytes(),
+    ))
+}
+
+/// Encode a private key as PKCS#8 PEM, encrypted if a passphrase is given.
+fn private_key_to_pem(key: &PKey<Private>, passphrase: Option<&str>) -> Result<Vec<u8>, String> {
+    match passphrase {
+        Some(passphrase) => {
+            key.private_key_to_pem_pkcs8_passphrase(Cipher::aes_256_cbc(), passphrase.as_bytes())
+        }
+        None => key.private_key_to_pem_pkcs8(),
+    }
+    .map_err(|err| format!("failed encoding private key: {}", err))
+}
+
+/// Write a file, refusing to overwrite it unless forced. Private keys are only readable by their
+/// owner.
+fn write_file(path: &Path, contents: &[u8], private: bool, force: bool) -> Result<(), String> {
+    let mut options = OpenOptions::new();
+    options.write(true);
+    if force {
+        options.create(true).truncate(true);
+    } else {
+        options.create_new(true);
+    }
+    if private {
+        options.mode(0o600);
+    }
+
+    let mut file = options.open(path).map_err(|err| match err.kind() {
+        ErrorKind::AlreadyExists => {
+            format!("{:?} already exists, use --force to overwrite it", path)
+        }
+        _ => format!("failed writing {:?}: {}", path, err),
+    })?;
+    // The mode only applies to new files, so a file overwritten with --force keeps its
+    // permissions unless they are set here.
+    if private {
+        file.set_permissions(Permissions::from_mode(0o600))
+            .map_err(|err| format!("failed setting permissions of {:?}: {}", path, err))?;
+    }
+    file.write_all(contents)
+        .map_err(|err| format!("failed writing {:?}: {}", path, err))
+}
+
+/// Load a mirror private key from a PEM file.
+fn load_private_key(
+    path: &Path,
+    passphrase: Option<&PassphraseSource>,
+) -> Result<Rsa<Private>, String> {
+    let pem = Zeroizing::new(
+        fs::read_to_string(path).map_err(|err| format!("failed reading {:?}: {}", path, err))?,
+    );
+    let passphrase = match passphrase {
+        Some(source) => source.read("Private key passphrase")?,
+        None if pem.contains("ENCRYPTED") => {
+            return Err(format!(
+                "{:?} is encrypted, but no passphrase was provided",
+                path
+            ))
+        }
+        None => Zeroizing::new(String::new()),
+    };
+    PKey::private_key_from_pem_passphrase(pem.as_bytes(), passphrase.as_bytes())
+        .and_then(|key| key.rsa())
+        .map_err(|err| format!("failed parsing private key {:?}: {}", path, err))
+}
+
+/// Load the public half of a mirror key from a PEM file holding either the private key or the
+/// public key.
+fn load_public_key(
+    path: &Path,
+    passphrase: Option<&PassphraseSource>,
+) -> Result<Rsa<Public>, String> {
+    let pem = Zeroizing::new(
+        fs::read_to_string(path).map_err(|err| format!("failed reading {:?}: {}", path, err))?,
+    );
+    if pem.contains("PRIVATE KEY") {
+        let key = load_private_key(path, passphrase)?;
+        let n = key.n().to_owned().map_err(|err| err.to_string())?;
+        let e = key.e().to_owned().map_err(|err| err.to_string())?;
+        return Rsa::from_public_components(n, e).map_err(|err| err.to_string());
+    }
+
+    if pem.contains("BEGIN RSA PUBLIC KEY") {
+        Rsa::public_key_from_pem_pkcs1(pem.as_bytes())
+    } else {
+        Rsa::public_key_from_pem(pem.as_bytes())
+    }
+    .map_err(|err| format!("failed parsing public key {:?}: {}", path, err))
+}
+
+/// Encode a mirror public key in the given format.
+fn export_public_key(key: &Rsa<Public>, format: ExportFormat) -> Result<String, String> {
+    match format {
+        ExportFormat::Pem => {
+            let pem = key.public_key_to_pem().map_err(|err| err.to_string())?;
+            String::from_utf8(pem).map_err(|err| err.to_string())
+        }
+        ExportFormat::Jwk => {
+            let jwk = json!({
+                "kty": "RSA",
+                "kid": key_fingerprint(key)?,
+                "n": base64::encode_config(key.n().to_vec(), base64::URL_SAFE_NO_PAD),
+                "e": base64::encode_config(key.e().to_vec(), base64::URL_SAFE_NO_PAD),
+            });
+            serde_json::to_string_pretty(&jwk).map_err(|err| err.to_string())
+        }
+        ExportFormat::SpkiHex => {
+            let der = key.public_key_to_der().map_err(|err| err.to_string())?;
+            Ok(hex::encode(der))
+        }
+    }
+}
+
+fn keys_match(private_key: &Rsa<Private>, public_key: &Rsa<Public>) -> bool {
+    private_key.n() == public_key.n() && private_key.e() == public_key.e()
+}
+
+/// Add (or, if forced, replace) a client key in a registry file.
+fn register_client(
+    registry_path: &Path,
+    name: &str,
+    key_config: ClientKeyConfig,
+    force: bool,
+) -> Result<(), String> {
+    key_config
+        .parse()
+        .map_err(|err| format!("invalid public key: {}", err))?;
+
+    let mut registry = if registry_path.exists() {
+        ClientRegistry::load(registry_path)?
+    } else {
+        ClientRegistry::default()
+    };
+    if registry.clients.contains_key(name) && !force {
+        return Err(format!(
+            "{} is already registered, use --force to replace its key",
+            name
+        ));
+    }
+    registry.clients.insert(name.to_owned(), key_config);
+    registry.save(registry_path)
+}
+
+/// Remove a client from a registry file.
+fn revoke_client(registry_path: &Path, name: &str) -> Result<(), String> {
+    let mut registry = ClientRegistry::load(registry_path)?;
+    if registry.clients.remove(name).is_none() {
+        return Err(format!("{} is not registered", name));
+    }
+    registry.save(registry_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+
+    #[test]
+    fn public_keys_are_exported_in_every_format() {
+        let (private_pem, public_pem) = generate_rsa(2048, None).unwrap();
+        let private_key = PKey::private_key_from_pem(&private_pem)
+            .and_then(|key| key.rsa())
+            .unwrap();
+        let public_key = Rsa::public_key_from_pem(&public_pem).unwrap();
+        assert!(keys_match(&private_key, &public_key));
+        assert!(!keys_match(&Rsa::generate(2048).unwrap(), &public_key));
+
+        let pem = export_public_key(&public_key, ExportFormat::Pem).unwrap();
+        assert_eq!(pem.as_bytes(), &public_pem[..]);
+
+        let spki =
+            hex::decode(export_public_key(&public_key, ExportFormat::SpkiHex).unwrap()).unwrap();
+        assert_eq!(Rsa::public_key_from_der(&spki).unwrap().n(), public_key.n());
+
+        let jwk: Value =
+            serde_json::from_str(&export_public_key(&public_key, ExportFormat::Jwk).unwrap())
+                .unwrap();
+        assert_eq!(jwk["kty"], "RSA");
+        assert_eq!(jwk["kid"], key_fingerprint(&public_key).unwrap());
+        assert_eq!(jwk["e"], "AQAB");
+        let n = base64::decode_config(jwk["n"].as_str().unwrap(), base64::URL_SAFE_NO_PAD).unwrap();
+        assert_eq!(n, public_key.n().to_vec());
+    }
+
+    #[test]
+    fn clients_are_registered_and_revoked() {
+        let registry_path = std::env::temp_dir().join("mirror-keytool-test-registry.json");
+        let _ = fs::remove_file(&registry_path);
+        let (_, public_key) = generate_ed25519().unwrap();
+        let key_config = ClientKeyConfig::Ed25519 {
+            public_key: String::from_utf8(public_key).unwrap(),
+        };
+
+        register_client(&registry_path, "acme-app", key_config.clone(), false).unwrap();
+        assert!(register_client(&registry_path, "acme-app", key_config.clone(), false).is_err());
+        register_client(&registry_path, "acme-app", key_config.clone(), true).unwrap();
+        assert!(register_client(
+            &registry_path,
+            "ops",
+            ClientKeyConfig::EcdsaP256 {
+                public_key: "04abcd".to_owned()
+            },
+            false
+        )
+        .is_err());
+
+        let registry = ClientRegistry::load(&registry_path).unwrap();
+        assert_eq!(registry.clients.len(), 1);
+        assert_eq!(registry.clients["acme-app"], key_config);
+
+        revoke_client(&registry_path, "acme-app").unwrap();
+        assert!(revoke_client(&registry_path, "acme-app").is_err());
+        assert!(ClientRegistry::load(&registry_path)
+            .unwrap()
+            .clients
+            .is_empty());
+    }
+
+    #[test]
+    fn overwritten_private_files_are_only_readable_by_their_owner() {
+        let path =
+            std::env::temp_dir().join(format!("mirror-keytool-test-{}.pem", std::process::id()));
+        fs::write(&path, b"old").unwrap();
+        fs::set_permissions(&path, Permissions::from_mode(0o644)).unwrap();
+
+        assert!(write_file(&path, b"new", true, false).is_err());
+        write_file(&path, b"new", true, true).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"new");
+        assert_eq!(
+            fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+        fs::remove_file(&path).unwrap();
+    }
+}
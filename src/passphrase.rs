@@ -0,0 +1,103 @@
+// Copyright (c) 2018-2022 MobileCoin Inc.
+
+//! Passphrases protecting mirror private keys at rest.
+
+use std::{
+    env, fs,
+    io::{self, Write},
+    path::PathBuf,
+    process::{Command, Stdio},
+    str::FromStr,
+};
+use zeroize::Zeroizing;
+
+/// Where a passphrase is read from: `file:<path>`, `env:<variable>` or `prompt`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum PassphraseSource {
+    /// The first line of a file.
+    File(PathBuf),
+
+    /// An environment variable, which is removed from the environment once read.
+    Env(String),
+
+    /// An interactive prompt on the terminal.
+    Prompt,
+}
+
+impl FromStr for PassphraseSource {
+    type Err = String;
+
+    fn from_str(src: &str) -> Result<Self, Self::Err> {
+        if src == "prompt" {
+            return Ok(PassphraseSource::Prompt);
+        }
+        if let Some(path) = src.strip_prefix("file:") {
+            return Ok(PassphraseSource::File(PathBuf::from(path)));
+        }
+        if let Some(variable) = src.strip_prefix("env:") {
+            return Ok(PassphraseSource::Env(variable.to_owned()));
+        }
+        Err(format!(
+            "invalid passphrase source {}, expected file:<path>, env:<variable> or prompt",
+            src
+        ))
+    }
+}
+
+impl PassphraseSource {
+    /// Read the passphrase, showing the given prompt if it is read interactively. The passphrase
+    /// is zeroized once dropped.
+    pub fn read(&self, prompt: &str) -> Result<Zeroizing<String>, String> {
+        let passphrase = match self {
+            PassphraseSource::File(path) => {
+                let contents = Zeroizing::new(fs::read_to_string(path).map_err(|err| {
+                    format!("failed reading passphrase file {:?}: {}", path, err)
+                })?);
+                Zeroizing::new(contents.lines().next().unwrap_or("").to_owned())
+            }
+            PassphraseSource::Env(variable) => {
+                let passphrase = Zeroizing::new(env::var(variable).map_err(|err| {
+                    format!("failed reading passphrase from ${}: {}", variable, err)
+                })?);
+                // Child processes have no business seeing it.
+                env::remove_var(variable);
+                passphrase
+            }
+            PassphraseSource::Prompt => prompt_passphrase(prompt)?,
+        };
+
+        if passphrase.is_empty() {
+            return Err("empty passphrase".into());
+        }
+        Ok(passphrase)
+    }
+}
+
+fn prompt_passphrase(prompt: &str) -> Result<Zeroizing<String>, String> {
+    eprint!("{}: ", prompt);
+    io::stderr().flush().map_err(|err| err.to_string())?;
+
+    // Typed passphrases are not echoed, if stdin is a terminal.
+    let echo_disabled = set_echo(false);
+    let mut passphrase = Zeroizing::new(String::with_capacity(256));
+    let result = io::stdin().read_line(&mut passphrase);
+    if echo_disabled {
+        set_echo(true);
+        eprintln!();
+    }
+    result.map_err(|err| format!("failed reading passphrase: {}", err))?;
+
+    Ok(Zeroizing::new(
+        passphrase.trim_end_matches(&['\r', '\n'][..]).to_owned(),
+    ))
+}
+
+/// Turn terminal echo on or off, returning whether it succeeded.
+fn set_echo(enabled: bool) -> bool {
+    Command::new("stty")
+        .arg(if enabled { "echo" } else { "-echo" })
+        .stdin(Stdio::inherit())
+        .stderr(Stdio::null())
+        .status()
+        .map_or(false, |status| status.success())
+}
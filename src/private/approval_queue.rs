@@ -0,0 +1,635 @@
+// Copyright (c) 2018-2022 MobileCoin Inc.
+
+//! Multi-party approval of requests for methods that are not safe to expose directly, such as
+//! transaction submission. Such requests are queued in the approval spool and only forwarded to
+//! full-service once enough approvers have signed them using the `mirror-approve` tool.
+//!
+//! The approval config is a JSON file of the form:
+//! ```json
+//! {
+//!     "methods": ["build_and_submit_transaction"],
+//!     "threshold": 2,
+//!     "approvers": {
+//!         "alice": "<hex-encoded Ed25519 public key>",
+//!         "bob": "<hex-encoded Ed25519 public key>",
+//!         "carol": "<hex-encoded Ed25519 public key>"
+//!     },
+//!     "spool_dir": "/var/lib/wallet-service-mirror/approvals",
+//!     "expiry_secs": 86400,
+//!     "max_pending": 100,
+//!     "completed_retention_secs": 604800
+//! }
+//! ```
+//!
+//! Once `max_pending` requests are waiting for approval, further requests are rejected. The
+//! outcome of a request can be polled for `completed_retention_secs` after it completes.
+
+use crate::{
+    json_rpc::{JsonRpcError, JSON_RPC_INTERNAL_ERROR, JSON_RPC_INVALID_PARAMS},
+    request::Client,
+};
+use mc_common::logger::{log, Logger};
+use mc_crypto_keys::{Ed25519Public, Ed25519Signature, Verifier};
+use mc_wallet_service_mirror::approval::{
+    approval_message, generate_approval_id, is_valid_approval_id, now_timestamp, ApprovalSpool,
+    ApprovalStatus, CompletedApproval, PendingApproval,
+};
+use serde_derive::Deserialize;
+use serde_json::{json, Value};
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// The mirror-specific method clients use to poll the status of a request that needs approval.
+pub const GET_APPROVAL_STATUS_METHOD: &str = "get_approval_status";
+
+/// JSON-RPC error code for a request that cannot be queued because too many requests are waiting
+/// for approval.
+pub const JSON_RPC_APPROVAL_QUEUE_FULL: i64 = -32004;
+
+/// How often are the outcomes of completed requests checked for removal?
+const COMPLETED_PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+fn default_expiry_secs() -> u64 {
+    24 * 60 * 60
+}
+
+fn default_max_pending() -> usize {
+    100
+}
+
+fn default_completed_retention_secs() -> u64 {
+    7 * 24 * 60 * 60
+}
+
+#[derive(Deserialize)]
+struct ApprovalConfig {
+    /// Methods that require approval.
+    methods: Vec<String>,
+
+    /// How many distinct approvers need to sign a request.
+    threshold: usize,
+
+    /// Map of approver name -> hex-encoded Ed25519 public key.
+    approvers: HashMap<String, String>,
+
+    /// The approval spool directory.
+    spool_dir: PathBuf,
+
+    /// How long a request may wait for approval.
+    #[serde(default = "default_expiry_secs")]
+    expiry_secs: u64,
+
+    /// How many requests may wait for approval at the same time.
+    #[serde(default = "default_max_pending")]
+    max_pending: usize,
+
+    /// How long the outcome of a request is kept for after it completes.
+    #[serde(default = "default_completed_retention_secs")]
+    completed_retention_secs: u64,
+}
+
+/// The queue of requests waiting for approval.
+#[derive(Clone, Debug)]
+pub struct ApprovalQueue {
+    methods: Vec<String>,
+    threshold: usize,
+    approvers: HashMap<String, Ed25519Public>,
+    spool: ApprovalSpool,
+    expiry_secs: u64,
+    max_pending: usize,
+    completed_retention: Duration,
+    last_pruned: Arc<Mutex<Instant>>,
+}
+
+impl ApprovalQueue {
+    /// Load the approval config from a JSON file.
+    pub fn load(src: &str) -> Result<Self, String> {
+        let config_str = std::fs::read_to_string(src)
+            .map_err(|err| format!("failed reading approval config {}: {:?}", src, err))?;
+        let config: ApprovalConfig = serde_json::from_str(&config_str)
+            .map_err(|err| format!("failed parsing approval config {}: {}", src, err))?;
+
+        let approvers = config
+            .approvers
+            .iter()
+            .map(|(name, key_hex)| {
+                let key_bytes = hex::decode(key_hex)
+                    .map_err(|err| format!("invalid key for approver {}: {}", name, err))?;
+                let key = Ed25519Public::try_from(&key_bytes[..])
+                    .map_err(|err| format!("invalid key for approver {}: {:?}", name, err))?;
+                Ok((name.clone(), key))
+            })
+            .collect::<Result<HashMap<_, _>, String>>()?;
+
+        if config.threshold == 0 || config.threshold > approvers.len() {
+            return Err(format!(
+                "approval threshold must be between 1 and the number of approvers ({})",
+                approvers.len()
+            ));
+        }
+
+        Ok(Self {
+            methods: config.methods,
+            threshold: config.threshold,
+            approvers,
+            spool: ApprovalSpool::new(&config.spool_dir)?,
+            expiry_secs: config.expiry_secs,
+            max_pending: config.max_pending,
+            completed_retention: Duration::from_secs(config.completed_retention_secs),
+            last_pruned: Arc::new(Mutex::new(Instant::now())),
+        })
+    }
+
+    /// Returns true if requests for the given method need to be approved.
+    pub fn requires_approval(&self, method: &str) -> bool {
+        self.methods.iter().any(|m| m == method)
+    }
+
+    /// Check that there is room for another request in the queue, which needs to be done before
+    /// `submit`.
+    pub fn check_capacity(&self) -> Result<(), JsonRpcError> {
+        let pending_count = self
+            .spool
+            .pending_count()
+            .map_err(|err| JsonRpcError::new(JSON_RPC_INTERNAL_ERROR, err))?;
+        if pending_count >= self.max_pending {
+            return Err(JsonRpcError::new(
+                JSON_RPC_APPROVAL_QUEUE_FULL,
+                format!(
+                    "Approval queue full: {} requests are waiting for approval",
+                    pending_count
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Queue a request for approval, returning the JSON-RPC result to hand back to the client.
+    pub fn submit(
+        &self,
+        client: &Client,
+        method: &str,
+        request: &Value,
+        logger: &Logger,
+    ) -> Result<Value, String> {
+        let pending = PendingApproval {
+            approval_id: generate_approval_id(),
+            method: method.to_owned(),
+            json_request: request.to_string(),
+            submitted_at: now_timestamp(),
+            transport: client.transport.to_owned(),
+            signer: client.signer.map(str::to_owned),
+        };
+        self.spool.add_pending(&pending)?;
+
+        log::info!(
+            logger,
+            "Request {} ({}) queued for approval",
+            pending.approval_id,
+            method
+        );
+
+        Ok(json!({
+            "approval_id": pending.approval_id,
+            "status": "pending",
+            "approvals": 0,
+            "threshold": self.threshold,
+        }))
+    }
+
+    /// Returns the JSON-RPC result describing the status of a request, and the completed
+    /// approval if the request is no longer pending.
+    pub fn status(
+        &self,
+        approval_id: &str,
+    ) -> Result<(Value, Option<CompletedApproval>), JsonRpcError> {
+        let internal_error = |err: String| JsonRpcError::new(JSON_RPC_INTERNAL_ERROR, err);
+
+        if !is_valid_approval_id(approval_id) {
+            return Err(JsonRpcError::new(
+                JSON_RPC_INVALID_PARAMS,
+                format!("Invalid params: malformed approval id \"{}\"", approval_id),
+            ));
+        }
+
+        if let Some(completed) = self
+            .spool
+            .get_completed(approval_id)
+            .map_err(internal_error)?
+        {
+            let response = completed
+                .json_response
+                .as_ref()
+                .and_then(|json_response| serde_json::from_str::<Value>(json_response).ok());
+            let result = json!({
+                "approval_id": approval_id,
+                "status": completed.status,
+                "approvers": completed.approvers,
+                "threshold": self.threshold,
+                "response": response,
+                "error": completed.error,
+            });
+            return Ok((result, Some(completed)));
+        }
+
+        // A request that is being forwarded right now is reported as still pending.
+        let pending = self
+            .spool
+            .get_pending(approval_id)
+            .map_err(internal_error)?;
+        let processing = self
+            .spool
+            .processing()
+            .map_err(internal_error)?
+            .into_iter()
+            .any(|processing| processing.approval_id == approval_id);
+        if pending.is_none() && !processing {
+            return Err(JsonRpcError::new(
+                JSON_RPC_INVALID_PARAMS,
+                format!("Invalid params: unknown approval id \"{}\"", approval_id),
+            ));
+        }
+
+        let approvers = match pending {
+            Some(pending) => self.valid_approvers(&pending).map_err(internal_error)?,
+            None => vec![],
+        };
+        Ok((
+            json!({
+                "approval_id": approval_id,
+                "status": "pending",
+                "approvals": approvers.len(),
+                "threshold": self.threshold,
+            }),
+            None,
+        ))
+    }
+
+    /// Mark requests that were being forwarded when the mirror last stopped as interrupted. These
+    /// are never retried automatically, since full-service may have already received them.
+    pub fn recover_interrupted(&self, logger: &Logger) -> Result<(), String> {
+        for pending in self.spool.processing()? {
+            log::warn!(
+                logger,
+                "Request {} ({}) was interrupted while being forwarded, it will not be retried",
+                pending.approval_id,
+                pending.method
+            );
+            self.spool.complete(&CompletedApproval {
+                approval_id: pending.approval_id,
+                method: pending.method,
+                status: ApprovalStatus::Interrupted,
+                approvers: vec![],
+                json_response: None,
+                error: Some("interrupted while forwarding to full-service".to_owned()),
+                completed_at: now_timestamp(),
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Forward every request that has gathered enough approvals, and expire requests that have
+    /// waited too long.
+    pub fn process_pending(
+        &self,
+        forward: impl Fn(&PendingApproval) -> Result<String, String>,
+        logger: &Logger,
+    ) -> Result<(), String> {
+        self.prune_completed()?;
+
+        let now = now_timestamp();
+
+        for pending in self.spool.pending()? {
+            let approvers = self.valid_approvers(&pending)?;
+
+            if approvers.len() < self.threshold {
+                if now >= pending.submitted_at + self.expiry_secs {
+                    log::info!(
+                        logger,
+                        "Request {} ({}) expired with {} of {} approvals",
+                        pending.approval_id,
+                        pending.method,
+                        approvers.len(),
+                        self.threshold
+                    );
+                    let completed = CompletedApproval {
+                        approval_id: pending.approval_id,
+                        method: pending.method,
+                        status: ApprovalStatus::Expired,
+                        approvers,
+                        json_response: None,
+                        error: None,
+                        completed_at: now,
+                    };
+                    self.spool.complete(&completed)?;
+                }
+                continue;
+            }
+
+            if !self.spool.claim(&pending.approval_id)? {
+                continue;
+            }
+
+            log::info!(
+                logger,
+                "Request {} ({}) approved by {:?}, forwarding to full-service",
+                pending.approval_id,
+                pending.method,
+                approvers
+            );
+
+            let (status, json_response, error) = match forward(&pending) {
+                Ok(json_response) => (ApprovalStatus::Approved, Some(json_response), None),
+                Err(err) => {
+                    log::error!(
+                        logger,
+                        "Forwarding approved request {} failed: {}",
+                        pending.approval_id,
+                        err
+                    );
+                    (ApprovalStatus::Failed, None, Some(err))
+                }
+            };
+
+            let completed = CompletedApproval {
+                approval_id: pending.approval_id,
+                method: pending.method,
+                status,
+                approvers,
+                json_response,
+                error,
+                completed_at: now_timestamp(),
+            };
+            self.spool.complete(&completed)?;
+        }
+
+        Ok(())
+    }
+
+    /// Remove the outcomes of requests past the retention period, at most once per prune interval.
+    fn prune_completed(&self) -> Result<(), String> {
+        {
+            let mut last_pruned = self.last_pruned.lock().expect("mutex poisoned");
+            if last_pruned.elapsed() < COMPLETED_PRUNE_INTERVAL {
+                return Ok(());
+            }
+            *last_pruned = Instant::now();
+        }
+        self.spool.prune_completed(self.completed_retention)
+    }
+
+    /// Returns the names of the approvers that have validly signed a request.
+    fn valid_approvers(&self, pending: &PendingApproval) -> Result<Vec<String>, String> {
+        let message = approval_message(&pending.approval_id, &pending.json_request);
+
+        let mut approvers: Vec<String> = self
+            .spool
+            .signatures(&pending.approval_id)?
+            .into_iter()
+            .filter(|(approver, signature_bytes)| {
+                let key = match self.approvers.get(approver) {
+                    Some(key) => key,
+                    None => return false,
+                };
+                Ed25519Signature::try_from(&signature_bytes[..])
+                    .map(|signature| key.verify(&message, &signature).is_ok())
+                    .unwrap_or(false)
+            })
+            .map(|(approver, _)| approver)
+            .collect();
+        approvers.sort();
+        approvers.dedup();
+        Ok(approvers)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mc_common::logger::{test_with_logger, Logger};
+    use mc_crypto_keys::{Ed25519Pair, Ed25519Private, Signer};
+    use std::cell::RefCell;
+
+    fn approver_pair(seed: u8) -> Ed25519Pair {
+        Ed25519Pair::from(Ed25519Private::try_from(&[seed; 32][..]).unwrap())
+    }
+
+    fn test_queue(name: &str, threshold: usize) -> ApprovalQueue {
+        let dir = std::env::temp_dir().join(format!(
+            "mirror-approval-queue-test-{}-{}",
+            name,
+            generate_approval_id()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let config = json!({
+            "methods": ["build_and_submit_transaction"],
+            "threshold": threshold,
+            "approvers": {
+                "alice": hex::encode(approver_pair(1).public_key()),
+                "bob": hex::encode(approver_pair(2).public_key()),
+            },
+            "spool_dir": dir.join("spool"),
+        });
+        let config_path = dir.join("approval.json");
+        std::fs::write(&config_path, config.to_string()).unwrap();
+
+        ApprovalQueue::load(config_path.to_str().unwrap()).unwrap()
+    }
+
+    fn sign(queue: &ApprovalQueue, approval_id: &str, approver: &str, pair: &Ed25519Pair) {
+        let pending = queue.spool.get_pending(approval_id).unwrap().unwrap();
+        let signature = pair.sign(&approval_message(approval_id, &pending.json_request));
+        queue
+            .spool
+            .add_signature(approval_id, approver, signature.as_ref())
+            .unwrap();
+    }
+
+    #[test_with_logger]
+    fn request_is_forwarded_once_threshold_is_reached(logger: Logger) {
+        let queue = test_queue("threshold", 2);
+        let request = json!({"jsonrpc": "2.0", "method": "build_and_submit_transaction", "params": {}, "id": 1});
+
+        let result = queue
+            .submit(
+                &Client::unencrypted(),
+                "build_and_submit_transaction",
+                &request,
+                &logger,
+            )
+            .unwrap();
+        let approval_id = result["approval_id"].as_str().unwrap().to_owned();
+
+        let forwarded = RefCell::new(Vec::new());
+        let forward = |pending: &PendingApproval| {
+            forwarded.borrow_mut().push(pending.json_request.clone());
+            Ok(r#"{"jsonrpc": "2.0", "result": {"ok": true}, "id": 1}"#.to_owned())
+        };
+
+        // One valid signature, one signature by an unknown approver and one made with the wrong
+        // key are not enough.
+        sign(&queue, &approval_id, "alice", &approver_pair(1));
+        sign(&queue, &approval_id, "mallory", &approver_pair(3));
+        sign(&queue, &approval_id, "bob", &approver_pair(4));
+        queue.process_pending(forward, &logger).unwrap();
+        assert!(forwarded.borrow().is_empty());
+
+        let (status, completed) = queue.status(&approval_id).unwrap();
+        assert_eq!(status["status"], "pending");
+        assert_eq!(status["approvals"], 1);
+        assert!(completed.is_none());
+
+        sign(&queue, &approval_id, "bob", &approver_pair(2));
+        queue.process_pending(forward, &logger).unwrap();
+        assert_eq!(*forwarded.borrow(), vec![request.to_string()]);
+
+        let (status, completed) = queue.status(&approval_id).unwrap();
+        assert_eq!(status["status"], "approved");
+        assert_eq!(status["approvers"], json!(["alice", "bob"]));
+        assert_eq!(status["response"]["result"]["ok"], true);
+        assert_eq!(completed.unwrap().status, ApprovalStatus::Approved);
+
+        // Requests are only ever forwarded once.
+        queue.process_pending(forward, &logger).unwrap();
+        assert_eq!(forwarded.borrow().len(), 1);
+    }
+
+    #[test_with_logger]
+    fn interrupted_requests_are_not_retried(logger: Logger) {
+        let queue = test_queue("interrupted", 1);
+        let request = json!({"jsonrpc": "2.0", "method": "build_and_submit_transaction", "id": 1});
+
+        let result = queue
+            .submit(
+                &Client::unencrypted(),
+                "build_and_submit_transaction",
+                &request,
+                &logger,
+            )
+            .unwrap();
+        let approval_id = result["approval_id"].as_str().unwrap().to_owned();
+        sign(&queue, &approval_id, "alice", &approver_pair(1));
+
+        // Simulate a restart in the middle of forwarding.
+        assert!(queue.spool.claim(&approval_id).unwrap());
+        queue.recover_interrupted(&logger).unwrap();
+
+        queue
+            .process_pending(|_| panic!("should not forward"), &logger)
+            .unwrap();
+        let (status, _) = queue.status(&approval_id).unwrap();
+        assert_eq!(status["status"], "interrupted");
+    }
+
+    #[test_with_logger]
+    fn failed_requests_are_reported_as_complete(logger: Logger) {
+        let queue = test_queue("failed", 1);
+        let request = json!({"jsonrpc": "2.0", "method": "build_and_submit_transaction", "id": 1});
+
+        let result = queue
+            .submit(
+                &Client::encrypted(Some("acme-app")),
+                "build_and_submit_transaction",
+                &request,
+                &logger,
+            )
+            .unwrap();
+        let approval_id = result["approval_id"].as_str().unwrap().to_owned();
+        sign(&queue, &approval_id, "alice", &approver_pair(1));
+
+        // The forwarder gets to know who submitted the request.
+        let submitters = RefCell::new(Vec::new());
+        queue
+            .process_pending(
+                |pending| {
+                    submitters
+                        .borrow_mut()
+                        .push((pending.transport.clone(), pending.signer.clone()));
+                    Err("full-service is down".to_owned())
+                },
+                &logger,
+            )
+            .unwrap();
+        assert_eq!(
+            submitters.into_inner(),
+            vec![("encrypted".to_owned(), Some("acme-app".to_owned()))]
+        );
+
+        let (status, completed) = queue.status(&approval_id).unwrap();
+        assert_eq!(status["status"], "failed");
+        assert_eq!(status["error"], "full-service is down");
+        assert_eq!(completed.unwrap().status, ApprovalStatus::Failed);
+    }
+
+    #[test]
+    fn unknown_approval_id_is_invalid_params() {
+        let queue = test_queue("unknown", 1);
+        assert_eq!(
+            queue.status("0123456789abcdef").unwrap_err().code,
+            JSON_RPC_INVALID_PARAMS
+        );
+    }
+
+    #[test]
+    fn malformed_approval_id_is_invalid_params() {
+        let queue = test_queue("malformed", 1);
+        assert_eq!(
+            queue.status("../pending/x").unwrap_err().code,
+            JSON_RPC_INVALID_PARAMS
+        );
+    }
+
+    #[test_with_logger]
+    fn requests_are_rejected_once_the_queue_is_full(logger: Logger) {
+        let mut queue = test_queue("full", 1);
+        queue.max_pending = 2;
+        let request = json!({"jsonrpc": "2.0", "method": "build_and_submit_transaction", "params": {}, "id": 1});
+
+        for _ in 0..2 {
+            queue.check_capacity().unwrap();
+            queue
+                .submit(
+                    &Client::unencrypted(),
+                    "build_and_submit_transaction",
+                    &request,
+                    &logger,
+                )
+                .unwrap();
+        }
+        assert_eq!(
+            queue.check_capacity().unwrap_err().code,
+            JSON_RPC_APPROVAL_QUEUE_FULL
+        );
+    }
+
+    #[test_with_logger]
+    fn completed_requests_are_pruned_after_the_retention_period(logger: Logger) {
+        let mut queue = test_queue("pruned", 1);
+        queue.completed_retention = Duration::from_secs(0);
+        let result = queue
+            .submit(
+                &Client::unencrypted(),
+                "build_and_submit_transaction",
+                &json!({"jsonrpc": "2.0", "method": "build_and_submit_transaction", "params": {}, "id": 1}),
+                &logger,
+            )
+            .unwrap();
+        let approval_id = result["approval_id"].as_str().unwrap().to_owned();
+        sign(&queue, &approval_id, "alice", &approver_pair(1));
+        queue
+            .process_pending(|_| Err("unreachable".to_owned()), &logger)
+            .unwrap();
+        assert!(queue.spool.get_completed(&approval_id).unwrap().is_some());
+
+        std::thread::sleep(Duration::from_millis(10));
+        *queue.last_pruned.lock().unwrap() -= COMPLETED_PRUNE_INTERVAL;
+        queue.prune_completed().unwrap();
+        assert!(queue.spool.get_completed(&approval_id).unwrap().is_none());
+    }
+}
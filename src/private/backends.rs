@@ -0,0 +1,379 @@
+// Copyright (c) 2018-2022 MobileCoin Inc.
+
+//! Routing of requests among one or more full-service instances.
+//!
+//! Strategies:
+//! - `failover`: requests go to the first backend that is up, moving on to the next one when a
+//!   backend cannot be connected to.
+//! - `round-robin`: read methods are spread among the backends that are up, other methods are
+//!   routed as with `failover`.
+//! - `account-id`: requests with an `account_id` param go to the backend holding that account
+//!   (as reported by `get_all_accounts`), other requests are routed as with `failover`.
+//!
+//! Backends are periodically health checked, and backends that are down are only tried once
+//! every other backend failed.
+
+use crate::hardening::ZeroizingValue;
+use mc_common::logger::{log, Logger};
+use serde_json::{json, Value};
+use std::{
+    collections::HashSet,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+use zeroize::Zeroizing;
+
+/// How long do we wait for full-service to reply?
+const FULL_SERVICE_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// How long do we wait for full-service to reply to a health check?
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How requests are routed among backends.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RoutingStrategy {
+    Failover,
+    RoundRobin,
+    AccountId,
+}
+
+impl FromStr for RoutingStrategy {
+    type Err = String;
+
+    fn from_str(src: &str) -> Result<Self, Self::Err> {
+        match src {
+            "failover" => Ok(Self::Failover),
+            "round-robin" => Ok(Self::RoundRobin),
+            "account-id" => Ok(Self::AccountId),
+            _ => Err(format!(
+                "unknown routing strategy \"{}\" (expected failover, round-robin or account-id)",
+                src
+            )),
+        }
+    }
+}
+
+struct Backend {
+    uri: String,
+
+    /// False if the last request or health check failed to connect.
+    healthy: AtomicBool,
+
+    /// The accounts held by the backend, as of the last health check. Only tracked with the
+    /// `account-id` strategy.
+    account_ids: Mutex<HashSet<String>>,
+}
+
+enum PostError {
+    /// The backend could not be connected to, so the request was never received.
+    Connect(String),
+
+    Other(String),
+}
+
+impl From<PostError> for String {
+    fn from(src: PostError) -> String {
+        match src {
+            PostError::Connect(err) | PostError::Other(err) => err,
+        }
+    }
+}
+
+/// The full-service instances requests are forwarded to.
+#[derive(Clone)]
+pub struct FullServiceBackends {
+    backends: Arc<Vec<Backend>>,
+    strategy: RoutingStrategy,
+    next_backend: Arc<AtomicUsize>,
+}
+
+impl FullServiceBackends {
+    pub fn new(uris: Vec<String>, strategy: RoutingStrategy) -> Self {
+        assert!(
+            !uris.is_empty(),
+            "at least one full-service URI is required"
+        );
+        Self {
+            backends: Arc::new(
+                uris.into_iter()
+                    .map(|uri| Backend {
+                        uri,
+                        healthy: AtomicBool::new(true),
+                        account_ids: Mutex::new(HashSet::new()),
+                    })
+                    .collect(),
+            ),
+            strategy,
+            next_backend: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Pass a single JSON request along to full-service, returning the response body, which is
+    /// zeroized once dropped.
+    pub fn call(&self, json_request: &str) -> Result<Zeroizing<String>, String> {
+        let request = ZeroizingValue(serde_json::from_str(json_request).unwrap_or(Value::Null));
+
+        let mut last_err = None;
+        for index in self.candidates(&request) {
+            let backend = &self.backends[index];
+            match post(&backend.uri, json_request, FULL_SERVICE_TIMEOUT) {
+                Ok(json_response) => {
+                    backend.healthy.store(true, Ordering::SeqCst);
+                    return Ok(json_response);
+                }
+                // Only requests that never reached a backend are retried, since anything else may
+                // have been acted upon.
+                Err(PostError::Connect(err)) => {
+                    backend.healthy.store(false, Ordering::SeqCst);
+                    last_err = Some(err);
+                }
+                Err(PostError::Other(err)) => return Err(err),
+            }
+        }
+        Err(last_err.unwrap_or_else(|| "No full-service backends".into()))
+    }
+
+    /// Call a full-service method, returning the parsed response. JSON-RPC error responses are
+    /// returned as errors.
+    pub fn call_method(&self, method: &str, params: Option<Value>) -> Result<Value, String> {
+        let json_response = self.call(&method_request(method, params))?;
+        parse_response(method, &json_response)
+    }
+
+    /// Call `get_network_status` on the first backend that replies within the health check
+    /// timeout, returning the parsed response.
+    pub fn network_status(&self) -> Result<Value, String> {
+        let method = "get_network_status";
+        let mut last_err = None;
+        for index in self.candidates(&Value::Null) {
+            let result = post(
+                &self.backends[index].uri,
+                &method_request(method, None),
+                HEALTH_CHECK_TIMEOUT,
+            )
+            .map_err(String::from)
+            .and_then(|json_response| parse_response(method, &json_response));
+            match result {
+                Ok(response) => return Ok(response),
+                Err(err) => last_err = Some(err),
+            }
+        }
+        Err(last_err.unwrap_or_else(|| "No full-service backends".into()))
+    }
+
+    /// Start health checking the backends on a background thread.
+    pub fn start_health_checks(&self, interval: Duration, logger: Logger) {
+        let backends = self.clone();
+        thread::Builder::new()
+            .name("FullServiceHealth".into())
+            .spawn(move || loop {
+                backends.check_health(&logger);
+                thread::sleep(interval);
+            })
+            .expect("Failed spawning health check thread");
+    }
+
+    fn check_health(&self, logger: &Logger) {
+        // With account routing the health check doubles as a refresh of the accounts each backend
+        // holds.
+        let method = match self.strategy {
+            RoutingStrategy::AccountId => "get_all_accounts",
+            _ => "get_network_status",
+        };
+
+        for backend in self.backends.iter() {
+            let result = post(
+                &backend.uri,
+                &method_request(method, None),
+                HEALTH_CHECK_TIMEOUT,
+            )
+            .map_err(String::from)
+            .and_then(|json_response| parse_response(method, &json_response));
+
+            match result {
+                Ok(response) => {
+                    if self.strategy == RoutingStrategy::AccountId {
+                        let account_ids = response["result"]["account_ids"]
+                            .as_array()
+                            .map(|account_ids| {
+                                account_ids
+                                    .iter()
+                                    .filter_map(|account_id| account_id.as_str())
+                                    .map(str::to_owned)
+                                    .collect()
+                            })
+                            .unwrap_or_default();
+                        *backend.account_ids.lock().expect("mutex poisoned") = account_ids;
+                    }
+                    if !backend.healthy.swap(true, Ordering::SeqCst) {
+                        log::info!(logger, "Full-service backend {} is up", backend.uri);
+                    }
+                }
+                Err(err) => {
+                    if backend.healthy.swap(false, Ordering::SeqCst) {
+                        log::warn!(
+                            logger,
+                            "Full-service backend {} is down: {}",
+                            backend.uri,
+                            err
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    /// The order in which backends are tried for a request.
+    fn candidates(&self, request: &Value) -> Vec<usize> {
+        let num_backends = self.backends.len();
+        let method = request["method"].as_str().unwrap_or("");
+
+        let start = match self.strategy {
+            RoutingStrategy::RoundRobin if is_read_method(method) => {
+                self.next_backend.fetch_add(1, Ordering::SeqCst) % num_backends
+            }
+            RoutingStrategy::AccountId => request["params"]["account_id"]
+                .as_str()
+                .and_then(|account_id| {
+                    self.backends.iter().position(|backend| {
+                        backend
+                            .account_ids
+                            .lock()
+                            .expect("mutex poisoned")
+                            .contains(account_id)
+                    })
+                })
+                .unwrap_or(0),
+            _ => 0,
+        };
+
+        let mut candidates: Vec<usize> = (0..num_backends)
+            .map(|offset| (start + offset) % num_backends)
+            .collect();
+        // Backends that are down go last, otherwise keeping their order.
+        candidates.sort_by_key(|&index| !self.backends[index].healthy.load(Ordering::SeqCst));
+        candidates
+    }
+}
+
+/// Methods that do not change the state of the wallet, and can be served by any backend.
+fn is_read_method(method: &str) -> bool {
+    method.starts_with("get_")
+        || matches!(
+            method,
+            "check_receiver_receive_status" | "validate_confirmation" | "verify_address"
+        )
+}
+
+fn method_request(method: &str, params: Option<Value>) -> String {
+    let mut request = json!({
+        "jsonrpc": "2.0",
+        "method": method,
+        "id": 1,
+    });
+    if let Some(params) = params {
+        request["params"] = params;
+    }
+    request.to_string()
+}
+
+fn parse_response(method: &str, json_response: &str) -> Result<Value, String> {
+    let response: Value = serde_json::from_str(json_response)
+        .map_err(|err| format!("Error parsing full-service response: {}", err))?;
+    if !response["error"].is_null() {
+        return Err(format!("{} failed: {}", method, response["error"]));
+    }
+    Ok(response)
+}
+
+fn post(uri: &str, json_request: &str, timeout: Duration) -> Result<Zeroizing<String>, PostError> {
+    let client = reqwest::blocking::Client::builder()
+        .timeout(timeout)
+        .build()
+        .map_err(|e| PostError::Other(e.to_string()))?;
+    let res = client
+        .post(uri)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .body(json_request.to_owned())
+        .send()
+        .map_err(|e| {
+            if e.is_connect() {
+                PostError::Connect(e.to_string())
+            } else {
+                PostError::Other(e.to_string())
+            }
+        })?;
+    res.text()
+        .map(Zeroizing::new)
+        .map_err(|e| PostError::Other(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_backends(strategy: RoutingStrategy) -> FullServiceBackends {
+        FullServiceBackends::new(
+            vec![
+                "http://a/".to_owned(),
+                "http://b/".to_owned(),
+                "http://c/".to_owned(),
+            ],
+            strategy,
+        )
+    }
+
+    #[test]
+    fn failover_prefers_healthy_backends_in_order() {
+        let backends = test_backends(RoutingStrategy::Failover);
+        let request = json!({"method": "get_block"});
+        assert_eq!(backends.candidates(&request), vec![0, 1, 2]);
+
+        backends.backends[0].healthy.store(false, Ordering::SeqCst);
+        assert_eq!(backends.candidates(&request), vec![1, 2, 0]);
+    }
+
+    #[test]
+    fn round_robin_only_spreads_reads() {
+        let backends = test_backends(RoutingStrategy::RoundRobin);
+        let read = json!({"method": "get_block"});
+        let write = json!({"method": "build_and_submit_transaction"});
+
+        assert_eq!(backends.candidates(&read)[0], 0);
+        assert_eq!(backends.candidates(&read)[0], 1);
+        assert_eq!(backends.candidates(&write)[0], 0);
+        assert_eq!(backends.candidates(&read)[0], 2);
+        assert_eq!(backends.candidates(&read)[0], 0);
+    }
+
+    #[test]
+    fn account_requests_go_to_the_backend_holding_the_account() {
+        let backends = test_backends(RoutingStrategy::AccountId);
+        backends.backends[1]
+            .account_ids
+            .lock()
+            .unwrap()
+            .insert("alice".to_owned());
+
+        let request = json!({"method": "get_account", "params": {"account_id": "alice"}});
+        assert_eq!(backends.candidates(&request), vec![1, 2, 0]);
+
+        let request = json!({"method": "get_account", "params": {"account_id": "bob"}});
+        assert_eq!(backends.candidates(&request), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn unreachable_backends_are_marked_down() {
+        let backends = FullServiceBackends::new(
+            vec!["http://127.0.0.1:1/".to_owned()],
+            RoutingStrategy::Failover,
+        );
+        assert!(backends.call(r#"{"method": "get_block"}"#).is_err());
+        assert!(!backends.backends[0].healthy.load(Ordering::SeqCst));
+    }
+}
@@ -0,0 +1,265 @@
+// Copyright (c) 2018-2022 MobileCoin Inc.
+
+//! Authentication of encrypted requests by client signatures.
+//!
+//! When a client key registry is configured, the plaintext of every encrypted request must be a
+//! signed envelope:
+//! ```json
+//! {
+//!     "signer": "acme-app",
+//!     "signature": "<hex-encoded signature>",
+//!     "payload": "<the JSON-RPC request (or replay protection envelope), as a string>"
+//! }
+//! ```
+//! The signature covers `wallet-service-mirror-request\n<signer>\n<payload>`. Ed25519 signatures
+//! are the raw 64 bytes, ECDSA P-256 signatures are DER-encoded over the SHA-256 digest of the
+//! message.
+//!
+//! Session handshakes are signed the same way, with `wallet-service-mirror-session-handshake` in
+//! place of `wallet-service-mirror-request` (see `sessions.rs`).
+//!
+//! The registry of client keys is described in `client_registry.rs`.
+
+use crate::json_rpc::JsonRpcError;
+use mc_wallet_service_mirror::client_registry::{ClientKey, ClientRegistry};
+use serde_json::Value;
+use std::{collections::HashMap, fmt, sync::Arc};
+
+/// JSON-RPC error code (from the implementation-defined server error range) for requests that
+/// are not signed by an authorized client.
+pub const JSON_RPC_UNAUTHORIZED: i64 = -32003;
+
+/// Domain separator for request signatures.
+const REQUEST_SIGNATURE_CONTEXT: &str = "wallet-service-mirror-request";
+
+/// Domain separator for session handshake signatures.
+const HANDSHAKE_SIGNATURE_CONTEXT: &str = "wallet-service-mirror-session-handshake";
+
+/// The registry of clients allowed to submit encrypted requests.
+#[derive(Clone)]
+pub struct ClientKeys {
+    keys: Arc<HashMap<String, ClientKey>>,
+}
+
+impl fmt::Debug for ClientKeys {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut clients: Vec<&String> = self.keys.keys().collect();
+        clients.sort();
+        f.debug_struct("ClientKeys")
+            .field("clients", &clients)
+            .finish()
+    }
+}
+
+impl ClientKeys {
+    /// Load the client key registry from a JSON file.
+    pub fn load(src: &str) -> Result<Self, String> {
+        let config_str = std::fs::read_to_string(src)
+            .map_err(|err| format!("failed reading client keys {}: {:?}", src, err))?;
+        Self::from_json(&config_str)
+            .map_err(|err| format!("failed parsing client keys {}: {}", src, err))
+    }
+
+    fn from_json(config_str: &str) -> Result<Self, String> {
+        let registry = ClientRegistry::from_json(config_str)?;
+
+        let keys = registry
+            .clients
+            .into_iter()
+            .map(|(name, key_config)| {
+                let key = key_config
+                    .parse()
+                    .map_err(|err| format!("invalid key for client {}: {}", name, err))?;
+                Ok((name, key))
+            })
+            .collect::<Result<HashMap<_, _>, String>>()?;
+
+        Ok(Self {
+            keys: Arc::new(keys),
+        })
+    }
+
+    /// Verify the signature of a signed envelope, returning the name of the signer and the
+    /// payload it holds.
+    pub fn verify(&self, envelope: &Value) -> Result<(String, Value), JsonRpcError> {
+        let (signer, payload) = self.verify_signature(request_message, envelope)?;
+        let payload = serde_json::from_str(payload)
+            .map_err(|err| unauthorized(format!("invalid payload: {}", err)))?;
+        Ok((signer.to_owned(), payload))
+    }
+
+    /// Verify the signature of a signed session handshake, returning the name of the signer and
+    /// the handshake it holds.
+    pub fn verify_handshake<'a>(
+        &self,
+        envelope: &'a Value,
+    ) -> Result<(String, &'a str), JsonRpcError> {
+        let (signer, payload) = self.verify_signature(handshake_message, envelope)?;
+        Ok((signer.to_owned(), payload))
+    }
+
+    /// Verify the signature of a signed envelope over the message built by `message`.
+    fn verify_signature<'a>(
+        &self,
+        message: fn(&str, &str) -> Vec<u8>,
+        envelope: &'a Value,
+    ) -> Result<(&'a str, &'a str), JsonRpcError> {
+        let signer = envelope["signer"]
+            .as_str()
+            .ok_or_else(|| unauthorized("missing signer"))?;
+        let signature = envelope["signature"]
+            .as_str()
+            .and_then(|signature| hex::decode(signature).ok())
+            .ok_or_else(|| unauthorized("missing or invalid signature"))?;
+        let payload = envelope["payload"]
+            .as_str()
+            .ok_or_else(|| unauthorized("missing payload"))?;
+
+        let key = self
+            .keys
+            .get(signer)
+            .ok_or_else(|| unauthorized("unknown signer"))?;
+        if !key.verify(&message(signer, payload), &signature) {
+            return Err(unauthorized("invalid signature"));
+        }
+        Ok((signer, payload))
+    }
+}
+
+/// The message clients sign in order to authenticate a request.
+pub fn request_message(signer: &str, payload: &str) -> Vec<u8> {
+    signed_message(REQUEST_SIGNATURE_CONTEXT, signer, payload)
+}
+
+/// The message clients sign in order to authenticate a session handshake.
+pub fn handshake_message(signer: &str, payload: &str) -> Vec<u8> {
+    signed_message(HANDSHAKE_SIGNATURE_CONTEXT, signer, payload)
+}
+
+fn signed_message(context: &str, signer: &str, payload: &str) -> Vec<u8> {
+    format!("{}\n{}\n{}", context, signer, payload).into_bytes()
+}
+
+fn unauthorized(reason: impl AsRef<str>) -> JsonRpcError {
+    JsonRpcError::new(
+        JSON_RPC_UNAUTHORIZED,
+        format!("Unauthorized: {}", reason.as_ref()),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use boring::{
+        bn::BigNumContext,
+        ec::{EcGroup, EcKey, PointConversionForm},
+        ecdsa::EcdsaSig,
+        nid::Nid,
+        pkey::Private,
+    };
+    use mc_crypto_keys::{Ed25519Pair, Ed25519Private, Signer};
+    use serde_json::json;
+    use sha2::{Digest, Sha256};
+    use std::convert::TryFrom;
+
+    const PAYLOAD: &str = r#"{"method": "get_block", "jsonrpc": "2.0", "id": 1}"#;
+
+    fn ed25519_pair() -> Ed25519Pair {
+        Ed25519Pair::from(Ed25519Private::try_from(&[1u8; 32][..]).unwrap())
+    }
+
+    fn ecdsa_key() -> EcKey<Private> {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        EcKey::generate(&group).unwrap()
+    }
+
+    fn test_keys(ecdsa_key: &EcKey<Private>) -> ClientKeys {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let mut ctx = BigNumContext::new().unwrap();
+        let ecdsa_public = ecdsa_key
+            .public_key()
+            .to_bytes(&group, PointConversionForm::UNCOMPRESSED, &mut ctx)
+            .unwrap();
+
+        ClientKeys::from_json(
+            &json!({
+                "clients": {
+                    "alice": {"type": "ed25519", "public_key": hex::encode(ed25519_pair().public_key())},
+                    "bob": {"type": "ecdsa-p256", "public_key": hex::encode(ecdsa_public)},
+                },
+            })
+            .to_string(),
+        )
+        .unwrap()
+    }
+
+    fn envelope(signer: &str, signature: &[u8], payload: &str) -> Value {
+        json!({"signer": signer, "signature": hex::encode(signature), "payload": payload})
+    }
+
+    #[test]
+    fn ed25519_and_ecdsa_signatures_are_verified() {
+        let ecdsa_key = ecdsa_key();
+        let keys = test_keys(&ecdsa_key);
+
+        let signature = ed25519_pair().sign(&request_message("alice", PAYLOAD));
+        let (signer, payload) = keys
+            .verify(&envelope("alice", signature.as_ref(), PAYLOAD))
+            .unwrap();
+        assert_eq!(signer, "alice");
+        assert_eq!(payload["method"], "get_block");
+
+        let digest = Sha256::digest(&request_message("bob", PAYLOAD));
+        let signature = EcdsaSig::sign(&digest, &ecdsa_key)
+            .unwrap()
+            .to_der()
+            .unwrap();
+        let (signer, _) = keys.verify(&envelope("bob", &signature, PAYLOAD)).unwrap();
+        assert_eq!(signer, "bob");
+    }
+
+    #[test]
+    fn forged_and_unsigned_requests_are_rejected() {
+        let keys = test_keys(&ecdsa_key());
+        let signature = ed25519_pair().sign(&request_message("alice", PAYLOAD));
+
+        // The signature is bound to the signer and the payload.
+        assert!(keys
+            .verify(&envelope("bob", signature.as_ref(), PAYLOAD))
+            .is_err());
+        assert!(keys
+            .verify(&envelope(
+                "alice",
+                signature.as_ref(),
+                r#"{"method": "get_account", "jsonrpc": "2.0", "id": 1}"#
+            ))
+            .is_err());
+        assert!(keys
+            .verify(&envelope("carol", signature.as_ref(), PAYLOAD))
+            .is_err());
+
+        let err = keys
+            .verify(&serde_json::from_str(PAYLOAD).unwrap())
+            .unwrap_err();
+        assert_eq!(err.code, JSON_RPC_UNAUTHORIZED);
+    }
+
+    #[test]
+    fn request_and_handshake_signatures_are_not_interchangeable() {
+        let keys = test_keys(&ecdsa_key());
+        let handshake = r#"{"ephemeral_public_key": "00", "secret": "00"}"#;
+
+        let signature = ed25519_pair().sign(&handshake_message("alice", handshake));
+        let signed = envelope("alice", signature.as_ref(), handshake);
+        assert_eq!(
+            keys.verify_handshake(&signed).unwrap(),
+            ("alice".to_owned(), handshake)
+        );
+        assert!(keys.verify(&signed).is_err());
+
+        let signature = ed25519_pair().sign(&request_message("alice", handshake));
+        assert!(keys
+            .verify_handshake(&envelope("alice", signature.as_ref(), handshake))
+            .is_err());
+    }
+}
@@ -2,30 +2,30 @@
 
 //! Cryptographic primitives.
 
+use crate::key_backend::KeyBackend;
 use boring::{
-    pkey::Private,
-    rsa::{Padding, Rsa},
+    pkey::{PKey, Private},
+    rsa::Rsa,
 };
+use hmac::{Hmac, Mac, NewMac};
+use sha2::Sha256;
+use std::path::Path;
+use zeroize::Zeroizing;
+
+type HmacSha256 = Hmac<Sha256>;
 
 const PKCS1_PADDING_LEN: usize = 11;
 
 /// Encrypt a payload of arbitrary length using a private key.
-pub fn encrypt(key: &Rsa<Private>, payload: &[u8]) -> Result<Vec<u8>, String> {
+pub fn encrypt(key: &dyn KeyBackend, payload: &[u8]) -> Result<Vec<u8>, String> {
     // Each encrypted chunk must be no longer than the length of the public modulus minus 11 (PKCS1 padding size).
     // (Taken from `rsa::oaep::encrypt`).
-    let key_size = key.size() as usize;
+    let key_size = key.key_size();
     let max_chunk_size = key_size - PKCS1_PADDING_LEN;
 
     let chunks: Vec<Vec<u8>> = payload
         .chunks(max_chunk_size)
-        .map(|chunk| {
-            let mut output = vec![0u8; key_size];
-
-            key.private_encrypt(&chunk[..], &mut output, Padding::PKCS1)
-                .map_err(|e| format!("encrypt failed: {:?}", e))?;
-
-            Ok(output)
-        })
+        .map(|chunk| key.encrypt_pkcs1(chunk))
         .collect::<Result<Vec<_>, String>>()?;
 
     Ok(chunks
@@ -35,42 +35,160 @@ pub fn encrypt(key: &Rsa<Private>, payload: &[u8]) -> Result<Vec<u8>, String> {
 }
 
 /// Decrypt a payload of arbitrary length using a private key.
-pub fn decrypt(key: &Rsa<Private>, payload: &[u8]) -> Result<Vec<u8>, String> {
-    let key_size = key.size() as usize;
+///
+/// Chunks with invalid PKCS#1 v1.5 padding are not reported as errors, since that would make this
+/// a padding oracle. Instead, padding is checked in constant time and invalid chunks decrypt to a
+/// synthetic message derived from the key and the ciphertext ("implicit rejection"), which callers
+/// then fail to parse like any other garbage. The only errors are for payloads whose length is not
+/// a multiple of the key size, which does not depend on the key, and for keys that cannot be used
+/// at all (such as a PKCS#11 token going away). The plaintext is zeroized once dropped.
+pub fn decrypt(key: &dyn KeyBackend, payload: &[u8]) -> Result<Zeroizing<Vec<u8>>, String> {
+    let key_size = key.key_size();
+    if payload.is_empty() || payload.len() % key_size != 0 {
+        return Err(DECRYPT_FAILED.into());
+    }
 
-    let chunks: Vec<Vec<u8>> = payload
-        .chunks(key_size)
-        .map(|chunk| {
-            let mut output = vec![0u8; key_size];
-            let num_bytes = key
-                .private_decrypt(&chunk[..], &mut output, Padding::PKCS1)
-                .map_err(|e| format!("decrypt failed: {:?}", e))?;
-            output.truncate(num_bytes as usize);
-            Ok(output)
-        })
-        .collect::<Result<Vec<_>, String>>()?;
+    let rejection_key = key.rejection_key()?;
 
-    Ok(chunks
-        .into_iter()
-        .flat_map(|chunk| chunk.into_iter())
-        .collect())
+    // The plaintext is never longer than the ciphertext, so it is never reallocated (which would
+    // leave a copy behind).
+    let mut plaintext = Zeroizing::new(Vec::with_capacity(payload.len()));
+    for chunk in payload.chunks(key_size) {
+        plaintext.extend_from_slice(&decrypt_chunk(key, &rejection_key, chunk));
+    }
+    Ok(plaintext)
 }
 
-/// Load a private key from a file
-pub fn load_private_key(src: &str) -> Result<Rsa<Private>, String> {
-    let key_str = std::fs::read_to_string(src)
-        .map_err(|err| format!("failed reading key file {}: {:?}", src, err))?;
+/// The error returned for payloads that cannot be decrypted.
+const DECRYPT_FAILED: &str = "decrypt failed";
+
+/// Decrypt a single chunk with implicit rejection.
+fn decrypt_chunk(key: &dyn KeyBackend, rejection_key: &[u8], chunk: &[u8]) -> Zeroizing<Vec<u8>> {
+    let key_size = chunk.len();
+
+    // Raw RSA only fails for ciphertexts that are not smaller than the modulus, which anyone
+    // holding the public key can tell. Those get the synthetic message too.
+    let (mut em, raw_ok) = match key.decrypt_raw(chunk) {
+        Ok(em) if em.len() <= key_size => {
+            // Tokens may strip the leading zero bytes of the result.
+            let mut padded = Zeroizing::new(Vec::with_capacity(key_size));
+            padded.resize(key_size - em.len(), 0);
+            padded.extend_from_slice(&em);
+            (padded, true)
+        }
+        _ => (Zeroizing::new(vec![0u8; key_size]), false),
+    };
+    let raw_ok = 0u64.wrapping_sub(raw_ok as u64);
+
+    // EM = 0x00 || 0x02 || PS (at least 8 non-zero bytes) || 0x00 || M
+    let mut good = raw_ok & ct_eq(em[0] as u64, 0) & ct_eq(em[1] as u64, 2);
+    let mut zero_index = 0u64;
+    let mut looking_for_index = u64::MAX;
+    for (i, byte) in em.iter().enumerate().skip(2) {
+        let is_zero = ct_eq(*byte as u64, 0);
+        zero_index = ct_select(looking_for_index & is_zero, i as u64, zero_index);
+        looking_for_index = ct_select(is_zero, 0, looking_for_index);
+    }
+    good &= !looking_for_index;
+    good &= !ct_lt(zero_index, 2 + 8);
+
+    let message_index = zero_index + 1;
+    let message_len = (key_size as u64).wrapping_sub(message_index);
+
+    // Shift the message to the start of the buffer without indexing by the secret offset, one bit
+    // of the offset at a time.
+    let mut shift = 1;
+    while shift < key_size {
+        let mask = !ct_eq(message_index & shift as u64, 0);
+        for i in 0..key_size {
+            let shifted = if i + shift < key_size {
+                em[i + shift]
+            } else {
+                0
+            };
+            em[i] = ct_select(mask, shifted as u64, em[i] as u64) as u8;
+        }
+        shift <<= 1;
+    }
+
+    let (synthetic, synthetic_len) = synthetic_message(rejection_key, chunk);
+    for (byte, synthetic_byte) in em.iter_mut().zip(synthetic.iter()) {
+        *byte = ct_select(good, *byte as u64, *synthetic_byte as u64) as u8;
+    }
+    em.truncate(ct_select(good, message_len, synthetic_len as u64) as usize);
+    em
+}
+
+/// Derive the message an invalid chunk decrypts to, and its length, from the ciphertext. This is
+/// deterministic so that resending the same ciphertext does not tell it apart from a valid one.
+fn synthetic_message(rejection_key: &[u8], chunk: &[u8]) -> (Zeroizing<Vec<u8>>, usize) {
+    let prf = |label: &[u8], counter: u32| {
+        let mut mac = HmacSha256::new_varkey(rejection_key).expect("HMAC accepts any key length");
+        mac.update(chunk);
+        mac.update(label);
+        mac.update(&counter.to_be_bytes());
+        mac.finalize().into_bytes()
+    };
+
+    let mut message = Zeroizing::new(Vec::with_capacity(chunk.len() + 32));
+    let mut counter = 0;
+    while message.len() < chunk.len() {
+        message.extend_from_slice(&prf(b"message", counter));
+        counter += 1;
+    }
+    message.truncate(chunk.len());
 
-    Ok(
-        Rsa::private_key_from_pem_passphrase(key_str.as_bytes(), &[])
-            .map_err(|err| format!("failed parsing key file {}: {:?}", src, err))?,
-    )
+    let length_bytes = prf(b"length", 0);
+    let max_len = chunk.len() - PKCS1_PADDING_LEN;
+    let len = u16::from_be_bytes([length_bytes[0], length_bytes[1]]) as usize % (max_len + 1);
+    (message, len)
+}
+
+/// All ones if `a == b`, zero otherwise.
+fn ct_eq(a: u64, b: u64) -> u64 {
+    let x = a ^ b;
+    // The top bit of `!x & (x - 1)` is only set when x is zero.
+    0u64.wrapping_sub((!x & x.wrapping_sub(1)) >> 63)
+}
+
+/// All ones if `a < b`, zero otherwise.
+fn ct_lt(a: u64, b: u64) -> u64 {
+    0u64.wrapping_sub((a ^ ((a ^ b) | (a.wrapping_sub(b) ^ a))) >> 63)
+}
+
+/// `a` if `mask` is all ones, `b` if it is zero.
+fn ct_select(mask: u64, a: u64, b: u64) -> u64 {
+    (a & mask) | (b & !mask)
+}
+
+/// Load a private key from a PEM file, which may be an encrypted traditional or PKCS#8 key if a
+/// passphrase is given.
+pub fn load_private_key(src: &Path, passphrase: Option<&str>) -> Result<Rsa<Private>, String> {
+    let key_str = Zeroizing::new(
+        std::fs::read_to_string(src)
+            .map_err(|err| format!("failed reading key file {:?}: {:?}", src, err))?,
+    );
+
+    let passphrase = match passphrase {
+        Some(passphrase) => passphrase,
+        None if key_str.contains("ENCRYPTED") => {
+            return Err(format!(
+                "key file {:?} is encrypted, but no passphrase was provided",
+                src
+            ))
+        }
+        None => "",
+    };
+
+    PKey::private_key_from_pem_passphrase(key_str.as_bytes(), passphrase.as_bytes())
+        .and_then(|key| key.rsa())
+        .map_err(|err| format!("failed parsing key file {:?}: {:?}", src, err))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use boring::pkey::Public;
+    use boring::{pkey::Public, rsa::Padding};
     use rand_core::{RngCore, SeedableRng};
     use rand_hc::Hc128Rng;
 
@@ -121,6 +239,72 @@ mod tests {
             .collect())
     }
 
+    #[test]
+    fn invalid_padding_decrypts_to_a_deterministic_synthetic_message() {
+        let priv_key = Rsa::generate(2048).unwrap();
+        let key_size = priv_key.size() as usize;
+
+        let mut rng = Hc128Rng::from_seed([0u8; 32]);
+        let mut ciphertext = vec![0u8; key_size];
+        rng.fill_bytes(&mut ciphertext);
+        ciphertext[0] = 0;
+
+        let decrypted = decrypt(&priv_key, &ciphertext).unwrap();
+        assert!(decrypted.len() <= key_size - PKCS1_PADDING_LEN);
+        assert_eq!(decrypt(&priv_key, &ciphertext).unwrap(), decrypted);
+
+        // Ciphertexts not smaller than the modulus are rejected the same way.
+        let ciphertext = vec![0xffu8; key_size];
+        assert!(decrypt(&priv_key, &ciphertext).is_ok());
+    }
+
+    #[test]
+    fn payloads_that_are_not_whole_chunks_are_rejected() {
+        let priv_key = Rsa::generate(2048).unwrap();
+        let key_size = priv_key.size() as usize;
+
+        assert_eq!(decrypt(&priv_key, &[]), Err(DECRYPT_FAILED.to_owned()));
+        assert_eq!(
+            decrypt(&priv_key, &vec![1u8; key_size + 1]),
+            Err(DECRYPT_FAILED.to_owned())
+        );
+    }
+
+    #[test]
+    fn encrypted_private_keys_are_loaded_with_their_passphrase() {
+        let key = Rsa::generate(2048).unwrap();
+        let path = std::env::temp_dir().join("mirror-crypto-test-encrypted-key.pem");
+        let pem = PKey::from_rsa(key.clone())
+            .unwrap()
+            .private_key_to_pem_pkcs8_passphrase(
+                boring::symm::Cipher::aes_256_cbc(),
+                b"correct horse",
+            )
+            .unwrap();
+        std::fs::write(&path, pem).unwrap();
+
+        let loaded = load_private_key(&path, Some("correct horse")).unwrap();
+        assert_eq!(loaded.n(), key.n());
+        assert!(load_private_key(&path, Some("wrong")).is_err());
+        assert!(load_private_key(&path, None).is_err());
+
+        std::fs::write(&path, key.private_key_to_pem().unwrap()).unwrap();
+        assert_eq!(load_private_key(&path, None).unwrap().n(), key.n());
+    }
+
+    #[test]
+    fn constant_time_helpers_work() {
+        assert_eq!(ct_eq(3, 3), u64::MAX);
+        assert_eq!(ct_eq(0, 0), u64::MAX);
+        assert_eq!(ct_eq(3, 4), 0);
+        assert_eq!(ct_eq(0, 1 << 63), 0);
+        assert_eq!(ct_lt(1, 2), u64::MAX);
+        assert_eq!(ct_lt(2, 2), 0);
+        assert_eq!(ct_lt(u64::MAX, 0), 0);
+        assert_eq!(ct_select(u64::MAX, 1, 2), 1);
+        assert_eq!(ct_select(0, 1, 2), 2);
+    }
+
     #[test]
     fn encrypt_private_decrypt_public_works_with_short_message() {
         let priv_key = Rsa::generate(2048).unwrap();
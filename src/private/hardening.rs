@@ -0,0 +1,98 @@
+// Copyright (c) 2018-2022 MobileCoin Inc.
+
+//! Keeping the mirror key and decrypted requests out of core dumps, swap and freed memory.
+
+use serde_json::Value;
+use std::{
+    io,
+    ops::{Deref, DerefMut},
+};
+use zeroize::Zeroize;
+
+/// A JSON value (such as a decrypted request or its response) whose strings, including object
+/// keys, are zeroized once dropped. serde_json gives no way of overwriting numbers and booleans,
+/// so those are left as they are.
+#[derive(Debug, Default)]
+pub struct ZeroizingValue(pub Value);
+
+impl Deref for ZeroizingValue {
+    type Target = Value;
+
+    fn deref(&self) -> &Value {
+        &self.0
+    }
+}
+
+impl DerefMut for ZeroizingValue {
+    fn deref_mut(&mut self) -> &mut Value {
+        &mut self.0
+    }
+}
+
+impl Drop for ZeroizingValue {
+    fn drop(&mut self) {
+        zeroize_value(&mut self.0);
+    }
+}
+
+fn zeroize_value(value: &mut Value) {
+    match value {
+        Value::String(string) => string.zeroize(),
+        Value::Array(values) => values.iter_mut().for_each(zeroize_value),
+        Value::Object(map) => {
+            for (mut key, mut value) in std::mem::take(map) {
+                key.zeroize();
+                zeroize_value(&mut value);
+            }
+        }
+        Value::Null | Value::Bool(_) | Value::Number(_) => {}
+    }
+}
+
+/// Exclude the process from core dumps. On Linux this also stops other processes of the same user
+/// from attaching to it with ptrace or reading its memory through `/proc`.
+pub fn disable_core_dumps() -> Result<(), String> {
+    let limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    // SAFETY: setrlimit only reads the limit it is given.
+    if unsafe { libc::setrlimit(libc::RLIMIT_CORE, &limit) } != 0 {
+        return Err(format!(
+            "failed setting RLIMIT_CORE: {}",
+            io::Error::last_os_error()
+        ));
+    }
+    set_not_dumpable()
+}
+
+#[cfg(target_os = "linux")]
+fn set_not_dumpable() -> Result<(), String> {
+    // SAFETY: PR_SET_DUMPABLE takes no pointers.
+    if unsafe { libc::prctl(libc::PR_SET_DUMPABLE, 0, 0, 0, 0) } != 0 {
+        return Err(format!(
+            "failed clearing PR_SET_DUMPABLE: {}",
+            io::Error::last_os_error()
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_not_dumpable() -> Result<(), String> {
+    Ok(())
+}
+
+/// Lock all current and future memory of the process in RAM, so that it is never written to swap.
+/// Allocations fail once `RLIMIT_MEMLOCK` is reached, so the limit must be raised (or the process
+/// given `CAP_IPC_LOCK`) accordingly.
+pub fn lock_memory() -> Result<(), String> {
+    // SAFETY: mlockall takes no pointers.
+    if unsafe { libc::mlockall(libc::MCL_CURRENT | libc::MCL_FUTURE) } != 0 {
+        return Err(format!(
+            "failed locking memory: {}",
+            io::Error::last_os_error()
+        ));
+    }
+    Ok(())
+}
@@ -0,0 +1,144 @@
+// Copyright (c) 2018-2022 MobileCoin Inc.
+
+//! A local HTTP health endpoint for the private side of the mirror.
+//!
+//! `GET /healthz` replies 200 if full-service answers `get_network_status`, and 503 otherwise.
+//! The private side has no other listening port, so this is served on a minimal dedicated listener
+//! that is expected to only be reachable locally (for example by an orchestrator's probes).
+
+use crate::backends::FullServiceBackends;
+use mc_common::logger::{log, Logger};
+use mc_wallet_service_mirror::http_request::{
+    read_request_head, reject_connection, ConnectionLimit,
+};
+use serde_json::json;
+use std::{
+    io::Write,
+    net::{SocketAddr, TcpListener, TcpStream},
+    thread,
+};
+
+/// The maximum number of health checks handled at the same time. Connections over the limit are
+/// rejected without spawning a thread for them.
+const MAX_CONNECTIONS: usize = 16;
+
+/// Start serving health checks on a background thread.
+pub fn start_health_server(
+    listen_addr: SocketAddr,
+    backends: FullServiceBackends,
+    logger: Logger,
+) -> Result<(), String> {
+    let listener = TcpListener::bind(listen_addr)
+        .map_err(|err| format!("failed binding {}: {}", listen_addr, err))?;
+
+    let connection_limit = ConnectionLimit::new(MAX_CONNECTIONS);
+    thread::Builder::new()
+        .name("HealthServer".into())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(err) => {
+                        log::warn!(logger, "Failed accepting health connection: {}", err);
+                        continue;
+                    }
+                };
+
+                let guard = match connection_limit.acquire() {
+                    Some(guard) => guard,
+                    None => {
+                        if let Err(err) = reject_connection(stream) {
+                            log::debug!(logger, "Failed rejecting health connection: {}", err);
+                        }
+                        continue;
+                    }
+                };
+
+                let backends = backends.clone();
+                let logger = logger.clone();
+                thread::spawn(move || {
+                    let _guard = guard;
+                    if let Err(err) = handle_connection(stream, &backends, &logger) {
+                        log::debug!(logger, "Health connection failed: {}", err);
+                    }
+                });
+            }
+        })
+        .map_err(|err| format!("failed spawning health server thread: {}", err))?;
+
+    Ok(())
+}
+
+fn handle_connection(
+    mut stream: TcpStream,
+    backends: &FullServiceBackends,
+    logger: &Logger,
+) -> Result<(), String> {
+    let request = read_request_head(&stream)?;
+
+    let (status, body) = match parse_health_request(&request.request_line) {
+        Ok(()) => match backends.network_status() {
+            Ok(response) => (
+                "200 OK",
+                json!({
+                    "status": "ok",
+                    "network_status": response["result"]["network_status"],
+                }),
+            ),
+            Err(err) => {
+                log::warn!(logger, "Health check failed: {}", err);
+                (
+                    "503 Service Unavailable",
+                    json!({ "status": "error", "error": err }),
+                )
+            }
+        },
+        Err(status) => (status, json!({ "status": "error", "error": status })),
+    };
+
+    let body = body.to_string();
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+    .map_err(|err| err.to_string())
+}
+
+/// Parse the request line of a health request, returning the HTTP status to reject the request
+/// with if it is not a health check.
+fn parse_health_request(request_line: &str) -> Result<(), &'static str> {
+    let mut parts = request_line.split_whitespace();
+    let (method, target) = match (parts.next(), parts.next()) {
+        (Some(method), Some(target)) => (method, target),
+        _ => return Err("400 Bad Request"),
+    };
+    if method != "GET" {
+        return Err("405 Method Not Allowed");
+    }
+    if target != "/healthz" {
+        return Err("404 Not Found");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn health_requests_are_parsed() {
+        assert_eq!(parse_health_request("GET /healthz HTTP/1.1\r\n"), Ok(()));
+        assert_eq!(
+            parse_health_request("POST /healthz HTTP/1.1\r\n"),
+            Err("405 Method Not Allowed")
+        );
+        assert_eq!(
+            parse_health_request("GET /other HTTP/1.1\r\n"),
+            Err("404 Not Found")
+        );
+        assert_eq!(parse_health_request(""), Err("400 Bad Request"));
+    }
+}
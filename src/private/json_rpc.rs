@@ -0,0 +1,403 @@
+// Copyright (c) 2018-2022 MobileCoin Inc.
+
+//! JSON-RPC 2.0 request validation.
+//! Requests are checked for structural validity, and the params of supported methods are checked
+//! against a schema, so that malformed requests are rejected without contacting full-service.
+
+use serde_json::{json, Value};
+
+/// JSON-RPC error code for a request that is not a valid request object.
+pub const JSON_RPC_INVALID_REQUEST: i64 = -32600;
+
+/// JSON-RPC error code for a method that does not exist or is not available.
+pub const JSON_RPC_METHOD_NOT_FOUND: i64 = -32601;
+
+/// JSON-RPC error code for invalid method parameters.
+pub const JSON_RPC_INVALID_PARAMS: i64 = -32602;
+
+/// JSON-RPC error code for an internal error.
+pub const JSON_RPC_INTERNAL_ERROR: i64 = -32603;
+
+/// The fields a request object is allowed to contain.
+const REQUEST_FIELDS: &[&str] = &["jsonrpc", "method", "params", "id"];
+
+/// A JSON-RPC error, to be returned to the client in place of a result.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct JsonRpcError {
+    pub code: i64,
+    pub message: String,
+}
+
+impl JsonRpcError {
+    pub fn new(code: i64, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+
+    fn invalid_request(reason: impl AsRef<str>) -> Self {
+        Self::new(
+            JSON_RPC_INVALID_REQUEST,
+            format!("Invalid Request: {}", reason.as_ref()),
+        )
+    }
+
+    fn invalid_params(reason: impl AsRef<str>) -> Self {
+        Self::new(
+            JSON_RPC_INVALID_PARAMS,
+            format!("Invalid params: {}", reason.as_ref()),
+        )
+    }
+
+    /// Build the JSON-RPC response object carrying this error.
+    pub fn to_response(&self, id: &Value) -> Value {
+        json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "error": {
+                "code": self.code,
+                "message": self.message,
+            },
+        })
+    }
+}
+
+/// The type of a method parameter.
+#[derive(Clone, Copy, Debug)]
+enum ParamType {
+    /// Any string.
+    String,
+
+    /// A string holding an unsigned 64 bit integer, the way full-service encodes amounts and
+    /// indices.
+    U64String,
+
+    /// A JSON integer.
+    Integer,
+
+    /// A JSON object.
+    Object,
+}
+
+/// The schema of a single method parameter.
+struct ParamSchema {
+    name: &'static str,
+    param_type: ParamType,
+    required: bool,
+}
+
+const fn required(name: &'static str, param_type: ParamType) -> ParamSchema {
+    ParamSchema {
+        name,
+        param_type,
+        required: true,
+    }
+}
+
+const fn optional(name: &'static str, param_type: ParamType) -> ParamSchema {
+    ParamSchema {
+        name,
+        param_type,
+        required: false,
+    }
+}
+
+/// Params schemas of the methods the mirror supports.
+const METHOD_SCHEMAS: &[(&str, &[ParamSchema])] = &[
+    (
+        "check_receiver_receive_status",
+        &[
+            required("address", ParamType::String),
+            required("receiver_receipt", ParamType::Object),
+        ],
+    ),
+    (
+        "create_payment_request",
+        &[
+            required("account_id", ParamType::String),
+            required("amount_pmob", ParamType::U64String),
+            optional("subaddress_index", ParamType::Integer),
+            optional("memo", ParamType::String),
+        ],
+    ),
+    ("get_account", &[required("account_id", ParamType::String)]),
+    (
+        "get_account_status",
+        &[required("account_id", ParamType::String)],
+    ),
+    (
+        "get_address_for_account",
+        &[
+            required("account_id", ParamType::String),
+            required("index", ParamType::Integer),
+        ],
+    ),
+    (
+        "get_addresses_for_account",
+        &[
+            required("account_id", ParamType::String),
+            optional("offset", ParamType::U64String),
+            optional("limit", ParamType::U64String),
+        ],
+    ),
+    ("get_all_accounts", &[]),
+    (
+        "get_approval_status",
+        &[required("approval_id", ParamType::String)],
+    ),
+    (
+        "get_all_transaction_logs_for_block",
+        &[required("block_index", ParamType::U64String)],
+    ),
+    (
+        "get_balance_for_account",
+        &[required("account_id", ParamType::String)],
+    ),
+    (
+        "get_balance_for_address",
+        &[required("address", ParamType::String)],
+    ),
+    (
+        "get_block",
+        &[required("block_index", ParamType::U64String)],
+    ),
+    (
+        "get_confirmations",
+        &[required("transaction_log_id", ParamType::String)],
+    ),
+    ("get_network_status", &[]),
+    (
+        "get_transaction_log",
+        &[required("transaction_log_id", ParamType::String)],
+    ),
+    (
+        "get_transaction_logs_for_account",
+        &[
+            required("account_id", ParamType::String),
+            optional("offset", ParamType::U64String),
+            optional("limit", ParamType::U64String),
+        ],
+    ),
+    ("get_wallet_status", &[]),
+    (
+        "validate_confirmation",
+        &[
+            required("account_id", ParamType::String),
+            required("txo_id", ParamType::String),
+            required("confirmation", ParamType::String),
+        ],
+    ),
+    ("verify_address", &[required("address", ParamType::String)]),
+];
+
+/// Build a successful JSON-RPC response object.
+pub fn result_response(id: &Value, result: Value) -> Value {
+    json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "result": result,
+    })
+}
+
+/// Returns the id of a request if it is usable in a response, or null otherwise.
+pub fn response_id(request: &Value) -> Value {
+    match request.get("id") {
+        Some(id) if is_valid_id(id) => id.clone(),
+        _ => Value::Null,
+    }
+}
+
+/// Validate the structure of a JSON-RPC 2.0 request, and its params if the method has a known
+/// schema. Whether the method is supported is left for the caller to decide.
+pub fn validate_request(request: &Value) -> Result<(), JsonRpcError> {
+    let object = request
+        .as_object()
+        .ok_or_else(|| JsonRpcError::invalid_request("request must be an object"))?;
+
+    if let Some(field) = object
+        .keys()
+        .find(|field| !REQUEST_FIELDS.contains(&field.as_str()))
+    {
+        return Err(JsonRpcError::invalid_request(format!(
+            "unexpected field \"{}\"",
+            field
+        )));
+    }
+
+    if object.get("jsonrpc").and_then(Value::as_str) != Some("2.0") {
+        return Err(JsonRpcError::invalid_request("\"jsonrpc\" must be \"2.0\""));
+    }
+
+    if let Some(id) = object.get("id") {
+        if !is_valid_id(id) {
+            return Err(JsonRpcError::invalid_request(
+                "\"id\" must be a string, an integer or null",
+            ));
+        }
+    }
+
+    let method = object
+        .get("method")
+        .and_then(Value::as_str)
+        .ok_or_else(|| JsonRpcError::invalid_request("\"method\" must be a string"))?;
+
+    let params = match object.get("params") {
+        None | Some(Value::Null) => None,
+        Some(Value::Object(params)) => Some(params),
+        Some(Value::Array(_)) => {
+            return Err(JsonRpcError::invalid_params(
+                "params must be passed by name",
+            ))
+        }
+        Some(_) => {
+            return Err(JsonRpcError::invalid_request(
+                "\"params\" must be an object",
+            ))
+        }
+    };
+
+    let schema = match METHOD_SCHEMAS.iter().find(|(name, _)| *name == method) {
+        Some((_, schema)) => schema,
+        None => return Ok(()),
+    };
+
+    if let Some(params) = params {
+        if let Some(name) = params
+            .keys()
+            .find(|name| !schema.iter().any(|param| param.name == name.as_str()))
+        {
+            return Err(JsonRpcError::invalid_params(format!(
+                "unexpected param \"{}\"",
+                name
+            )));
+        }
+    }
+
+    for param in schema.iter() {
+        match params.and_then(|params| params.get(param.name)) {
+            None | Some(Value::Null) if param.required => {
+                return Err(JsonRpcError::invalid_params(format!(
+                    "missing param \"{}\"",
+                    param.name
+                )));
+            }
+            None | Some(Value::Null) => {}
+            Some(value) => check_param_type(param, value)?,
+        }
+    }
+
+    Ok(())
+}
+
+/// A request id must be a string, an integer or null.
+fn is_valid_id(id: &Value) -> bool {
+    match id {
+        Value::Null | Value::String(_) => true,
+        Value::Number(number) => number.is_i64() || number.is_u64(),
+        _ => false,
+    }
+}
+
+fn check_param_type(param: &ParamSchema, value: &Value) -> Result<(), JsonRpcError> {
+    let (valid, expected) = match param.param_type {
+        ParamType::String => (value.is_string(), "a string"),
+        ParamType::U64String => (
+            value
+                .as_str()
+                .map(|s| s.parse::<u64>().is_ok())
+                .unwrap_or(false),
+            "a string holding an unsigned integer",
+        ),
+        ParamType::Integer => (value.is_i64() || value.is_u64(), "an integer"),
+        ParamType::Object => (value.is_object(), "an object"),
+    };
+
+    if valid {
+        Ok(())
+    } else {
+        Err(JsonRpcError::invalid_params(format!(
+            "param \"{}\" must be {}",
+            param.name, expected
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error_code(request: Value) -> i64 {
+        validate_request(&request).unwrap_err().code
+    }
+
+    #[test]
+    fn valid_requests_pass() {
+        validate_request(&json!({"jsonrpc": "2.0", "method": "get_wallet_status", "id": 1}))
+            .unwrap();
+        validate_request(
+            &json!({"jsonrpc": "2.0", "method": "get_wallet_status", "params": {}, "id": "a"}),
+        )
+        .unwrap();
+        validate_request(&json!({"jsonrpc": "2.0", "method": "get_block", "params": {"block_index": "12"}, "id": null}))
+            .unwrap();
+        validate_request(&json!({
+            "jsonrpc": "2.0",
+            "method": "get_transaction_logs_for_account",
+            "params": {"account_id": "abc", "limit": "10"},
+            "id": 1,
+        }))
+        .unwrap();
+    }
+
+    #[test]
+    fn unknown_methods_only_get_structural_validation() {
+        validate_request(
+            &json!({"jsonrpc": "2.0", "method": "remove_account", "params": {"x": 1}, "id": 1}),
+        )
+        .unwrap();
+        assert_eq!(
+            error_code(json!({"jsonrpc": "1.0", "method": "remove_account", "id": 1})),
+            JSON_RPC_INVALID_REQUEST
+        );
+    }
+
+    #[test]
+    fn malformed_requests_are_invalid() {
+        for request in vec![
+            json!("get_block"),
+            json!({"method": "get_wallet_status", "id": 1}),
+            json!({"jsonrpc": "2.0", "id": 1}),
+            json!({"jsonrpc": "2.0", "method": 5, "id": 1}),
+            json!({"jsonrpc": "2.0", "method": "get_wallet_status", "id": 1.5}),
+            json!({"jsonrpc": "2.0", "method": "get_wallet_status", "id": [1]}),
+            json!({"jsonrpc": "2.0", "method": "get_wallet_status", "params": "x", "id": 1}),
+            json!({"jsonrpc": "2.0", "method": "get_wallet_status", "extra": 1, "id": 1}),
+        ] {
+            assert_eq!(error_code(request), JSON_RPC_INVALID_REQUEST);
+        }
+    }
+
+    #[test]
+    fn bad_params_are_invalid() {
+        for request in vec![
+            json!({"jsonrpc": "2.0", "method": "get_block", "id": 1}),
+            json!({"jsonrpc": "2.0", "method": "get_block", "params": ["1"], "id": 1}),
+            json!({"jsonrpc": "2.0", "method": "get_block", "params": {"block_index": 1}, "id": 1}),
+            json!({"jsonrpc": "2.0", "method": "get_block", "params": {"block_index": "-1"}, "id": 1}),
+            json!({"jsonrpc": "2.0", "method": "get_block", "params": {"block_index": "1", "x": 1}, "id": 1}),
+            json!({"jsonrpc": "2.0", "method": "get_address_for_account", "params": {"account_id": "a", "index": "1"}, "id": 1}),
+            json!({"jsonrpc": "2.0", "method": "get_wallet_status", "params": {"x": 1}, "id": 1}),
+        ] {
+            assert_eq!(error_code(request), JSON_RPC_INVALID_PARAMS);
+        }
+    }
+
+    #[test]
+    fn response_id_ignores_invalid_ids() {
+        assert_eq!(response_id(&json!({"id": 3})), json!(3));
+        assert_eq!(response_id(&json!({"id": "x"})), json!("x"));
+        assert_eq!(response_id(&json!({"id": {"a": 1}})), Value::Null);
+        assert_eq!(response_id(&json!("x")), Value::Null);
+    }
+}
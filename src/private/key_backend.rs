@@ -0,0 +1,78 @@
+// Copyright (c) 2018-2022 MobileCoin Inc.
+
+//! Where the private operations of the mirror key happen.
+//!
+//! `--mirror-key` either names a PEM file, in which case the key is held in process memory, or is
+//! a `pkcs11:` URI (see `pkcs11.rs`), in which case the key never leaves the token.
+
+use crate::{crypto::load_private_key, pkcs11::Pkcs11Key};
+use boring::{
+    pkey::{Private, Public},
+    rsa::{Padding, Rsa},
+};
+use sha2::{Digest, Sha256};
+use std::{path::Path, sync::Arc};
+use zeroize::Zeroizing;
+
+/// An RSA mirror private key.
+pub trait KeyBackend: Send + Sync {
+    /// The size of the modulus (and of each encrypted chunk), in bytes.
+    fn key_size(&self) -> usize;
+
+    /// The public half of the key.
+    fn public_key(&self) -> Result<Rsa<Public>, String>;
+
+    /// Apply the raw RSA private key operation, without any padding, to a chunk. Padding is
+    /// checked by the caller, in constant time.
+    fn decrypt_raw(&self, chunk: &[u8]) -> Result<Zeroizing<Vec<u8>>, String>;
+
+    /// Apply the RSA private key operation with PKCS#1 v1.5 (type 1) padding to a chunk, which is
+    /// how responses are encrypted.
+    fn encrypt_pkcs1(&self, chunk: &[u8]) -> Result<Vec<u8>, String>;
+
+    /// A secret derived from the key, used to derive the messages that invalid ciphertexts
+    /// decrypt to.
+    fn rejection_key(&self) -> Result<Zeroizing<Vec<u8>>, String>;
+}
+
+/// A key held in process memory.
+impl KeyBackend for Rsa<Private> {
+    fn key_size(&self) -> usize {
+        self.size() as usize
+    }
+
+    fn public_key(&self) -> Result<Rsa<Public>, String> {
+        let n = self.n().to_owned().map_err(|err| err.to_string())?;
+        let e = self.e().to_owned().map_err(|err| err.to_string())?;
+        Rsa::from_public_components(n, e).map_err(|err| err.to_string())
+    }
+
+    fn decrypt_raw(&self, chunk: &[u8]) -> Result<Zeroizing<Vec<u8>>, String> {
+        let mut output = Zeroizing::new(vec![0u8; self.key_size()]);
+        self.private_decrypt(chunk, &mut output, Padding::NONE)
+            .map_err(|err| format!("decrypt failed: {:?}", err))?;
+        Ok(output)
+    }
+
+    fn encrypt_pkcs1(&self, chunk: &[u8]) -> Result<Vec<u8>, String> {
+        let mut output = vec![0u8; self.key_size()];
+        self.private_encrypt(chunk, &mut output, Padding::PKCS1)
+            .map_err(|err| format!("encrypt failed: {:?}", err))?;
+        Ok(output)
+    }
+
+    fn rejection_key(&self) -> Result<Zeroizing<Vec<u8>>, String> {
+        let d = Zeroizing::new(self.d().to_vec());
+        Ok(Zeroizing::new(Sha256::digest(&d).to_vec()))
+    }
+}
+
+/// Load a mirror key from a PEM file or a `pkcs11:` URI. The passphrase decrypts encrypted PEM
+/// files, and is the PIN of tokens whose URI does not include one.
+pub fn load_mirror_key(src: &str, passphrase: Option<&str>) -> Result<Arc<dyn KeyBackend>, String> {
+    if src.starts_with("pkcs11:") {
+        Ok(Arc::new(Pkcs11Key::open(src, passphrase)?))
+    } else {
+        Ok(Arc::new(load_private_key(Path::new(src), passphrase)?))
+    }
+}
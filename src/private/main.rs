@@ -6,47 +6,63 @@
 //! requests which it then forwards to the wallet service. When a response is received it is then
 //! forwarded back to the mirror.
 
+mod approval_queue;
+mod backends;
+mod client_auth;
+mod counters;
 mod crypto;
-
-use crate::crypto::{decrypt, encrypt, load_private_key};
-use boring::{pkey::Private, rsa::Rsa};
+mod hardening;
+mod health;
+mod json_rpc;
+mod key_backend;
+mod mirror_keys;
+mod pkcs11;
+mod replay;
+mod request;
+mod response_filter;
+mod response_signer;
+mod sessions;
+mod spending_limits;
+mod watcher;
+
+use crate::{
+    approval_queue::ApprovalQueue,
+    backends::{FullServiceBackends, RoutingStrategy},
+    client_auth::ClientKeys,
+    hardening::{disable_core_dumps, lock_memory},
+    health::start_health_server,
+    key_backend::load_mirror_key,
+    mirror_keys::MirrorKeys,
+    replay::ReplayGuard,
+    request::RequestHandler,
+    response_filter::ResponseFilter,
+    response_signer::ResponseSigner,
+    sessions::Sessions,
+    spending_limits::SpendingLimits,
+    watcher::{Event, Watcher},
+};
 use grpcio::ChannelBuilder;
 use mc_common::logger::{create_app_logger, log, o, Logger};
-use mc_util_grpc::ConnectionUriGrpcioChannel;
+use mc_util_grpc::{AdminServer, ConnectionUriGrpcioChannel};
+use mc_util_uri::{AdminUri, ConnectionUri};
 use mc_wallet_service_mirror::{
+    passphrase::PassphraseSource,
     uri::WalletServiceMirrorUri,
-    wallet_service_mirror_api::{
-        EncryptedResponse, PollRequest, QueryRequest, QueryResponse, UnencryptedResponse,
-    },
+    wallet_service_mirror_api::{AccountSubscription, Notification, PollRequest, QueryResponse},
     wallet_service_mirror_api_grpc::WalletServiceMirrorClient,
 };
-use std::{collections::HashMap, str::FromStr, sync::Arc, thread::sleep, time::Duration};
+use rand::RngCore;
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    path::PathBuf,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    thread::{self, sleep},
+    time::{Duration, Instant},
+};
 use structopt::StructOpt;
 
-const SUPPORTED_ENDPOINTS: &[&str] = &[
-    "check_receiver_receive_status",
-    "create_payment_request",
-    "get_account",
-    "get_account_status",
-    "get_address_for_account",
-    "get_addresses_for_account",
-    "get_all_accounts",
-    "get_all_transaction_logs_for_block",
-    "get_balance_for_account",
-    "get_balance_for_address",
-    "get_block",
-    "get_confirmations",
-    "get_network_status",
-    "get_transaction_log",
-    "get_transaction_logs_for_account",
-    "get_wallet_status",
-    "validate_confirmation",
-    "verify_address",
-];
-
-/// How long do we wait for full-service to reply?
-const FULL_SERVICE_TIMEOUT: Duration = Duration::from_secs(120);
-
 /// A wrapper to ease monitor id parsing from a hex string when using `StructOpt`.
 #[derive(Clone, Debug)]
 pub struct MonitorId(pub Vec<u8>);
@@ -69,23 +85,171 @@ impl FromStr for MonitorId {
     about = "The private side of wallet-service-mirror, receiving requests from the public side and forwarding them to the wallet service."
 )]
 pub struct Config {
-    /// Wallet service URI.
-    #[structopt(long, default_value = "http://127.0.0.1:9090/")]
-    pub wallet_service_uri: String,
+    /// Wallet service URI. May be specified multiple times, in which case requests are routed
+    /// among the wallet service instances according to `--routing-strategy`.
+    #[structopt(long = "wallet-service-uri", default_value = "http://127.0.0.1:9090/")]
+    pub wallet_service_uris: Vec<String>,
 
-    /// URI for the public side of the mirror.
-    #[structopt(long)]
-    pub mirror_public_uri: WalletServiceMirrorUri,
+    /// How requests are routed among wallet service instances: `failover`, `round-robin` (for
+    /// read methods) or `account-id`.
+    #[structopt(long, default_value = "failover")]
+    pub routing_strategy: RoutingStrategy,
+
+    /// How many milliseconds to wait between wallet service health checks.
+    #[structopt(long, default_value = "10000", parse(try_from_str=parse_duration_in_milliseconds))]
+    pub health_check_interval: Duration,
+
+    /// URI for the public side of the mirror. May be specified multiple times, in which case
+    /// every public side is polled concurrently.
+    #[structopt(long = "mirror-public-uri", required = true)]
+    pub mirror_public_uris: Vec<WalletServiceMirrorUri>,
 
     /// How many milliseconds to wait between polling.
     #[structopt(long, default_value = "100", parse(try_from_str=parse_duration_in_milliseconds))]
     pub poll_interval: Duration,
 
-    /// Optional encryption public key. If provided, only encrypted requests are accepted.
+    /// Optional encryption private key: a PEM file, or a `pkcs11:` URI naming a key held by a
+    /// PKCS#11 token (see `pkcs11.rs`). If provided, only encrypted requests are accepted.
     /// See `example-client.js` for an example on how to submit encrypted requests through
     /// the mirror.
-    #[structopt(long, parse(try_from_str=load_private_key))]
-    pub mirror_key: Option<Rsa<Private>>,
+    #[structopt(long)]
+    pub mirror_key: Option<String>,
+
+    /// Previous mirror keys that are still accepted while clients move over to the current
+    /// `--mirror-key` (see `mirror_keys.rs` for the rotation procedure). May be repeated.
+    #[structopt(long)]
+    pub previous_mirror_key: Vec<String>,
+
+    /// Where to read the passphrase of encrypted mirror keys (or the PIN of the PKCS#11 token, if
+    /// the URI does not hold one) from: `file:<path>`, `env:<variable>` or `prompt`.
+    #[structopt(long)]
+    pub mirror_key_passphrase: Option<PassphraseSource>,
+
+    /// Lock the memory of the process in RAM (with `mlockall`), so that the mirror key and
+    /// decrypted requests are never written to swap. Requires a large enough `RLIMIT_MEMLOCK`, or
+    /// `CAP_IPC_LOCK`.
+    #[structopt(long)]
+    pub lock_memory: bool,
+
+    /// Optional JSON file of rules for redacting fields from full-service responses before they
+    /// are returned to clients.
+    #[structopt(long, parse(try_from_str=ResponseFilter::load))]
+    pub response_filter: Option<ResponseFilter>,
+
+    /// Optional JSON file configuring methods (such as transaction submission) that are only
+    /// forwarded to full-service once approved by enough approvers using `mirror-approve`.
+    #[structopt(long, parse(try_from_str=ApprovalQueue::load))]
+    pub approval_config: Option<ApprovalQueue>,
+
+    /// Optional JSON file configuring per-account and per-client caps on the amounts
+    /// transaction-submitting requests may spend.
+    #[structopt(long, parse(try_from_str=SpendingLimits::load))]
+    pub spending_limits: Option<SpendingLimits>,
+
+    /// Optional replay protection for encrypted requests: how many seconds a request timestamp
+    /// may differ from the local clock. When set, encrypted requests must be wrapped in an
+    /// envelope carrying a timestamp and nonce (see `replay.rs`). Should be combined with
+    /// `--client-keys`, since the number of recent nonces is limited per client key.
+    #[structopt(long, parse(try_from_str=parse_duration_in_seconds))]
+    pub replay_window: Option<Duration>,
+
+    /// Optional directory, shared by the private instances polling the same public side, in which
+    /// the nonces of requests are recorded so that a request cannot be replayed to another
+    /// instance (see `replay.rs`). Requires `--replay-window`.
+    #[structopt(long, parse(from_os_str))]
+    pub replay_nonce_dir: Option<PathBuf>,
+
+    /// Optional JSON file holding the public keys of the clients allowed to submit encrypted
+    /// requests. When set, encrypted requests and session handshakes must be signed by one of
+    /// them (see `client_auth.rs`). The file is only read on startup, so registering or revoking
+    /// a client requires a restart.
+    #[structopt(long, parse(try_from_str=ClientKeys::load))]
+    pub client_keys: Option<ClientKeys>,
+
+    /// Optional file holding a hex-encoded Ed25519 private key (such as one generated by
+    /// `mirror-approve generate-key`) used to sign every response, so that clients can verify that
+    /// responses were not altered by the public side (see `response_signature.rs`). Clients that
+    /// rely on this must reject unsigned responses, such as the ones served from the response
+    /// cache of the public side.
+    #[structopt(long, parse(try_from_str=ResponseSigner::load))]
+    pub response_signing_key: Option<ResponseSigner>,
+
+    /// How long (in seconds) a forward-secret session established by a client may be used for,
+    /// after which its keys are forgotten (see `sessions.rs`). Sessions require `--mirror-key`.
+    #[structopt(long, default_value = "3600", parse(try_from_str=parse_duration_in_seconds))]
+    pub session_lifetime: Duration,
+
+    /// Optional interval (in milliseconds) at which full-service is checked for new blocks and
+    /// transaction logs, which are pushed to clients subscribed on the public side. Notifications
+    /// are disabled if not provided.
+    #[structopt(long, parse(try_from_str=parse_duration_in_milliseconds))]
+    pub watch_interval: Option<Duration>,
+
+    /// Identifies this instance to the public side of the mirror, when several private instances
+    /// poll the same public side. A random id is generated if not provided.
+    #[structopt(long)]
+    pub instance_id: Option<String>,
+
+    /// A tenant whose requests this instance serves, when the public side fronts several wallets.
+    /// May be specified multiple times. Without it, only requests made without a tenant are
+    /// served.
+    #[structopt(long = "tenant")]
+    pub tenants: Vec<String>,
+
+    /// Optional admin listening URI, exposing metrics.
+    #[structopt(long)]
+    pub admin_listen_uri: Option<AdminUri>,
+
+    /// Optional local address (e.g. `127.0.0.1:9091`) serving `GET /healthz`, which checks that
+    /// full-service is reachable.
+    #[structopt(long)]
+    pub health_listen_addr: Option<SocketAddr>,
+}
+
+/// The maximum number of notifications held while the public side of the mirror is unreachable.
+const MAX_PENDING_NOTIFICATIONS: usize = 1000;
+
+/// State shared between the polling loop of a public side of the mirror and the main loop.
+struct PublicSide {
+    uri: WalletServiceMirrorUri,
+
+    /// The accounts clients of this public side are subscribed to notifications for.
+    account_subscriptions: Mutex<Vec<AccountSubscription>>,
+
+    /// Notifications waiting to be delivered to this public side.
+    pending_notifications: Mutex<PendingNotifications>,
+}
+
+/// Notifications waiting to be delivered to a public side, numbered in the order they were
+/// queued so that the delivered ones can be told apart from the ones queued since.
+#[derive(Default)]
+struct PendingNotifications {
+    next_sequence: u64,
+    notifications: Vec<(u64, Notification)>,
+}
+
+impl PendingNotifications {
+    fn push(&mut self, notification: Notification) {
+        self.notifications.push((self.next_sequence, notification));
+        self.next_sequence += 1;
+    }
+
+    /// Returns the queued notifications, and the sequence number of the last of them.
+    fn snapshot(&self) -> (Vec<Notification>, Option<u64>) {
+        (
+            self.notifications
+                .iter()
+                .map(|(_, notification)| notification.clone())
+                .collect(),
+            self.notifications.last().map(|(sequence, _)| *sequence),
+        )
+    }
+
+    /// Drop the notifications queued up to and including the given one.
+    fn drop_delivered(&mut self, last_delivered: u64) {
+        self.notifications
+            .retain(|(sequence, _)| *sequence > last_delivered);
+    }
 }
 
 fn main() {
@@ -94,14 +258,225 @@ fn main() {
 
     let config = Config::from_args();
 
+    let instance_id = config.instance_id.clone().unwrap_or_else(|| {
+        let mut bytes = [0u8; 8];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        hex::encode(bytes)
+    });
+
     let (logger, _global_logger_guard) = create_app_logger(o!());
     log::info!(
         logger,
-        "Starting wallet-service-mirror private forwarder on {}, connecting to wallet service at {}",
-        config.mirror_public_uri,
-        config.wallet_service_uri,
+        "Starting wallet-service-mirror private forwarder {} on {:?}, connecting to wallet service at {:?} ({:?})",
+        instance_id,
+        config.mirror_public_uris,
+        config.wallet_service_uris,
+        config.routing_strategy,
+    );
+    if config.mirror_key.is_none() && !config.previous_mirror_key.is_empty() {
+        panic!("--previous-mirror-key requires --mirror-key");
+    }
+    if config.replay_window.is_none() && config.replay_nonce_dir.is_some() {
+        panic!("--replay-nonce-dir requires --replay-window");
+    }
+    if config.replay_window.is_some() && config.client_keys.is_none() {
+        log::warn!(
+            logger,
+            "--replay-window without --client-keys: all requests share a single nonce limit"
+        );
+    }
+    if config.mirror_key.is_some() {
+        disable_core_dumps().expect("Failed disabling core dumps");
+    }
+    if config.lock_memory {
+        lock_memory().expect("Failed locking memory");
+    }
+    let mirror_keys = config.mirror_key.as_ref().map(|mirror_key| {
+        let passphrase = config.mirror_key_passphrase.as_ref().map(|source| {
+            source
+                .read("Mirror key passphrase")
+                .expect("Failed reading mirror key passphrase")
+        });
+        let load = |src: &String| {
+            load_mirror_key(
+                src,
+                passphrase.as_ref().map(|passphrase| passphrase.as_str()),
+            )
+            .expect("Failed loading mirror key")
+        };
+        MirrorKeys::new(
+            load(mirror_key),
+            config.previous_mirror_key.iter().map(load).collect(),
+        )
+        .expect("Failed loading mirror keys")
+    });
+    if let Some(mirror_keys) = mirror_keys.as_ref() {
+        log::info!(
+            logger,
+            "Accepting encrypted requests with mirror key {} (previous keys: {:?})",
+            mirror_keys.current_fingerprint(),
+            mirror_keys.previous_fingerprints()
+        );
+    }
+    if let Some(response_signer) = config.response_signing_key.as_ref() {
+        log::info!(
+            logger,
+            "Signing responses with public key {}",
+            response_signer.public_key_hex()
+        );
+    }
+    if !config.tenants.is_empty() {
+        log::info!(logger, "Serving tenants {:?}", config.tenants);
+    }
+
+    let backends =
+        FullServiceBackends::new(config.wallet_service_uris.clone(), config.routing_strategy);
+    backends.start_health_checks(config.health_check_interval, logger.clone());
+
+    let request_handler = RequestHandler::new(
+        backends.clone(),
+        config.response_filter.clone(),
+        config.approval_config.clone(),
+        config.spending_limits.clone(),
+        config.replay_window.map(|replay_window| {
+            ReplayGuard::new(replay_window, config.replay_nonce_dir.as_deref())
+                .expect("Failed creating replay nonce dir")
+        }),
+        config.client_keys.clone(),
+        config.response_signing_key.clone(),
+        Sessions::new(config.session_lifetime),
     );
+    request_handler
+        .recover_approvals(&logger)
+        .expect("Failed recovering interrupted approvals");
+
+    // Start the health server, if requested.
+    if let Some(health_listen_addr) = config.health_listen_addr {
+        log::info!(logger, "Starting health server on {}", health_listen_addr);
+        start_health_server(health_listen_addr, backends.clone(), logger.clone())
+            .expect("Failed starting health server");
+    }
+
+    // Start the admin server, if requested.
+    let _admin_server = config.admin_listen_uri.as_ref().map(|admin_listen_uri| {
+        AdminServer::start(
+            None,
+            admin_listen_uri,
+            "Wallet Service Mirror Private".to_owned(),
+            instance_id.clone(),
+            None,
+            logger.clone(),
+        )
+        .expect("Failed starting admin server")
+    });
+
+    // Start polling every public side of the mirror.
+    let request_handler = Arc::new(request_handler);
+    let public_sides: Vec<Arc<PublicSide>> = config
+        .mirror_public_uris
+        .iter()
+        .map(|uri| {
+            Arc::new(PublicSide {
+                uri: uri.clone(),
+                account_subscriptions: Mutex::new(Vec::new()),
+                pending_notifications: Mutex::new(PendingNotifications::default()),
+            })
+        })
+        .collect();
+
+    for public_side in public_sides.iter() {
+        let public_side = public_side.clone();
+        let request_handler = request_handler.clone();
+        let mirror_keys = mirror_keys.clone();
+        let instance_id = instance_id.clone();
+        let tenants = config.tenants.clone();
+        let poll_interval = config.poll_interval;
+        let logger = logger.new(o!("mirror_public_uri" => public_side.uri.to_string()));
+
+        thread::Builder::new()
+            .name(format!("Poll-{}", public_side.uri.addr()))
+            .spawn(move || {
+                poll_public_side(
+                    &public_side,
+                    &request_handler,
+                    mirror_keys.as_ref(),
+                    &instance_id,
+                    &tenants,
+                    poll_interval,
+                    &logger,
+                )
+            })
+            .expect("Failed spawning polling thread");
+    }
+
+    // Main loop, handling the work that is not specific to a public side.
+    log::debug!(logger, "Entering main loop");
+
+    let mut watcher = config
+        .watch_interval
+        .map(|_| Watcher::new(backends.clone(), config.response_filter.clone()));
+    let mut last_watched: Option<Instant> = None;
+
+    loop {
+        // Forward any requests that were approved since the last poll.
+        if let Err(err) = request_handler.process_approvals(&logger) {
+            log::error!(logger, "Processing approvals failed: {}", err);
+        }
 
+        // Check full-service for changes subscribed clients should be notified about.
+        if let (Some(watcher), Some(watch_interval)) = (watcher.as_mut(), config.watch_interval) {
+            if last_watched.map_or(true, |last_watched| {
+                last_watched.elapsed() >= watch_interval
+            }) {
+                last_watched = Some(Instant::now());
+
+                let mut subscribed_account_ids: Vec<String> = public_sides
+                    .iter()
+                    .flat_map(|public_side| {
+                        public_side
+                            .account_subscriptions
+                            .lock()
+                            .expect("mutex poisoned")
+                            .iter()
+                            .map(|subscription| subscription.get_account_id().to_owned())
+                            .collect::<Vec<_>>()
+                    })
+                    .collect();
+                subscribed_account_ids.sort();
+                subscribed_account_ids.dedup();
+
+                let encrypted = mirror_keys.is_some();
+                match watcher.check(&subscribed_account_ids, encrypted, &logger) {
+                    Ok(events) => {
+                        for public_side in public_sides.iter() {
+                            queue_notifications(
+                                public_side,
+                                &events,
+                                &request_handler,
+                                encrypted,
+                                &logger,
+                            );
+                        }
+                    }
+                    Err(err) => log::error!(logger, "Watching full-service failed: {}", err),
+                }
+            }
+        }
+
+        sleep(config.poll_interval);
+    }
+}
+
+/// The polling loop of a single public side of the mirror.
+fn poll_public_side(
+    public_side: &PublicSide,
+    request_handler: &RequestHandler,
+    mirror_keys: Option<&MirrorKeys>,
+    instance_id: &str,
+    tenants: &[String],
+    poll_interval: Duration,
+    logger: &Logger,
+) {
     // Set up the gRPC connection to the public side of the mirror.
     let mirror_api_client = {
         let env = Arc::new(grpcio::EnvBuilder::new().build());
@@ -110,20 +485,26 @@ fn main() {
             .max_send_message_len(-1)
             .max_reconnect_backoff(Duration::from_millis(2000))
             .initial_reconnect_backoff(Duration::from_millis(1000))
-            .connect_to_uri(&config.mirror_public_uri, &logger);
+            .connect_to_uri(&public_side.uri, logger);
 
         WalletServiceMirrorClient::new(ch)
     };
 
-    // Main polling loop.
-    log::debug!(logger, "Entering main loop");
-
     let mut pending_responses: HashMap<String, QueryResponse> = HashMap::new();
 
     loop {
         // Communicate with the public side of the mirror.
+        let (notifications, last_notification) = public_side
+            .pending_notifications
+            .lock()
+            .expect("mutex poisoned")
+            .snapshot();
+
         let mut request = PollRequest::new();
         request.set_query_responses(pending_responses.clone());
+        request.set_notifications(notifications.into());
+        request.set_instance_id(instance_id.to_owned());
+        request.set_tenants(tenants.to_vec().into());
 
         log::debug!(
             logger,
@@ -137,53 +518,56 @@ fn main() {
                     "Poll succeeded, got back {} requests",
                     response.query_requests.len()
                 );
+                counters::OP_COUNTERS.inc(&format!("poll_succeeded:{}", public_side.uri));
 
                 // Clear pending responses since we successfully delivered them to the other side.
                 pending_responses.clear();
+                if let Some(last_notification) = last_notification {
+                    public_side
+                        .pending_notifications
+                        .lock()
+                        .expect("mutex poisoned")
+                        .drop_delivered(last_notification);
+                }
+                *public_side
+                    .account_subscriptions
+                    .lock()
+                    .expect("mutex poisoned") = response.get_account_subscriptions().to_vec();
 
                 // Process requests.
                 for (query_id, query_request) in response.query_requests.iter() {
                     let query_logger = logger.new(o!("query_id" => query_id.clone()));
 
-                    let response = {
-                        if let Some(mirror_key) = config.mirror_key.as_ref() {
-                            process_encrypted_request(
-                                &config.wallet_service_uri,
-                                mirror_key,
+                    let result = match mirror_keys {
+                        Some(mirror_keys) if query_request.has_session_handshake_request() => {
+                            request_handler.process_session_handshake(
+                                mirror_keys,
                                 query_request,
                                 &query_logger,
                             )
-                            .unwrap_or_else(|err| {
-                                log::error!(
-                                    query_logger,
-                                    "process_encrypted_request failed: {:?}",
-                                    err
-                                );
-
-                                let mut err_query_response = QueryResponse::new();
-                                err_query_response.set_error(err);
-                                err_query_response
-                            })
-                        } else {
-                            process_unencrypted_request(
-                                &config.wallet_service_uri,
-                                query_request,
-                                &query_logger,
-                            )
-                            .unwrap_or_else(|err| {
-                                log::error!(
-                                    query_logger,
-                                    "process_unencrypted_request failed: {:?}",
-                                    err
-                                );
-
-                                let mut err_query_response = QueryResponse::new();
-                                err_query_response.set_error(err);
-                                err_query_response
-                            })
                         }
+                        Some(_) if query_request.has_session_request() => {
+                            request_handler.process_session_request(query_request, &query_logger)
+                        }
+                        Some(mirror_keys) => request_handler.process_encrypted_request(
+                            mirror_keys,
+                            query_request,
+                            &query_logger,
+                        ),
+                        None => request_handler
+                            .process_unencrypted_request(query_request, &query_logger),
                     };
+                    let mut response = result.unwrap_or_else(|err| {
+                        log::error!(query_logger, "Processing request failed: {:?}", err);
+
+                        let mut err_query_response = QueryResponse::new();
+                        err_query_response.set_error(err);
+                        err_query_response
+                    });
+
+                    request_handler.sign_response(query_id, query_request, &mut response);
 
+                    counters::OP_COUNTERS.inc(&format!("query_processed:{}", public_side.uri));
                     pending_responses.insert(query_id.clone(), response);
                 }
             }
@@ -194,131 +578,96 @@ fn main() {
                     "Polling the public side of the mirror failed: {:?}",
                     err
                 );
+                counters::OP_COUNTERS.inc(&format!("poll_failed:{}", public_side.uri));
             }
         }
 
-        sleep(config.poll_interval);
+        sleep(poll_interval);
     }
 }
 
-fn validate_method(json: &str) -> serde_json::Result<bool> {
-    let json: serde_json::Value = serde_json::from_str(json)?;
-    let method = json["method"].as_str().unwrap_or("");
-    Ok(SUPPORTED_ENDPOINTS.iter().any(|&s| s == method))
-}
-
-fn process_unencrypted_request(
-    wallet_service_uri: &str,
-    query_request: &QueryRequest,
+/// Queue notifications about events for delivery to a public side, keeping only the ones its
+/// clients are subscribed to. When requests have to be encrypted, events about an account are
+/// encrypted for each session subscribed to the account.
+fn queue_notifications(
+    public_side: &PublicSide,
+    events: &[Event],
+    request_handler: &RequestHandler,
+    encrypted: bool,
     logger: &Logger,
-) -> Result<QueryResponse, String> {
-    if !query_request.has_unencrypted_request() {
-        return Err("Only processing unencrypted requests".into());
-    }
-
-    let unencrypted_request = query_request.get_unencrypted_request();
+) {
+    let account_subscriptions = public_side
+        .account_subscriptions
+        .lock()
+        .expect("mutex poisoned")
+        .clone();
+
+    let mut notifications = Vec::new();
+    for event in events {
+        let json_event = event.event.to_string();
+        if event.account_id.is_empty() {
+            let mut notification = Notification::new();
+            notification.set_json_event(json_event);
+            notifications.push(notification);
+            continue;
+        }
 
-    log::debug!(
-        logger,
-        "Incoming unencrypted request ({})",
-        unencrypted_request.json_request
-    );
+        let mut subscriptions = account_subscriptions
+            .iter()
+            .filter(|subscription| subscription.get_account_id() == event.account_id)
+            .peekable();
+        if !encrypted {
+            if subscriptions.peek().is_some() {
+                let mut notification = Notification::new();
+                notification.set_account_id(event.account_id.clone());
+                notification.set_json_event(json_event);
+                notifications.push(notification);
+            }
+            continue;
+        }
 
-    // Check that the request is of an allowed type.
-    match validate_method(&unencrypted_request.json_request) {
-        Ok(true) => (),
-        Ok(false) => return Err("Unsupported request".into()),
-        Err(err) => {
-            let mut err_query_response = QueryResponse::new();
-            err_query_response.set_error(format!("Error parsing JSON request: {}", err));
-            return Ok(err_query_response);
+        for subscription in subscriptions {
+            let session_id = subscription.get_session_id();
+            if session_id.is_empty() {
+                continue;
+            }
+            match request_handler.seal_notification(session_id, json_event.as_bytes()) {
+                Ok(encrypted_event) => {
+                    let mut notification = Notification::new();
+                    notification.set_account_id(event.account_id.clone());
+                    notification.set_session_id(session_id.to_owned());
+                    notification.set_encrypted_event(encrypted_event);
+                    notifications.push(notification);
+                }
+                Err(err) => log::debug!(logger, "Not notifying session {}: {}", session_id, err),
+            }
         }
     }
 
-    // Pass request along to full-service.
-    let client = reqwest::blocking::Client::builder()
-        .timeout(FULL_SERVICE_TIMEOUT)
-        .build()
-        .map_err(|e| e.to_string())?;
-    let res = client
-        .post(wallet_service_uri)
-        .header(reqwest::header::CONTENT_TYPE, "application/json")
-        .body(unencrypted_request.json_request.clone())
-        .send()
-        .map_err(|e| e.to_string())?;
-    let json_response = res.text().map_err(|e| e.to_string())?;
-
-    let mut unencrypted_response = UnencryptedResponse::new();
-    unencrypted_response.set_json_response(json_response);
-
-    let mut mirror_response = QueryResponse::new();
-    mirror_response.set_unencrypted_response(unencrypted_response);
-    Ok(mirror_response)
-}
-
-fn process_encrypted_request(
-    wallet_service_uri: &str,
-    mirror_key: &Rsa<Private>,
-    query_request: &QueryRequest,
-    logger: &Logger,
-) -> Result<QueryResponse, String> {
-    if !query_request.has_encrypted_request() {
-        return Err("Only processing encrypted requests".into());
+    let mut pending_notifications = public_side
+        .pending_notifications
+        .lock()
+        .expect("mutex poisoned");
+    for notification in notifications {
+        pending_notifications.push(notification);
     }
 
-    let encrypted_request = query_request.get_encrypted_request();
-
-    // Decrypt the request.
-    let json_request = match decrypt(mirror_key, &encrypted_request.payload)
-        .map_err(|err| format!("Error decrypting request: {}", err))
-        .and_then(|decrypted| {
-            String::from_utf8(decrypted).map_err(|err| format!("Error parsing utf8: {}", err))
-        }) {
-        Ok(json_request) => json_request,
-        Err(err) => {
-            let mut err_query_response = QueryResponse::new();
-            err_query_response.set_error(err);
-            return Ok(err_query_response);
-        }
-    };
-
-    log::debug!(logger, "Incoming encrypted request ({})", json_request,);
-
-    // Check that the request is of an allowed type.
-    match validate_method(&json_request) {
-        Ok(true) => (),
-        Ok(false) => return Err("Unsupported request".into()),
-        Err(err) => {
-            let mut err_query_response = QueryResponse::new();
-            err_query_response.set_error(format!("Error parsing JSON request: {}", err));
-            return Ok(err_query_response);
-        }
+    if pending_notifications.notifications.len() > MAX_PENDING_NOTIFICATIONS {
+        let excess = pending_notifications.notifications.len() - MAX_PENDING_NOTIFICATIONS;
+        log::warn!(
+            logger,
+            "Dropping {} notifications undelivered to {}",
+            excess,
+            public_side.uri
+        );
+        pending_notifications.notifications.drain(..excess);
     }
-
-    // Pass request along to full-service.
-    let client = reqwest::blocking::Client::builder()
-        .timeout(FULL_SERVICE_TIMEOUT)
-        .build()
-        .map_err(|e| e.to_string())?;
-    let res = client
-        .post(wallet_service_uri)
-        .header(reqwest::header::CONTENT_TYPE, "application/json")
-        .body(json_request.clone())
-        .send()
-        .map_err(|e| e.to_string())?;
-    let json_response = res.text().map_err(|e| e.to_string())?;
-
-    let encrypted_payload =
-        encrypt(mirror_key, &json_response.as_bytes()).map_err(|_e| "Encryption failed")?;
-
-    let mut encrypted_response = EncryptedResponse::new();
-    encrypted_response.set_payload(encrypted_payload);
-
-    let mut mirror_response = QueryResponse::new();
-    mirror_response.set_encrypted_response(encrypted_response);
-    Ok(mirror_response)
 }
 
 fn parse_duration_in_milliseconds(src: &str) -> Result<Duration, std::num::ParseIntError> {
     Ok(Duration::from_millis(u64::from_str(src)?))
 }
+
+fn parse_duration_in_seconds(src: &str) -> Result<Duration, std::num::ParseIntError> {
+    Ok(Duration::from_secs(u64::from_str(src)?))
+}
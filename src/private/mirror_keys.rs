@@ -0,0 +1,233 @@
+// Copyright (c) 2018-2022 MobileCoin Inc.
+
+//! The mirror keys encrypted requests are encrypted with.
+//!
+//! The private side holds the current mirror key (`--mirror-key`), and optionally previous keys
+//! (`--previous-mirror-key`) that are still accepted while clients move over to the current one.
+//! Keys are identified by their fingerprint (see `key_fingerprint.rs`), which clients pass in the
+//! `X-Mirror-Key-Fingerprint` header. Requests that do not name a key are tried with every key,
+//! starting with the current one, until one decrypts them to a JSON object or array. Responses are encrypted with the key that decrypted the
+//! request.
+//!
+//! Rotating the mirror key:
+//! 1. Generate a new key pair with `mirror-keytool generate --out <private key>
+//!    --public-out <public key>`, and note the fingerprint it prints (`mirror-keytool
+//!    fingerprint` prints it again later).
+//! 2. Restart the private side with the new key as `--mirror-key`, and the old key as
+//!    `--previous-mirror-key`. Clients keep working with the old key.
+//! 3. Distribute the new public key to clients, which send its fingerprint from then on.
+//! 4. Once the `deprecated_mirror_key:<fingerprint>` counter stops increasing (requests using
+//!    the old key are also logged), restart the private side without `--previous-mirror-key`.
+
+use crate::{counters, crypto::decrypt, key_backend::KeyBackend};
+use mc_common::logger::{log, Logger};
+use mc_wallet_service_mirror::key_fingerprint::key_fingerprint;
+use serde_json::Value;
+use std::{fmt, sync::Arc};
+use zeroize::Zeroizing;
+
+/// A mirror key and its fingerprint.
+#[derive(Clone)]
+struct MirrorKey {
+    fingerprint: String,
+    key: Arc<dyn KeyBackend>,
+}
+
+impl MirrorKey {
+    fn new(key: Arc<dyn KeyBackend>) -> Result<Self, String> {
+        Ok(Self {
+            fingerprint: key_fingerprint(&key.public_key()?)?,
+            key,
+        })
+    }
+}
+
+/// The current mirror key, and the previous keys that are still accepted.
+#[derive(Clone)]
+pub struct MirrorKeys {
+    current: MirrorKey,
+    previous: Vec<MirrorKey>,
+}
+
+impl fmt::Debug for MirrorKeys {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("MirrorKeys")
+            .field("current", &self.current.fingerprint)
+            .field(
+                "previous",
+                &self
+                    .previous
+                    .iter()
+                    .map(|previous| &previous.fingerprint)
+                    .collect::<Vec<_>>(),
+            )
+            .finish()
+    }
+}
+
+impl MirrorKeys {
+    pub fn new(
+        current: Arc<dyn KeyBackend>,
+        previous: Vec<Arc<dyn KeyBackend>>,
+    ) -> Result<Self, String> {
+        Ok(Self {
+            current: MirrorKey::new(current)?,
+            previous: previous
+                .into_iter()
+                .map(MirrorKey::new)
+                .collect::<Result<_, _>>()?,
+        })
+    }
+
+    /// The fingerprint of the current mirror key.
+    pub fn current_fingerprint(&self) -> &str {
+        &self.current.fingerprint
+    }
+
+    /// The fingerprints of the previous mirror keys.
+    pub fn previous_fingerprints(&self) -> Vec<&str> {
+        self.previous
+            .iter()
+            .map(|previous| previous.fingerprint.as_str())
+            .collect()
+    }
+
+    /// Decrypt a payload with the mirror key of the given fingerprint, or with the first key that
+    /// decrypts it to a JSON object or array if no fingerprint is given. Other JSON values are too
+    /// short to tell a wrong key's output from a request. Returns the plaintext (None if the payload
+    /// cannot be decrypted) and the key responses should be encrypted with, or an error if there
+    /// is no key with the given fingerprint.
+    pub fn decrypt(
+        &self,
+        fingerprint: &str,
+        payload: &[u8],
+        logger: &Logger,
+    ) -> Result<(Option<Zeroizing<Vec<u8>>>, &dyn KeyBackend), String> {
+        if !fingerprint.is_empty() {
+            let mirror_key = self
+                .keys()
+                .find(|mirror_key| mirror_key.fingerprint == fingerprint)
+                .ok_or_else(|| format!("Unknown mirror key {}", fingerprint))?;
+            self.note_use(mirror_key, logger);
+            return Ok((decrypt(&*mirror_key.key, payload).ok(), &*mirror_key.key));
+        }
+
+        for mirror_key in self.keys() {
+            let decrypted = match decrypt(&*mirror_key.key, payload) {
+                Ok(decrypted) => decrypted,
+                Err(_) => continue,
+            };
+            if matches!(
+                serde_json::from_slice::<Value>(&decrypted),
+                Ok(Value::Object(_)) | Ok(Value::Array(_))
+            ) {
+                self.note_use(mirror_key, logger);
+                return Ok((Some(decrypted), &*mirror_key.key));
+            }
+        }
+        Ok((None, &*self.current.key))
+    }
+
+    /// All the mirror keys, starting with the current one.
+    fn keys(&self) -> impl Iterator<Item = &MirrorKey> {
+        std::iter::once(&self.current).chain(self.previous.iter())
+    }
+
+    /// Report the use of previous keys, so that operators know when they can be dropped.
+    fn note_use(&self, mirror_key: &MirrorKey, logger: &Logger) {
+        if mirror_key.fingerprint != self.current.fingerprint {
+            log::warn!(
+                logger,
+                "Request encrypted with deprecated mirror key {}",
+                mirror_key.fingerprint
+            );
+            counters::OP_COUNTERS.inc(&format!("deprecated_mirror_key:{}", mirror_key.fingerprint));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use boring::{
+        pkey::Private,
+        rsa::{Padding, Rsa},
+    };
+    use mc_common::logger::test_with_logger;
+
+    fn public_encrypt(key: &Rsa<Private>, plaintext: &[u8]) -> Vec<u8> {
+        let mut ciphertext = vec![0u8; key.size() as usize];
+        key.public_encrypt(plaintext, &mut ciphertext, Padding::PKCS1)
+            .unwrap();
+        ciphertext
+    }
+
+    #[test_with_logger]
+    fn payloads_are_decrypted_with_the_named_key(logger: Logger) {
+        let current = Rsa::generate(2048).unwrap();
+        let previous = Rsa::generate(2048).unwrap();
+        let mirror_keys =
+            MirrorKeys::new(Arc::new(current), vec![Arc::new(previous.clone())]).unwrap();
+        let previous_fingerprint = key_fingerprint(&previous).unwrap();
+        assert_eq!(
+            mirror_keys.previous_fingerprints(),
+            vec![previous_fingerprint.as_str()]
+        );
+
+        let payload = public_encrypt(&previous, b"{}");
+        let (decrypted, key) = mirror_keys
+            .decrypt(&previous_fingerprint, &payload, &logger)
+            .unwrap();
+        assert_eq!(&decrypted.unwrap()[..], b"{}");
+        assert_eq!(
+            key_fingerprint(&key.public_key().unwrap()).unwrap(),
+            previous_fingerprint.as_str()
+        );
+
+        // Naming the wrong key does not fall back to the other keys.
+        let (decrypted, _) = mirror_keys
+            .decrypt(mirror_keys.current_fingerprint(), &payload, &logger)
+            .unwrap();
+        assert_ne!(decrypted, Some(Zeroizing::new(b"{}".to_vec())));
+
+        assert!(mirror_keys.decrypt("abcd", &payload, &logger).is_err());
+    }
+
+    #[test_with_logger]
+    fn unnamed_payloads_are_tried_with_every_key(logger: Logger) {
+        let current = Rsa::generate(2048).unwrap();
+        let previous = Rsa::generate(2048).unwrap();
+        let mirror_keys =
+            MirrorKeys::new(Arc::new(current.clone()), vec![Arc::new(previous.clone())]).unwrap();
+
+        for key in &[&current, &previous] {
+            let (decrypted, response_key) = mirror_keys
+                .decrypt("", &public_encrypt(key, b"[1]"), &logger)
+                .unwrap();
+            assert_eq!(&decrypted.unwrap()[..], b"[1]");
+            assert_eq!(
+                key_fingerprint(&response_key.public_key().unwrap()).unwrap(),
+                key_fingerprint(*key).unwrap()
+            );
+        }
+
+        let (decrypted, response_key) = mirror_keys
+            .decrypt("", &public_encrypt(&current, b"not json"), &logger)
+            .unwrap();
+        assert_eq!(decrypted, None);
+        assert_eq!(
+            key_fingerprint(&response_key.public_key().unwrap()).unwrap(),
+            mirror_keys.current_fingerprint()
+        );
+
+        // JSON values other than objects and arrays are not taken as a match.
+        let (decrypted, _) = mirror_keys
+            .decrypt("", &public_encrypt(&previous, b"7"), &logger)
+            .unwrap();
+        assert_eq!(decrypted, None);
+        assert_eq!(
+            key_fingerprint(&response_key.public_key().unwrap()).unwrap(),
+            mirror_keys.current_fingerprint()
+        );
+    }
+}
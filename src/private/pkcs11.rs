@@ -0,0 +1,345 @@
+// Copyright (c) 2018-2022 MobileCoin Inc.
+
+//! Mirror keys held by a PKCS#11 token, such as an HSM.
+//!
+//! The key is named by an RFC 7512 URI passed as `--mirror-key`, e.g.
+//! `pkcs11:token=mirror;object=mirror-key?module-path=/usr/lib/softhsm/libsofthsm2.so&pin-source=file:/etc/mirror/pin`.
+//! The supported path attributes are `token` (the token label), `object` (the key label), `id`
+//! (the key id) and `type` (which must be `private`), and the supported query attributes are
+//! `module-path` (required), `pin-value` and `pin-source` (a passphrase source, as accepted by
+//! `--mirror-key-passphrase`). Without a PIN in the URI, the `--mirror-key-passphrase` is used.
+//!
+//! Requests are decrypted with `CKM_RSA_X_509` (raw RSA, the padding being checked in constant
+//! time by `crypto.rs`), and responses encrypted with `CKM_RSA_PKCS` signatures, so the token must
+//! allow both for the key.
+//!
+//! Trying it out locally with SoftHSM:
+//! ```sh
+//! softhsm2-util --init-token --free --label mirror --pin 1234 --so-pin 5678
+//! openssl pkcs8 -topk8 -nocrypt -in mirror-private.pem -out mirror-private.p8
+//! softhsm2-util --import mirror-private.p8 --token mirror --label mirror-key --id 01 --pin 1234
+//! MIRROR_PKCS11_TEST_URI='pkcs11:token=mirror;object=mirror-key?module-path=/usr/lib/softhsm/libsofthsm2.so&pin-value=1234' \
+//!     cargo test --bin wallet-service-mirror-private pkcs11 -- --ignored
+//! ```
+
+use crate::key_backend::KeyBackend;
+use boring::{bn::BigNum, pkey::Public, rsa::Rsa};
+use cryptoki::{
+    context::{CInitializeArgs, Pkcs11},
+    mechanism::Mechanism,
+    object::{Attribute, AttributeType, KeyType, ObjectClass, ObjectHandle},
+    session::{Session, UserType},
+};
+use mc_wallet_service_mirror::passphrase::PassphraseSource;
+use sha2::{Digest, Sha256};
+use std::sync::Mutex;
+use zeroize::Zeroizing;
+
+/// Signed to derive the rejection key (see `KeyBackend::rejection_key`), since the private
+/// exponent cannot be read from the token. It is not valid UTF-8, so it is never a response.
+const REJECTION_KEY_MESSAGE: &[u8] = b"\xffwallet-service-mirror-rejection-key\x00";
+
+/// The parts of a `pkcs11:` URI identifying a key.
+#[derive(Debug, Default, Eq, PartialEq)]
+pub struct Pkcs11Uri {
+    pub token: Option<String>,
+    pub object: Option<String>,
+    pub id: Option<Vec<u8>>,
+    pub module_path: Option<String>,
+    pub pin_value: Option<String>,
+    pub pin_source: Option<String>,
+}
+
+impl Pkcs11Uri {
+    pub fn parse(uri: &str) -> Result<Self, String> {
+        let rest = uri
+            .strip_prefix("pkcs11:")
+            .ok_or_else(|| "PKCS#11 URIs start with pkcs11:".to_owned())?;
+        let (path, query) = match rest.find('?') {
+            Some(index) => (&rest[..index], &rest[index + 1..]),
+            None => (rest, ""),
+        };
+
+        let mut parsed = Self::default();
+        for attribute in path.split(';').filter(|attribute| !attribute.is_empty()) {
+            let (name, value) = split_attribute(attribute)?;
+            match name {
+                "token" => parsed.token = Some(percent_decode_string(value)?),
+                "object" => parsed.object = Some(percent_decode_string(value)?),
+                "id" => parsed.id = Some(percent_decode(value)?),
+                "type" if value == "private" => {}
+                "type" => return Err(format!("the mirror key must be private, not {}", value)),
+                _ => return Err(format!("unsupported PKCS#11 URI attribute {}", name)),
+            }
+        }
+        for attribute in query.split('&').filter(|attribute| !attribute.is_empty()) {
+            let (name, value) = split_attribute(attribute)?;
+            match name {
+                "module-path" => parsed.module_path = Some(percent_decode_string(value)?),
+                "pin-value" => parsed.pin_value = Some(percent_decode_string(value)?),
+                "pin-source" => parsed.pin_source = Some(percent_decode_string(value)?),
+                _ => return Err(format!("unsupported PKCS#11 URI attribute {}", name)),
+            }
+        }
+        Ok(parsed)
+    }
+}
+
+fn split_attribute(attribute: &str) -> Result<(&str, &str), String> {
+    let index = attribute
+        .find('=')
+        .ok_or_else(|| format!("invalid PKCS#11 URI attribute {}", attribute))?;
+    Ok((&attribute[..index], &attribute[index + 1..]))
+}
+
+fn percent_decode(value: &str) -> Result<Vec<u8>, String> {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = value
+                .get(i + 1..i + 3)
+                .ok_or_else(|| format!("invalid percent-encoding in {}", value))?;
+            let byte = u8::from_str_radix(hex, 16)
+                .map_err(|_| format!("invalid percent-encoding in {}", value))?;
+            decoded.push(byte);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    Ok(decoded)
+}
+
+fn percent_decode_string(value: &str) -> Result<String, String> {
+    String::from_utf8(percent_decode(value)?)
+        .map_err(|_| format!("invalid UTF-8 in PKCS#11 URI attribute {}", value))
+}
+
+/// An RSA private key held by a PKCS#11 token.
+pub struct Pkcs11Key {
+    session: Mutex<Session>,
+    key: ObjectHandle,
+    public_key: Rsa<Public>,
+    rejection_key: Zeroizing<Vec<u8>>,
+}
+
+impl Pkcs11Key {
+    /// Open the key named by a `pkcs11:` URI, logging in with the PIN from the URI or, failing
+    /// that, the given passphrase.
+    pub fn open(uri: &str, passphrase: Option<&str>) -> Result<Self, String> {
+        let uri = Pkcs11Uri::parse(uri)?;
+        let module_path = uri
+            .module_path
+            .as_ref()
+            .ok_or_else(|| "PKCS#11 URI is missing module-path".to_owned())?;
+
+        let pkcs11 = Pkcs11::new(module_path)
+            .map_err(|err| format!("failed loading PKCS#11 module {}: {}", module_path, err))?;
+        pkcs11
+            .initialize(CInitializeArgs::OsThreads)
+            .map_err(|err| format!("failed initializing PKCS#11 module: {}", err))?;
+
+        let slots = pkcs11
+            .get_slots_with_token()
+            .map_err(|err| format!("failed listing PKCS#11 tokens: {}", err))?;
+        let mut slot = None;
+        for candidate in slots {
+            let info = pkcs11
+                .get_token_info(candidate)
+                .map_err(|err| format!("failed reading PKCS#11 token info: {}", err))?;
+            if uri
+                .token
+                .as_ref()
+                .map_or(true, |token| info.label().trim_end() == token.as_str())
+            {
+                slot = Some(candidate);
+                break;
+            }
+        }
+        let slot = slot.ok_or_else(|| format!("no PKCS#11 token matching {:?}", uri.token))?;
+
+        let session = pkcs11
+            .open_session_no_callback(slot, false)
+            .map_err(|err| format!("failed opening PKCS#11 session: {}", err))?;
+
+        let pin = match (&uri.pin_value, &uri.pin_source) {
+            (Some(pin), _) => Some(Zeroizing::new(pin.clone())),
+            (None, Some(pin_source)) => Some(
+                pin_source
+                    .parse::<PassphraseSource>()?
+                    .read("PKCS#11 token PIN")?,
+            ),
+            (None, None) => passphrase.map(|passphrase| Zeroizing::new(passphrase.to_owned())),
+        };
+        if let Some(pin) = pin {
+            session
+                .login(UserType::User, Some(pin.as_str()))
+                .map_err(|err| format!("failed logging in to PKCS#11 token: {}", err))?;
+        }
+
+        let mut template = vec![
+            Attribute::Class(ObjectClass::PRIVATE_KEY),
+            Attribute::KeyType(KeyType::RSA),
+        ];
+        if let Some(object) = &uri.object {
+            template.push(Attribute::Label(object.as_bytes().to_vec()));
+        }
+        if let Some(id) = &uri.id {
+            template.push(Attribute::Id(id.clone()));
+        }
+        let keys = session
+            .find_objects(&template)
+            .map_err(|err| format!("failed searching PKCS#11 token: {}", err))?;
+        let key = match keys[..] {
+            [key] => key,
+            [] => return Err("no matching RSA private key on the PKCS#11 token".into()),
+            _ => {
+                return Err("several matching RSA private keys, pick one with object or id".into())
+            }
+        };
+
+        let public_key = read_public_key(&session, key)?;
+        let signature = Zeroizing::new(
+            session
+                .sign(&Mechanism::RsaPkcs, key, REJECTION_KEY_MESSAGE)
+                .map_err(|err| format!("failed deriving rejection key: {}", err))?,
+        );
+        let rejection_key = Zeroizing::new(Sha256::digest(&signature).to_vec());
+
+        Ok(Self {
+            session: Mutex::new(session),
+            key,
+            public_key,
+            rejection_key,
+        })
+    }
+}
+
+/// Read the public half of a private key from its modulus and public exponent attributes.
+fn read_public_key(session: &Session, key: ObjectHandle) -> Result<Rsa<Public>, String> {
+    let attributes = session
+        .get_attributes(
+            key,
+            &[AttributeType::Modulus, AttributeType::PublicExponent],
+        )
+        .map_err(|err| format!("failed reading PKCS#11 key attributes: {}", err))?;
+
+    let (mut n, mut e) = (None, None);
+    for attribute in attributes {
+        match attribute {
+            Attribute::Modulus(modulus) => n = Some(modulus),
+            Attribute::PublicExponent(exponent) => e = Some(exponent),
+            _ => {}
+        }
+    }
+    let (n, e) = n
+        .zip(e)
+        .ok_or_else(|| "PKCS#11 key does not expose its public components".to_owned())?;
+    let n = BigNum::from_slice(&n).map_err(|err| err.to_string())?;
+    let e = BigNum::from_slice(&e).map_err(|err| err.to_string())?;
+    Rsa::from_public_components(n, e).map_err(|err| err.to_string())
+}
+
+impl KeyBackend for Pkcs11Key {
+    fn key_size(&self) -> usize {
+        self.public_key.size() as usize
+    }
+
+    fn public_key(&self) -> Result<Rsa<Public>, String> {
+        Ok(self.public_key.clone())
+    }
+
+    fn decrypt_raw(&self, chunk: &[u8]) -> Result<Zeroizing<Vec<u8>>, String> {
+        self.session
+            .lock()
+            .expect("mutex poisoned")
+            .decrypt(&Mechanism::RsaX509, self.key, chunk)
+            .map(Zeroizing::new)
+            .map_err(|err| format!("decrypt failed: {}", err))
+    }
+
+    fn encrypt_pkcs1(&self, chunk: &[u8]) -> Result<Vec<u8>, String> {
+        self.session
+            .lock()
+            .expect("mutex poisoned")
+            .sign(&Mechanism::RsaPkcs, self.key, chunk)
+            .map_err(|err| format!("encrypt failed: {}", err))
+    }
+
+    fn rejection_key(&self) -> Result<Zeroizing<Vec<u8>>, String> {
+        Ok(self.rejection_key.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::{decrypt, encrypt};
+    use boring::rsa::Padding;
+
+    #[test]
+    fn uris_are_parsed() {
+        let uri = Pkcs11Uri::parse(
+            "pkcs11:token=My%20HSM;object=mirror-key;id=%01%02;type=private?module-path=/usr/lib/softhsm/libsofthsm2.so&pin-source=file:/etc/pin",
+        )
+        .unwrap();
+        assert_eq!(
+            uri,
+            Pkcs11Uri {
+                token: Some("My HSM".to_owned()),
+                object: Some("mirror-key".to_owned()),
+                id: Some(vec![1, 2]),
+                module_path: Some("/usr/lib/softhsm/libsofthsm2.so".to_owned()),
+                pin_value: None,
+                pin_source: Some("file:/etc/pin".to_owned()),
+            }
+        );
+
+        assert_eq!(Pkcs11Uri::parse("pkcs11:").unwrap(), Pkcs11Uri::default());
+        assert!(Pkcs11Uri::parse("/etc/mirror-key.pem").is_err());
+        assert!(Pkcs11Uri::parse("pkcs11:type=public").is_err());
+        assert!(Pkcs11Uri::parse("pkcs11:serial=1234").is_err());
+        assert!(Pkcs11Uri::parse("pkcs11:object=%4").is_err());
+        assert!(Pkcs11Uri::parse("pkcs11:object").is_err());
+    }
+
+    /// Runs against the key named by `MIRROR_PKCS11_TEST_URI`, see the module documentation.
+    #[test]
+    #[ignore]
+    fn pkcs11_key_decrypts_requests_and_encrypts_responses() {
+        let uri = std::env::var("MIRROR_PKCS11_TEST_URI").expect("MIRROR_PKCS11_TEST_URI not set");
+        let key = Pkcs11Key::open(&uri, None).unwrap();
+        let public_key = key.public_key().unwrap();
+        let key_size = key.key_size();
+
+        let mut request = vec![0u8; key_size];
+        public_key
+            .public_encrypt(
+                b"{\"method\": \"get_all_accounts\"}",
+                &mut request,
+                Padding::PKCS1,
+            )
+            .unwrap();
+        assert_eq!(
+            decrypt(&key, &request).unwrap(),
+            b"{\"method\": \"get_all_accounts\"}"
+        );
+
+        // Invalid ciphertexts are implicitly rejected, as with keys held in memory.
+        let garbage = vec![1u8; key_size];
+        assert_eq!(
+            decrypt(&key, &garbage).unwrap(),
+            decrypt(&key, &garbage).unwrap()
+        );
+
+        let response = encrypt(&key, b"{\"result\": {}}").unwrap();
+        let mut decrypted = vec![0u8; key_size];
+        let len = public_key
+            .public_decrypt(&response, &mut decrypted, Padding::PKCS1)
+            .unwrap();
+        assert_eq!(&decrypted[..len], b"{\"result\": {}}");
+    }
+}
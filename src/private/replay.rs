@@ -0,0 +1,379 @@
+// Copyright (c) 2018-2022 MobileCoin Inc.
+
+//! Replay protection for encrypted requests.
+//!
+//! When enabled, the plaintext of an encrypted request is an envelope around the JSON-RPC request
+//! (or batch):
+//! ```json
+//! {
+//!     "timestamp": 1650000000,
+//!     "nonce": "<unique random string, e.g. 32 hex characters>",
+//!     "request": { "method": "get_block", "params": { ... }, "jsonrpc": "2.0", "id": 1 }
+//! }
+//! ```
+//! Requests whose Unix timestamp (seconds) is further than the replay window from the local clock
+//! are rejected, as are nonces that were already seen within the window.
+//!
+//! Each instance remembers the nonces it saw in memory. Private instances polling the same public
+//! side should share a clock, and also a nonce directory (`--replay-nonce-dir`, for example on a
+//! shared volume), since the public side may hand a replayed request to any of them. A nonce is
+//! recorded in the directory by atomically creating a file named after its SHA-256 digest, so that
+//! only the first instance to see it accepts the request. Files are removed once they are older
+//! than twice the window, by which time the timestamp of the request is stale.
+//!
+//! Each client may only have `MAX_SEEN_NONCES_PER_SIGNER` nonces within the window, so that one
+//! client cannot get the requests of the others rejected. Clients are told apart by the key their
+//! requests are signed with, so without `--client-keys` all requests share that limit, and anyone
+//! able to submit requests can exhaust it. Replay protection should therefore be combined with
+//! `--client-keys`.
+
+use crate::json_rpc::{JsonRpcError, JSON_RPC_INTERNAL_ERROR};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap, HashSet},
+    fs::{self, OpenOptions},
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// JSON-RPC error code (from the implementation-defined server error range) for requests that
+/// were already seen, or whose timestamp is outside the replay window.
+pub const JSON_RPC_REPLAYED_REQUEST: i64 = -32002;
+
+/// The maximum length of a nonce.
+const MAX_NONCE_LEN: usize = 128;
+
+/// The maximum number of nonces remembered. Requests are rejected while the cache is full, rather
+/// than forgetting nonces that are still within the window.
+const MAX_SEEN_NONCES: usize = 1_000_000;
+
+/// The maximum number of nonces remembered for the requests of a single signer.
+const MAX_SEEN_NONCES_PER_SIGNER: usize = 100_000;
+
+/// How often expired nonces are removed from the nonce directory.
+const NONCE_DIR_PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The nonces seen within the window.
+#[derive(Debug, Default)]
+struct SeenNonces {
+    nonces: HashSet<String>,
+
+    /// Map of signer -> the number of nonces seen in its requests. Unsigned requests are counted
+    /// under an empty signer.
+    signer_counts: HashMap<String, usize>,
+
+    /// The (nonce, signer) pairs, ordered by the time they expire at (the timestamp of their
+    /// request, plus the window).
+    expiry_queue: BinaryHeap<Reverse<(u64, String, String)>>,
+}
+
+impl SeenNonces {
+    /// Forget the nonces that expired.
+    fn expire(&mut self, now: u64) {
+        while let Some(Reverse((expires_at, _, _))) = self.expiry_queue.peek() {
+            if *expires_at >= now {
+                break;
+            }
+            let Reverse((_, nonce, signer)) = self.expiry_queue.pop().expect("queue is not empty");
+            self.nonces.remove(&nonce);
+            if let Some(count) = self.signer_counts.get_mut(&signer) {
+                *count -= 1;
+                if *count == 0 {
+                    self.signer_counts.remove(&signer);
+                }
+            }
+        }
+    }
+
+    fn signer_count(&self, signer: &str) -> usize {
+        self.signer_counts.get(signer).copied().unwrap_or(0)
+    }
+
+    fn insert(&mut self, nonce: &str, signer: &str, expires_at: u64) {
+        self.nonces.insert(nonce.to_owned());
+        *self.signer_counts.entry(signer.to_owned()).or_default() += 1;
+        self.expiry_queue
+            .push(Reverse((expires_at, nonce.to_owned(), signer.to_owned())));
+    }
+}
+
+/// A directory, shared by the private instances, in which nonces are recorded.
+#[derive(Debug)]
+struct NonceDir {
+    path: PathBuf,
+    last_pruned: Mutex<Instant>,
+}
+
+impl NonceDir {
+    /// Record a nonce, returning false if it was already recorded.
+    fn record(&self, nonce: &str) -> Result<bool, String> {
+        let path = self
+            .path
+            .join(hex::encode(Sha256::digest(nonce.as_bytes())));
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(_) => Ok(true),
+            Err(err) if err.kind() == ErrorKind::AlreadyExists => Ok(false),
+            Err(err) => Err(format!("failed creating {:?}: {}", path, err)),
+        }
+    }
+
+    /// Remove the nonces recorded longer ago than the given age, at most once per prune interval.
+    fn prune(&self, max_age: Duration) -> Result<(), String> {
+        {
+            let mut last_pruned = self.last_pruned.lock().expect("mutex poisoned");
+            if last_pruned.elapsed() < NONCE_DIR_PRUNE_INTERVAL {
+                return Ok(());
+            }
+            *last_pruned = Instant::now();
+        }
+
+        for entry in fs::read_dir(&self.path)
+            .map_err(|err| format!("failed reading {:?}: {}", self.path, err))?
+        {
+            let entry = entry.map_err(|err| format!("failed reading {:?}: {}", self.path, err))?;
+            let expired = entry
+                .metadata()
+                .and_then(|metadata| metadata.modified())
+                .ok()
+                .and_then(|modified| modified.elapsed().ok())
+                .map_or(false, |age| age > max_age);
+            // Another instance may be removing the same files.
+            if expired {
+                let _ = fs::remove_file(entry.path());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Rejects encrypted requests that are stale or were already seen.
+#[derive(Clone, Debug)]
+pub struct ReplayGuard {
+    /// How far a request timestamp may be from the local clock, in either direction.
+    window: Duration,
+
+    seen_nonces: Arc<Mutex<SeenNonces>>,
+
+    /// Optional directory the nonces are also recorded in.
+    nonce_dir: Option<Arc<NonceDir>>,
+}
+
+impl ReplayGuard {
+    pub fn new(window: Duration, nonce_dir: Option<&Path>) -> Result<Self, String> {
+        let nonce_dir = match nonce_dir {
+            Some(path) => {
+                fs::create_dir_all(path)
+                    .map_err(|err| format!("failed creating {:?}: {}", path, err))?;
+                Some(Arc::new(NonceDir {
+                    path: path.to_owned(),
+                    last_pruned: Mutex::new(Instant::now()),
+                }))
+            }
+            None => None,
+        };
+        Ok(Self {
+            window,
+            seen_nonces: Arc::new(Mutex::new(SeenNonces::default())),
+            nonce_dir,
+        })
+    }
+
+    /// Check the envelope of a request made by the given signer (if requests are signed),
+    /// returning the request it holds.
+    pub fn open(&self, envelope: &Value, signer: Option<&str>) -> Result<Value, JsonRpcError> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("clock went backwards")
+            .as_secs();
+        self.open_at(envelope, signer, now)
+    }
+
+    fn open_at(
+        &self,
+        envelope: &Value,
+        signer: Option<&str>,
+        now: u64,
+    ) -> Result<Value, JsonRpcError> {
+        let signer = signer.unwrap_or_default();
+        let timestamp = envelope["timestamp"]
+            .as_u64()
+            .ok_or_else(|| replay_error("missing timestamp"))?;
+        let nonce = envelope["nonce"]
+            .as_str()
+            .filter(|nonce| !nonce.is_empty() && nonce.len() <= MAX_NONCE_LEN)
+            .ok_or_else(|| replay_error("missing or invalid nonce"))?;
+        let request = envelope
+            .get("request")
+            .ok_or_else(|| replay_error("missing request"))?;
+
+        let window = self.window.as_secs();
+        let mut seen_nonces = self.seen_nonces.lock().expect("mutex poisoned");
+        seen_nonces.expire(now);
+
+        if timestamp.saturating_add(window) < now || timestamp > now.saturating_add(window) {
+            return Err(replay_error("stale timestamp"));
+        }
+        if seen_nonces.nonces.contains(nonce) {
+            return Err(replay_error("replayed request"));
+        }
+        if seen_nonces.nonces.len() >= MAX_SEEN_NONCES
+            || seen_nonces.signer_count(signer) >= MAX_SEEN_NONCES_PER_SIGNER
+        {
+            return Err(replay_error("too many recent requests"));
+        }
+        if let Some(nonce_dir) = self.nonce_dir.as_ref() {
+            let recorded = nonce_dir
+                .prune(self.window * 2)
+                .and_then(|_| nonce_dir.record(nonce))
+                .map_err(|err| {
+                    JsonRpcError::new(
+                        JSON_RPC_INTERNAL_ERROR,
+                        format!("Failed recording nonce: {}", err),
+                    )
+                })?;
+            if !recorded {
+                return Err(replay_error("replayed request"));
+            }
+        }
+        seen_nonces.insert(nonce, signer, timestamp.saturating_add(window));
+
+        Ok(request.clone())
+    }
+}
+
+fn replay_error(reason: &str) -> JsonRpcError {
+    JsonRpcError::new(
+        JSON_RPC_REPLAYED_REQUEST,
+        format!("Request rejected: {}", reason),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const NOW: u64 = 1_650_000_000;
+
+    fn envelope(timestamp: u64, nonce: &str) -> Value {
+        json!({
+            "timestamp": timestamp,
+            "nonce": nonce,
+            "request": {"method": "get_block", "jsonrpc": "2.0", "id": 1},
+        })
+    }
+
+    #[test]
+    fn replayed_nonces_are_rejected_within_the_window() {
+        let replay_guard = ReplayGuard::new(Duration::from_secs(60), None).unwrap();
+
+        assert_eq!(
+            replay_guard.open_at(&envelope(NOW, "a"), None, NOW),
+            Ok(json!({"method": "get_block", "jsonrpc": "2.0", "id": 1}))
+        );
+        assert_eq!(
+            replay_guard
+                .open_at(&envelope(NOW, "a"), None, NOW + 30)
+                .unwrap_err()
+                .code,
+            JSON_RPC_REPLAYED_REQUEST
+        );
+        assert!(replay_guard
+            .open_at(&envelope(NOW, "b"), None, NOW + 30)
+            .is_ok());
+
+        // Once the window has passed, the timestamp itself is stale.
+        assert!(replay_guard
+            .open_at(&envelope(NOW, "a"), None, NOW + 61)
+            .is_err());
+        assert!(replay_guard.seen_nonces.lock().unwrap().nonces.is_empty());
+    }
+
+    #[test]
+    fn nonces_are_shared_through_the_nonce_dir() {
+        let dir = std::env::temp_dir().join(format!("mirror-replay-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+
+        let window = Duration::from_secs(60);
+        let first = ReplayGuard::new(window, Some(&dir)).unwrap();
+        let second = ReplayGuard::new(window, Some(&dir)).unwrap();
+
+        assert!(first.open_at(&envelope(NOW, "a"), None, NOW).is_ok());
+        assert_eq!(
+            second
+                .open_at(&envelope(NOW, "a"), None, NOW)
+                .unwrap_err()
+                .code,
+            JSON_RPC_REPLAYED_REQUEST
+        );
+        assert!(second.open_at(&envelope(NOW, "b"), None, NOW).is_ok());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn nonces_are_limited_per_signer() {
+        let replay_guard = ReplayGuard::new(Duration::from_secs(60), None).unwrap();
+
+        for index in 0..MAX_SEEN_NONCES_PER_SIGNER {
+            let nonce = format!("a{}", index);
+            assert!(replay_guard
+                .open_at(&envelope(NOW, &nonce), Some("alice"), NOW)
+                .is_ok());
+        }
+        assert!(replay_guard
+            .open_at(&envelope(NOW, "a"), Some("alice"), NOW)
+            .is_err());
+        assert!(replay_guard
+            .open_at(&envelope(NOW, "b"), Some("bob"), NOW)
+            .is_ok());
+        assert!(replay_guard.open_at(&envelope(NOW, "c"), None, NOW).is_ok());
+
+        // Expired nonces no longer count.
+        assert!(replay_guard
+            .open_at(&envelope(NOW + 61, "a"), Some("alice"), NOW + 61)
+            .is_ok());
+        assert_eq!(
+            replay_guard.seen_nonces.lock().unwrap().signer_count("bob"),
+            0
+        );
+    }
+
+    #[test]
+    fn stale_and_future_timestamps_are_rejected() {
+        let replay_guard = ReplayGuard::new(Duration::from_secs(60), None).unwrap();
+
+        assert!(replay_guard
+            .open_at(&envelope(NOW - 61, "a"), None, NOW)
+            .is_err());
+        assert!(replay_guard
+            .open_at(&envelope(NOW + 61, "b"), None, NOW)
+            .is_err());
+        assert!(replay_guard
+            .open_at(&envelope(NOW - 60, "c"), None, NOW)
+            .is_ok());
+        assert!(replay_guard
+            .open_at(&envelope(NOW + 60, "d"), None, NOW)
+            .is_ok());
+    }
+
+    #[test]
+    fn malformed_envelopes_are_rejected() {
+        let replay_guard = ReplayGuard::new(Duration::from_secs(60), None).unwrap();
+
+        assert!(replay_guard
+            .open_at(&json!({"method": "get_block"}), None, NOW)
+            .is_err());
+        assert!(replay_guard
+            .open_at(&json!({"timestamp": NOW, "nonce": "a"}), None, NOW)
+            .is_err());
+        assert!(replay_guard.open_at(&envelope(NOW, ""), None, NOW).is_err());
+        assert!(replay_guard
+            .open_at(&envelope(NOW, &"a".repeat(MAX_NONCE_LEN + 1)), None, NOW)
+            .is_err());
+    }
+}
@@ -0,0 +1,892 @@
+// Copyright (c) 2018-2022 MobileCoin Inc.
+
+//! Processing of the requests received from the public side of the mirror.
+
+use crate::{
+    approval_queue::{ApprovalQueue, GET_APPROVAL_STATUS_METHOD},
+    backends::FullServiceBackends,
+    client_auth::ClientKeys,
+    crypto::encrypt,
+    hardening::ZeroizingValue,
+    json_rpc::{
+        response_id, result_response, validate_request, JsonRpcError, JSON_RPC_INTERNAL_ERROR,
+        JSON_RPC_INVALID_REQUEST, JSON_RPC_METHOD_NOT_FOUND,
+    },
+    mirror_keys::MirrorKeys,
+    replay::ReplayGuard,
+    response_filter::ResponseFilter,
+    response_signer::ResponseSigner,
+    sessions::Sessions,
+    spending_limits::SpendingLimits,
+};
+use mc_common::logger::{log, o, Logger};
+use mc_wallet_service_mirror::{
+    approval::PendingApproval,
+    wallet_service_mirror_api::{
+        EncryptedResponse, QueryRequest, QueryResponse, SessionHandshakeResponse,
+        UnencryptedResponse,
+    },
+};
+use serde_json::Value;
+use zeroize::Zeroizing;
+
+const SUPPORTED_ENDPOINTS: &[&str] = &[
+    "check_receiver_receive_status",
+    "create_payment_request",
+    "get_account",
+    "get_account_status",
+    "get_address_for_account",
+    "get_addresses_for_account",
+    "get_all_accounts",
+    "get_all_transaction_logs_for_block",
+    "get_balance_for_account",
+    "get_balance_for_address",
+    "get_block",
+    "get_confirmations",
+    "get_network_status",
+    "get_transaction_log",
+    "get_transaction_logs_for_account",
+    "get_wallet_status",
+    "validate_confirmation",
+    "verify_address",
+];
+
+/// The client name response filter rules use to refer to unencrypted requests.
+pub const UNENCRYPTED_CLIENT: &str = "unencrypted";
+
+/// The client name response filter rules use to refer to encrypted requests.
+pub const ENCRYPTED_CLIENT: &str = "encrypted";
+
+/// Who a request came from, as far as per-client response filter rules and spending limits are
+/// concerned.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Client<'a> {
+    /// How the request reached the private side, `UNENCRYPTED_CLIENT` or `ENCRYPTED_CLIENT`.
+    pub transport: &'a str,
+
+    /// The client that signed the request, when encrypted requests have to be signed.
+    pub signer: Option<&'a str>,
+}
+
+impl<'a> Client<'a> {
+    pub fn unencrypted() -> Self {
+        Self {
+            transport: UNENCRYPTED_CLIENT,
+            signer: None,
+        }
+    }
+
+    pub fn encrypted(signer: Option<&'a str>) -> Self {
+        Self {
+            transport: ENCRYPTED_CLIENT,
+            signer,
+        }
+    }
+
+    /// The names per-client rules may refer to this client by.
+    pub fn names(&self) -> impl Iterator<Item = &'a str> {
+        std::iter::once(self.transport).chain(self.signer)
+    }
+}
+
+/// The error returned for encrypted requests that cannot be decrypted or parsed, whatever the
+/// reason.
+const INVALID_ENCRYPTED_REQUEST: &str = "Invalid encrypted request";
+
+/// The maximum number of requests allowed in a single JSON-RPC batch.
+const MAX_BATCH_LEN: usize = 100;
+
+fn error_response(error: &str) -> QueryResponse {
+    let mut query_response = QueryResponse::new();
+    query_response.set_error(error.to_owned());
+    query_response
+}
+
+fn validate_method(json: &Value) -> bool {
+    let method = json["method"].as_str().unwrap_or("");
+    SUPPORTED_ENDPOINTS.iter().any(|&s| s == method)
+}
+
+/// Validates requests received through the mirror and forwards them to full-service.
+pub struct RequestHandler {
+    /// The full-service instances requests are forwarded to.
+    backends: FullServiceBackends,
+
+    /// Optional redaction rules applied to full-service responses.
+    response_filter: Option<ResponseFilter>,
+
+    /// Optional queue of requests that need approval before being forwarded.
+    approval_queue: Option<ApprovalQueue>,
+
+    /// Optional limits on the amounts transaction-submitting requests may spend.
+    spending_limits: Option<SpendingLimits>,
+
+    /// Optional replay protection for encrypted requests.
+    replay_guard: Option<ReplayGuard>,
+
+    /// Optional registry of the clients whose signed encrypted requests are accepted.
+    client_keys: Option<ClientKeys>,
+
+    /// Optional key responses are signed with.
+    response_signer: Option<ResponseSigner>,
+
+    /// The forward-secret sessions established with clients.
+    sessions: Sessions,
+}
+
+impl RequestHandler {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        backends: FullServiceBackends,
+        response_filter: Option<ResponseFilter>,
+        approval_queue: Option<ApprovalQueue>,
+        spending_limits: Option<SpendingLimits>,
+        replay_guard: Option<ReplayGuard>,
+        client_keys: Option<ClientKeys>,
+        response_signer: Option<ResponseSigner>,
+        sessions: Sessions,
+    ) -> Self {
+        Self {
+            backends,
+            response_filter,
+            approval_queue,
+            spending_limits,
+            replay_guard,
+            client_keys,
+            response_signer,
+            sessions,
+        }
+    }
+
+    pub fn process_unencrypted_request(
+        &self,
+        query_request: &QueryRequest,
+        logger: &Logger,
+    ) -> Result<QueryResponse, String> {
+        if !query_request.has_unencrypted_request() {
+            return Err("Only processing unencrypted requests".into());
+        }
+
+        let unencrypted_request = query_request.get_unencrypted_request();
+
+        log::debug!(
+            logger,
+            "Incoming unencrypted request ({})",
+            unencrypted_request.json_request
+        );
+
+        let json: Value = match serde_json::from_str(&unencrypted_request.json_request) {
+            Ok(json) => json,
+            Err(err) => {
+                let mut err_query_response = QueryResponse::new();
+                err_query_response.set_error(format!("Error parsing JSON request: {}", err));
+                return Ok(err_query_response);
+            }
+        };
+
+        // Check that the request is of an allowed type and pass it along to full-service.
+        let json_response = self.forward_json_request(Client::unencrypted(), &json, logger)?;
+
+        let mut unencrypted_response = UnencryptedResponse::new();
+        unencrypted_response.set_json_response(json_response.to_string());
+
+        let mut mirror_response = QueryResponse::new();
+        mirror_response.set_unencrypted_response(unencrypted_response);
+        Ok(mirror_response)
+    }
+
+    pub fn process_encrypted_request(
+        &self,
+        mirror_keys: &MirrorKeys,
+        query_request: &QueryRequest,
+        logger: &Logger,
+    ) -> Result<QueryResponse, String> {
+        if !query_request.has_encrypted_request() {
+            return Err("Only processing encrypted requests".into());
+        }
+
+        let encrypted_request = query_request.get_encrypted_request();
+
+        let (decrypted, mirror_key) = match mirror_keys.decrypt(
+            encrypted_request.get_key_fingerprint(),
+            encrypted_request.get_payload(),
+            logger,
+        ) {
+            Ok(decrypted) => decrypted,
+            Err(err) => return Ok(error_response(&err)),
+        };
+        let json_response = match self.forward_decrypted_request(decrypted, logger)? {
+            Some(json_response) => json_response,
+            None => return Ok(error_response(INVALID_ENCRYPTED_REQUEST)),
+        };
+
+        let encrypted_payload =
+            encrypt(mirror_key, &json_response.as_bytes()).map_err(|_e| "Encryption failed")?;
+
+        let mut encrypted_response = EncryptedResponse::new();
+        encrypted_response.set_payload(encrypted_payload);
+
+        let mut mirror_response = QueryResponse::new();
+        mirror_response.set_encrypted_response(encrypted_response);
+        Ok(mirror_response)
+    }
+
+    pub fn process_session_handshake(
+        &self,
+        mirror_keys: &MirrorKeys,
+        query_request: &QueryRequest,
+        logger: &Logger,
+    ) -> Result<QueryResponse, String> {
+        if !query_request.has_session_handshake_request() {
+            return Err("Only processing session handshake requests".into());
+        }
+
+        // Handshakes are encrypted with the mirror key, so failures get the same error as
+        // encrypted requests that cannot be decrypted.
+        let handshake_request = query_request.get_session_handshake_request();
+        let decrypted = match mirror_keys.decrypt(
+            handshake_request.get_key_fingerprint(),
+            handshake_request.get_payload(),
+            logger,
+        ) {
+            Ok((decrypted, _)) => decrypted,
+            Err(err) => return Ok(error_response(&err)),
+        };
+        let handshake_response = decrypted
+            .ok_or_else(|| "Decryption failed".to_owned())
+            .and_then(|decrypted| self.establish_session(&decrypted, logger));
+        let handshake_response = match handshake_response {
+            Ok(handshake_response) => handshake_response,
+            Err(err) => {
+                log::warn!(logger, "Rejecting session handshake: {}", err);
+                return Ok(error_response(INVALID_ENCRYPTED_REQUEST));
+            }
+        };
+
+        log::info!(
+            logger,
+            "Established session {}",
+            handshake_response.get_session_id()
+        );
+
+        let mut mirror_response = QueryResponse::new();
+        mirror_response.set_session_handshake_response(handshake_response);
+        Ok(mirror_response)
+    }
+
+    /// Establish a session from the decrypted payload of a handshake request, which has to be
+    /// signed by a registered client when client keys are configured.
+    fn establish_session(
+        &self,
+        decrypted: &[u8],
+        logger: &Logger,
+    ) -> Result<SessionHandshakeResponse, String> {
+        let client_keys = match self.client_keys.as_ref() {
+            Some(client_keys) => client_keys,
+            None => return self.sessions.handshake(decrypted),
+        };
+
+        let envelope: Value = serde_json::from_slice(decrypted)
+            .map_err(|err| format!("Invalid session handshake: {}", err))?;
+        let (signer, handshake) = client_keys
+            .verify_handshake(&envelope)
+            .map_err(|err| err.message)?;
+        log::info!(logger, "Verified session handshake signed by {}", signer);
+        self.sessions.handshake(handshake.as_bytes())
+    }
+
+    pub fn process_session_request(
+        &self,
+        query_request: &QueryRequest,
+        logger: &Logger,
+    ) -> Result<QueryResponse, String> {
+        if !query_request.has_session_request() {
+            return Err("Only processing session requests".into());
+        }
+
+        let session_request = query_request.get_session_request();
+        let session_id = session_request.get_session_id();
+        let logger = logger.new(o!("session_id" => session_id.to_owned()));
+
+        let (decrypted, response_key) = match self
+            .sessions
+            .open(session_id, session_request.get_payload())
+        {
+            Ok(opened) => opened,
+            Err(err) => return Ok(error_response(&err)),
+        };
+        let json_response = match self.forward_decrypted_request(Some(decrypted), &logger)? {
+            Some(json_response) => json_response,
+            None => return Ok(error_response(INVALID_ENCRYPTED_REQUEST)),
+        };
+
+        let mut encrypted_response = EncryptedResponse::new();
+        encrypted_response.set_payload(response_key.seal(session_id, json_response.as_bytes())?);
+
+        let mut mirror_response = QueryResponse::new();
+        mirror_response.set_encrypted_response(encrypted_response);
+        Ok(mirror_response)
+    }
+
+    /// Encrypt a notification for the subscriber that established a session.
+    pub fn seal_notification(&self, session_id: &str, event: &[u8]) -> Result<Vec<u8>, String> {
+        self.sessions.seal_notification(session_id, event)
+    }
+
+    /// Sign the response to a query, if response signing is enabled.
+    pub fn sign_response(
+        &self,
+        query_id: &str,
+        request: &QueryRequest,
+        response: &mut QueryResponse,
+    ) {
+        if let Some(response_signer) = self.response_signer.as_ref() {
+            response_signer.sign(query_id, request, response);
+        }
+    }
+
+    /// Parse, authenticate and forward the plaintext of an encrypted (or session) request,
+    /// returning the JSON response, or None if it could not be parsed. Failures at any step of
+    /// decrypting and parsing get the same error, so that clients cannot learn anything about the
+    /// plaintext of a ciphertext they did not create.
+    fn forward_decrypted_request(
+        &self,
+        decrypted: Option<Zeroizing<Vec<u8>>>,
+        logger: &Logger,
+    ) -> Result<Option<Zeroizing<String>>, String> {
+        let json = match decrypted
+            .as_ref()
+            .and_then(|decrypted| serde_json::from_slice(decrypted).ok())
+        {
+            Some(json) => ZeroizingValue(json),
+            None => return Ok(None),
+        };
+
+        // Authenticate the request and check that it is of an allowed type and pass it along to
+        // full-service.
+        let json_response = match self.open_envelopes(&json, logger) {
+            Ok((request, signer, logger)) => {
+                self.forward_json_request(Client::encrypted(signer.as_deref()), &request, &logger)?
+            }
+            Err(err) => {
+                log::warn!(logger, "Rejecting encrypted request: {}", err.message);
+                Zeroizing::new(err.to_response(&Value::Null).to_string())
+            }
+        };
+        Ok(Some(json_response))
+    }
+
+    /// Verify the signature and replay protection envelopes of a decrypted request, when enabled,
+    /// returning the JSON-RPC request they hold, the verified signer and a logger identifying it.
+    fn open_envelopes(
+        &self,
+        json: &Value,
+        logger: &Logger,
+    ) -> Result<(ZeroizingValue, Option<String>, Logger), JsonRpcError> {
+        let (payload, signer, logger) = match self.client_keys.as_ref() {
+            Some(client_keys) => {
+                let (signer, payload) = client_keys.verify(json)?;
+                let logger = logger.new(o!("signer" => signer.clone()));
+                log::info!(logger, "Verified request signed by {}", signer);
+                (Some(ZeroizingValue(payload)), Some(signer), logger)
+            }
+            None => (None, None, logger.clone()),
+        };
+        let json = payload.as_deref().unwrap_or(json);
+
+        let request = match self.replay_guard.as_ref() {
+            Some(replay_guard) => ZeroizingValue(replay_guard.open(json, signer.as_deref())?),
+            None => ZeroizingValue(json.clone()),
+        };
+        Ok((request, signer, logger))
+    }
+
+    /// Mark approved requests that were interrupted by a restart, so that they are not retried.
+    pub fn recover_approvals(&self, logger: &Logger) -> Result<(), String> {
+        match self.approval_queue.as_ref() {
+            Some(approval_queue) => approval_queue.recover_interrupted(logger),
+            None => Ok(()),
+        }
+    }
+
+    /// Forward requests that have gathered enough approvals to full-service.
+    pub fn process_approvals(&self, logger: &Logger) -> Result<(), String> {
+        match self.approval_queue.as_ref() {
+            Some(approval_queue) => {
+                approval_queue.process_pending(|pending| self.forward_approved(pending), logger)
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Forward an approved request to full-service, counting it towards the spending limits of
+    /// the client that submitted it. Requests that would now exceed the limits are not forwarded.
+    fn forward_approved(&self, pending: &PendingApproval) -> Result<String, String> {
+        if let Some(spending_limits) = self
+            .spending_limits
+            .as_ref()
+            .filter(|spending_limits| spending_limits.applies_to(&pending.method))
+        {
+            let request = ZeroizingValue(
+                serde_json::from_str(&pending.json_request)
+                    .map_err(|err| format!("Error parsing approved request: {}", err))?,
+            );
+            let client = Client {
+                transport: &pending.transport,
+                signer: pending.signer.as_deref(),
+            };
+            spending_limits
+                .check_and_record(&client, &request)
+                .map_err(|err| err.message)?;
+        }
+
+        // Approved responses are written to the spool as they are, so there is nothing to gain
+        // from zeroizing them here.
+        self.backends
+            .call(&pending.json_request)
+            .map(|json_response| json_response.to_string())
+    }
+
+    /// Validate a JSON-RPC request and forward it to full-service, returning the JSON response.
+    /// Structurally invalid requests get a JSON-RPC error response without full-service being
+    /// contacted.
+    /// Batch requests are handled one element at a time, and the responses are reassembled in
+    /// the order of the batch. Batch elements that are invalid or not allowed get a JSON-RPC
+    /// error response of their own.
+    fn forward_json_request(
+        &self,
+        client: Client,
+        json: &Value,
+        logger: &Logger,
+    ) -> Result<Zeroizing<String>, String> {
+        let requests = match json {
+            Value::Array(requests) => requests,
+            request => {
+                return self
+                    .handle_request(client, request, logger)?
+                    .ok_or_else(|| "Unsupported request".into())
+            }
+        };
+
+        if requests.is_empty() {
+            return Ok(Zeroizing::new(
+                JsonRpcError::new(JSON_RPC_INVALID_REQUEST, "Invalid Request: empty batch")
+                    .to_response(&Value::Null)
+                    .to_string(),
+            ));
+        }
+        if requests.len() > MAX_BATCH_LEN {
+            return Err(format!(
+                "Batch too large ({} requests, at most {} allowed)",
+                requests.len(),
+                MAX_BATCH_LEN
+            ));
+        }
+
+        // The responses are reassembled in place, so that all of them are zeroized once the batch
+        // response has been serialized, or if one of the requests fails.
+        let mut responses = ZeroizingValue(Value::Array(Vec::with_capacity(requests.len())));
+        for request in requests {
+            let id = response_id(request);
+            let response = match self.handle_request(client, request, logger)? {
+                Some(json_response) => serde_json::from_str(&json_response).unwrap_or_else(|err| {
+                    JsonRpcError::new(
+                        JSON_RPC_INTERNAL_ERROR,
+                        format!("Error parsing full-service response: {}", err),
+                    )
+                    .to_response(&id)
+                }),
+                None => JsonRpcError::new(JSON_RPC_METHOD_NOT_FOUND, "Unsupported request")
+                    .to_response(&id),
+            };
+            if let Some(responses) = responses.as_array_mut() {
+                responses.push(response);
+            }
+        }
+
+        Ok(Zeroizing::new(responses.to_string()))
+    }
+
+    /// Handle a single (non-batch) JSON-RPC request, returning the JSON response, or None if the
+    /// method is not supported.
+    fn handle_request(
+        &self,
+        client: Client,
+        request: &Value,
+        logger: &Logger,
+    ) -> Result<Option<Zeroizing<String>>, String> {
+        let id = response_id(request);
+        if let Err(err) = validate_request(request) {
+            return Ok(Some(Zeroizing::new(err.to_response(&id).to_string())));
+        }
+
+        let method = request["method"].as_str().unwrap_or("");
+
+        if let Some(approval_queue) = self.approval_queue.as_ref() {
+            if method == GET_APPROVAL_STATUS_METHOD {
+                let approval_id = request["params"]["approval_id"].as_str().unwrap_or("");
+                let response = match approval_queue.status(approval_id) {
+                    Ok((result, completed)) => {
+                        let mut result = ZeroizingValue(result);
+                        // The full-service response is redacted as if it was returned directly.
+                        if let (Some(completed), Some(response_filter)) =
+                            (completed, self.response_filter.as_ref())
+                        {
+                            response_filter.apply(
+                                &completed.method,
+                                &client,
+                                &mut result["response"],
+                            );
+                        }
+                        ZeroizingValue(result_response(&id, std::mem::take(&mut result)))
+                    }
+                    Err(err) => ZeroizingValue(err.to_response(&id)),
+                };
+                return Ok(Some(Zeroizing::new(response.to_string())));
+            }
+        }
+
+        let approval_queue = self
+            .approval_queue
+            .as_ref()
+            .filter(|approval_queue| approval_queue.requires_approval(method));
+        if approval_queue.is_none() && !validate_method(request) {
+            return Ok(None);
+        }
+
+        // A full approval queue is reported before any spending limit.
+        if let Some(approval_queue) = approval_queue {
+            if let Err(err) = approval_queue.check_capacity() {
+                log::warn!(logger, "Rejecting {} request: {}", method, err.message);
+                return Ok(Some(Zeroizing::new(err.to_response(&id).to_string())));
+            }
+        }
+
+        // Requests queued for approval are checked against the spending limits right away, but
+        // only count towards them once they are approved and forwarded.
+        if let Some(spending_limits) = self.spending_limits.as_ref() {
+            if spending_limits.applies_to(method) {
+                let result = match approval_queue {
+                    Some(_) => spending_limits.check(&client, request),
+                    None => spending_limits.check_and_record(&client, request),
+                };
+                if let Err(err) = result {
+                    log::warn!(logger, "Rejecting {} request: {}", method, err.message);
+                    return Ok(Some(Zeroizing::new(err.to_response(&id).to_string())));
+                }
+            }
+        }
+
+        if let Some(approval_queue) = approval_queue {
+            let result = approval_queue.submit(&client, method, request, logger)?;
+            return Ok(Some(Zeroizing::new(
+                result_response(&id, result).to_string(),
+            )));
+        }
+
+        let json_response = self.backends.call(&Zeroizing::new(request.to_string()))?;
+
+        match self.response_filter.as_ref() {
+            Some(response_filter) if response_filter.has_rules_for(method, &client) => {
+                // Fail closed - a response that cannot be parsed cannot be redacted.
+                let mut response = ZeroizingValue(
+                    serde_json::from_str(&json_response)
+                        .map_err(|err| format!("Error parsing full-service response: {}", err))?,
+                );
+                response_filter.apply(method, &client, &mut response);
+                Ok(Some(Zeroizing::new(response.to_string())))
+            }
+            _ => Ok(Some(json_response)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        backends::RoutingStrategy, client_auth::handshake_message,
+        json_rpc::JSON_RPC_INVALID_PARAMS,
+    };
+    use boring::rsa::{Padding, Rsa};
+    use mc_common::logger::test_with_logger;
+    use mc_crypto_keys::{Ed25519Pair, Ed25519Private, KexPrivate, Signer, X25519EphemeralPrivate};
+    use mc_wallet_service_mirror::wallet_service_mirror_api::{
+        EncryptedRequest, SessionHandshakeRequest, SessionRequest,
+    };
+    use serde_json::json;
+    use std::{convert::TryFrom, fs, sync::Arc, time::Duration};
+
+    /// A URI nothing listens on, so that any attempt to contact full-service fails.
+    const UNREACHABLE_URI: &str = "http://127.0.0.1:1/";
+
+    fn test_handler() -> RequestHandler {
+        RequestHandler::new(
+            FullServiceBackends::new(vec![UNREACHABLE_URI.to_owned()], RoutingStrategy::Failover),
+            None,
+            None,
+            None,
+            None,
+            None,
+            None,
+            Sessions::new(Duration::from_secs(60 * 60)),
+        )
+    }
+
+    #[test_with_logger]
+    fn unsupported_single_request_is_rejected(logger: Logger) {
+        let json = json!({"method": "build_and_submit_transaction", "jsonrpc": "2.0", "id": 1});
+        assert_eq!(
+            test_handler().forward_json_request(Client::unencrypted(), &json, &logger),
+            Err("Unsupported request".to_owned())
+        );
+    }
+
+    #[test_with_logger]
+    fn invalid_single_request_gets_json_rpc_error(logger: Logger) {
+        let json =
+            json!({"method": "get_block", "params": {"block_index": 5}, "jsonrpc": "2.0", "id": 1});
+
+        let response: Value = serde_json::from_str(
+            &test_handler()
+                .forward_json_request(Client::unencrypted(), &json, &logger)
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(response["id"], 1);
+        assert_eq!(response["error"]["code"], JSON_RPC_INVALID_PARAMS);
+    }
+
+    #[test_with_logger]
+    fn batch_elements_that_are_not_allowed_get_individual_errors(logger: Logger) {
+        let json = json!([
+            {"method": "build_and_submit_transaction", "jsonrpc": "2.0", "id": 1},
+            "not a request",
+            {"method": "get_block", "jsonrpc": "2.0", "id": "abc"},
+        ]);
+
+        let response: Value = serde_json::from_str(
+            &test_handler()
+                .forward_json_request(Client::unencrypted(), &json, &logger)
+                .unwrap(),
+        )
+        .unwrap();
+        let response = response.as_array().unwrap();
+        assert_eq!(response.len(), 3);
+
+        assert_eq!(response[0]["id"], 1);
+        assert_eq!(response[0]["error"]["code"], JSON_RPC_METHOD_NOT_FOUND);
+
+        assert_eq!(response[1]["id"], Value::Null);
+        assert_eq!(response[1]["error"]["code"], JSON_RPC_INVALID_REQUEST);
+
+        assert_eq!(response[2]["id"], "abc");
+        assert_eq!(response[2]["error"]["code"], JSON_RPC_INVALID_PARAMS);
+    }
+
+    #[test_with_logger]
+    fn empty_batch_is_an_invalid_request(logger: Logger) {
+        let response: Value = serde_json::from_str(
+            &test_handler()
+                .forward_json_request(Client::unencrypted(), &json!([]), &logger)
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(response["error"]["code"], JSON_RPC_INVALID_REQUEST);
+    }
+
+    #[test_with_logger]
+    fn malformed_encrypted_requests_get_identical_errors(logger: Logger) {
+        let mirror_key = Rsa::generate(2048).unwrap();
+        let mirror_keys = MirrorKeys::new(Arc::new(mirror_key.clone()), vec![]).unwrap();
+        let key_size = mirror_key.size() as usize;
+        let public_encrypt = |plaintext: &[u8]| {
+            let mut ciphertext = vec![0u8; key_size];
+            mirror_key
+                .public_encrypt(plaintext, &mut ciphertext, Padding::PKCS1)
+                .unwrap();
+            ciphertext
+        };
+
+        let mut flipped = public_encrypt(br#"{"method": "get_block", "jsonrpc": "2.0", "id": 1}"#);
+        flipped[key_size / 2] ^= 1;
+
+        let payloads = vec![
+            vec![],
+            vec![0u8; key_size],
+            vec![0xffu8; key_size],
+            vec![0x42u8; key_size - 1],
+            vec![0x42u8; key_size * 2 + 1],
+            flipped,
+            public_encrypt(&[0xc3, 0x28, 0xa0, 0xa1]),
+            public_encrypt(b"not json"),
+            public_encrypt(br#"{"method": "get_block""#),
+        ];
+
+        let mut expected = QueryResponse::new();
+        expected.set_error(INVALID_ENCRYPTED_REQUEST.to_owned());
+        for payload in payloads {
+            let mut encrypted_request = EncryptedRequest::new();
+            encrypted_request.set_payload(payload);
+            let mut query_request = QueryRequest::new();
+            query_request.set_encrypted_request(encrypted_request);
+
+            assert_eq!(
+                test_handler().process_encrypted_request(&mirror_keys, &query_request, &logger),
+                Ok(expected.clone())
+            );
+        }
+    }
+
+    #[test_with_logger]
+    fn sessions_are_established_with_encrypted_handshakes(logger: Logger) {
+        let mirror_key = Rsa::generate(2048).unwrap();
+        let mirror_keys = MirrorKeys::new(Arc::new(mirror_key.clone()), vec![]).unwrap();
+        let handler = test_handler();
+
+        let client_public =
+            X25519EphemeralPrivate::from_random(&mut rand::thread_rng()).compute_public();
+        let handshake = json!({
+            "ephemeral_public_key": hex::encode(client_public.as_ref()),
+            "secret": hex::encode([5u8; 32]),
+        })
+        .to_string();
+        let mut payload = vec![0u8; mirror_key.size() as usize];
+        mirror_key
+            .public_encrypt(handshake.as_bytes(), &mut payload, Padding::PKCS1)
+            .unwrap();
+
+        let mut handshake_request = SessionHandshakeRequest::new();
+        handshake_request.set_payload(payload);
+        let mut query_request = QueryRequest::new();
+        query_request.set_session_handshake_request(handshake_request);
+        let response = handler
+            .process_session_handshake(&mirror_keys, &query_request, &logger)
+            .unwrap();
+        let session_id = response.get_session_handshake_response().get_session_id();
+        assert!(!session_id.is_empty());
+
+        // Handshakes that cannot be decrypted get the same error as encrypted requests.
+        let mut handshake_request = SessionHandshakeRequest::new();
+        handshake_request.set_payload(vec![0x42u8; mirror_key.size() as usize]);
+        query_request.set_session_handshake_request(handshake_request);
+        assert_eq!(
+            handler
+                .process_session_handshake(&mirror_keys, &query_request, &logger)
+                .unwrap()
+                .get_error(),
+            INVALID_ENCRYPTED_REQUEST
+        );
+
+        // Session requests must be encrypted with the session keys.
+        let mut session_request = SessionRequest::new();
+        session_request.set_session_id(session_id.to_owned());
+        session_request.set_payload(vec![0u8; 64]);
+        let mut query_request = QueryRequest::new();
+        query_request.set_session_request(session_request);
+        assert!(handler
+            .process_session_request(&query_request, &logger)
+            .unwrap()
+            .has_error());
+    }
+
+    #[test_with_logger]
+    fn handshakes_must_be_signed_when_client_keys_are_set(logger: Logger) {
+        let mirror_key = Rsa::generate(2048).unwrap();
+        let mirror_keys = MirrorKeys::new(Arc::new(mirror_key.clone()), vec![]).unwrap();
+
+        let client_pair = Ed25519Pair::from(Ed25519Private::try_from(&[1u8; 32][..]).unwrap());
+        let client_keys_path = std::env::temp_dir().join(format!(
+            "mirror-request-test-client-keys-{}.json",
+            std::process::id()
+        ));
+        fs::write(
+            &client_keys_path,
+            json!({
+                "clients": {
+                    "alice": {"type": "ed25519", "public_key": hex::encode(client_pair.public_key())},
+                },
+            })
+            .to_string(),
+        )
+        .unwrap();
+        let client_keys = ClientKeys::load(client_keys_path.to_str().unwrap()).unwrap();
+        fs::remove_file(&client_keys_path).unwrap();
+
+        let handler = RequestHandler::new(
+            FullServiceBackends::new(vec![UNREACHABLE_URI.to_owned()], RoutingStrategy::Failover),
+            None,
+            None,
+            None,
+            None,
+            Some(client_keys),
+            None,
+            Sessions::new(Duration::from_secs(60 * 60)),
+        );
+
+        let client_public =
+            X25519EphemeralPrivate::from_random(&mut rand::thread_rng()).compute_public();
+        let handshake = json!({
+            "ephemeral_public_key": hex::encode(client_public.as_ref()),
+            "secret": hex::encode([5u8; 32]),
+        })
+        .to_string();
+        let signature = client_pair.sign(&handshake_message("alice", &handshake));
+        let signed_handshake = json!({
+            "signer": "alice",
+            "signature": hex::encode(signature.as_ref()),
+            "payload": handshake,
+        })
+        .to_string();
+
+        // The signed handshake does not fit in a single RSA block.
+        let handshake_query = |plaintext: &str| {
+            let key_size = mirror_key.size() as usize;
+            let mut payload = Vec::new();
+            for chunk in plaintext.as_bytes().chunks(key_size - 11) {
+                let mut ciphertext = vec![0u8; key_size];
+                mirror_key
+                    .public_encrypt(chunk, &mut ciphertext, Padding::PKCS1)
+                    .unwrap();
+                payload.extend(ciphertext);
+            }
+            let mut handshake_request = SessionHandshakeRequest::new();
+            handshake_request.set_payload(payload);
+            let mut query_request = QueryRequest::new();
+            query_request.set_session_handshake_request(handshake_request);
+            query_request
+        };
+
+        let response = handler
+            .process_session_handshake(&mirror_keys, &handshake_query(&handshake), &logger)
+            .unwrap();
+        assert_eq!(response.get_error(), INVALID_ENCRYPTED_REQUEST);
+
+        let response = handler
+            .process_session_handshake(&mirror_keys, &handshake_query(&signed_handshake), &logger)
+            .unwrap();
+        assert!(response.has_session_handshake_response());
+    }
+
+    #[test_with_logger]
+    fn oversized_batch_is_rejected(logger: Logger) {
+        let json = Value::Array(vec![json!({"method": "get_block"}); MAX_BATCH_LEN + 1]);
+        assert!(test_handler()
+            .forward_json_request(Client::unencrypted(), &json, &logger)
+            .is_err());
+    }
+
+    #[test]
+    fn signed_requests_are_known_by_transport_and_signer() {
+        assert_eq!(
+            Client::encrypted(Some("acme-app"))
+                .names()
+                .collect::<Vec<_>>(),
+            vec![ENCRYPTED_CLIENT, "acme-app"]
+        );
+        assert_eq!(
+            Client::unencrypted().names().collect::<Vec<_>>(),
+            vec![UNENCRYPTED_CLIENT]
+        );
+    }
+}
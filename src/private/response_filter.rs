@@ -0,0 +1,292 @@
+// Copyright (c) 2018-2022 MobileCoin Inc.
+
+//! Configurable redaction of full-service responses before they are handed back to clients.
+//!
+//! Rules are loaded from a JSON file of the form:
+//! ```json
+//! {
+//!     "rules": [
+//!         { "method": "get_all_accounts", "path": "result.account_map.*.main_address", "action": "remove" },
+//!         { "method": "get_account", "path": "result.account.key_derivation_version", "action": "mask", "clients": ["unencrypted"] }
+//!     ]
+//! }
+//! ```
+//! Paths are dot-separated, with `*` matching every key of an object or every element of an
+//! array, and numbers matching array indices.
+//!
+//! Rules limited to `clients` apply to requests arriving through the named transport
+//! (`unencrypted` or `encrypted`), and to signed encrypted requests from the named clients of the
+//! client key registry (`--client-keys`).
+
+use crate::request::Client;
+use serde_derive::Deserialize;
+use serde_json::Value;
+
+/// The value that masked fields are replaced with.
+const MASKED_VALUE: &str = "[redacted]";
+
+/// What to do with the fields a rule matches.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum FilterAction {
+    /// Strip the field from the response.
+    Remove,
+
+    /// Keep the field but replace its value.
+    Mask,
+}
+
+/// A single redaction rule, as it appears in the config file.
+#[derive(Clone, Debug, Deserialize)]
+struct FilterRuleConfig {
+    /// The method whose responses this rule applies to.
+    method: String,
+
+    /// The path of the fields to redact.
+    path: String,
+
+    /// What to do with matching fields.
+    action: FilterAction,
+
+    /// The clients this rule applies to: "unencrypted", "encrypted" or the name of a client in the
+    /// client key registry. Applies to all clients if empty.
+    #[serde(default)]
+    clients: Vec<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct ResponseFilterConfig {
+    rules: Vec<FilterRuleConfig>,
+}
+
+/// A parsed redaction rule.
+#[derive(Clone, Debug)]
+struct FilterRule {
+    method: String,
+    path: Vec<String>,
+    action: FilterAction,
+    clients: Vec<String>,
+}
+
+/// A set of redaction rules applied to full-service responses.
+#[derive(Clone, Debug)]
+pub struct ResponseFilter {
+    rules: Vec<FilterRule>,
+}
+
+impl ResponseFilter {
+    /// Load redaction rules from a JSON file.
+    pub fn load(src: &str) -> Result<Self, String> {
+        let config_str = std::fs::read_to_string(src)
+            .map_err(|err| format!("failed reading response filter file {}: {:?}", src, err))?;
+        Self::from_json(&config_str)
+            .map_err(|err| format!("failed parsing response filter file {}: {}", src, err))
+    }
+
+    fn from_json(config_str: &str) -> Result<Self, String> {
+        let config: ResponseFilterConfig =
+            serde_json::from_str(config_str).map_err(|err| err.to_string())?;
+
+        let rules = config
+            .rules
+            .into_iter()
+            .map(|rule| {
+                let path: Vec<String> = rule.path.split('.').map(str::to_owned).collect();
+                if path.iter().any(|segment| segment.is_empty()) {
+                    return Err(format!("invalid path \"{}\"", rule.path));
+                }
+                Ok(FilterRule {
+                    method: rule.method,
+                    path,
+                    action: rule.action,
+                    clients: rule.clients,
+                })
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+
+        Ok(Self { rules })
+    }
+
+    /// Returns true if any rule applies to the given method and client.
+    pub fn has_rules_for(&self, method: &str, client: &Client) -> bool {
+        self.rules.iter().any(|rule| rule.matches(method, client))
+    }
+
+    /// Redact a response to the given method, returned to the given client.
+    pub fn apply(&self, method: &str, client: &Client, response: &mut Value) {
+        for rule in self
+            .rules
+            .iter()
+            .filter(|rule| rule.matches(method, client))
+        {
+            apply_path(response, &rule.path, rule.action);
+        }
+    }
+}
+
+impl FilterRule {
+    fn matches(&self, method: &str, client: &Client) -> bool {
+        self.method == method
+            && (self.clients.is_empty()
+                || client
+                    .names()
+                    .any(|name| self.clients.iter().any(|c| c == name)))
+    }
+}
+
+/// Apply an action to every field under `value` that matches `path`.
+fn apply_path(value: &mut Value, path: &[String], action: FilterAction) {
+    let (segment, rest) = match path.split_first() {
+        Some(split) => split,
+        None => return,
+    };
+
+    // At the last segment, the action is applied to the matching children of `value`.
+    if rest.is_empty() {
+        match (value, action) {
+            (Value::Object(map), FilterAction::Remove) if segment == "*" => map.clear(),
+            (Value::Object(map), FilterAction::Remove) => {
+                map.remove(segment);
+            }
+            (Value::Array(values), FilterAction::Remove) if segment == "*" => values.clear(),
+            (Value::Array(values), FilterAction::Remove) => {
+                if let Ok(index) = segment.parse::<usize>() {
+                    if index < values.len() {
+                        values.remove(index);
+                    }
+                }
+            }
+            (value, FilterAction::Mask) => {
+                for child in children(value, segment) {
+                    *child = Value::from(MASKED_VALUE);
+                }
+            }
+            _ => {}
+        }
+        return;
+    }
+
+    for child in children(value, segment) {
+        apply_path(child, rest, action);
+    }
+}
+
+/// Returns the children of `value` matched by a single path segment.
+fn children<'a>(value: &'a mut Value, segment: &str) -> Vec<&'a mut Value> {
+    match value {
+        Value::Object(map) if segment == "*" => map.values_mut().collect(),
+        Value::Object(map) => map.get_mut(segment).into_iter().collect(),
+        Value::Array(values) if segment == "*" => values.iter_mut().collect(),
+        Value::Array(values) => segment
+            .parse::<usize>()
+            .ok()
+            .and_then(move |index| values.get_mut(index))
+            .into_iter()
+            .collect(),
+        _ => vec![],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn test_filter() -> ResponseFilter {
+        ResponseFilter::from_json(
+            r#"{
+                "rules": [
+                    { "method": "get_all_accounts", "path": "result.account_map.*.main_address", "action": "remove" },
+                    { "method": "get_all_accounts", "path": "result.account_ids.0", "action": "mask" },
+                    { "method": "get_account", "path": "result.account.name", "action": "mask", "clients": ["unencrypted"] },
+                    { "method": "get_account", "path": "result.account.main_address", "action": "remove", "clients": ["acme-app"] }
+                ]
+            }"#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn remove_and_mask_with_wildcards_and_indices() {
+        let mut response = json!({
+            "result": {
+                "account_ids": ["a", "b"],
+                "account_map": {
+                    "a": {"name": "alice", "main_address": "addr_a"},
+                    "b": {"name": "bob", "main_address": "addr_b"},
+                },
+            },
+        });
+
+        test_filter().apply("get_all_accounts", &Client::encrypted(None), &mut response);
+
+        assert_eq!(
+            response,
+            json!({
+                "result": {
+                    "account_ids": [MASKED_VALUE, "b"],
+                    "account_map": {
+                        "a": {"name": "alice"},
+                        "b": {"name": "bob"},
+                    },
+                },
+            })
+        );
+    }
+
+    #[test]
+    fn rules_only_apply_to_matching_clients() {
+        let filter = test_filter();
+        let original = json!({"result": {"account": {"name": "alice"}}});
+
+        let mut response = original.clone();
+        filter.apply("get_account", &Client::encrypted(None), &mut response);
+        assert_eq!(response, original);
+        assert!(!filter.has_rules_for("get_account", &Client::encrypted(None)));
+
+        filter.apply("get_account", &Client::unencrypted(), &mut response);
+        assert_eq!(response["result"]["account"]["name"], MASKED_VALUE);
+    }
+
+    #[test]
+    fn rules_apply_to_signed_requests_of_named_clients() {
+        let filter = test_filter();
+        let original = json!({"result": {"account": {"name": "alice", "main_address": "addr"}}});
+
+        let mut response = original.clone();
+        filter.apply(
+            "get_account",
+            &Client::encrypted(Some("other")),
+            &mut response,
+        );
+        assert_eq!(response, original);
+
+        filter.apply(
+            "get_account",
+            &Client::encrypted(Some("acme-app")),
+            &mut response,
+        );
+        assert_eq!(response, json!({"result": {"account": {"name": "alice"}}}));
+    }
+
+    #[test]
+    fn missing_paths_are_ignored() {
+        let original = json!({"error": {"code": -32603, "message": "oops"}});
+
+        let mut response = original.clone();
+        test_filter().apply("get_all_accounts", &Client::unencrypted(), &mut response);
+        assert_eq!(response, original);
+    }
+
+    #[test]
+    fn invalid_config_is_rejected() {
+        assert!(ResponseFilter::from_json(
+            r#"{"rules": [{"method": "get_account", "path": "result..x", "action": "remove"}]}"#
+        )
+        .is_err());
+        assert!(ResponseFilter::from_json(
+            r#"{"rules": [{"method": "get_account", "path": "result", "action": "explode"}]}"#
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,139 @@
+// Copyright (c) 2018-2022 MobileCoin Inc.
+
+//! Signing of the responses returned to the public side of the mirror.
+
+use mc_crypto_keys::{Ed25519Pair, Ed25519Private, Signer};
+use mc_wallet_service_mirror::{
+    response_signature::response_message,
+    wallet_service_mirror_api::{QueryRequest, QueryResponse},
+};
+use std::{convert::TryFrom, fmt, sync::Arc};
+
+/// The key responses are signed with.
+#[derive(Clone)]
+pub struct ResponseSigner {
+    pair: Arc<Ed25519Pair>,
+}
+
+impl fmt::Debug for ResponseSigner {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ResponseSigner")
+            .field("public_key", &hex::encode(self.pair.public_key()))
+            .finish()
+    }
+}
+
+impl ResponseSigner {
+    /// Load a key from a file holding the hex-encoded Ed25519 private key.
+    pub fn load(src: &str) -> Result<Self, String> {
+        let key_hex = std::fs::read_to_string(src)
+            .map_err(|err| format!("failed reading signing key {}: {:?}", src, err))?;
+        let seed = hex::decode(key_hex.trim())
+            .map_err(|err| format!("failed decoding signing key {}: {}", src, err))?;
+        let private_key = Ed25519Private::try_from(&seed[..])
+            .map_err(|err| format!("failed parsing signing key {}: {:?}", src, err))?;
+        Ok(Self {
+            pair: Arc::new(Ed25519Pair::from(private_key)),
+        })
+    }
+
+    /// The hex-encoded public key clients verify responses with.
+    pub fn public_key_hex(&self) -> String {
+        hex::encode(self.pair.public_key())
+    }
+
+    /// Sign the body of a response to a query, along with the request it answers. Error responses
+    /// are not signed.
+    pub fn sign(&self, query_id: &str, request: &QueryRequest, response: &mut QueryResponse) {
+        let request = request_body(request);
+        if response.has_unencrypted_response() {
+            let unencrypted_response = response.mut_unencrypted_response();
+            let message = response_message(
+                query_id,
+                request,
+                unencrypted_response.get_json_response().as_bytes(),
+            );
+            let signature = self.pair.sign(&message);
+            unencrypted_response.set_signature(signature.as_ref().to_vec());
+        } else if response.has_encrypted_response() {
+            let encrypted_response = response.mut_encrypted_response();
+            let message = response_message(query_id, request, encrypted_response.get_payload());
+            let signature = self.pair.sign(&message);
+            encrypted_response.set_signature(signature.as_ref().to_vec());
+        }
+    }
+}
+
+/// The body of a request, which is covered by the signature of its response.
+fn request_body(request: &QueryRequest) -> &[u8] {
+    if request.has_unencrypted_request() {
+        request
+            .get_unencrypted_request()
+            .get_json_request()
+            .as_bytes()
+    } else if request.has_encrypted_request() {
+        request.get_encrypted_request().get_payload()
+    } else if request.has_session_request() {
+        request.get_session_request().get_payload()
+    } else {
+        &[]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mc_crypto_keys::{Ed25519Signature, Verifier};
+    use mc_wallet_service_mirror::wallet_service_mirror_api::{
+        UnencryptedRequest, UnencryptedResponse,
+    };
+
+    #[test]
+    fn responses_are_signed_over_query_id_request_and_body() {
+        let key_path = std::env::temp_dir().join("mirror-response-signer-test.key");
+        std::fs::write(&key_path, hex::encode([7u8; 32])).unwrap();
+        let signer = ResponseSigner::load(key_path.to_str().unwrap()).unwrap();
+
+        let mut unencrypted_response = UnencryptedResponse::new();
+        unencrypted_response.set_json_response(r#"{"result": {}}"#.to_owned());
+        let mut response = QueryResponse::new();
+        response.set_unencrypted_response(unencrypted_response);
+        let mut unencrypted_request = UnencryptedRequest::new();
+        unencrypted_request.set_json_request(r#"{"method": "get_block"}"#.to_owned());
+        let mut request = QueryRequest::new();
+        request.set_unencrypted_request(unencrypted_request);
+        signer.sign("abc", &request, &mut response);
+
+        let signature =
+            Ed25519Signature::try_from(response.get_unencrypted_response().get_signature())
+                .unwrap();
+        let public_key = signer.pair.public_key();
+        assert!(public_key
+            .verify(
+                &response_message("abc", br#"{"method": "get_block"}"#, br#"{"result": {}}"#),
+                &signature
+            )
+            .is_ok());
+        assert!(public_key
+            .verify(
+                &response_message("abd", br#"{"method": "get_block"}"#, br#"{"result": {}}"#),
+                &signature
+            )
+            .is_err());
+        assert!(public_key
+            .verify(
+                &response_message(
+                    "abc",
+                    br#"{"method": "get_wallet_status"}"#,
+                    br#"{"result": {}}"#
+                ),
+                &signature
+            )
+            .is_err());
+
+        let mut response = QueryResponse::new();
+        response.set_error("oops".to_owned());
+        signer.sign("abc", &request, &mut response);
+        assert_eq!(response.get_error(), "oops");
+    }
+}
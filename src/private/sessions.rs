@@ -0,0 +1,461 @@
+// Copyright (c) 2018-2022 MobileCoin Inc.
+
+//! Forward-secret sessions between clients and the private side of the mirror.
+//!
+//! Encrypted requests are encrypted with the long-lived mirror key, so anyone who obtains it can
+//! decrypt all the traffic they captured. Sessions instead encrypt requests with keys derived from
+//! ephemeral X25519 keys, which are forgotten once the session expires.
+//!
+//! To establish a session, the client generates an ephemeral X25519 key pair and a random 32-byte
+//! secret, and sends a `SessionHandshakeRequest` whose payload is encrypted with the mirror key
+//! (just like an encrypted request):
+//! ```json
+//! { "ephemeral_public_key": "<hex-encoded X25519 public key>", "secret": "<hex-encoded secret>" }
+//! ```
+//! When a client key registry is configured, the handshake has to be signed by a registered client
+//! just like a request (see `client_auth.rs`), with `wallet-service-mirror-session-handshake` in
+//! place of `wallet-service-mirror-request` in the signed message.
+//! The private side replies with a `SessionHandshakeResponse` holding a session id, its own
+//! ephemeral public key and a key confirmation. Both sides derive 96 bytes with HKDF-SHA256, using
+//! `wallet-service-mirror-session` as salt, the X25519 shared secret followed by the secret as
+//! input keying material, and the client public key, mirror public key and session id as info:
+//! the request key, the response key and the key confirmation, 32 bytes each. Only the holder of
+//! the mirror key learns the secret, so the public side cannot substitute its own ephemeral key,
+//! and only the holders of the ephemeral keys can compute the shared secret, so the session keys
+//! cannot be recovered from the mirror key.
+//!
+//! Requests within the session are sent as `SessionRequest`s, and answered with
+//! `EncryptedResponse`s. Both payloads are a random 12-byte nonce, followed by the AES-256-GCM
+//! ciphertext and the 16-byte tag, with the session id as additional data. The plaintext of a
+//! request is what the plaintext of an encrypted request would be.
+//!
+//! Notifications about an account are only pushed to subscribers that name a session (see
+//! `watcher.rs`), encrypted like responses with the response key of the session, but with the
+//! session id followed by `/notification` as additional data, so that the public side cannot pass
+//! them off as responses.
+
+use boring::symm::{decrypt_aead, encrypt_aead, Cipher};
+use hmac::{Hmac, Mac, NewMac};
+use mc_crypto_keys::{KexEphemeralPrivate, KexPrivate, X25519EphemeralPrivate, X25519Public};
+use mc_wallet_service_mirror::wallet_service_mirror_api::SessionHandshakeResponse;
+use rand::RngCore;
+use serde_derive::Deserialize;
+use sha2::Sha256;
+use std::{
+    collections::{HashMap, VecDeque},
+    convert::TryFrom,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use zeroize::{Zeroize, Zeroizing};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Salt for deriving session keys.
+const SESSION_CONTEXT: &str = "wallet-service-mirror-session";
+
+/// The maximum number of sessions held at once. The oldest session is forgotten when a new one
+/// would exceed it.
+const MAX_SESSIONS: usize = 100_000;
+
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+
+/// Appended to the session id to form the additional data of notifications.
+const NOTIFICATION_CONTEXT: &str = "/notification";
+
+/// The error returned for session requests that cannot be decrypted, whatever the reason.
+const INVALID_SESSION_REQUEST: &str = "Invalid session request";
+
+#[derive(Deserialize)]
+struct Handshake {
+    ephemeral_public_key: String,
+    secret: String,
+}
+
+struct Session {
+    request_key: [u8; KEY_LEN],
+    response_key: [u8; KEY_LEN],
+    established_at: Instant,
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.request_key.zeroize();
+        self.response_key.zeroize();
+    }
+}
+
+/// The key a response within a session is encrypted with.
+pub struct ResponseKey([u8; KEY_LEN]);
+
+impl Drop for ResponseKey {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+impl ResponseKey {
+    /// Encrypt a response to a request made within a session.
+    pub fn seal(&self, session_id: &str, plaintext: &[u8]) -> Result<Vec<u8>, String> {
+        seal(&self.0, session_id, plaintext)
+    }
+}
+
+#[derive(Default)]
+struct SessionsInner {
+    /// Map of session id -> session.
+    sessions: HashMap<String, Session>,
+
+    /// The session ids, in the order the sessions were established in.
+    established: VecDeque<String>,
+}
+
+impl SessionsInner {
+    /// Forget the oldest sessions, while they expired or there are more than `max_len` of them.
+    fn prune(&mut self, lifetime: Duration, max_len: usize) {
+        while let Some(session_id) = self.established.front() {
+            let expired = self
+                .sessions
+                .get(session_id)
+                .map_or(true, |session| session.established_at.elapsed() >= lifetime);
+            if !expired && self.sessions.len() <= max_len {
+                break;
+            }
+            let session_id = self
+                .established
+                .pop_front()
+                .expect("established is not empty");
+            self.sessions.remove(&session_id);
+        }
+    }
+}
+
+/// The sessions established with clients.
+#[derive(Clone)]
+pub struct Sessions {
+    /// How long a session may be used for after it was established.
+    lifetime: Duration,
+
+    sessions: Arc<Mutex<SessionsInner>>,
+}
+
+impl Sessions {
+    pub fn new(lifetime: Duration) -> Self {
+        Self {
+            lifetime,
+            sessions: Arc::new(Mutex::new(SessionsInner::default())),
+        }
+    }
+
+    /// Establish a session from the decrypted payload of a handshake request.
+    pub fn handshake(&self, handshake: &[u8]) -> Result<SessionHandshakeResponse, String> {
+        let handshake: Handshake = serde_json::from_slice(handshake)
+            .map_err(|err| format!("Invalid session handshake: {}", err))?;
+        let client_public = hex::decode(&handshake.ephemeral_public_key)
+            .ok()
+            .and_then(|key_bytes| X25519Public::try_from(&key_bytes[..]).ok())
+            .ok_or("Invalid session handshake: invalid ephemeral public key")?;
+        let secret = hex::decode(&handshake.secret)
+            .ok()
+            .map(Zeroizing::new)
+            .filter(|secret| secret.len() == KEY_LEN)
+            .ok_or("Invalid session handshake: invalid secret")?;
+
+        let mut rng = rand::thread_rng();
+        let mut session_id_bytes = [0u8; 16];
+        rng.fill_bytes(&mut session_id_bytes);
+        let session_id = hex::encode(session_id_bytes);
+
+        let mirror_private = X25519EphemeralPrivate::from_random(&mut rng);
+        let mirror_public = mirror_private.compute_public();
+        let shared_secret = mirror_private.key_exchange(&client_public);
+
+        let keys = derive_keys(
+            shared_secret.as_ref(),
+            &secret,
+            client_public.as_ref(),
+            mirror_public.as_ref(),
+            &session_id,
+        );
+
+        let mut inner = self.sessions.lock().expect("mutex poisoned");
+        inner.prune(self.lifetime, MAX_SESSIONS - 1);
+        inner.sessions.insert(
+            session_id.clone(),
+            Session {
+                request_key: keys.request_key,
+                response_key: keys.response_key,
+                established_at: Instant::now(),
+            },
+        );
+        inner.established.push_back(session_id.clone());
+
+        let mut response = SessionHandshakeResponse::new();
+        response.set_session_id(session_id);
+        response.set_ephemeral_public_key(mirror_public.as_ref().to_vec());
+        response.set_key_confirmation(keys.key_confirmation.to_vec());
+        Ok(response)
+    }
+
+    /// Decrypt a request made within a session, returning its plaintext and the key to encrypt
+    /// the response with.
+    pub fn open(
+        &self,
+        session_id: &str,
+        payload: &[u8],
+    ) -> Result<(Zeroizing<Vec<u8>>, ResponseKey), String> {
+        let inner = self.sessions.lock().expect("mutex poisoned");
+        let session = inner
+            .sessions
+            .get(session_id)
+            .filter(|session| session.established_at.elapsed() < self.lifetime)
+            .ok_or("Unknown or expired session")?;
+
+        let plaintext = open(&session.request_key, session_id, payload)?;
+        Ok((plaintext, ResponseKey(session.response_key)))
+    }
+
+    /// Encrypt a notification for the subscriber that established a session.
+    pub fn seal_notification(&self, session_id: &str, plaintext: &[u8]) -> Result<Vec<u8>, String> {
+        let inner = self.sessions.lock().expect("mutex poisoned");
+        let session = inner
+            .sessions
+            .get(session_id)
+            .filter(|session| session.established_at.elapsed() < self.lifetime)
+            .ok_or("Unknown or expired session")?;
+
+        seal(
+            &session.response_key,
+            &format!("{}{}", session_id, NOTIFICATION_CONTEXT),
+            plaintext,
+        )
+    }
+}
+
+/// The keys derived for a session.
+struct SessionKeys {
+    request_key: [u8; KEY_LEN],
+    response_key: [u8; KEY_LEN],
+    key_confirmation: [u8; KEY_LEN],
+}
+
+impl Drop for SessionKeys {
+    fn drop(&mut self) {
+        self.request_key.zeroize();
+        self.response_key.zeroize();
+    }
+}
+
+fn derive_keys(
+    shared_secret: &[u8],
+    secret: &[u8],
+    client_public: &[u8],
+    mirror_public: &[u8],
+    session_id: &str,
+) -> SessionKeys {
+    let ikm = Zeroizing::new([shared_secret, secret].concat());
+    let info = [client_public, mirror_public, session_id.as_bytes()].concat();
+    let okm = hkdf_sha256(SESSION_CONTEXT.as_bytes(), &ikm, &info, 3 * KEY_LEN);
+
+    let mut keys = SessionKeys {
+        request_key: [0u8; KEY_LEN],
+        response_key: [0u8; KEY_LEN],
+        key_confirmation: [0u8; KEY_LEN],
+    };
+    keys.request_key.copy_from_slice(&okm[..KEY_LEN]);
+    keys.response_key
+        .copy_from_slice(&okm[KEY_LEN..2 * KEY_LEN]);
+    keys.key_confirmation.copy_from_slice(&okm[2 * KEY_LEN..]);
+    keys
+}
+
+/// HKDF (RFC 5869) with HMAC-SHA256.
+fn hkdf_sha256(salt: &[u8], ikm: &[u8], info: &[u8], len: usize) -> Zeroizing<Vec<u8>> {
+    let mut mac = HmacSha256::new_varkey(salt).expect("HMAC accepts any key length");
+    mac.update(ikm);
+    let prk = mac.finalize().into_bytes();
+
+    let mut okm = Zeroizing::new(Vec::with_capacity(len + 32));
+    let mut block = Zeroizing::new(Vec::new());
+    let mut counter = 1u8;
+    while okm.len() < len {
+        let mut mac = HmacSha256::new_varkey(&prk).expect("HMAC accepts any key length");
+        mac.update(&block);
+        mac.update(info);
+        mac.update(&[counter]);
+        block = Zeroizing::new(mac.finalize().into_bytes().to_vec());
+        okm.extend_from_slice(&block);
+        counter += 1;
+    }
+    okm.truncate(len);
+    okm
+}
+
+fn seal(key: &[u8], session_id: &str, plaintext: &[u8]) -> Result<Vec<u8>, String> {
+    let mut nonce = [0u8; NONCE_LEN];
+    rand::thread_rng().fill_bytes(&mut nonce);
+    let mut tag = [0u8; TAG_LEN];
+    let ciphertext = encrypt_aead(
+        Cipher::aes_256_gcm(),
+        key,
+        Some(&nonce),
+        session_id.as_bytes(),
+        plaintext,
+        &mut tag,
+    )
+    .map_err(|err| format!("encrypt failed: {:?}", err))?;
+
+    Ok([&nonce[..], &ciphertext, &tag].concat())
+}
+
+fn open(key: &[u8], session_id: &str, payload: &[u8]) -> Result<Zeroizing<Vec<u8>>, String> {
+    if payload.len() < NONCE_LEN + TAG_LEN {
+        return Err(INVALID_SESSION_REQUEST.into());
+    }
+    let (nonce, rest) = payload.split_at(NONCE_LEN);
+    let (ciphertext, tag) = rest.split_at(rest.len() - TAG_LEN);
+    decrypt_aead(
+        Cipher::aes_256_gcm(),
+        key,
+        Some(nonce),
+        session_id.as_bytes(),
+        ciphertext,
+        tag,
+    )
+    .map(Zeroizing::new)
+    .map_err(|_| INVALID_SESSION_REQUEST.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    const LIFETIME: Duration = Duration::from_secs(60 * 60);
+
+    /// Perform the client side of a handshake, returning the session id and the client's request
+    /// and response keys.
+    fn client_handshake(sessions: &Sessions) -> (String, SessionKeys) {
+        let mut rng = rand::thread_rng();
+        let client_private = X25519EphemeralPrivate::from_random(&mut rng);
+        let client_public = client_private.compute_public();
+        let secret = [5u8; KEY_LEN];
+
+        let handshake = json!({
+            "ephemeral_public_key": hex::encode(client_public.as_ref()),
+            "secret": hex::encode(secret),
+        });
+        let response = sessions
+            .handshake(handshake.to_string().as_bytes())
+            .unwrap();
+
+        let mirror_public = X25519Public::try_from(response.get_ephemeral_public_key()).unwrap();
+        let shared_secret = client_private.key_exchange(&mirror_public);
+        let keys = derive_keys(
+            shared_secret.as_ref(),
+            &secret,
+            client_public.as_ref(),
+            mirror_public.as_ref(),
+            response.get_session_id(),
+        );
+        assert_eq!(response.get_key_confirmation(), &keys.key_confirmation[..]);
+
+        (response.get_session_id().to_owned(), keys)
+    }
+
+    #[test]
+    fn requests_and_responses_are_encrypted_with_session_keys() {
+        let sessions = Sessions::new(LIFETIME);
+        let (session_id, keys) = client_handshake(&sessions);
+
+        let request = br#"{"method": "get_block", "jsonrpc": "2.0", "id": 1}"#;
+        let payload = seal(&keys.request_key, &session_id, request).unwrap();
+        let (plaintext, response_key) = sessions.open(&session_id, &payload).unwrap();
+        assert_eq!(&plaintext[..], &request[..]);
+
+        let response = response_key.seal(&session_id, b"{}").unwrap();
+        assert_eq!(
+            &open(&keys.response_key, &session_id, &response).unwrap()[..],
+            b"{}"
+        );
+
+        // Requests must be encrypted with the request key of the same session.
+        let (other_session_id, _) = client_handshake(&sessions);
+        assert!(sessions.open(&other_session_id, &payload).is_err());
+        let payload = seal(&keys.response_key, &session_id, request).unwrap();
+        assert_eq!(
+            sessions.open(&session_id, &payload).err().unwrap(),
+            INVALID_SESSION_REQUEST
+        );
+    }
+
+    #[test]
+    fn notifications_are_not_valid_responses() {
+        let sessions = Sessions::new(LIFETIME);
+        let (session_id, keys) = client_handshake(&sessions);
+
+        let notification = sessions.seal_notification(&session_id, b"{}").unwrap();
+        assert_eq!(
+            &open(
+                &keys.response_key,
+                &format!("{}{}", session_id, NOTIFICATION_CONTEXT),
+                &notification
+            )
+            .unwrap()[..],
+            b"{}"
+        );
+        assert!(open(&keys.response_key, &session_id, &notification).is_err());
+
+        assert!(sessions.seal_notification("unknown", b"{}").is_err());
+    }
+
+    #[test]
+    fn expired_sessions_are_forgotten() {
+        let sessions = Sessions::new(Duration::from_secs(0));
+        let (session_id, keys) = client_handshake(&sessions);
+
+        let payload = seal(&keys.request_key, &session_id, b"{}").unwrap();
+        assert!(sessions.open(&session_id, &payload).is_err());
+
+        client_handshake(&sessions);
+        assert_eq!(sessions.sessions.lock().unwrap().sessions.len(), 1);
+    }
+
+    #[test]
+    fn the_oldest_sessions_are_evicted() {
+        let sessions = Sessions::new(LIFETIME);
+        let (first, _) = client_handshake(&sessions);
+        let (second, _) = client_handshake(&sessions);
+        let (third, _) = client_handshake(&sessions);
+
+        sessions.sessions.lock().unwrap().prune(LIFETIME, 2);
+
+        let inner = sessions.sessions.lock().unwrap();
+        assert!(!inner.sessions.contains_key(&first));
+        assert!(inner.sessions.contains_key(&second));
+        assert!(inner.sessions.contains_key(&third));
+        assert_eq!(inner.established, vec![second, third]);
+    }
+
+    #[test]
+    fn invalid_handshakes_are_rejected() {
+        let sessions = Sessions::new(LIFETIME);
+        assert!(sessions.handshake(b"garbage").is_err());
+        assert!(sessions
+            .handshake(
+                json!({"ephemeral_public_key": hex::encode([1u8; 32]), "secret": "00"})
+                    .to_string()
+                    .as_bytes()
+            )
+            .is_err());
+        assert!(sessions
+            .handshake(
+                json!({"ephemeral_public_key": "00", "secret": hex::encode([1u8; 32])})
+                    .to_string()
+                    .as_bytes()
+            )
+            .is_err());
+    }
+}
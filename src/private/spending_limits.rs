@@ -0,0 +1,516 @@
+// Copyright (c) 2018-2022 MobileCoin Inc.
+
+//! Spending limits and velocity controls for transaction-submitting methods.
+//! Amounts are parsed out of `build_and_submit_transaction`-style params, checked against
+//! per-account and per-client caps, and the running totals are persisted in a local state file so
+//! that they survive restarts.
+//!
+//! The config is a JSON file of the form (amounts are in picoMOB):
+//! ```json
+//! {
+//!     "methods": ["build_and_submit_transaction"],
+//!     "state_file": "/var/lib/wallet-service-mirror/spending.json",
+//!     "default_account_limits": { "per_transaction": 1000000000000, "per_day": 10000000000000 },
+//!     "account_limits": { "<account id>": { "per_hour": 5000000000000 } },
+//!     "default_client_limits": { "per_day": 20000000000000 },
+//!     "client_limits": { "encrypted": { "per_transaction": 2000000000000 } }
+//! }
+//! ```
+//! Clients are either transports (`unencrypted` or `encrypted`), or the names of clients in the
+//! client key registry (`--client-keys`), whose signed requests count towards both their own caps
+//! and the caps of the `encrypted` transport.
+//!
+//! Requests that need approval are checked against the limits when they are queued, but only
+//! count towards the totals once they are approved and forwarded, so that requests nobody
+//! approves cannot use up the caps.
+
+use crate::{
+    json_rpc::{JsonRpcError, JSON_RPC_INTERNAL_ERROR, JSON_RPC_INVALID_PARAMS},
+    request::Client,
+};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// JSON-RPC error code (from the implementation-defined server error range) for requests that
+/// would exceed a spending limit.
+pub const JSON_RPC_SPENDING_LIMIT_EXCEEDED: i64 = -32001;
+
+const HOUR_SECS: u64 = 60 * 60;
+const DAY_SECS: u64 = 24 * HOUR_SECS;
+
+/// Caps on the amount spent, in picoMOB. Unset caps are not enforced.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq)]
+pub struct Limits {
+    #[serde(default)]
+    per_transaction: Option<u64>,
+
+    #[serde(default)]
+    per_hour: Option<u64>,
+
+    #[serde(default)]
+    per_day: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct SpendingLimitsConfig {
+    /// Methods that spend funds and are subject to the limits.
+    methods: Vec<String>,
+
+    /// File the running totals are persisted in.
+    state_file: PathBuf,
+
+    /// Limits for accounts not listed in `account_limits`.
+    #[serde(default)]
+    default_account_limits: Limits,
+
+    /// Map of account id -> limits.
+    #[serde(default)]
+    account_limits: HashMap<String, Limits>,
+
+    /// Limits for clients not listed in `client_limits`.
+    #[serde(default)]
+    default_client_limits: Limits,
+
+    /// Map of client (transport or signer) -> limits.
+    #[serde(default)]
+    client_limits: HashMap<String, Limits>,
+}
+
+/// A single accepted spend, counted towards the totals of one account or client.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+struct SpendRecord {
+    /// `account:<account id>` or `client:<client>`.
+    key: String,
+
+    /// Amount in picoMOB.
+    amount: u64,
+
+    /// Unix timestamp (seconds).
+    timestamp: u64,
+}
+
+/// Enforces spending limits on transaction-submitting requests.
+#[derive(Clone, Debug)]
+pub struct SpendingLimits {
+    methods: Vec<String>,
+    state_file: PathBuf,
+    default_account_limits: Limits,
+    account_limits: HashMap<String, Limits>,
+    default_client_limits: Limits,
+    client_limits: HashMap<String, Limits>,
+
+    /// Spends within the last day.
+    records: Arc<Mutex<Vec<SpendRecord>>>,
+}
+
+impl SpendingLimits {
+    /// Load the spending limits config from a JSON file, along with the persisted totals.
+    pub fn load(src: &str) -> Result<Self, String> {
+        let config_str = fs::read_to_string(src)
+            .map_err(|err| format!("failed reading spending limits config {}: {:?}", src, err))?;
+        let config: SpendingLimitsConfig = serde_json::from_str(&config_str)
+            .map_err(|err| format!("failed parsing spending limits config {}: {}", src, err))?;
+
+        let records = if config.state_file.exists() {
+            let state = fs::read(&config.state_file).map_err(|err| {
+                format!(
+                    "failed reading spending state {:?}: {}",
+                    config.state_file, err
+                )
+            })?;
+            serde_json::from_slice(&state).map_err(|err| {
+                format!(
+                    "failed parsing spending state {:?}: {}",
+                    config.state_file, err
+                )
+            })?
+        } else {
+            vec![]
+        };
+
+        Ok(Self {
+            methods: config.methods,
+            state_file: config.state_file,
+            default_account_limits: config.default_account_limits,
+            account_limits: config.account_limits,
+            default_client_limits: config.default_client_limits,
+            client_limits: config.client_limits,
+            records: Arc::new(Mutex::new(records)),
+        })
+    }
+
+    /// Returns true if requests for the given method are subject to spending limits.
+    pub fn applies_to(&self, method: &str) -> bool {
+        self.methods.iter().any(|m| m == method)
+    }
+
+    /// Check that a request stays within the limits of its account and client, without counting
+    /// it towards their totals.
+    pub fn check(&self, client: &Client, request: &Value) -> Result<(), JsonRpcError> {
+        self.enforce_at(client, request, false, now())
+    }
+
+    /// Check that a request stays within the limits of its account and client, and if so count it
+    /// towards their totals.
+    pub fn check_and_record(&self, client: &Client, request: &Value) -> Result<(), JsonRpcError> {
+        self.enforce_at(client, request, true, now())
+    }
+
+    fn enforce_at(
+        &self,
+        client: &Client,
+        request: &Value,
+        record: bool,
+        now: u64,
+    ) -> Result<(), JsonRpcError> {
+        let params = &request["params"];
+        let amount = request_amount(params).map_err(|err| {
+            JsonRpcError::new(JSON_RPC_INVALID_PARAMS, format!("Invalid params: {}", err))
+        })?;
+        let account_id = params["account_id"].as_str().ok_or_else(|| {
+            JsonRpcError::new(
+                JSON_RPC_INVALID_PARAMS,
+                "Invalid params: missing param \"account_id\"",
+            )
+        })?;
+
+        let mut checks = vec![(
+            format!("account:{}", account_id),
+            self.account_limits
+                .get(account_id)
+                .unwrap_or(&self.default_account_limits),
+        )];
+        // Limits configured for a transport apply to every request arriving through it. The
+        // default client limits apply to each signer separately, or to the transport if the
+        // request is not signed.
+        for name in client.names() {
+            let limits = match self.client_limits.get(name) {
+                Some(limits) => limits,
+                None if client.signer.is_some() && name == client.transport => continue,
+                None => &self.default_client_limits,
+            };
+            checks.push((format!("client:{}", name), limits));
+        }
+
+        let mut records = self.records.lock().expect("mutex poisoned");
+        records.retain(|record| record.timestamp + DAY_SECS > now);
+
+        for (key, limits) in checks.iter() {
+            let spent_since = |since: u64| -> u64 {
+                records
+                    .iter()
+                    .filter(|record| &record.key == key && record.timestamp > since)
+                    .fold(0u64, |total, record| total.saturating_add(record.amount))
+            };
+
+            let windows = [
+                ("per transaction", limits.per_transaction, 0),
+                (
+                    "per hour",
+                    limits.per_hour,
+                    spent_since(now.saturating_sub(HOUR_SECS)),
+                ),
+                (
+                    "per day",
+                    limits.per_day,
+                    spent_since(now.saturating_sub(DAY_SECS)),
+                ),
+            ];
+            for (window, limit, spent) in windows.iter() {
+                if let Some(limit) = limit {
+                    if spent.saturating_add(amount) > *limit {
+                        return Err(JsonRpcError::new(
+                            JSON_RPC_SPENDING_LIMIT_EXCEEDED,
+                            format!(
+                                "Spending limit exceeded: {} would spend {} of {} picoMOB {}",
+                                key,
+                                spent.saturating_add(amount),
+                                limit,
+                                window
+                            ),
+                        ));
+                    }
+                }
+            }
+        }
+
+        if !record {
+            return Ok(());
+        }
+
+        let new_records: Vec<SpendRecord> = checks
+            .iter()
+            .map(|(key, _)| SpendRecord {
+                key: key.clone(),
+                amount,
+                timestamp: now,
+            })
+            .collect();
+        records.extend(new_records.iter().cloned());
+
+        // If the totals cannot be persisted the request is rejected, since otherwise a restart
+        // would forget about it.
+        if let Err(err) = write_state(&self.state_file, &records) {
+            let len = records.len();
+            records.truncate(len - new_records.len());
+            return Err(JsonRpcError::new(JSON_RPC_INTERNAL_ERROR, err));
+        }
+
+        Ok(())
+    }
+}
+
+/// Current time as a Unix timestamp (seconds).
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+/// The total amount (in picoMOB) a `build_and_submit_transaction`-style request sends, including
+/// the fee if one is given.
+fn request_amount(params: &Value) -> Result<u64, String> {
+    let parse_amount = |value: &Value, name: &str| -> Result<u64, String> {
+        value
+            .as_str()
+            .and_then(|value| value.parse::<u64>().ok())
+            .ok_or_else(|| format!("\"{}\" must be a string holding an amount", name))
+    };
+
+    let mut amounts = Vec::new();
+    if !params["value_pmob"].is_null() {
+        amounts.push(parse_amount(&params["value_pmob"], "value_pmob")?);
+    }
+    if !params["addresses_and_values"].is_null() {
+        let addresses_and_values = params["addresses_and_values"]
+            .as_array()
+            .ok_or("\"addresses_and_values\" must be an array")?;
+        for address_and_value in addresses_and_values {
+            amounts.push(parse_amount(&address_and_value[1], "addresses_and_values")?);
+        }
+    }
+    if amounts.is_empty() {
+        return Err("could not find the amount being sent".into());
+    }
+    if !params["fee"].is_null() {
+        amounts.push(parse_amount(&params["fee"], "fee")?);
+    }
+
+    amounts
+        .into_iter()
+        .try_fold(0u64, |total, amount| total.checked_add(amount))
+        .ok_or_else(|| "amount overflow".into())
+}
+
+/// Persist the spend records by writing a temporary file and renaming it.
+fn write_state(state_file: &Path, records: &[SpendRecord]) -> Result<(), String> {
+    let json = serde_json::to_vec(records)
+        .map_err(|err| format!("failed serializing spending state: {}", err))?;
+    let tmp_path = state_file.with_extension("tmp");
+    fs::write(&tmp_path, json)
+        .map_err(|err| format!("failed writing spending state {:?}: {}", tmp_path, err))?;
+    fs::rename(&tmp_path, state_file)
+        .map_err(|err| format!("failed writing spending state {:?}: {}", state_file, err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn test_config(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "mirror-spending-limits-test-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let config = json!({
+            "methods": ["build_and_submit_transaction"],
+            "state_file": dir.join("state.json"),
+            "default_account_limits": {"per_transaction": 100, "per_hour": 150, "per_day": 250},
+            "account_limits": {"big": {"per_transaction": 1000}},
+            "default_client_limits": {"per_day": 450},
+            "client_limits": {"encrypted": {"per_day": 500}},
+        });
+        let config_path = dir.join("limits.json");
+        fs::write(&config_path, config.to_string()).unwrap();
+        config_path
+    }
+
+    fn test_limits(name: &str) -> SpendingLimits {
+        SpendingLimits::load(test_config(name).to_str().unwrap()).unwrap()
+    }
+
+    fn request(account_id: &str, value_pmob: u64) -> Value {
+        json!({
+            "jsonrpc": "2.0",
+            "method": "build_and_submit_transaction",
+            "params": {
+                "account_id": account_id,
+                "recipient_public_address": "addr",
+                "value_pmob": value_pmob.to_string(),
+            },
+            "id": 1,
+        })
+    }
+
+    #[test]
+    fn per_transaction_hour_and_day_limits() {
+        let limits = test_limits("windows");
+        let now = 1_000_000;
+
+        // Per transaction.
+        assert_eq!(
+            limits
+                .enforce_at(&Client::unencrypted(), &request("a", 101), true, now)
+                .unwrap_err()
+                .code,
+            JSON_RPC_SPENDING_LIMIT_EXCEEDED
+        );
+
+        // Per hour.
+        limits
+            .enforce_at(&Client::unencrypted(), &request("a", 100), true, now)
+            .unwrap();
+        assert!(limits
+            .enforce_at(&Client::unencrypted(), &request("a", 51), true, now + 10)
+            .is_err());
+        limits
+            .enforce_at(&Client::unencrypted(), &request("a", 50), true, now + 10)
+            .unwrap();
+
+        // Per day - the hour has passed, but the day has not.
+        limits
+            .enforce_at(
+                &Client::unencrypted(),
+                &request("a", 100),
+                true,
+                now + HOUR_SECS + 10,
+            )
+            .unwrap();
+        assert!(limits
+            .enforce_at(
+                &Client::unencrypted(),
+                &request("a", 1),
+                true,
+                now + HOUR_SECS + 20
+            )
+            .is_err());
+        limits
+            .enforce_at(
+                &Client::unencrypted(),
+                &request("a", 100),
+                true,
+                now + DAY_SECS + 10,
+            )
+            .unwrap();
+
+        // Other accounts have their own totals.
+        limits
+            .enforce_at(&Client::unencrypted(), &request("b", 100), true, now)
+            .unwrap();
+    }
+
+    #[test]
+    fn client_limits_apply_across_accounts() {
+        let limits = test_limits("clients");
+        let now = 1_000_000;
+
+        for _ in 0..5 {
+            limits
+                .enforce_at(&Client::encrypted(None), &request("big", 100), true, now)
+                .unwrap();
+        }
+        assert!(limits
+            .enforce_at(&Client::encrypted(None), &request("big", 1), true, now)
+            .is_err());
+        limits
+            .enforce_at(&Client::unencrypted(), &request("big", 1), true, now)
+            .unwrap();
+    }
+
+    #[test]
+    fn default_client_limits_apply_to_each_signer() {
+        let limits = test_limits("signers");
+        let now = 1_000_000;
+        let acme = Client::encrypted(Some("acme-app"));
+        let ops = Client::encrypted(Some("ops"));
+
+        for _ in 0..4 {
+            limits
+                .enforce_at(&acme, &request("big", 100), true, now)
+                .unwrap();
+        }
+        assert!(limits
+            .enforce_at(&acme, &request("big", 51), true, now)
+            .is_err());
+
+        // Other signers have their own totals, but all of them count towards the limits of the
+        // encrypted transport.
+        limits
+            .enforce_at(&ops, &request("big", 100), true, now)
+            .unwrap();
+        assert!(limits
+            .enforce_at(&ops, &request("big", 1), true, now)
+            .is_err());
+    }
+
+    #[test]
+    fn checked_requests_do_not_count_towards_the_totals() {
+        let limits = test_limits("check");
+        let now = 1_000_000;
+
+        for _ in 0..3 {
+            limits
+                .enforce_at(&Client::unencrypted(), &request("a", 100), false, now)
+                .unwrap();
+        }
+        limits
+            .enforce_at(&Client::unencrypted(), &request("a", 100), true, now)
+            .unwrap();
+        assert!(limits
+            .enforce_at(&Client::unencrypted(), &request("a", 100), false, now)
+            .is_err());
+    }
+
+    #[test]
+    fn totals_survive_restarts() {
+        let config_path = test_config("restart");
+        let limits = SpendingLimits::load(config_path.to_str().unwrap()).unwrap();
+        let now = 1_000_000;
+
+        limits
+            .enforce_at(&Client::unencrypted(), &request("a", 100), true, now)
+            .unwrap();
+
+        let reloaded = SpendingLimits::load(config_path.to_str().unwrap()).unwrap();
+        assert!(reloaded
+            .enforce_at(&Client::unencrypted(), &request("a", 100), true, now)
+            .is_err());
+    }
+
+    #[test]
+    fn request_amount_includes_all_outputs_and_fee() {
+        assert_eq!(
+            request_amount(&json!({
+                "addresses_and_values": [["a", "10"], ["b", "20"]],
+                "fee": "5",
+            })),
+            Ok(35)
+        );
+        assert!(request_amount(&json!({"value_pmob": 10})).is_err());
+        assert!(request_amount(&json!({"fee": "10"})).is_err());
+        assert!(request_amount(&json!({"value_pmob": u64::MAX.to_string(), "fee": "1"})).is_err());
+    }
+}
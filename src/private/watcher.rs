@@ -0,0 +1,268 @@
+// Copyright (c) 2018-2022 MobileCoin Inc.
+
+//! Watches full-service for changes that clients subscribed to on the public side of the mirror
+//! want to be notified about: new blocks, and new or updated transaction logs of the subscribed
+//! accounts.
+//!
+//! Only accounts of the wallet are watched, and at most `MAX_WATCHED_ACCOUNTS` of them. Subscribed
+//! accounts that are not watched are only checked again when a new block arrives. When the
+//! private side only accepts encrypted requests, events about an account are only pushed to
+//! subscribers that name a session, encrypted with its keys (see `sessions.rs`), since those are
+//! the clients that could have requested the transaction logs themselves. Events that concern
+//! every subscriber (new blocks) are public information, and are always pushed as JSON.
+
+use crate::{backends::FullServiceBackends, request::Client, response_filter::ResponseFilter};
+use mc_common::logger::{log, Logger};
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet};
+
+const GET_NETWORK_STATUS_METHOD: &str = "get_network_status";
+const GET_ALL_ACCOUNTS_METHOD: &str = "get_all_accounts";
+const GET_TRANSACTION_LOGS_METHOD: &str = "get_transaction_logs_for_account";
+
+/// The maximum number of accounts whose transaction logs are checked on every new block.
+const MAX_WATCHED_ACCOUNTS: usize = 100;
+
+/// A change clients may be notified about.
+pub struct Event {
+    /// The account the event concerns. Empty for events that concern every subscriber.
+    pub account_id: String,
+
+    /// A JSON object describing the change.
+    pub event: Value,
+}
+
+/// Polls full-service and turns the changes it finds into notifications.
+pub struct Watcher {
+    /// The full-service instances to watch.
+    backends: FullServiceBackends,
+
+    /// Optional redaction rules, applied to transaction logs as if they were returned by
+    /// `get_transaction_logs_for_account`.
+    response_filter: Option<ResponseFilter>,
+
+    /// The local block height full-service reported when last polled.
+    local_block_height: Option<u64>,
+
+    /// Map of account id -> transaction log id -> transaction log status, for the subscribed
+    /// accounts.
+    transaction_logs: HashMap<String, HashMap<String, Value>>,
+
+    /// Subscribed accounts that were not watched when last checked, because they did not belong to
+    /// the wallet or too many accounts were watched already.
+    rejected_account_ids: HashSet<String>,
+}
+
+impl Watcher {
+    pub fn new(backends: FullServiceBackends, response_filter: Option<ResponseFilter>) -> Self {
+        Self {
+            backends,
+            response_filter,
+            local_block_height: None,
+            transaction_logs: HashMap::new(),
+            rejected_account_ids: HashSet::new(),
+        }
+    }
+
+    /// Poll full-service, returning events for everything that changed since the last call.
+    /// Transaction logs are only checked when a new block arrives, or when an account is first
+    /// subscribed to (which does not produce events for existing logs).
+    pub fn check(
+        &mut self,
+        account_ids: &[String],
+        encrypted: bool,
+        logger: &Logger,
+    ) -> Result<Vec<Event>, String> {
+        let response = self.backends.call_method(GET_NETWORK_STATUS_METHOD, None)?;
+        let local_block_height = response["result"]["network_status"]["local_block_height"]
+            .as_str()
+            .and_then(|height| height.parse::<u64>().ok())
+            .ok_or_else(|| format!("Unexpected {} response", GET_NETWORK_STATUS_METHOD))?;
+
+        let new_block = self
+            .local_block_height
+            .map_or(false, |previous| local_block_height > previous);
+        self.local_block_height = Some(local_block_height);
+
+        let mut events = Vec::new();
+        if new_block {
+            events.push(Event {
+                account_id: String::new(),
+                event: json!({
+                    "type": "new_block",
+                    "local_block_height": local_block_height.to_string(),
+                }),
+            });
+        }
+
+        if new_block {
+            self.rejected_account_ids.clear();
+        }
+        let account_ids = self.watched_account_ids(account_ids, logger)?;
+
+        // Forget accounts nobody is subscribed to anymore.
+        self.transaction_logs
+            .retain(|account_id, _| account_ids.contains(account_id));
+
+        let client = if encrypted {
+            Client::encrypted(None)
+        } else {
+            Client::unencrypted()
+        };
+
+        for account_id in account_ids.iter() {
+            let known = self.transaction_logs.contains_key(account_id);
+            if known && !new_block {
+                continue;
+            }
+
+            let mut response = match self.backends.call_method(
+                GET_TRANSACTION_LOGS_METHOD,
+                Some(json!({ "account_id": account_id })),
+            ) {
+                Ok(response) => response,
+                Err(err) => {
+                    log::warn!(
+                        logger,
+                        "Failed getting transaction logs for {}: {}",
+                        account_id,
+                        err
+                    );
+                    continue;
+                }
+            };
+            if let Some(response_filter) = self.response_filter.as_ref() {
+                response_filter.apply(GET_TRANSACTION_LOGS_METHOD, &client, &mut response);
+            }
+
+            let seen = self.transaction_logs.entry(account_id.clone()).or_default();
+            let changed = changed_transaction_logs(seen, &response);
+            if known {
+                events.extend(changed.into_iter().map(|transaction_log| Event {
+                    account_id: account_id.clone(),
+                    event: json!({
+                        "type": "transaction_log",
+                        "account_id": account_id,
+                        "transaction_log": transaction_log,
+                    }),
+                }));
+            }
+        }
+
+        Ok(events)
+    }
+
+    /// Returns the subscribed accounts to watch: the ones that belong to the wallet, up to
+    /// `MAX_WATCHED_ACCOUNTS` of them. Accounts that are already watched keep being watched, and
+    /// rejected accounts stay rejected until the next block.
+    fn watched_account_ids(
+        &mut self,
+        account_ids: &[String],
+        logger: &Logger,
+    ) -> Result<Vec<String>, String> {
+        self.rejected_account_ids
+            .retain(|account_id| account_ids.contains(account_id));
+
+        let mut watched: Vec<String> = account_ids
+            .iter()
+            .filter(|account_id| self.transaction_logs.contains_key(*account_id))
+            .cloned()
+            .collect();
+        let unchecked: Vec<&String> = account_ids
+            .iter()
+            .filter(|account_id| {
+                !self.transaction_logs.contains_key(*account_id)
+                    && !self.rejected_account_ids.contains(*account_id)
+            })
+            .collect();
+        if unchecked.is_empty() {
+            return Ok(watched);
+        }
+
+        let response = self.backends.call_method(GET_ALL_ACCOUNTS_METHOD, None)?;
+        let wallet_account_ids: HashSet<&str> = response["result"]["account_ids"]
+            .as_array()
+            .ok_or_else(|| format!("Unexpected {} response", GET_ALL_ACCOUNTS_METHOD))?
+            .iter()
+            .filter_map(Value::as_str)
+            .collect();
+
+        for account_id in unchecked {
+            if !wallet_account_ids.contains(account_id.as_str()) {
+                self.rejected_account_ids.insert(account_id.clone());
+            } else if watched.len() >= MAX_WATCHED_ACCOUNTS {
+                log::warn!(
+                    logger,
+                    "Not watching {}: already watching {} accounts",
+                    account_id,
+                    MAX_WATCHED_ACCOUNTS
+                );
+                self.rejected_account_ids.insert(account_id.clone());
+            } else {
+                watched.push(account_id.clone());
+            }
+        }
+        Ok(watched)
+    }
+}
+
+/// Returns the transaction logs in a `get_transaction_logs_for_account` response that are new, or
+/// whose status changed, since they were last seen. `seen` is updated to the current statuses.
+fn changed_transaction_logs(seen: &mut HashMap<String, Value>, response: &Value) -> Vec<Value> {
+    let transaction_log_map = match response["result"]["transaction_log_map"].as_object() {
+        Some(transaction_log_map) => transaction_log_map,
+        None => return vec![],
+    };
+
+    let mut changed = Vec::new();
+    for (transaction_log_id, transaction_log) in transaction_log_map {
+        let status = transaction_log["status"].clone();
+        if seen.get(transaction_log_id) != Some(&status) {
+            seen.insert(transaction_log_id.clone(), status);
+            changed.push(transaction_log.clone());
+        }
+    }
+    changed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn new_and_updated_transaction_logs_are_reported() {
+        let mut seen = HashMap::new();
+
+        let response = json!({
+            "result": {
+                "transaction_log_ids": ["a"],
+                "transaction_log_map": {"a": {"transaction_log_id": "a", "status": "tx_status_pending"}},
+            },
+        });
+        assert_eq!(changed_transaction_logs(&mut seen, &response).len(), 1);
+        assert!(changed_transaction_logs(&mut seen, &response).is_empty());
+
+        let response = json!({
+            "result": {
+                "transaction_log_ids": ["a", "b"],
+                "transaction_log_map": {
+                    "a": {"transaction_log_id": "a", "status": "tx_status_succeeded"},
+                    "b": {"transaction_log_id": "b", "status": "tx_status_succeeded"},
+                },
+            },
+        });
+        let changed = changed_transaction_logs(&mut seen, &response);
+        assert_eq!(changed.len(), 2);
+        assert!(changed
+            .iter()
+            .all(|transaction_log| transaction_log["status"] == "tx_status_succeeded"));
+    }
+
+    #[test]
+    fn error_responses_are_ignored() {
+        let mut seen = HashMap::new();
+        let response = json!({"error": {"code": -32603, "message": "oops"}});
+        assert!(changed_transaction_logs(&mut seen, &response).is_empty());
+        assert!(seen.is_empty());
+    }
+}
@@ -0,0 +1,332 @@
+// Copyright (c) 2018-2022 MobileCoin Inc.
+
+//! An opt-in cache for responses to idempotent unencrypted requests. This allows the public side
+//! of the mirror to answer frequently repeated queries without a round-trip through the private
+//! side.
+
+use crate::{counters, utils::request_key};
+use serde_json::Value;
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// How long a cached response remains valid.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CacheTtl {
+    /// The response is immutable and can be cached indefinitely.
+    Forever,
+
+    /// The response expires after the given duration.
+    Expires(Duration),
+}
+
+impl FromStr for CacheTtl {
+    type Err = String;
+
+    fn from_str(src: &str) -> Result<Self, Self::Err> {
+        if src == "forever" {
+            return Ok(Self::Forever);
+        }
+
+        let secs =
+            u64::from_str(src).map_err(|err| format!("Invalid cache TTL \"{}\": {}", src, err))?;
+        Ok(Self::Expires(Duration::from_secs(secs)))
+    }
+}
+
+/// TTLs used for methods that were not explicitly configured.
+/// `get_block` only ever succeeds for blocks that are already part of the ledger, and these never
+/// change, so successful responses can be held indefinitely.
+pub const DEFAULT_CACHE_TTLS: &[(&str, CacheTtl)] = &[
+    ("get_block", CacheTtl::Forever),
+    (
+        "get_network_status",
+        CacheTtl::Expires(Duration::from_secs(5)),
+    ),
+    (
+        "get_wallet_status",
+        CacheTtl::Expires(Duration::from_secs(5)),
+    ),
+];
+
+/// Parse a `method=ttl` pair, where ttl is either a number of seconds or `forever`.
+pub fn parse_cache_ttl(src: &str) -> Result<(String, CacheTtl), String> {
+    let mut parts = src.splitn(2, '=');
+    let method = parts.next().unwrap_or("");
+    let ttl = parts
+        .next()
+        .ok_or_else(|| format!("Expected method=ttl, got \"{}\"", src))?;
+    if method.is_empty() {
+        return Err(format!("Missing method name in \"{}\"", src));
+    }
+    Ok((method.to_owned(), CacheTtl::from_str(ttl)?))
+}
+
+/// A single cached response.
+struct CacheEntry {
+    /// The cached JSON-RPC response.
+    response: Value,
+
+    /// When does this entry expire (None if it never does).
+    expires_at: Option<Instant>,
+
+    /// Insertion sequence number, used for evicting the oldest entry when the cache is full.
+    sequence: u64,
+}
+
+impl CacheEntry {
+    fn is_expired(&self, now: Instant) -> bool {
+        self.expires_at
+            .map(|expires_at| now >= expires_at)
+            .unwrap_or(false)
+    }
+}
+
+/// A TTL cache of JSON-RPC responses, keyed by the normalized method and params of the request.
+#[derive(Clone)]
+pub struct ResponseCache {
+    /// Map of method name -> TTL. Methods not in this map are never cached.
+    ttls: Arc<HashMap<String, CacheTtl>>,
+
+    /// Maximum number of entries held in the cache.
+    max_entries: usize,
+
+    /// Cached responses.
+    inner: Arc<Mutex<ResponseCacheInner>>,
+}
+
+struct ResponseCacheInner {
+    /// Map of cache key -> cached response.
+    entries: HashMap<String, CacheEntry>,
+
+    /// Sequence number to assign to the next inserted entry.
+    next_sequence: u64,
+}
+
+impl ResponseCache {
+    pub fn new(ttls: HashMap<String, CacheTtl>, max_entries: usize) -> Self {
+        Self {
+            ttls: Arc::new(ttls),
+            max_entries,
+            inner: Arc::new(Mutex::new(ResponseCacheInner {
+                entries: HashMap::new(),
+                next_sequence: 0,
+            })),
+        }
+    }
+
+    /// Look up a cached response for a JSON-RPC request made for a tenant. On a hit, the response
+    /// `id` is replaced with the one from the request.
+    pub fn get(&self, tenant: &str, json_request: &str) -> Option<String> {
+        let request: Value = serde_json::from_str(json_request).ok()?;
+        let (method, key) = self.cache_key(tenant, &request)?;
+
+        let mut inner = self.inner.lock().expect("mutex poisoned");
+        let now = Instant::now();
+        let cached = inner
+            .entries
+            .get(&key)
+            .map(|entry| (entry.is_expired(now), entry.response.clone()));
+        match cached {
+            Some((false, mut response)) => {
+                counters::OP_COUNTERS.inc("cache_hit");
+                counters::OP_COUNTERS.inc(&format!("cache_hit_{}", method));
+
+                response["id"] = request.get("id").cloned().unwrap_or(Value::Null);
+                Some(response.to_string())
+            }
+            Some((true, _)) => {
+                inner.entries.remove(&key);
+                counters::OP_COUNTERS.inc("cache_miss");
+                None
+            }
+            None => {
+                counters::OP_COUNTERS.inc("cache_miss");
+                None
+            }
+        }
+    }
+
+    /// Store the response to a JSON-RPC request made for a tenant, if the method is cacheable and
+    /// the response was successful.
+    pub fn insert(&self, tenant: &str, json_request: &str, json_response: &str) {
+        let request: Value = match serde_json::from_str(json_request) {
+            Ok(request) => request,
+            Err(_) => return,
+        };
+        let (method, key) = match self.cache_key(tenant, &request) {
+            Some(method_and_key) => method_and_key,
+            None => return,
+        };
+
+        // Only successful responses are cached - errors may be transient.
+        let response: Value = match serde_json::from_str(json_response) {
+            Ok(response) => response,
+            Err(_) => return,
+        };
+        if response.get("error").is_some() || response.get("result").is_none() {
+            return;
+        }
+
+        let now = Instant::now();
+        let expires_at = match self.ttls[&method] {
+            CacheTtl::Forever => None,
+            CacheTtl::Expires(ttl) => Some(now + ttl),
+        };
+
+        let mut inner = self.inner.lock().expect("mutex poisoned");
+        if inner.entries.len() >= self.max_entries && !inner.entries.contains_key(&key) {
+            inner.entries.retain(|_, entry| !entry.is_expired(now));
+        }
+        if inner.entries.len() >= self.max_entries && !inner.entries.contains_key(&key) {
+            let oldest_key = inner
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.sequence)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest_key) = oldest_key {
+                inner.entries.remove(&oldest_key);
+            }
+        }
+        if self.max_entries > 0 {
+            let sequence = inner.next_sequence;
+            inner.next_sequence += 1;
+            inner.entries.insert(
+                key,
+                CacheEntry {
+                    response,
+                    expires_at,
+                    sequence,
+                },
+            );
+        }
+    }
+
+    /// Returns the method name and cache key for a request, or None if the method is not
+    /// cacheable. Tenants are served by separate wallets, so they never share entries.
+    fn cache_key(&self, tenant: &str, request: &Value) -> Option<(String, String)> {
+        let (method, key) = request_key(request)?;
+        if !self.ttls.contains_key(&method) {
+            return None;
+        }
+        Some((method, format!("{}/{}", tenant, key)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_cache(ttl: CacheTtl) -> ResponseCache {
+        let mut ttls = HashMap::new();
+        ttls.insert("get_block".to_owned(), ttl);
+        ResponseCache::new(ttls, 2)
+    }
+
+    #[test]
+    fn cache_hit_ignores_id_and_key_order() {
+        let cache = test_cache(CacheTtl::Forever);
+
+        cache.insert(
+            "",
+            r#"{"method": "get_block", "params": {"block_index": "1", "x": 2}, "jsonrpc": "2.0", "id": 1}"#,
+            r#"{"result": {"block": "b"}, "jsonrpc": "2.0", "id": 1}"#,
+        );
+
+        let response = cache.get("", r#"{"id": 7, "jsonrpc": "2.0", "params": {"x": 2, "block_index": "1"}, "method": "get_block"}"#)
+            .unwrap();
+        let response: Value = serde_json::from_str(&response).unwrap();
+        assert_eq!(response["id"], 7);
+        assert_eq!(response["result"]["block"], "b");
+
+        assert!(cache.get("", r#"{"method": "get_block", "params": {"block_index": "2"}, "jsonrpc": "2.0", "id": 1}"#)
+            .is_none());
+    }
+
+    #[test]
+    fn errors_and_uncached_methods_are_not_stored() {
+        let cache = test_cache(CacheTtl::Forever);
+
+        let request = r#"{"method": "get_block", "params": {"block_index": "100"}, "jsonrpc": "2.0", "id": 1}"#;
+        cache.insert(
+            "",
+            request,
+            r#"{"error": {"code": -32603, "message": "not found"}, "jsonrpc": "2.0", "id": 1}"#,
+        );
+        assert!(cache.get("", request).is_none());
+
+        let request = r#"{"method": "get_account", "params": {}, "jsonrpc": "2.0", "id": 1}"#;
+        cache.insert("", request, r#"{"result": {}, "jsonrpc": "2.0", "id": 1}"#);
+        assert!(cache.get("", request).is_none());
+    }
+
+    #[test]
+    fn tenants_do_not_share_entries() {
+        let cache = test_cache(CacheTtl::Forever);
+
+        let request =
+            r#"{"method": "get_block", "params": {"block_index": "1"}, "jsonrpc": "2.0", "id": 1}"#;
+        cache.insert(
+            "acme",
+            request,
+            r#"{"result": {}, "jsonrpc": "2.0", "id": 1}"#,
+        );
+        assert!(cache.get("acme", request).is_some());
+        assert!(cache.get("globex", request).is_none());
+        assert!(cache.get("", request).is_none());
+    }
+
+    #[test]
+    fn expired_entries_are_not_returned() {
+        let cache = test_cache(CacheTtl::Expires(Duration::from_secs(0)));
+
+        let request =
+            r#"{"method": "get_block", "params": {"block_index": "1"}, "jsonrpc": "2.0", "id": 1}"#;
+        cache.insert("", request, r#"{"result": {}, "jsonrpc": "2.0", "id": 1}"#);
+        assert!(cache.get("", request).is_none());
+    }
+
+    #[test]
+    fn oldest_entry_is_evicted_when_full() {
+        let cache = test_cache(CacheTtl::Forever);
+
+        let request = |index: u64| {
+            format!(
+                r#"{{"method": "get_block", "params": {{"block_index": "{}"}}, "jsonrpc": "2.0", "id": 1}}"#,
+                index
+            )
+        };
+        for index in 0..3 {
+            cache.insert(
+                "",
+                &request(index),
+                r#"{"result": {}, "jsonrpc": "2.0", "id": 1}"#,
+            );
+        }
+
+        assert!(cache.get("", &request(0)).is_none());
+        assert!(cache.get("", &request(1)).is_some());
+        assert!(cache.get("", &request(2)).is_some());
+    }
+
+    #[test]
+    fn parse_cache_ttl_works() {
+        assert_eq!(
+            parse_cache_ttl("get_block=forever").unwrap(),
+            ("get_block".to_owned(), CacheTtl::Forever)
+        );
+        assert_eq!(
+            parse_cache_ttl("get_network_status=10").unwrap(),
+            (
+                "get_network_status".to_owned(),
+                CacheTtl::Expires(Duration::from_secs(10))
+            )
+        );
+        assert!(parse_cache_ttl("get_block").is_err());
+        assert!(parse_cache_ttl("=5").is_err());
+        assert!(parse_cache_ttl("get_block=soon").is_err());
+    }
+}
@@ -0,0 +1,9 @@
+// Copyright (c) 2018-2022 MobileCoin Inc.
+
+//! Metrics exposed by the public side of the mirror.
+
+use mc_util_metrics::OpMetrics;
+
+lazy_static::lazy_static! {
+    pub static ref OP_COUNTERS: OpMetrics = OpMetrics::new_and_registered("wallet_service_mirror_public");
+}
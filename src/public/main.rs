@@ -8,29 +8,46 @@
 
 #![feature(decl_macro)]
 
+mod cache;
+mod counters;
 mod mirror_service;
+mod notifications;
 mod query;
+mod tenants;
 mod utils;
+mod webhook;
 
+use cache::{parse_cache_ttl, CacheTtl, ResponseCache, DEFAULT_CACHE_TTLS};
 use grpcio::{ChannelBuilder, EnvBuilder, ServerBuilder};
 use mc_common::logger::{create_app_logger, log, o, Logger};
-use mc_util_grpc::{BuildInfoService, ConnectionUriGrpcioServer, HealthService};
-use mc_util_uri::{ConnectionUri, Uri, UriScheme};
+use mc_util_grpc::{
+    rpc_internal_error, AdminServer, BuildInfoService, ConnectionUriGrpcioServer, GetConfigJsonFn,
+    HealthCheckStatus, HealthService, ServiceHealthCheckCallback,
+};
+use mc_util_uri::{AdminUri, ConnectionUri, Uri, UriScheme};
 use mc_wallet_service_mirror::{
     uri::WalletServiceMirrorUri,
-    wallet_service_mirror_api::{EncryptedRequest, QueryRequest, UnencryptedRequest},
+    wallet_service_mirror_api::{
+        EncryptedRequest, QueryRequest, SessionHandshakeRequest, SessionRequest, UnencryptedRequest,
+    },
 };
 use mirror_service::MirrorService;
-use query::QueryManager;
+use notifications::{start_sse_server, NotificationHub};
+use query::{JobError, QueryManager};
 use rocket::{
     config::{Config as RocketConfig, Environment as RocketEnvironment},
+    get,
     http::Status,
     post,
-    response::Responder,
+    request::{FromRequest, Outcome},
+    response::{content, status, Responder},
     routes, Data, Request, Response,
 };
-use std::{io::Read, sync::Arc};
+use serde_json::json;
+use std::{collections::HashMap, io::Read, str::FromStr, sync::Arc, thread, time::Duration};
 use structopt::StructOpt;
+use tenants::{resolve_tenant, ApiKey, Tenants};
+use webhook::{HmacKey, WebhookSender};
 
 pub type ClientUri = Uri<ClientUriScheme>;
 
@@ -70,14 +87,90 @@ pub struct Config {
     /// Allow using self-signed TLS certificate for GRPC connections.
     #[structopt(long)]
     pub allow_self_signed_tls: bool,
+
+    /// Cache responses to idempotent unencrypted requests (`get_block`, `get_network_status`,
+    /// `get_wallet_status` by default) on the public side. Cached responses are served without a
+    /// response signature, so clients that require signatures reject them.
+    #[structopt(long)]
+    pub enable_response_cache: bool,
+
+    /// Cache TTL for a method, in the form `method=seconds` or `method=forever`.
+    /// May be specified multiple times, and overrides the default TTL for the given method.
+    #[structopt(long = "cache-ttl", parse(try_from_str = parse_cache_ttl))]
+    pub cache_ttls: Vec<(String, CacheTtl)>,
+
+    /// Maximum number of responses held in the response cache.
+    #[structopt(long, default_value = "10000")]
+    pub cache_max_entries: usize,
+
+    /// Coalesce identical unencrypted requests that are already waiting on the private side into a
+    /// single query, whose response is then shared by all of them.
+    #[structopt(long)]
+    pub coalesce_requests: bool,
+
+    /// Optional admin listening URI, exposing metrics and the health of the private instances.
+    #[structopt(long)]
+    pub admin_listen_uri: Option<AdminUri>,
+
+    /// How many seconds the results of requests submitted to `/jobs` are retained for after they
+    /// complete. Each tenant may have up to 1000 jobs waiting to be resolved.
+    #[structopt(long, default_value = "300", parse(try_from_str=parse_duration_in_seconds))]
+    pub job_retention: Duration,
+
+    /// URL prefix that job callbacks may be sent to. May be specified multiple times.
+    /// Callbacks are only enabled if `--callback-hmac-key` is also provided.
+    #[structopt(long = "callback-allowlist")]
+    pub callback_allowlist: Vec<String>,
+
+    /// File holding the hex-encoded key used to sign job callbacks with HMAC-SHA256.
+    #[structopt(long, parse(try_from_str=HmacKey::load))]
+    pub callback_hmac_key: Option<HmacKey>,
+
+    /// Optional listening URI for server-sent events subscriptions to new blocks and transaction
+    /// logs (HTTP). Requires the private side to be started with `--watch-interval`.
+    #[structopt(long)]
+    pub notifications_listen_uri: Option<ClientUri>,
+
+    /// How many seconds a private instance may go without polling before the queries it is
+    /// working on are handed to other private instances. Only unencrypted requests for read
+    /// methods are handed over; the others fail, since they may already have been processed.
+    #[structopt(long, default_value = "180", parse(try_from_str=parse_duration_in_seconds))]
+    pub instance_timeout: Duration,
+
+    /// Optional JSON file configuring the tenants requests can be made for, when fronting several
+    /// wallets. See `tenants.rs` for the format.
+    #[structopt(long, parse(try_from_str=Tenants::load))]
+    pub tenants: Option<Tenants>,
+
+    /// How many seconds may pass since a private instance last polled before the public side is
+    /// reported as not ready, by `/readyz` and the GRPC health service. Defaults to
+    /// `--instance-timeout`, since a private instance does not poll while it is waiting on
+    /// full-service.
+    #[structopt(long, parse(try_from_str=parse_duration_in_seconds))]
+    pub max_poll_age: Option<Duration>,
 }
 
 /// State that is accessible by all rocket requests
 struct State {
     query_manager: QueryManager,
+    response_cache: Option<ResponseCache>,
+    coalesce_requests: bool,
+    tenants: Option<Tenants>,
+    max_poll_age: Duration,
     logger: Logger,
 }
 
+impl State {
+    /// Determine the tenant a request was made for.
+    fn tenant(&self, path_tenant: Option<&str>, api_key: &ApiKey) -> Result<String, BadRequest> {
+        Ok(resolve_tenant(
+            self.tenants.as_ref(),
+            path_tenant,
+            api_key.0.as_deref(),
+        )?)
+    }
+}
+
 /// Sets the status of the response to 400 (Bad Request).
 #[derive(Debug, Clone, PartialEq)]
 pub struct BadRequest(pub String);
@@ -92,6 +185,7 @@ impl<'r> Responder<'r> for BadRequest {
         build.status(Status::BadRequest).ok()
     }
 }
+
 impl From<&str> for BadRequest {
     fn from(src: &str) -> Self {
         Self(src.to_owned())
@@ -103,33 +197,163 @@ impl From<String> for BadRequest {
     }
 }
 
+/// A job that was not accepted. Tenants with too many jobs waiting to be resolved get 429 Too Many
+/// Requests, anything else gets 400 Bad Request.
+pub enum JobRejected {
+    BadRequest(BadRequest),
+    TooManyJobs,
+}
+
+impl<'r> Responder<'r> for JobRejected {
+    fn respond_to(self, req: &Request) -> Result<Response<'r>, Status> {
+        match self {
+            JobRejected::BadRequest(bad_request) => bad_request.respond_to(req),
+            JobRejected::TooManyJobs => {
+                status::Custom(Status::TooManyRequests, "Too many outstanding jobs").respond_to(req)
+            }
+        }
+    }
+}
+
+impl From<BadRequest> for JobRejected {
+    fn from(src: BadRequest) -> Self {
+        Self::BadRequest(src)
+    }
+}
+impl From<String> for JobRejected {
+    fn from(src: String) -> Self {
+        Self::BadRequest(src.into())
+    }
+}
+impl From<JobError> for JobRejected {
+    fn from(src: JobError) -> Self {
+        match src {
+            JobError::Invalid(err) => Self::BadRequest(err.into()),
+            JobError::TooManyJobs => Self::TooManyJobs,
+        }
+    }
+}
+
+/// Header holding the id of the query a signed response was returned for.
+const QUERY_ID_HEADER: &str = "X-Mirror-Query-Id";
+
+/// Header holding the hex-encoded signature of the private side over a response.
+const RESPONSE_SIGNATURE_HEADER: &str = "X-Mirror-Response-Signature";
+
+/// A response body, along with the signature the private side of the mirror made over it (empty
+/// if response signing is not enabled, or the body is not the one that was signed).
+pub struct SignedResponse<R> {
+    body: R,
+    query_id: String,
+    signature: Vec<u8>,
+}
+
+/// Adds the query id and signature headers to the response, if it was signed.
+impl<'r, R: Responder<'r>> Responder<'r> for SignedResponse<R> {
+    fn respond_to(self, req: &Request) -> Result<Response<'r>, Status> {
+        let mut build = Response::build_from(self.body.respond_to(req)?);
+        if !self.signature.is_empty() {
+            build.raw_header(QUERY_ID_HEADER, self.query_id);
+            build.raw_header(RESPONSE_SIGNATURE_HEADER, hex::encode(self.signature));
+        }
+        build.ok()
+    }
+}
+
+/// Header naming the fingerprint of the mirror key an encrypted request was encrypted with.
+const KEY_FINGERPRINT_HEADER: &str = "X-Mirror-Key-Fingerprint";
+
+/// The fingerprint of the mirror key passed with an encrypted request, if any.
+pub struct KeyFingerprint(pub Option<String>);
+
+impl<'a, 'r> FromRequest<'a, 'r> for KeyFingerprint {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(KeyFingerprint(
+            request
+                .headers()
+                .get_one(KEY_FINGERPRINT_HEADER)
+                .map(str::to_owned),
+        ))
+    }
+}
+
 #[post("/unencrypted-request", format = "json", data = "<request_data>")]
 fn unencrypted_request(
     state: rocket::State<State>,
-    request_data: rocket::Data,
-) -> Result<String, BadRequest> {
+    api_key: ApiKey,
+    request_data: Data,
+) -> Result<SignedResponse<String>, BadRequest> {
+    let tenant = state.tenant(None, &api_key)?;
+    handle_unencrypted_request(&state, tenant, request_data)
+}
+
+#[post(
+    "/t/<tenant>/unencrypted-request",
+    format = "json",
+    data = "<request_data>"
+)]
+fn tenant_unencrypted_request(
+    state: rocket::State<State>,
+    tenant: String,
+    api_key: ApiKey,
+    request_data: Data,
+) -> Result<SignedResponse<String>, BadRequest> {
+    let tenant = state.tenant(Some(&tenant), &api_key)?;
+    handle_unencrypted_request(&state, tenant, request_data)
+}
+
+fn handle_unencrypted_request(
+    state: &State,
+    tenant: String,
+    request_data: Data,
+) -> Result<SignedResponse<String>, BadRequest> {
+    let logger = state.logger.new(o!("tenant" => tenant.clone()));
+
     let mut request = String::new();
     let res = request_data.open().read_to_string(&mut request);
     if res.is_err() {
         let msg = "Could not read request data for unencrypted request.";
-        log::error!(state.logger, "{}", msg,);
+        log::error!(logger, "{}", msg,);
         return Err(msg.into());
     }
 
-    log::debug!(state.logger, "Enqueueing UnencryptedRequest({})", &request);
+    if let Some(json_response) = state
+        .response_cache
+        .as_ref()
+        .and_then(|cache| cache.get(&tenant, &request))
+    {
+        // Cached responses are not signed, since the query they were signed for is gone.
+        log::debug!(logger, "UnencryptedRequest({}) served from cache", &request);
+        return Ok(SignedResponse {
+            body: json_response,
+            query_id: String::new(),
+            signature: Vec::new(),
+        });
+    }
+
+    log::debug!(logger, "Enqueueing UnencryptedRequest({})", &request);
 
     let mut unencrypted_request = UnencryptedRequest::new();
     unencrypted_request.set_json_request(request.clone());
 
     let mut query_request = QueryRequest::new();
     query_request.set_unencrypted_request(unencrypted_request);
+    query_request.set_tenant(tenant.clone());
 
     let query = state.query_manager.enqueue_query(query_request);
+    let query_id = query.query_id().to_owned();
+    let signed_request = query
+        .request()
+        .get_unencrypted_request()
+        .get_json_request()
+        .to_owned();
     let query_response = query.wait()?;
 
     if query_response.has_error() {
         log::error!(
-            state.logger,
+            logger,
             "UnencryptedRequest({}) failed: {}",
             request,
             query_response.get_error()
@@ -138,7 +362,7 @@ fn unencrypted_request(
     }
     if !query_response.has_unencrypted_response() {
         log::error!(
-            state.logger,
+            logger,
             "UnencryptedRequest({}) returned incorrect response type",
             request,
         );
@@ -146,13 +370,34 @@ fn unencrypted_request(
     }
 
     log::info!(
-        state.logger,
+        logger,
         "UnencryptedRequest({}) completed successfully",
         request,
     );
 
     let response = query_response.get_unencrypted_response();
-    Ok(response.get_json_response().to_string())
+    if let Some(cache) = state.response_cache.as_ref() {
+        cache.insert(&tenant, &request, response.get_json_response());
+    }
+
+    // A coalesced query carries the JSON-RPC id of whichever request created it. The signature
+    // only holds for the request that created the query and the body the private side returned, so
+    // it is dropped if the request differs or the id is rewritten.
+    let body = if state.coalesce_requests {
+        utils::with_request_id(&request, response.get_json_response())
+    } else {
+        response.get_json_response().to_string()
+    };
+    let signature = if signed_request == request && body == response.get_json_response() {
+        response.get_signature().to_vec()
+    } else {
+        Vec::new()
+    };
+    Ok(SignedResponse {
+        body,
+        query_id,
+        signature,
+    })
 }
 
 #[post(
@@ -160,58 +405,419 @@ fn unencrypted_request(
     format = "application/octet-stream",
     data = "<data>"
 )]
-fn encrypted_request(state: rocket::State<State>, data: Data) -> Result<Vec<u8>, BadRequest> {
-    let mut payload = Vec::new();
-    if let Err(err) = data.open().read_to_end(&mut payload) {
-        let msg = format!(
-            "Could not read request data for unencrypted request: {}",
-            err
-        );
-        log::error!(state.logger, "{}", msg);
-        return Err(msg.into());
-    }
-    let payload_len = payload.len();
+fn encrypted_request(
+    state: rocket::State<State>,
+    api_key: ApiKey,
+    key_fingerprint: KeyFingerprint,
+    data: Data,
+) -> Result<SignedResponse<Vec<u8>>, BadRequest> {
+    let tenant = state.tenant(None, &api_key)?;
+    handle_encrypted_request(&state, tenant, key_fingerprint, data)
+}
+
+#[post(
+    "/t/<tenant>/encrypted-request",
+    format = "application/octet-stream",
+    data = "<data>"
+)]
+fn tenant_encrypted_request(
+    state: rocket::State<State>,
+    tenant: String,
+    api_key: ApiKey,
+    key_fingerprint: KeyFingerprint,
+    data: Data,
+) -> Result<SignedResponse<Vec<u8>>, BadRequest> {
+    let tenant = state.tenant(Some(&tenant), &api_key)?;
+    handle_encrypted_request(&state, tenant, key_fingerprint, data)
+}
+
+fn handle_encrypted_request(
+    state: &State,
+    tenant: String,
+    key_fingerprint: KeyFingerprint,
+    data: Data,
+) -> Result<SignedResponse<Vec<u8>>, BadRequest> {
+    let logger = state.logger.new(o!("tenant" => tenant.clone()));
+
+    let payload = read_payload(data, &logger)?;
+    let description = format!("EncryptedRequest({} bytes)", payload.len());
 
     let mut encrypted_request = EncryptedRequest::new();
     encrypted_request.set_payload(payload);
+    encrypted_request.set_key_fingerprint(key_fingerprint.0.unwrap_or_default());
 
     let mut query_request = QueryRequest::new();
     query_request.set_encrypted_request(encrypted_request);
+    query_request.set_tenant(tenant);
 
-    log::debug!(
-        state.logger,
-        "Enqueueing EncryptedRequest({} bytes)",
-        payload_len,
+    forward_encrypted_query(state, query_request, &description, &logger)
+}
+
+#[post(
+    "/session-handshake",
+    format = "application/octet-stream",
+    data = "<data>"
+)]
+fn session_handshake(
+    state: rocket::State<State>,
+    api_key: ApiKey,
+    key_fingerprint: KeyFingerprint,
+    data: Data,
+) -> Result<content::Json<String>, BadRequest> {
+    let tenant = state.tenant(None, &api_key)?;
+    handle_session_handshake(&state, tenant, key_fingerprint, data)
+}
+
+#[post(
+    "/t/<tenant>/session-handshake",
+    format = "application/octet-stream",
+    data = "<data>"
+)]
+fn tenant_session_handshake(
+    state: rocket::State<State>,
+    tenant: String,
+    api_key: ApiKey,
+    key_fingerprint: KeyFingerprint,
+    data: Data,
+) -> Result<content::Json<String>, BadRequest> {
+    let tenant = state.tenant(Some(&tenant), &api_key)?;
+    handle_session_handshake(&state, tenant, key_fingerprint, data)
+}
+
+fn handle_session_handshake(
+    state: &State,
+    tenant: String,
+    key_fingerprint: KeyFingerprint,
+    data: Data,
+) -> Result<content::Json<String>, BadRequest> {
+    let logger = state.logger.new(o!("tenant" => tenant.clone()));
+
+    let mut handshake_request = SessionHandshakeRequest::new();
+    handshake_request.set_payload(read_payload(data, &logger)?);
+    handshake_request.set_key_fingerprint(key_fingerprint.0.unwrap_or_default());
+
+    let mut query_request = QueryRequest::new();
+    query_request.set_session_handshake_request(handshake_request);
+    query_request.set_tenant(tenant);
+
+    log::debug!(logger, "Enqueueing SessionHandshakeRequest");
+    let query_response = state.query_manager.enqueue_query(query_request).wait()?;
+
+    if query_response.has_error() {
+        log::error!(
+            logger,
+            "SessionHandshakeRequest failed: {}",
+            query_response.get_error()
+        );
+        return Err(query_response.get_error().into());
+    }
+    if !query_response.has_session_handshake_response() {
+        log::error!(
+            logger,
+            "SessionHandshakeRequest returned incorrect response type"
+        );
+        return Err("Incorrect response type received".into());
+    }
+
+    let response = query_response.get_session_handshake_response();
+    log::info!(
+        logger,
+        "SessionHandshakeRequest established session {}",
+        response.get_session_id()
     );
+
+    Ok(content::Json(
+        json!({
+            "session_id": response.get_session_id(),
+            "ephemeral_public_key": hex::encode(response.get_ephemeral_public_key()),
+            "key_confirmation": hex::encode(response.get_key_confirmation()),
+        })
+        .to_string(),
+    ))
+}
+
+#[post(
+    "/sessions/<session_id>",
+    format = "application/octet-stream",
+    data = "<data>"
+)]
+fn session_request(
+    state: rocket::State<State>,
+    session_id: String,
+    api_key: ApiKey,
+    data: Data,
+) -> Result<SignedResponse<Vec<u8>>, BadRequest> {
+    let tenant = state.tenant(None, &api_key)?;
+    handle_session_request(&state, tenant, session_id, data)
+}
+
+#[post(
+    "/t/<tenant>/sessions/<session_id>",
+    format = "application/octet-stream",
+    data = "<data>"
+)]
+fn tenant_session_request(
+    state: rocket::State<State>,
+    tenant: String,
+    session_id: String,
+    api_key: ApiKey,
+    data: Data,
+) -> Result<SignedResponse<Vec<u8>>, BadRequest> {
+    let tenant = state.tenant(Some(&tenant), &api_key)?;
+    handle_session_request(&state, tenant, session_id, data)
+}
+
+fn handle_session_request(
+    state: &State,
+    tenant: String,
+    session_id: String,
+    data: Data,
+) -> Result<SignedResponse<Vec<u8>>, BadRequest> {
+    let logger = state.logger.new(o!(
+        "tenant" => tenant.clone(),
+        "session_id" => session_id.clone(),
+    ));
+
+    let payload = read_payload(data, &logger)?;
+    let description = format!("SessionRequest({} bytes)", payload.len());
+
+    let mut session_request = SessionRequest::new();
+    session_request.set_session_id(session_id);
+    session_request.set_payload(payload);
+
+    let mut query_request = QueryRequest::new();
+    query_request.set_session_request(session_request);
+    query_request.set_tenant(tenant);
+
+    forward_encrypted_query(state, query_request, &description, &logger)
+}
+
+/// Read the body of a request holding binary data.
+fn read_payload(data: Data, logger: &Logger) -> Result<Vec<u8>, BadRequest> {
+    let mut payload = Vec::new();
+    if let Err(err) = data.open().read_to_end(&mut payload) {
+        let msg = format!("Could not read request data: {}", err);
+        log::error!(logger, "{}", msg);
+        return Err(msg.into());
+    }
+    Ok(payload)
+}
+
+/// Enqueue a query that is answered with an encrypted response, and wait for the response.
+fn forward_encrypted_query(
+    state: &State,
+    query_request: QueryRequest,
+    description: &str,
+    logger: &Logger,
+) -> Result<SignedResponse<Vec<u8>>, BadRequest> {
+    log::debug!(logger, "Enqueueing {}", description);
     let query = state.query_manager.enqueue_query(query_request);
+    let query_id = query.query_id().to_owned();
     let query_response = query.wait()?;
 
     if query_response.has_error() {
         log::error!(
-            state.logger,
-            "EncryptedRequest({} bytes) failed: {}",
-            payload_len,
+            logger,
+            "{} failed: {}",
+            description,
             query_response.get_error()
         );
         return Err(query_response.get_error().into());
     }
     if !query_response.has_encrypted_response() {
-        log::error!(
-            state.logger,
-            "EncryptedRequest({} bytes) returned incorrect response type",
-            payload_len,
-        );
+        log::error!(logger, "{} returned incorrect response type", description);
         return Err("Incorrect response type received".into());
     }
 
-    log::info!(
-        state.logger,
-        "EncryptedRequest({} bytes) completed successfully",
+    log::info!(logger, "{} completed successfully", description);
+
+    let response = query_response.get_encrypted_response();
+    Ok(SignedResponse {
+        body: response.get_payload().to_vec(),
+        query_id,
+        signature: response.get_signature().to_vec(),
+    })
+}
+
+#[post("/jobs?<callback>", format = "json", data = "<request_data>")]
+fn unencrypted_job(
+    state: rocket::State<State>,
+    api_key: ApiKey,
+    request_data: Data,
+    callback: Option<String>,
+) -> Result<content::Json<String>, JobRejected> {
+    let tenant = state.tenant(None, &api_key)?;
+    handle_unencrypted_job(&state, tenant, request_data, callback)
+}
+
+#[post(
+    "/t/<tenant>/jobs?<callback>",
+    format = "json",
+    data = "<request_data>"
+)]
+fn tenant_unencrypted_job(
+    state: rocket::State<State>,
+    tenant: String,
+    api_key: ApiKey,
+    request_data: Data,
+    callback: Option<String>,
+) -> Result<content::Json<String>, JobRejected> {
+    let tenant = state.tenant(Some(&tenant), &api_key)?;
+    handle_unencrypted_job(&state, tenant, request_data, callback)
+}
+
+fn handle_unencrypted_job(
+    state: &State,
+    tenant: String,
+    request_data: Data,
+    callback: Option<String>,
+) -> Result<content::Json<String>, JobRejected> {
+    let logger = state.logger.new(o!("tenant" => tenant.clone()));
+
+    let mut request = String::new();
+    if let Err(err) = request_data.open().read_to_string(&mut request) {
+        let msg = format!("Could not read request data for unencrypted job: {}", err);
+        log::error!(logger, "{}", msg);
+        return Err(msg.into());
+    }
+
+    let mut unencrypted_request = UnencryptedRequest::new();
+    unencrypted_request.set_json_request(request.clone());
+
+    let mut query_request = QueryRequest::new();
+    query_request.set_unencrypted_request(unencrypted_request);
+    query_request.set_tenant(tenant);
+
+    let query_id = state.query_manager.enqueue_job(query_request, callback)?;
+    log::debug!(
+        logger,
+        "Enqueued UnencryptedRequest({}) as job {}",
+        request,
+        query_id
+    );
+    Ok(content::Json(json!({ "job_id": query_id }).to_string()))
+}
+
+#[post(
+    "/jobs?<callback>",
+    format = "application/octet-stream",
+    data = "<data>",
+    rank = 2
+)]
+fn encrypted_job(
+    state: rocket::State<State>,
+    api_key: ApiKey,
+    key_fingerprint: KeyFingerprint,
+    data: Data,
+    callback: Option<String>,
+) -> Result<content::Json<String>, JobRejected> {
+    let tenant = state.tenant(None, &api_key)?;
+    handle_encrypted_job(&state, tenant, key_fingerprint, data, callback)
+}
+
+#[post(
+    "/t/<tenant>/jobs?<callback>",
+    format = "application/octet-stream",
+    data = "<data>",
+    rank = 2
+)]
+fn tenant_encrypted_job(
+    state: rocket::State<State>,
+    tenant: String,
+    api_key: ApiKey,
+    key_fingerprint: KeyFingerprint,
+    data: Data,
+    callback: Option<String>,
+) -> Result<content::Json<String>, JobRejected> {
+    let tenant = state.tenant(Some(&tenant), &api_key)?;
+    handle_encrypted_job(&state, tenant, key_fingerprint, data, callback)
+}
+
+fn handle_encrypted_job(
+    state: &State,
+    tenant: String,
+    key_fingerprint: KeyFingerprint,
+    data: Data,
+    callback: Option<String>,
+) -> Result<content::Json<String>, JobRejected> {
+    let logger = state.logger.new(o!("tenant" => tenant.clone()));
+
+    let mut payload = Vec::new();
+    if let Err(err) = data.open().read_to_end(&mut payload) {
+        let msg = format!("Could not read request data for encrypted job: {}", err);
+        log::error!(logger, "{}", msg);
+        return Err(msg.into());
+    }
+    let payload_len = payload.len();
+
+    let mut encrypted_request = EncryptedRequest::new();
+    encrypted_request.set_payload(payload);
+    encrypted_request.set_key_fingerprint(key_fingerprint.0.unwrap_or_default());
+
+    let mut query_request = QueryRequest::new();
+    query_request.set_encrypted_request(encrypted_request);
+    query_request.set_tenant(tenant);
+
+    let query_id = state.query_manager.enqueue_job(query_request, callback)?;
+    log::debug!(
+        logger,
+        "Enqueued EncryptedRequest({} bytes) as job {}",
         payload_len,
+        query_id
     );
+    Ok(content::Json(json!({ "job_id": query_id }).to_string()))
+}
 
-    let response = query_response.get_encrypted_response();
-    Ok(response.get_payload().to_vec())
+/// Returns the status of a job.
+#[get("/jobs/<query_id>")]
+fn get_job(
+    state: rocket::State<State>,
+    api_key: ApiKey,
+    query_id: String,
+) -> Result<Option<content::Json<String>>, BadRequest> {
+    let tenant = state.tenant(None, &api_key)?;
+    Ok(job_status(&state, &tenant, &query_id))
+}
+
+/// Returns the status of a job made for a tenant.
+#[get("/t/<tenant>/jobs/<query_id>")]
+fn tenant_get_job(
+    state: rocket::State<State>,
+    tenant: String,
+    api_key: ApiKey,
+    query_id: String,
+) -> Result<Option<content::Json<String>>, BadRequest> {
+    let tenant = state.tenant(Some(&tenant), &api_key)?;
+    Ok(job_status(&state, &tenant, &query_id))
+}
+
+fn job_status(state: &State, tenant: &str, query_id: &str) -> Option<content::Json<String>> {
+    let status = state.query_manager.get_job(tenant, query_id)?;
+    Some(content::Json(status.to_json(query_id).to_string()))
+}
+
+/// Liveness check, succeeding as long as the public side is up.
+#[get("/healthz")]
+fn healthz() -> &'static str {
+    "ok"
+}
+
+/// Readiness check, succeeding only if a private instance polled recently enough for requests to
+/// be served.
+#[get("/readyz")]
+fn readyz(state: rocket::State<State>) -> status::Custom<content::Json<String>> {
+    let time_since_last_poll = state.query_manager.time_since_last_poll();
+    let ready = is_ready(time_since_last_poll, state.max_poll_age);
+    let body = json!({
+        "ready": ready,
+        "secs_since_last_poll": time_since_last_poll.map(|elapsed| elapsed.as_secs()),
+    });
+    let status = if ready {
+        Status::Ok
+    } else {
+        Status::ServiceUnavailable
+    };
+    status::Custom(status, content::Json(body.to_string()))
 }
 
 fn main() {
@@ -234,14 +840,91 @@ fn main() {
     );
 
     // Common state.
-    let query_manager = QueryManager::default();
+    let webhook_sender = config.callback_hmac_key.clone().map(|hmac_key| {
+        log::info!(
+            logger,
+            "Job callbacks enabled for {:?}",
+            config.callback_allowlist
+        );
+        WebhookSender::new(config.callback_allowlist.clone(), hmac_key, logger.clone())
+    });
+    let query_manager = QueryManager::new(
+        config.coalesce_requests,
+        config.job_retention,
+        webhook_sender,
+        config.instance_timeout,
+    );
+    let max_poll_age = config.max_poll_age.unwrap_or(config.instance_timeout);
+
+    // Jobs that time out get a callback even if no private instance polls us.
+    if config.callback_hmac_key.is_some() {
+        let query_manager = query_manager.clone();
+        thread::Builder::new()
+            .name("job-timeouts".to_owned())
+            .spawn(move || loop {
+                thread::sleep(Duration::from_secs(1));
+                query_manager.time_out_jobs();
+            })
+            .expect("Failed spawning job timeout thread");
+    }
+
+    let response_cache = if config.enable_response_cache {
+        let mut ttls: HashMap<String, CacheTtl> = DEFAULT_CACHE_TTLS
+            .iter()
+            .map(|(method, ttl)| (method.to_string(), *ttl))
+            .collect();
+        ttls.extend(config.cache_ttls.iter().cloned());
+        log::info!(logger, "Response cache enabled: {:?}", ttls);
+        Some(ResponseCache::new(ttls, config.cache_max_entries))
+    } else {
+        None
+    };
+
+    // Start the admin server, if requested. The health of the private instances polling us is
+    // reported in its config JSON, rather than to clients.
+    let _admin_server = config.admin_listen_uri.as_ref().map(|admin_listen_uri| {
+        let get_config_json: GetConfigJsonFn = {
+            let query_manager = query_manager.clone();
+            let logger = logger.clone();
+            Arc::new(move || {
+                serde_json::to_string(&json!({ "backends": query_manager.instances() }))
+                    .map_err(|err| rpc_internal_error("get_config_json", err, &logger))
+            })
+        };
+        AdminServer::start(
+            None,
+            admin_listen_uri,
+            "Wallet Service Mirror Public".to_owned(),
+            config.mirror_listen_uri.to_string(),
+            Some(get_config_json),
+            logger.clone(),
+        )
+        .expect("Failed starting admin server")
+    });
 
     // Start the mirror-facing GRPC server.
     log::info!(logger, "Starting mirror GRPC server");
 
     let build_info_service = BuildInfoService::new(logger.clone()).into_service();
-    let health_service = HealthService::new(None, logger.clone()).into_service();
-    let mirror_service = MirrorService::new(query_manager.clone(), logger.clone()).into_service();
+    let health_check_callback: ServiceHealthCheckCallback = {
+        let query_manager = query_manager.clone();
+        Arc::new(move |_service| {
+            if is_ready(query_manager.time_since_last_poll(), max_poll_age) {
+                HealthCheckStatus::SERVING
+            } else {
+                HealthCheckStatus::NOT_SERVING
+            }
+        })
+    };
+    let health_service =
+        HealthService::new(Some(health_check_callback), logger.clone()).into_service();
+    let notification_hub = NotificationHub::default();
+    let mirror_service = MirrorService::new(
+        query_manager.clone(),
+        notification_hub.clone(),
+        logger.clone(),
+    )
+    .into_service();
 
     let env = Arc::new(
         EnvBuilder::new()
@@ -263,6 +946,25 @@ fn main() {
     let mut server = server_builder.build().unwrap();
     server.start();
 
+    // Start the notifications server, if requested.
+    if let Some(notifications_listen_uri) = config.notifications_listen_uri.as_ref() {
+        if notifications_listen_uri.use_tls() {
+            panic!("Notifications listening using TLS is currently not supported.");
+        }
+        log::info!(
+            logger,
+            "Starting notifications server on {}",
+            notifications_listen_uri.addr()
+        );
+        start_sse_server(
+            notifications_listen_uri,
+            notification_hub,
+            config.tenants.clone(),
+            logger.clone(),
+        )
+        .expect("Failed starting notifications server");
+    }
+
     // Start the client-facing webserver.
     if config.client_listen_uri.use_tls() {
         panic!("Client-listening using TLS is currently not supported due to `ring` crate version compatibility issues.");
@@ -294,10 +996,43 @@ fn main() {
 
     log::info!(logger, "Starting client web server");
     rocket::custom(rocket_config)
-        .mount("/", routes![unencrypted_request, encrypted_request])
+        .mount(
+            "/",
+            routes![
+                unencrypted_request,
+                tenant_unencrypted_request,
+                encrypted_request,
+                tenant_encrypted_request,
+                session_handshake,
+                tenant_session_handshake,
+                session_request,
+                tenant_session_request,
+                unencrypted_job,
+                tenant_unencrypted_job,
+                encrypted_job,
+                tenant_encrypted_job,
+                get_job,
+                tenant_get_job,
+                healthz,
+                readyz
+            ],
+        )
         .manage(State {
             query_manager,
+            response_cache,
+            coalesce_requests: config.coalesce_requests,
+            tenants: config.tenants,
+            max_poll_age,
             logger,
         })
         .launch();
 }
+
+/// The public side is ready if a private instance polled within the maximum poll age.
+fn is_ready(time_since_last_poll: Option<Duration>, max_poll_age: Duration) -> bool {
+    time_since_last_poll.map_or(false, |elapsed| elapsed < max_poll_age)
+}
+
+fn parse_duration_in_seconds(src: &str) -> Result<Duration, std::num::ParseIntError> {
+    Ok(Duration::from_secs(u64::from_str(src)?))
+}
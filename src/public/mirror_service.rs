@@ -1,6 +1,6 @@
-use crate::query::QueryManager;
+use crate::{notifications::NotificationHub, query::QueryManager};
 use grpcio::{RpcContext, RpcStatus, Service, UnarySink};
-use mc_common::logger::{log, Logger};
+use mc_common::logger::{log, o, Logger};
 use mc_util_grpc::{rpc_logger, send_result};
 use mc_wallet_service_mirror::{
     wallet_service_mirror_api::{PollRequest, PollResponse},
@@ -12,14 +12,22 @@ pub struct MirrorService {
     /// Query manager.
     query_manager: QueryManager,
 
+    /// Notification subscribers.
+    notification_hub: NotificationHub,
+
     /// Logger.
     logger: Logger,
 }
 
 impl MirrorService {
-    pub fn new(query_manager: QueryManager, logger: Logger) -> Self {
+    pub fn new(
+        query_manager: QueryManager,
+        notification_hub: NotificationHub,
+        logger: Logger,
+    ) -> Self {
         Self {
             query_manager,
+            notification_hub,
             logger,
         }
     }
@@ -29,16 +37,30 @@ impl MirrorService {
     }
 
     fn poll_impl(&self, request: PollRequest, logger: &Logger) -> Result<PollResponse, RpcStatus> {
+        let instance_id = request.get_instance_id();
+        let tenants = request.get_tenants();
+        let logger = &logger.new(o!("instance_id" => instance_id.to_owned()));
+
         // Go over any responses we may have received and attempt to resolve them.
         for (query_id, query_response) in request.get_query_responses().iter() {
-            match self.query_manager.resolve_query(query_id, query_response) {
+            match self
+                .query_manager
+                .resolve_query(instance_id, tenants, query_id, query_response)
+            {
                 Ok(()) => log::info!(logger, "Query {} resolved", query_id),
                 Err(err) => log::error!(logger, "Query {} failed resolving: {}", query_id, err),
             }
         }
 
+        // Push notifications to subscribed clients.
+        for notification in request.get_notifications() {
+            self.notification_hub.publish(tenants, notification);
+        }
+
         // Return any queries we have received.
-        let pending_requests = self.query_manager.get_pending_requests();
+        let pending_requests = self
+            .query_manager
+            .get_pending_requests(instance_id, tenants);
 
         log::debug!(
             logger,
@@ -49,6 +71,8 @@ impl MirrorService {
 
         let mut response = PollResponse::new();
         response.set_query_requests(pending_requests);
+        response
+            .set_account_subscriptions(self.notification_hub.account_subscriptions(tenants).into());
         Ok(response)
     }
 }
@@ -0,0 +1,547 @@
+// Copyright (c) 2018-2022 MobileCoin Inc.
+
+//! Server-sent events (SSE) subscriptions to the notifications pushed by the private side of the
+//! mirror.
+//!
+//! Clients connect to `GET /subscribe` to receive notifications about new blocks, or to
+//! `GET /subscribe?account_id=<account id>` to also receive notifications about the transaction
+//! logs of an account. Subscriptions are made for a tenant just like requests (see `tenants.rs`),
+//! either with a `/t/<tenant>` path prefix or with an API key in the `X-Api-Key` header, and are
+//! rejected with 403 if the tenant cannot be resolved. Subscribers only receive the notifications
+//! of private instances that serve their tenant.
+//!
+//! If the private side only accepts encrypted requests, notifications about an account are only
+//! sent to subscribers that also pass the id of a session they established with the private side
+//! (`&session_id=<session id>`), encrypted with the keys of that session. Each notification is sent
+//! as a single SSE `data:` line, holding either the JSON event or
+//! `{"account_id": ..., "session_id": ..., "payload": <hex-encoded encrypted event>}`.
+//!
+//! This is served on a dedicated listener rather than through rocket, since every subscription
+//! holds its connection open indefinitely.
+
+use crate::{
+    counters,
+    query::serves_tenant,
+    tenants::{resolve_tenant, Tenants, API_KEY_HEADER},
+    ClientUri,
+};
+use mc_common::logger::{log, Logger};
+use mc_util_uri::ConnectionUri;
+use mc_wallet_service_mirror::{
+    http_request::{read_request_head, reject_connection, ConnectionLimit},
+    wallet_service_mirror_api::{AccountSubscription, Notification, Notification_oneof_event},
+};
+use serde_json::json;
+use std::{
+    collections::HashMap,
+    io::Write,
+    net::{TcpListener, TcpStream},
+    sync::{
+        mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+/// The maximum number of concurrent subscriptions.
+const MAX_SUBSCRIBERS: usize = 1000;
+
+/// The maximum number of connections handled at the same time, including the ones that have not
+/// subscribed yet. Connections over the limit are rejected without spawning a thread for them.
+const MAX_CONNECTIONS: usize = MAX_SUBSCRIBERS + 100;
+
+/// The number of notifications buffered for a subscriber before new ones are dropped.
+const SUBSCRIBER_BUFFER_LEN: usize = 100;
+
+/// How often a comment is sent to idle subscribers, so that closed connections are noticed.
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+struct Subscriber {
+    /// The tenant the subscription was made for.
+    tenant: String,
+
+    /// The account whose notifications the subscriber receives, in addition to the ones that
+    /// concern every subscriber.
+    account_id: Option<String>,
+
+    /// The session whose encrypted notifications about the account the subscriber receives.
+    session_id: Option<String>,
+
+    sender: SyncSender<String>,
+}
+
+struct NotificationHubInner {
+    next_subscriber_id: u64,
+    subscribers: HashMap<u64, Subscriber>,
+}
+
+/// Routes notifications received from the private side of the mirror to subscribers.
+#[derive(Clone)]
+pub struct NotificationHub {
+    inner: Arc<Mutex<NotificationHubInner>>,
+}
+
+/// A subscription to notifications. Unsubscribes when dropped.
+pub struct Subscription {
+    id: u64,
+    hub: NotificationHub,
+    receiver: Receiver<String>,
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        let mut inner = self.hub.inner.lock().expect("mutex poisoned");
+        inner.subscribers.remove(&self.id);
+    }
+}
+
+impl Default for NotificationHub {
+    fn default() -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(NotificationHubInner {
+                next_subscriber_id: 0,
+                subscribers: HashMap::new(),
+            })),
+        }
+    }
+}
+
+impl NotificationHub {
+    /// Subscribe to the notifications of a tenant, optionally including the ones about a specific
+    /// account (that are encrypted for a specific session). Returns None if there are too many
+    /// subscribers.
+    pub fn subscribe(
+        &self,
+        tenant: String,
+        account_id: Option<String>,
+        session_id: Option<String>,
+    ) -> Option<Subscription> {
+        let mut inner = self.inner.lock().expect("mutex poisoned");
+        if inner.subscribers.len() >= MAX_SUBSCRIBERS {
+            return None;
+        }
+
+        let id = inner.next_subscriber_id;
+        inner.next_subscriber_id += 1;
+
+        let (sender, receiver) = mpsc::sync_channel(SUBSCRIBER_BUFFER_LEN);
+        inner.subscribers.insert(
+            id,
+            Subscriber {
+                tenant,
+                account_id,
+                session_id,
+                sender,
+            },
+        );
+
+        Some(Subscription {
+            id,
+            hub: self.clone(),
+            receiver,
+        })
+    }
+
+    /// Returns the accounts (and sessions) that have subscribers among the given tenants, as
+    /// registered by a polling private instance.
+    pub fn account_subscriptions(&self, tenants: &[String]) -> Vec<AccountSubscription> {
+        let inner = self.inner.lock().expect("mutex poisoned");
+        let mut subscriptions: Vec<(String, String)> = inner
+            .subscribers
+            .values()
+            .filter(|subscriber| serves_tenant(tenants, &subscriber.tenant))
+            .filter_map(|subscriber| {
+                let account_id = subscriber.account_id.clone()?;
+                Some((
+                    account_id,
+                    subscriber.session_id.clone().unwrap_or_default(),
+                ))
+            })
+            .collect();
+        subscriptions.sort();
+        subscriptions.dedup();
+        subscriptions
+            .into_iter()
+            .map(|(account_id, session_id)| {
+                let mut subscription = AccountSubscription::new();
+                subscription.set_account_id(account_id);
+                subscription.set_session_id(session_id);
+                subscription
+            })
+            .collect()
+    }
+
+    /// Send a notification from a private instance serving the given tenants to the subscribers it
+    /// concerns.
+    pub fn publish(&self, tenants: &[String], notification: &Notification) {
+        let data = match notification.event.as_ref() {
+            Some(Notification_oneof_event::json_event(json_event)) => json_event.clone(),
+            Some(Notification_oneof_event::encrypted_event(encrypted_event)) => json!({
+                "account_id": notification.get_account_id(),
+                "session_id": notification.get_session_id(),
+                "payload": hex::encode(encrypted_event),
+            })
+            .to_string(),
+            None => return,
+        };
+
+        let inner = self.inner.lock().expect("mutex poisoned");
+        for subscriber in inner.subscribers.values() {
+            if !serves_tenant(tenants, &subscriber.tenant) {
+                continue;
+            }
+            if !notification.get_account_id().is_empty()
+                && subscriber.account_id.as_deref() != Some(notification.get_account_id())
+            {
+                continue;
+            }
+            if !notification.get_session_id().is_empty()
+                && subscriber.session_id.as_deref() != Some(notification.get_session_id())
+            {
+                continue;
+            }
+
+            // Slow subscribers miss notifications rather than holding up the others.
+            // Disconnected subscribers are removed when their subscription is dropped.
+            if let Err(TrySendError::Full(_)) = subscriber.sender.try_send(data.clone()) {
+                counters::OP_COUNTERS.inc("notification_dropped");
+            }
+        }
+    }
+}
+
+/// Start serving SSE subscriptions on a background thread.
+pub fn start_sse_server(
+    listen_uri: &ClientUri,
+    hub: NotificationHub,
+    tenants: Option<Tenants>,
+    logger: Logger,
+) -> Result<(), String> {
+    let listener = TcpListener::bind((listen_uri.host().as_str(), listen_uri.port()))
+        .map_err(|err| format!("failed binding {}: {}", listen_uri.addr(), err))?;
+
+    let connection_limit = ConnectionLimit::new(MAX_CONNECTIONS);
+    thread::Builder::new()
+        .name("SseServer".into())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(err) => {
+                        log::warn!(logger, "Failed accepting SSE connection: {}", err);
+                        continue;
+                    }
+                };
+
+                let guard = match connection_limit.acquire() {
+                    Some(guard) => guard,
+                    None => {
+                        counters::OP_COUNTERS.inc("sse_connection_rejected");
+                        if let Err(err) = reject_connection(stream) {
+                            log::debug!(logger, "Failed rejecting SSE connection: {}", err);
+                        }
+                        continue;
+                    }
+                };
+
+                let hub = hub.clone();
+                let tenants = tenants.clone();
+                let logger = logger.clone();
+                thread::spawn(move || {
+                    let _guard = guard;
+                    if let Err(err) = handle_connection(stream, &hub, tenants.as_ref()) {
+                        log::debug!(logger, "SSE connection closed: {}", err);
+                    }
+                });
+            }
+        })
+        .map_err(|err| format!("failed spawning SSE server thread: {}", err))?;
+
+    Ok(())
+}
+
+fn handle_connection(
+    mut stream: TcpStream,
+    hub: &NotificationHub,
+    tenants: Option<&Tenants>,
+) -> Result<(), String> {
+    let request = read_request_head(&stream)?;
+
+    let subscription = match parse_subscribe_request(&request.request_line).and_then(|subscribe| {
+        let tenant = resolve_tenant(
+            tenants,
+            subscribe.tenant.as_deref(),
+            request.header(API_KEY_HEADER),
+        )
+        .map_err(|_| "403 Forbidden")?;
+        hub.subscribe(tenant, subscribe.account_id, subscribe.session_id)
+            .ok_or("503 Service Unavailable")
+    }) {
+        Ok(subscription) => subscription,
+        Err(status) => {
+            return write!(
+                stream,
+                "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                status
+            )
+            .map_err(|err| err.to_string());
+        }
+    };
+
+    stream
+        .write_all(
+            b"HTTP/1.1 200 OK\r\n\
+              Content-Type: text/event-stream\r\n\
+              Cache-Control: no-cache\r\n\
+              Connection: keep-alive\r\n\r\n",
+        )
+        .map_err(|err| err.to_string())?;
+
+    loop {
+        let chunk = match subscription.receiver.recv_timeout(KEEPALIVE_INTERVAL) {
+            Ok(data) => format!("data: {}\n\n", data),
+            Err(RecvTimeoutError::Timeout) => ": keepalive\n\n".to_owned(),
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        };
+        stream
+            .write_all(chunk.as_bytes())
+            .and_then(|_| stream.flush())
+            .map_err(|err| err.to_string())?;
+    }
+}
+
+/// A parsed subscription request.
+#[derive(Debug, Default, PartialEq)]
+struct SubscribeRequest {
+    /// The tenant in the path of the request, if any.
+    tenant: Option<String>,
+
+    account_id: Option<String>,
+    session_id: Option<String>,
+}
+
+/// Parse the request line of a subscription request, returning what to subscribe to, or the HTTP
+/// status to reject the request with.
+fn parse_subscribe_request(request_line: &str) -> Result<SubscribeRequest, &'static str> {
+    let mut parts = request_line.split_whitespace();
+    let (method, target) = match (parts.next(), parts.next()) {
+        (Some(method), Some(target)) => (method, target),
+        _ => return Err("400 Bad Request"),
+    };
+    if method != "GET" {
+        return Err("405 Method Not Allowed");
+    }
+
+    let (path, query) = match target.find('?') {
+        Some(index) => (&target[..index], &target[index + 1..]),
+        None => (target, ""),
+    };
+    let tenant = match path
+        .strip_prefix("/t/")
+        .and_then(|path| path.strip_suffix("/subscribe"))
+    {
+        Some(tenant) if is_valid_param(tenant) => Some(tenant.to_owned()),
+        Some(_) => return Err("400 Bad Request"),
+        None if path == "/subscribe" => None,
+        None => return Err("404 Not Found"),
+    };
+
+    let mut request = SubscribeRequest {
+        tenant,
+        ..Default::default()
+    };
+    for param in query.split('&').filter(|param| !param.is_empty()) {
+        let (name, value) = match param.find('=') {
+            Some(index) => (&param[..index], &param[index + 1..]),
+            None => return Err("400 Bad Request"),
+        };
+        let field = match name {
+            "account_id" => &mut request.account_id,
+            "session_id" => &mut request.session_id,
+            _ => return Err("400 Bad Request"),
+        };
+        if field.is_some() || !is_valid_param(value) {
+            return Err("400 Bad Request");
+        }
+        *field = Some(value.to_owned());
+    }
+    if request.session_id.is_some() && request.account_id.is_none() {
+        return Err("400 Bad Request");
+    }
+    Ok(request)
+}
+
+fn is_valid_param(value: &str) -> bool {
+    !value.is_empty()
+        && value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn json_notification(account_id: &str, json_event: &str) -> Notification {
+        let mut notification = Notification::new();
+        notification.set_account_id(account_id.to_owned());
+        notification.set_json_event(json_event.to_owned());
+        notification
+    }
+
+    #[test]
+    fn notifications_are_routed_by_account() {
+        let hub = NotificationHub::default();
+        let everyone = hub.subscribe(String::new(), None, None).unwrap();
+        let alice = hub
+            .subscribe(String::new(), Some("alice".to_owned()), None)
+            .unwrap();
+        let _alice2 = hub
+            .subscribe(String::new(), Some("alice".to_owned()), None)
+            .unwrap();
+        let bob = hub
+            .subscribe(String::new(), Some("bob".to_owned()), None)
+            .unwrap();
+
+        assert_eq!(subscribed_account_ids(&hub, &[]), vec!["alice", "bob"]);
+
+        hub.publish(&[], &json_notification("", "block"));
+        hub.publish(&[], &json_notification("alice", "alice_log"));
+
+        assert_eq!(
+            everyone.receiver.try_iter().collect::<Vec<_>>(),
+            vec!["block"]
+        );
+        assert_eq!(
+            alice.receiver.try_iter().collect::<Vec<_>>(),
+            vec!["block", "alice_log"]
+        );
+        assert_eq!(bob.receiver.try_iter().collect::<Vec<_>>(), vec!["block"]);
+
+        drop(bob);
+        assert_eq!(subscribed_account_ids(&hub, &[]), vec!["alice"]);
+    }
+
+    fn subscribed_account_ids(hub: &NotificationHub, tenants: &[String]) -> Vec<String> {
+        hub.account_subscriptions(tenants)
+            .iter()
+            .map(|subscription| subscription.get_account_id().to_owned())
+            .collect()
+    }
+
+    #[test]
+    fn encrypted_events_are_routed_by_session() {
+        let hub = NotificationHub::default();
+        let alice = hub
+            .subscribe(
+                String::new(),
+                Some("alice".to_owned()),
+                Some("s1".to_owned()),
+            )
+            .unwrap();
+        let other_session = hub
+            .subscribe(
+                String::new(),
+                Some("alice".to_owned()),
+                Some("s2".to_owned()),
+            )
+            .unwrap();
+        let no_session = hub
+            .subscribe(String::new(), Some("alice".to_owned()), None)
+            .unwrap();
+
+        let subscriptions = hub.account_subscriptions(&[]);
+        assert_eq!(
+            subscriptions
+                .iter()
+                .map(|subscription| subscription.get_session_id())
+                .collect::<Vec<_>>(),
+            vec!["", "s1", "s2"]
+        );
+
+        let mut notification = Notification::new();
+        notification.set_account_id("alice".to_owned());
+        notification.set_session_id("s1".to_owned());
+        notification.set_encrypted_event(vec![1, 2, 3]);
+        hub.publish(&[], &notification);
+
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&alice.receiver.try_recv().unwrap()).unwrap(),
+            json!({"account_id": "alice", "session_id": "s1", "payload": "010203"})
+        );
+        assert!(other_session.receiver.try_recv().is_err());
+        assert!(no_session.receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn notifications_are_routed_by_tenant() {
+        let hub = NotificationHub::default();
+        let untenanted = hub
+            .subscribe(String::new(), Some("alice".to_owned()), None)
+            .unwrap();
+        let acme = hub
+            .subscribe("acme".to_owned(), Some("alice".to_owned()), None)
+            .unwrap();
+        let _globex = hub
+            .subscribe("globex".to_owned(), Some("bob".to_owned()), None)
+            .unwrap();
+
+        let acme_tenants = vec!["acme".to_owned()];
+        assert_eq!(subscribed_account_ids(&hub, &acme_tenants), vec!["alice"]);
+        assert_eq!(subscribed_account_ids(&hub, &[]), vec!["alice"]);
+
+        hub.publish(&acme_tenants, &json_notification("alice", "alice_log"));
+        assert!(untenanted.receiver.try_recv().is_err());
+        assert_eq!(acme.receiver.try_recv().unwrap(), "alice_log");
+    }
+
+    #[test]
+    fn subscribe_requests_are_parsed() {
+        assert_eq!(
+            parse_subscribe_request("GET /subscribe HTTP/1.1\r\n"),
+            Ok(SubscribeRequest::default())
+        );
+        assert_eq!(
+            parse_subscribe_request("GET /subscribe?account_id=abc123 HTTP/1.1\r\n"),
+            Ok(SubscribeRequest {
+                account_id: Some("abc123".to_owned()),
+                ..Default::default()
+            })
+        );
+        assert_eq!(
+            parse_subscribe_request(
+                "GET /t/acme/subscribe?account_id=abc123&session_id=def456 HTTP/1.1\r\n"
+            ),
+            Ok(SubscribeRequest {
+                tenant: Some("acme".to_owned()),
+                account_id: Some("abc123".to_owned()),
+                session_id: Some("def456".to_owned()),
+            })
+        );
+        assert_eq!(
+            parse_subscribe_request("POST /subscribe HTTP/1.1\r\n"),
+            Err("405 Method Not Allowed")
+        );
+        assert_eq!(
+            parse_subscribe_request("GET /other HTTP/1.1\r\n"),
+            Err("404 Not Found")
+        );
+        assert_eq!(
+            parse_subscribe_request("GET /subscribe?account_id=a%20b HTTP/1.1\r\n"),
+            Err("400 Bad Request")
+        );
+        assert_eq!(
+            parse_subscribe_request("GET /subscribe?account_id=a&account_id=b HTTP/1.1\r\n"),
+            Err("400 Bad Request")
+        );
+        assert_eq!(
+            parse_subscribe_request("GET /subscribe?session_id=a HTTP/1.1\r\n"),
+            Err("400 Bad Request")
+        );
+        assert_eq!(
+            parse_subscribe_request("GET /t/a%20b/subscribe HTTP/1.1\r\n"),
+            Err("400 Bad Request")
+        );
+    }
+}
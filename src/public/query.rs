@@ -3,21 +3,70 @@
 //! Utility entity for managing queries submitted over our rocket endpoint and resolved by the GRPC
 //! polling mechanism.
 
+use crate::{counters, utils::request_key, webhook::WebhookSender};
 use mc_wallet_service_mirror::wallet_service_mirror_api::{QueryRequest, QueryResponse};
 use rand::RngCore;
+use serde_derive::Serialize;
+use serde_json::{json, Value};
 use std::{
     collections::HashMap,
-    sync::{Arc, Condvar, Mutex},
-    time::Duration,
+    mem,
+    sync::{Arc, Condvar, Mutex, MutexGuard},
+    time::{Duration, Instant},
 };
 
-/// The length of the randomly generated query id that is used to tie requests and responses
-/// together.
-const QUERY_ID_LEN: usize = 8;
+/// The number of random bytes in the query id that is used to tie requests and responses together.
+/// Job ids are query ids, and anyone who knows one can fetch the result of the job, so they need
+/// to be unguessable.
+const QUERY_ID_LEN: usize = 16;
 
 /// The maximum amount of time to wait for a query to complete.
 const QUERY_MAX_DURATION: Duration = Duration::from_secs(120);
 
+/// Methods that only read the state of the wallet. Identical in-flight requests for them can share
+/// a single response, and they can be retried on another instance without side effects.
+const READ_METHODS: &[&str] = &[
+    "check_receiver_receive_status",
+    "get_account",
+    "get_account_status",
+    "get_address_for_account",
+    "get_addresses_for_account",
+    "get_all_accounts",
+    "get_all_transaction_logs_for_block",
+    "get_balance_for_account",
+    "get_balance_for_address",
+    "get_block",
+    "get_confirmations",
+    "get_network_status",
+    "get_transaction_log",
+    "get_transaction_logs_for_account",
+    "get_wallet_status",
+    "validate_confirmation",
+    "verify_address",
+];
+
+/// The default amount of time the results of jobs are retained for after they complete.
+pub const DEFAULT_JOB_RETENTION: Duration = Duration::from_secs(300);
+
+/// The maximum number of jobs of a single tenant that may be waiting to be resolved.
+const MAX_OUTSTANDING_JOBS: usize = 1000;
+
+/// The default amount of time a private instance may go without polling before it is considered
+/// gone. This is longer than the time full-service may take to reply to a single request, since
+/// the private side does not poll while it is waiting on full-service.
+pub const DEFAULT_INSTANCE_TIMEOUT: Duration = Duration::from_secs(180);
+
+/// The error lost queries that cannot be safely retried on another instance are resolved with.
+const LOST_QUERY_ERROR: &str =
+    "The private instance handling the request stopped polling, so it may or may not have been processed";
+
+/// How long instances that stopped polling are still reported on.
+const INSTANCE_FORGET_AFTER: Duration = Duration::from_secs(60 * 60);
+
+/// How long the instance that established a session is remembered after the session was last
+/// used.
+const SESSION_FORGET_AFTER: Duration = Duration::from_secs(24 * 60 * 60);
+
 /// The state held by each individual query.
 struct QueryInner {
     request: QueryRequest,
@@ -29,31 +78,60 @@ struct QueryInner {
 pub struct Query {
     inner: Arc<Mutex<QueryInner>>,
     condvar: Arc<Condvar>,
+    created_at: Instant,
+
+    /// The id the query is sent to the private side under.
+    query_id: String,
+
+    /// The tenant of the request, empty if it was made without one.
+    tenant: String,
+
+    /// The session of session requests.
+    session_id: Option<String>,
 }
 
 impl Query {
-    pub fn new(request: QueryRequest) -> Self {
+    pub fn new(query_id: String, request: QueryRequest) -> Self {
+        let tenant = request.get_tenant().to_owned();
+        let session_id = if request.has_session_request() {
+            Some(request.get_session_request().get_session_id().to_owned())
+        } else {
+            None
+        };
         Self {
             inner: Arc::new(Mutex::new(QueryInner {
                 request,
                 response: None,
             })),
             condvar: Arc::new(Condvar::new()),
+            created_at: Instant::now(),
+            query_id,
+            tenant,
+            session_id,
         }
     }
 
+    pub fn query_id(&self) -> &str {
+        &self.query_id
+    }
+
     pub fn request(&self) -> QueryRequest {
         self.inner.lock().expect("mutex poisoned").request.clone()
     }
 
+    /// Returns the response, if the query was resolved.
+    pub fn response(&self) -> Option<QueryResponse> {
+        self.inner.lock().expect("mutex poisoned").response.clone()
+    }
+
     pub fn resolve(&self, response: QueryResponse) {
         let mut inner = self.inner.lock().expect("mutex poisoned");
         inner.response = Some(response);
-        self.condvar.notify_one();
+        self.condvar.notify_all();
     }
 
     pub fn wait(self) -> Result<QueryResponse, String> {
-        let (mut inner, wait_result) = self
+        let (inner, wait_result) = self
             .condvar
             .wait_timeout_while(
                 self.inner.lock().expect("muted poisoned"),
@@ -66,11 +144,11 @@ impl Query {
             return Err("timeout".into());
         }
 
-        assert!(inner.response.is_some());
-
+        // The response is cloned rather than taken since coalesced queries share it between
+        // several waiters.
         Ok(inner
             .response
-            .take()
+            .clone()
             .expect("response should've had something in it"))
     }
 }
@@ -80,84 +158,1139 @@ struct QueryManagerInner {
     /// mirror.
     pending_requests: HashMap<String, Query>,
 
-    /// Map of query id -> query of queries that were resolved by the mirror.
-    pending_responses: HashMap<String, Query>,
+    /// Map of query id -> query of queries that were handed to a private instance and are waiting
+    /// to be resolved by it.
+    pending_responses: HashMap<String, LeasedQuery>,
+
+    /// Map of instance id -> private instance, for the instances that polled us.
+    instances: HashMap<String, PrivateInstance>,
+
+    /// Map of request key -> in-flight unencrypted query, used to coalesce identical requests.
+    /// Only populated when coalescing is enabled.
+    in_flight: HashMap<String, InFlightQuery>,
+
+    /// Map of query id -> job of queries submitted through the job API.
+    jobs: HashMap<String, Job>,
+
+    /// Map of session id -> the session, for the sessions established by private instances.
+    sessions: HashMap<String, SessionOwner>,
+
+    /// (callback URL, body) of job callbacks that are sent once the lock is released.
+    callbacks: Vec<(String, String)>,
+}
+
+/// The private instance that established a session, and therefore holds its keys.
+struct SessionOwner {
+    instance_id: String,
+    last_used: Instant,
+}
+
+/// An unencrypted query that has not been resolved yet, and that identical requests can attach
+/// to.
+struct InFlightQuery {
+    query_id: String,
+    query: Query,
+    enqueued_at: Instant,
+}
+
+/// A query handed to a private instance.
+struct LeasedQuery {
+    query: Query,
+    instance_id: String,
+}
+
+/// A private instance of the mirror polling us.
+struct PrivateInstance {
+    last_poll: Instant,
+
+    /// The tenants the instance registered for when it last polled.
+    tenants: Vec<String>,
+
+    resolved_queries: u64,
+    requeued_queries: u64,
+}
+
+/// The health of a private instance, as reported by `QueryManager::instances`.
+#[derive(Clone, Debug, Serialize)]
+pub struct InstanceHealth {
+    pub instance_id: String,
+
+    /// The tenants the instance serves.
+    pub tenants: Vec<String>,
+
+    /// False if the instance has not polled within the instance timeout.
+    pub healthy: bool,
+
+    pub secs_since_last_poll: u64,
+
+    /// The number of queries handed to the instance that it has not resolved yet.
+    pub leased_queries: usize,
+
+    pub resolved_queries: u64,
+
+    /// The number of queries that were taken back from the instance after it stopped polling.
+    pub requeued_queries: u64,
+}
+
+/// A query submitted through the job API, whose result is retained so that it can be fetched
+/// after the query completes.
+struct Job {
+    query: Query,
+    created_at: Instant,
+    resolved_at: Option<Instant>,
+
+    /// URL the result is posted to once the job is resolved.
+    callback: Option<String>,
+}
+
+/// Why a job was not accepted.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum JobError {
+    /// The job cannot be accepted as submitted.
+    Invalid(String),
+
+    /// The tenant already has the maximum number of jobs waiting to be resolved.
+    TooManyJobs,
+}
+
+/// The status of a job.
+#[derive(Clone, Debug, PartialEq)]
+pub enum JobStatus {
+    /// The job is waiting to be resolved by the private side of the mirror.
+    Pending,
+
+    /// The job was resolved.
+    Done(QueryResponse),
+
+    /// The job was not resolved within the maximum query duration.
+    TimedOut,
+}
+
+impl JobStatus {
+    /// The JSON representation of a job status. Unencrypted responses are returned as a JSON
+    /// string in `json_response`, and encrypted responses are hex-encoded in `payload`. Responses
+    /// signed by the private side carry the hex-encoded signature in `signature`.
+    pub fn to_json(&self, query_id: &str) -> Value {
+        let mut json = match self {
+            JobStatus::Pending => json!({ "status": "pending" }),
+            JobStatus::TimedOut => json!({ "status": "error", "error": "timeout" }),
+            JobStatus::Done(query_response) if query_response.has_error() => {
+                json!({ "status": "error", "error": query_response.get_error() })
+            }
+            JobStatus::Done(query_response) if query_response.has_unencrypted_response() => {
+                let response = query_response.get_unencrypted_response();
+                json!({
+                    "status": "done",
+                    "json_response": response.get_json_response(),
+                    "signature": hex::encode(response.get_signature()),
+                })
+            }
+            JobStatus::Done(query_response) if query_response.has_encrypted_response() => {
+                let response = query_response.get_encrypted_response();
+                json!({
+                    "status": "done",
+                    "payload": hex::encode(response.get_payload()),
+                    "signature": hex::encode(response.get_signature()),
+                })
+            }
+            JobStatus::Done(_) => {
+                json!({ "status": "error", "error": "Incorrect response type received" })
+            }
+        };
+        json["job_id"] = Value::from(query_id);
+        json
+    }
 }
 
 impl QueryManagerInner {
     pub fn generate_query_id(&self) -> String {
         let mut rng = rand::thread_rng();
 
-        const CHARSET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ\
-                            abcdefghijklmnopqrstuvwxyz\
-                            0123456789";
-
         loop {
-            let query_id: String = (0..QUERY_ID_LEN)
-                .map(|_| {
-                    let idx = (rng.next_u64() % CHARSET.len() as u64) as usize;
-                    char::from(CHARSET[idx])
-                })
-                .collect();
+            let mut query_id_bytes = [0u8; QUERY_ID_LEN];
+            rng.fill_bytes(&mut query_id_bytes);
+            let query_id = hex::encode(query_id_bytes);
 
             if !self.pending_requests.contains_key(&query_id)
                 && !self.pending_responses.contains_key(&query_id)
+                && !self.jobs.contains_key(&query_id)
             {
                 return query_id;
             }
         }
     }
+
+    /// Put the queries leased to instances that stopped polling back in the queue, so that another
+    /// instance picks them up. An instance that stopped polling may still have received and
+    /// processed its queries, so only unencrypted read requests are requeued. The other queries
+    /// are returned, for them to be resolved with an error. Queries that nobody waits on anymore
+    /// are dropped.
+    fn requeue_from_lost_instances(&mut self, instance_timeout: Duration) -> Vec<(String, Query)> {
+        let lost_instances: Vec<String> = self
+            .instances
+            .iter()
+            .filter(|(_, instance)| instance.last_poll.elapsed() >= instance_timeout)
+            .map(|(instance_id, _)| instance_id.clone())
+            .collect();
+        if lost_instances.is_empty() {
+            return vec![];
+        }
+
+        let lost_query_ids: Vec<String> = self
+            .pending_responses
+            .iter()
+            .filter(|(_, leased)| lost_instances.contains(&leased.instance_id))
+            .map(|(query_id, _)| query_id.clone())
+            .collect();
+        let mut lost_queries = Vec::new();
+        for query_id in lost_query_ids {
+            let leased = self
+                .pending_responses
+                .remove(&query_id)
+                .expect("query id was just found");
+            if leased.query.created_at.elapsed() >= QUERY_MAX_DURATION {
+                continue;
+            }
+            if !is_read_request(&leased.query.request()) {
+                counters::OP_COUNTERS.inc("query_lost");
+                lost_queries.push((query_id, leased.query));
+                continue;
+            }
+
+            if let Some(instance) = self.instances.get_mut(&leased.instance_id) {
+                instance.requeued_queries += 1;
+            }
+            counters::OP_COUNTERS.inc("query_requeued");
+            self.pending_requests.insert(query_id, leased.query);
+        }
+
+        self.instances
+            .retain(|_, instance| instance.last_poll.elapsed() < INSTANCE_FORGET_AFTER);
+        lost_queries
+    }
+
+    /// Whether a query may be handed to an instance. Session requests are only handed to the
+    /// instance that established the session, unless it stopped polling, since no other instance
+    /// holds the session keys.
+    fn may_handle(&self, instance_id: &str, query: &Query, instance_timeout: Duration) -> bool {
+        let owner = match query
+            .session_id
+            .as_ref()
+            .and_then(|session_id| self.sessions.get(session_id))
+        {
+            Some(owner) => &owner.instance_id,
+            None => return true,
+        };
+        owner == instance_id
+            || !self.instances.get(owner).map_or(false, |instance| {
+                instance.last_poll.elapsed() < instance_timeout
+            })
+    }
+
+    /// Drop the queries that were never handed to a private instance and that nobody waits on
+    /// anymore, so that they are not handed over once an instance starts polling.
+    fn prune_expired_requests(&mut self) {
+        let expired_query_ids: Vec<String> = self
+            .pending_requests
+            .iter()
+            .filter(|(_, query)| query.created_at.elapsed() >= QUERY_MAX_DURATION)
+            .map(|(query_id, _)| query_id.clone())
+            .collect();
+        for query_id in expired_query_ids {
+            self.pending_requests.remove(&query_id);
+            self.in_flight
+                .retain(|_, in_flight| in_flight.query_id != query_id);
+            counters::OP_COUNTERS.inc("query_expired");
+        }
+    }
+
+    /// Returns the number of jobs of a tenant that are waiting to be resolved.
+    fn outstanding_jobs(&self, tenant: &str) -> usize {
+        self.jobs
+            .values()
+            .filter(|job| {
+                job.query.tenant == tenant
+                    && job.resolved_at.is_none()
+                    && job.created_at.elapsed() < QUERY_MAX_DURATION
+            })
+            .count()
+    }
+
+    /// Mark jobs that did not complete in time as resolved, queueing a timeout callback for them.
+    fn queue_job_timeouts(&mut self) {
+        for (query_id, job) in self.jobs.iter_mut() {
+            if job.resolved_at.is_some() || job.created_at.elapsed() < QUERY_MAX_DURATION {
+                continue;
+            }
+            job.resolved_at = Some(Instant::now());
+            if let Some(callback) = job.callback.clone() {
+                let body = JobStatus::TimedOut.to_json(query_id);
+                self.callbacks.push((callback, body.to_string()));
+            }
+        }
+    }
+
+    /// Drop jobs whose results are past the retention window.
+    fn prune_jobs(&mut self, job_retention: Duration) {
+        self.jobs.retain(|_, job| match job.resolved_at {
+            Some(resolved_at) => resolved_at.elapsed() < job_retention,
+            None => job.created_at.elapsed() < QUERY_MAX_DURATION + job_retention,
+        });
+    }
 }
 
 #[derive(Clone)]
 pub struct QueryManager {
     inner: Arc<Mutex<QueryManagerInner>>,
+
+    /// Whether identical in-flight unencrypted requests share a single query.
+    coalesce_requests: bool,
+
+    /// How long the results of jobs are retained for after they complete.
+    job_retention: Duration,
+
+    /// Sends job results to callback URLs, if callbacks are enabled.
+    webhook_sender: Option<WebhookSender>,
+
+    /// How long a private instance may go without polling before the queries leased to it are
+    /// handed to other instances.
+    instance_timeout: Duration,
 }
 
 impl Default for QueryManager {
     fn default() -> Self {
+        Self::new(false, DEFAULT_JOB_RETENTION, None, DEFAULT_INSTANCE_TIMEOUT)
+    }
+}
+
+impl QueryManager {
+    pub fn new(
+        coalesce_requests: bool,
+        job_retention: Duration,
+        webhook_sender: Option<WebhookSender>,
+        instance_timeout: Duration,
+    ) -> Self {
         Self {
             inner: Arc::new(Mutex::new(QueryManagerInner {
                 pending_requests: HashMap::new(),
                 pending_responses: HashMap::new(),
+                instances: HashMap::new(),
+                in_flight: HashMap::new(),
+                jobs: HashMap::new(),
+                sessions: HashMap::new(),
+                callbacks: Vec::new(),
             })),
+            coalesce_requests,
+            job_retention,
+            webhook_sender,
+            instance_timeout,
         }
     }
-}
 
-impl QueryManager {
     pub fn enqueue_query(&self, request: QueryRequest) -> Query {
         let mut inner = self.inner.lock().expect("mutex poisoned");
+        inner.prune_expired_requests();
+
+        let key = if self.coalesce_requests {
+            coalescing_key(&request)
+        } else {
+            None
+        };
+
+        if let Some(key) = key.as_ref() {
+            let existing = inner
+                .in_flight
+                .get(key)
+                .map(|in_flight| (in_flight.enqueued_at, in_flight.query.clone()));
+
+            if let Some((enqueued_at, query)) = existing {
+                // Queries that outlived the maximum duration will never be waited on again, and
+                // may never be resolved, so they are not attached to.
+                if enqueued_at.elapsed() < QUERY_MAX_DURATION {
+                    counters::OP_COUNTERS.inc("query_coalesced");
+                    return query;
+                }
+                inner.in_flight.remove(key);
+            }
+        }
+
         let query_id = inner.generate_query_id();
-        let query = Query::new(request);
-        inner.pending_requests.insert(query_id, query.clone());
+        let query = Query::new(query_id.clone(), request);
+        inner
+            .pending_requests
+            .insert(query_id.clone(), query.clone());
+
+        if let Some(key) = key {
+            inner.in_flight.insert(
+                key,
+                InFlightQuery {
+                    query_id,
+                    query: query.clone(),
+                    enqueued_at: Instant::now(),
+                },
+            );
+        }
+
         query
     }
 
-    pub fn get_pending_requests(&self) -> HashMap<String, QueryRequest> {
+    /// Enqueue a query whose result is fetched later using `get_job`, returning its query id.
+    /// If a callback URL is given, the result is also posted to it once the query is resolved.
+    pub fn enqueue_job(
+        &self,
+        request: QueryRequest,
+        callback: Option<String>,
+    ) -> Result<String, JobError> {
+        if let Some(callback) = callback.as_ref() {
+            match self.webhook_sender.as_ref() {
+                Some(webhook_sender) if webhook_sender.is_allowed(callback) => {}
+                Some(_) => {
+                    return Err(JobError::Invalid(format!(
+                        "Callback URL {} is not allowed",
+                        callback
+                    )))
+                }
+                None => return Err(JobError::Invalid("Callbacks are not enabled".into())),
+            }
+        }
+
         let mut inner = self.inner.lock().expect("mutex poisoned");
-        let mut pending_requests = HashMap::new();
-        let mut pending_responses = HashMap::new();
+        inner.prune_jobs(self.job_retention);
+        inner.prune_expired_requests();
+        if inner.outstanding_jobs(request.get_tenant()) >= MAX_OUTSTANDING_JOBS {
+            counters::OP_COUNTERS.inc("job_rejected");
+            return Err(JobError::TooManyJobs);
+        }
 
-        for (query_id, query) in inner.pending_requests.drain() {
-            pending_requests.insert(query_id.clone(), query.request());
-            pending_responses.insert(query_id, query);
+        let query_id = inner.generate_query_id();
+        let query = Query::new(query_id.clone(), request);
+        inner
+            .pending_requests
+            .insert(query_id.clone(), query.clone());
+        inner.jobs.insert(
+            query_id.clone(),
+            Job {
+                query,
+                created_at: Instant::now(),
+                resolved_at: None,
+                callback,
+            },
+        );
+
+        Ok(query_id)
+    }
+
+    /// Returns the status of a job, or None if there is no such job for the tenant or its result
+    /// is no longer retained.
+    pub fn get_job(&self, tenant: &str, query_id: &str) -> Option<JobStatus> {
+        let mut inner = self.inner.lock().expect("mutex poisoned");
+        inner.prune_jobs(self.job_retention);
+
+        let job = inner.jobs.get(query_id)?;
+        if job.query.tenant != tenant {
+            return None;
         }
+        Some(match job.query.response() {
+            Some(response) => JobStatus::Done(response),
+            None if job.created_at.elapsed() >= QUERY_MAX_DURATION => JobStatus::TimedOut,
+            None => JobStatus::Pending,
+        })
+    }
+
+    /// Hand queries of the tenants it serves to a polling private instance. When several
+    /// instances serve the same tenants, each poll takes (the oldest) share of the queued queries
+    /// proportional to the number of healthy instances.
+    pub fn get_pending_requests(
+        &self,
+        instance_id: &str,
+        tenants: &[String],
+    ) -> HashMap<String, QueryRequest> {
+        let mut inner = self.inner.lock().expect("mutex poisoned");
+
+        let instance = inner
+            .instances
+            .entry(instance_id.to_owned())
+            .or_insert(PrivateInstance {
+                last_poll: Instant::now(),
+                tenants: vec![],
+                resolved_queries: 0,
+                requeued_queries: 0,
+            });
+        instance.last_poll = Instant::now();
+        instance.tenants = tenants.to_vec();
+        self.handle_lost_instances(&mut inner);
+        inner.queue_job_timeouts();
+        inner.prune_expired_requests();
+        inner
+            .sessions
+            .retain(|_, owner| owner.last_used.elapsed() < SESSION_FORGET_AFTER);
+
+        let healthy_instances = inner
+            .instances
+            .values()
+            .filter(|instance| {
+                instance.last_poll.elapsed() < self.instance_timeout && instance.tenants == tenants
+            })
+            .count()
+            .max(1);
 
-        for (query_id, query) in pending_responses.drain() {
-            inner.pending_responses.insert(query_id, query);
+        let mut query_ids: Vec<(Instant, String)> = inner
+            .pending_requests
+            .iter()
+            .filter(|(_, query)| {
+                serves_tenant(tenants, &query.tenant)
+                    && inner.may_handle(instance_id, query, self.instance_timeout)
+            })
+            .map(|(query_id, query)| (query.created_at, query_id.clone()))
+            .collect();
+        let share = (query_ids.len() + healthy_instances - 1) / healthy_instances;
+        query_ids.sort();
+
+        let mut pending_requests = HashMap::new();
+        for (_, query_id) in query_ids.into_iter().take(share) {
+            let query = inner
+                .pending_requests
+                .remove(&query_id)
+                .expect("query id was just found");
+            if let Some(owner) = query
+                .session_id
+                .as_ref()
+                .and_then(|session_id| inner.sessions.get_mut(session_id))
+            {
+                owner.last_used = Instant::now();
+            }
+            pending_requests.insert(query_id.clone(), query.request());
+            inner.pending_responses.insert(
+                query_id,
+                LeasedQuery {
+                    query,
+                    instance_id: instance_id.to_owned(),
+                },
+            );
         }
 
+        self.send_callbacks(inner);
         pending_requests
     }
 
-    pub fn resolve_query(&self, query_id: &str, response: &QueryResponse) -> Result<(), String> {
+    /// Resolve a query with the response of a private instance. Any instance serving the tenant of
+    /// the query may resolve it, including one the query was taken back from.
+    pub fn resolve_query(
+        &self,
+        instance_id: &str,
+        tenants: &[String],
+        query_id: &str,
+        response: &QueryResponse,
+    ) -> Result<(), String> {
         let mut inner = self.inner.lock().expect("mutex poisoned");
-        let query = inner
+        let tenant = inner
             .pending_responses
-            .remove(query_id)
+            .get(query_id)
+            .map(|leased| &leased.query)
+            .or_else(|| inner.pending_requests.get(query_id))
+            .map(|query| query.tenant.clone())
             .ok_or_else(|| format!("Unknown query id {}", query_id))?;
-        query.resolve(response.clone());
+        if !serves_tenant(tenants, &tenant) {
+            return Err(format!(
+                "Query {} belongs to a tenant the instance does not serve",
+                query_id
+            ));
+        }
+
+        let query = match inner.pending_responses.remove(query_id) {
+            Some(leased) => leased.query,
+            None => inner
+                .pending_requests
+                .remove(query_id)
+                .expect("query id was just found"),
+        };
+        if let Some(instance) = inner.instances.get_mut(instance_id) {
+            instance.resolved_queries += 1;
+        }
+        if response.has_session_handshake_response() {
+            inner.sessions.insert(
+                response
+                    .get_session_handshake_response()
+                    .get_session_id()
+                    .to_owned(),
+                SessionOwner {
+                    instance_id: instance_id.to_owned(),
+                    last_used: Instant::now(),
+                },
+            );
+        }
+        self.complete_query(&mut inner, query_id, query, response);
+        self.send_callbacks(inner);
         Ok(())
     }
+
+    /// Hand the response to a query to whoever waits on it.
+    fn complete_query(
+        &self,
+        inner: &mut QueryManagerInner,
+        query_id: &str,
+        query: Query,
+        response: &QueryResponse,
+    ) {
+        inner
+            .in_flight
+            .retain(|_, in_flight| in_flight.query_id != query_id);
+        if let Some(job) = inner.jobs.get_mut(query_id) {
+            // Jobs that already timed out got their callback then.
+            if job.resolved_at.is_none() {
+                job.resolved_at = Some(Instant::now());
+                if let Some(callback) = job.callback.clone() {
+                    let body = JobStatus::Done(response.clone()).to_json(query_id);
+                    inner.callbacks.push((callback, body.to_string()));
+                }
+            }
+        }
+        query.resolve(response.clone());
+    }
+
+    /// Post the callbacks of jobs that timed out, even if no private instance polls us.
+    pub fn time_out_jobs(&self) {
+        let mut inner = self.inner.lock().expect("mutex poisoned");
+        inner.queue_job_timeouts();
+        self.send_callbacks(inner);
+    }
+
+    /// Release the lock, then send the queued job callbacks.
+    fn send_callbacks(&self, mut inner: MutexGuard<QueryManagerInner>) {
+        let callbacks = mem::take(&mut inner.callbacks);
+        drop(inner);
+
+        if let Some(webhook_sender) = self.webhook_sender.as_ref() {
+            for (callback, body) in callbacks {
+                webhook_sender.send(callback, body);
+            }
+        }
+    }
+
+    /// Requeue the queries of instances that stopped polling, failing the ones that cannot be
+    /// retried.
+    fn handle_lost_instances(&self, inner: &mut QueryManagerInner) {
+        let mut response = QueryResponse::new();
+        response.set_error(LOST_QUERY_ERROR.to_owned());
+        for (query_id, query) in inner.requeue_from_lost_instances(self.instance_timeout) {
+            self.complete_query(inner, &query_id, query, &response);
+        }
+    }
+
+    /// Returns how long ago a private instance last polled us, or None if none did yet.
+    pub fn time_since_last_poll(&self) -> Option<Duration> {
+        let inner = self.inner.lock().expect("mutex poisoned");
+        inner
+            .instances
+            .values()
+            .map(|instance| instance.last_poll.elapsed())
+            .min()
+    }
+
+    /// Returns the health of the private instances that polled us.
+    pub fn instances(&self) -> Vec<InstanceHealth> {
+        let mut inner = self.inner.lock().expect("mutex poisoned");
+        self.handle_lost_instances(&mut inner);
+        inner.queue_job_timeouts();
+
+        let mut instances: Vec<InstanceHealth> = inner
+            .instances
+            .iter()
+            .map(|(instance_id, instance)| InstanceHealth {
+                instance_id: instance_id.clone(),
+                tenants: instance.tenants.clone(),
+                healthy: instance.last_poll.elapsed() < self.instance_timeout,
+                secs_since_last_poll: instance.last_poll.elapsed().as_secs(),
+                leased_queries: inner
+                    .pending_responses
+                    .values()
+                    .filter(|leased| &leased.instance_id == instance_id)
+                    .count(),
+                resolved_queries: instance.resolved_queries,
+                requeued_queries: instance.requeued_queries,
+            })
+            .collect();
+        instances.sort_by(|a, b| a.instance_id.cmp(&b.instance_id));
+        self.send_callbacks(inner);
+        instances
+    }
+}
+
+/// Whether an instance registered for the given tenants serves requests of a tenant. Instances
+/// that registered for no tenants serve the requests made without one.
+pub fn serves_tenant(tenants: &[String], tenant: &str) -> bool {
+    if tenants.is_empty() {
+        tenant.is_empty()
+    } else {
+        tenants.iter().any(|served| served == tenant)
+    }
+}
+
+/// Returns the method and the key of an unencrypted request for a read method, or None for other
+/// requests (the public side cannot see what an encrypted request contains).
+fn read_request_key(request: &QueryRequest) -> Option<(String, String)> {
+    if !request.has_unencrypted_request() {
+        return None;
+    }
+
+    let json_request =
+        serde_json::from_str(request.get_unencrypted_request().get_json_request()).ok()?;
+    request_key(&json_request).filter(|(method, _)| READ_METHODS.contains(&method.as_str()))
+}
+
+fn is_read_request(request: &QueryRequest) -> bool {
+    read_request_key(request).is_some()
+}
+
+/// Returns the key used for coalescing a request with identical in-flight requests, or None if
+/// the request cannot be coalesced. Only unencrypted requests for read methods are coalesced,
+/// since sharing the response of a method that changes the wallet would hide all but one of the
+/// calls.
+fn coalescing_key(request: &QueryRequest) -> Option<String> {
+    let (_, key) = read_request_key(request)?;
+    Some(format!("{}/{}", request.get_tenant(), key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mc_wallet_service_mirror::wallet_service_mirror_api::{
+        EncryptedRequest, SessionHandshakeRequest, SessionHandshakeResponse, SessionRequest,
+        UnencryptedRequest, UnencryptedResponse,
+    };
+    use std::thread;
+
+    fn unencrypted_query_request(json_request: &str) -> QueryRequest {
+        let mut unencrypted_request = UnencryptedRequest::new();
+        unencrypted_request.set_json_request(json_request.to_owned());
+
+        let mut query_request = QueryRequest::new();
+        query_request.set_unencrypted_request(unencrypted_request);
+        query_request
+    }
+
+    #[test]
+    fn identical_unencrypted_requests_are_coalesced() {
+        let query_manager =
+            QueryManager::new(true, DEFAULT_JOB_RETENTION, None, DEFAULT_INSTANCE_TIMEOUT);
+
+        let query1 = query_manager.enqueue_query(unencrypted_query_request(
+            r#"{"method": "get_network_status", "jsonrpc": "2.0", "id": 1}"#,
+        ));
+        let query2 = query_manager.enqueue_query(unencrypted_query_request(
+            r#"{"method": "get_network_status", "jsonrpc": "2.0", "id": 2}"#,
+        ));
+        query_manager.enqueue_query(unencrypted_query_request(
+            r#"{"method": "get_wallet_status", "jsonrpc": "2.0", "id": 3}"#,
+        ));
+
+        let pending_requests = query_manager.get_pending_requests("instance", &[]);
+        assert_eq!(pending_requests.len(), 2);
+
+        let query_id = pending_requests
+            .iter()
+            .find(|(_, request)| {
+                request
+                    .get_unencrypted_request()
+                    .get_json_request()
+                    .contains("get_network_status")
+            })
+            .map(|(query_id, _)| query_id.clone())
+            .unwrap();
+
+        let waiters = vec![query1, query2]
+            .into_iter()
+            .map(|query| thread::spawn(move || query.wait()))
+            .collect::<Vec<_>>();
+
+        let mut unencrypted_response = UnencryptedResponse::new();
+        unencrypted_response.set_json_response("{}".to_owned());
+        let mut response = QueryResponse::new();
+        response.set_unencrypted_response(unencrypted_response);
+        query_manager
+            .resolve_query("instance", &[], &query_id, &response)
+            .unwrap();
+
+        for waiter in waiters {
+            assert_eq!(waiter.join().unwrap().unwrap(), response);
+        }
+
+        // Once resolved, an identical request results in a new query.
+        query_manager.enqueue_query(unencrypted_query_request(
+            r#"{"method": "get_network_status", "jsonrpc": "2.0", "id": 4}"#,
+        ));
+        assert_eq!(query_manager.get_pending_requests("instance", &[]).len(), 1);
+    }
+
+    #[test]
+    fn job_results_are_retained_for_the_retention_window() {
+        let query_manager = QueryManager::new(
+            false,
+            Duration::from_millis(100),
+            None,
+            DEFAULT_INSTANCE_TIMEOUT,
+        );
+
+        let query_id = query_manager
+            .enqueue_job(
+                unencrypted_query_request(
+                    r#"{"method": "get_network_status", "jsonrpc": "2.0", "id": 1}"#,
+                ),
+                None,
+            )
+            .unwrap();
+        assert_eq!(
+            query_manager.get_job("", &query_id),
+            Some(JobStatus::Pending)
+        );
+        assert_eq!(query_manager.get_job("", "unknown"), None);
+
+        let pending_requests = query_manager.get_pending_requests("instance", &[]);
+        assert!(pending_requests.contains_key(&query_id));
+
+        let mut response = QueryResponse::new();
+        response.set_error("oops".to_owned());
+        query_manager
+            .resolve_query("instance", &[], &query_id, &response)
+            .unwrap();
+
+        assert_eq!(
+            query_manager.get_job("", &query_id),
+            Some(JobStatus::Done(response.clone()))
+        );
+        // Results can be fetched more than once.
+        assert_eq!(
+            query_manager.get_job("", &query_id),
+            Some(JobStatus::Done(response))
+        );
+
+        thread::sleep(Duration::from_millis(150));
+        assert_eq!(query_manager.get_job("", &query_id), None);
+    }
+
+    #[test]
+    fn job_callbacks_require_an_enabled_allowlist() {
+        let query_manager = QueryManager::default();
+        assert!(query_manager
+            .enqueue_job(
+                unencrypted_query_request(
+                    r#"{"method": "get_network_status", "jsonrpc": "2.0", "id": 1}"#,
+                ),
+                Some("https://example.com".to_owned()),
+            )
+            .is_err());
+        assert!(query_manager
+            .get_pending_requests("instance", &[])
+            .is_empty());
+    }
+
+    #[test]
+    fn outstanding_jobs_are_capped_per_tenant() {
+        let query_manager = QueryManager::default();
+        let job_request = |tenant: &str| {
+            let mut request = unencrypted_query_request(
+                r#"{"method": "get_network_status", "jsonrpc": "2.0", "id": 1}"#,
+            );
+            request.set_tenant(tenant.to_owned());
+            request
+        };
+
+        for _ in 0..MAX_OUTSTANDING_JOBS {
+            query_manager.enqueue_job(job_request("a"), None).unwrap();
+        }
+        assert_eq!(
+            query_manager.enqueue_job(job_request("a"), None),
+            Err(JobError::TooManyJobs)
+        );
+        query_manager.enqueue_job(job_request("b"), None).unwrap();
+
+        // Resolved jobs no longer count.
+        let query_id = query_manager
+            .get_pending_requests("instance", &["a".to_owned()])
+            .into_iter()
+            .map(|(query_id, _)| query_id)
+            .next()
+            .unwrap();
+        query_manager
+            .resolve_query(
+                "instance",
+                &["a".to_owned()],
+                &query_id,
+                &QueryResponse::new(),
+            )
+            .unwrap();
+        query_manager.enqueue_job(job_request("a"), None).unwrap();
+    }
+
+    #[test]
+    fn timed_out_jobs_get_a_single_callback() {
+        let query_manager = QueryManager::default();
+        let query_id = query_manager
+            .enqueue_job(
+                unencrypted_query_request(
+                    r#"{"method": "get_network_status", "jsonrpc": "2.0", "id": 1}"#,
+                ),
+                None,
+            )
+            .unwrap();
+
+        let mut inner = query_manager.inner.lock().unwrap();
+        let job = inner.jobs.get_mut(&query_id).unwrap();
+        job.callback = Some("https://example.com".to_owned());
+        job.created_at = Instant::now().checked_sub(QUERY_MAX_DURATION).unwrap();
+        inner.queue_job_timeouts();
+        inner.queue_job_timeouts();
+        assert_eq!(
+            inner.callbacks,
+            vec![(
+                "https://example.com".to_owned(),
+                JobStatus::TimedOut.to_json(&query_id).to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn queries_are_distributed_among_instances() {
+        let query_manager = QueryManager::default();
+
+        // A single instance takes everything.
+        for id in 0..4 {
+            query_manager.enqueue_query(unencrypted_query_request(&format!(
+                r#"{{"method": "get_block", "jsonrpc": "2.0", "id": {}}}"#,
+                id
+            )));
+        }
+        assert_eq!(query_manager.get_pending_requests("a", &[]).len(), 4);
+
+        // Once a second instance shows up, queued queries are split between them.
+        assert_eq!(query_manager.get_pending_requests("b", &[]).len(), 0);
+        for id in 0..4 {
+            query_manager.enqueue_query(unencrypted_query_request(&format!(
+                r#"{{"method": "get_block", "jsonrpc": "2.0", "id": {}}}"#,
+                id
+            )));
+        }
+        assert_eq!(query_manager.get_pending_requests("a", &[]).len(), 2);
+        assert_eq!(query_manager.get_pending_requests("b", &[]).len(), 1);
+        assert_eq!(query_manager.get_pending_requests("b", &[]).len(), 1);
+
+        let instances = query_manager.instances();
+        assert_eq!(instances.len(), 2);
+        assert_eq!(instances[0].leased_queries, 6);
+        assert_eq!(instances[1].leased_queries, 2);
+        assert!(instances.iter().all(|instance| instance.healthy));
+    }
+
+    #[test]
+    fn time_since_last_poll_tracks_the_latest_poll() {
+        let query_manager = QueryManager::default();
+        assert_eq!(query_manager.time_since_last_poll(), None);
+
+        query_manager.get_pending_requests("a", &[]);
+        thread::sleep(Duration::from_millis(100));
+        query_manager.get_pending_requests("b", &[]);
+        assert!(query_manager.time_since_last_poll().unwrap() < Duration::from_millis(100));
+    }
+
+    #[test]
+    fn queries_of_lost_instances_are_requeued() {
+        let query_manager = QueryManager::new(
+            false,
+            DEFAULT_JOB_RETENTION,
+            None,
+            Duration::from_millis(100),
+        );
+
+        let query = query_manager.enqueue_query(unencrypted_query_request(
+            r#"{"method": "get_network_status", "jsonrpc": "2.0", "id": 1}"#,
+        ));
+        let pending_requests = query_manager.get_pending_requests("a", &[]);
+        let query_id = pending_requests.keys().next().unwrap().clone();
+
+        thread::sleep(Duration::from_millis(150));
+
+        // Instance "a" stopped polling, so "b" gets its query.
+        assert_eq!(
+            query_manager
+                .get_pending_requests("b", &[])
+                .keys()
+                .collect::<Vec<_>>(),
+            vec![&query_id]
+        );
+
+        let instances = query_manager.instances();
+        assert_eq!(instances[0].instance_id, "a");
+        assert!(!instances[0].healthy);
+        assert_eq!(instances[0].requeued_queries, 1);
+
+        let mut response = QueryResponse::new();
+        response.set_error("oops".to_owned());
+        query_manager
+            .resolve_query("b", &[], &query_id, &response)
+            .unwrap();
+        assert_eq!(query.wait(), Ok(response.clone()));
+
+        // A late response from "a" is ignored.
+        assert!(query_manager
+            .resolve_query("a", &[], &query_id, &response)
+            .is_err());
+    }
+
+    #[test]
+    fn write_queries_of_lost_instances_are_failed() {
+        let query_manager = QueryManager::new(
+            false,
+            DEFAULT_JOB_RETENTION,
+            None,
+            Duration::from_millis(100),
+        );
+
+        let query = query_manager.enqueue_query(unencrypted_query_request(
+            r#"{"method": "build_and_submit_transaction", "jsonrpc": "2.0", "id": 1}"#,
+        ));
+        assert_eq!(query_manager.get_pending_requests("a", &[]).len(), 1);
+
+        thread::sleep(Duration::from_millis(150));
+
+        // Instance "a" may have submitted the transaction, so "b" does not get the query.
+        assert!(query_manager.get_pending_requests("b", &[]).is_empty());
+        assert_eq!(query_manager.instances()[0].requeued_queries, 0);
+        assert_eq!(query.wait().unwrap().get_error(), LOST_QUERY_ERROR);
+    }
+
+    #[test]
+    fn instances_only_get_queries_of_their_tenants() {
+        let query_manager =
+            QueryManager::new(true, DEFAULT_JOB_RETENTION, None, DEFAULT_INSTANCE_TIMEOUT);
+        let acme = vec!["acme".to_owned()];
+        let globex = vec!["globex".to_owned()];
+
+        for tenant in &["", "acme", "globex"] {
+            let mut query_request = unencrypted_query_request(
+                r#"{"method": "get_network_status", "jsonrpc": "2.0", "id": 1}"#,
+            );
+            query_request.set_tenant(tenant.to_string());
+            query_manager.enqueue_query(query_request);
+        }
+
+        let pending_requests = query_manager.get_pending_requests("a", &acme);
+        assert_eq!(pending_requests.len(), 1);
+        let (query_id, request) = pending_requests.into_iter().next().unwrap();
+        assert_eq!(request.get_tenant(), "acme");
+
+        // Another tenant's instance cannot resolve the query.
+        let mut response = QueryResponse::new();
+        response.set_error("oops".to_owned());
+        assert!(query_manager
+            .resolve_query("b", &globex, &query_id, &response)
+            .is_err());
+        query_manager
+            .resolve_query("a", &acme, &query_id, &response)
+            .unwrap();
+
+        let pending_requests = query_manager.get_pending_requests("b", &globex);
+        assert_eq!(pending_requests.len(), 1);
+        assert_eq!(
+            pending_requests.values().next().unwrap().get_tenant(),
+            "globex"
+        );
+
+        let pending_requests = query_manager.get_pending_requests("c", &[]);
+        assert_eq!(pending_requests.len(), 1);
+        assert_eq!(pending_requests.values().next().unwrap().get_tenant(), "");
+    }
+
+    #[test]
+    fn jobs_are_only_visible_to_their_tenant() {
+        let query_manager = QueryManager::default();
+
+        let mut query_request = unencrypted_query_request(
+            r#"{"method": "get_network_status", "jsonrpc": "2.0", "id": 1}"#,
+        );
+        query_request.set_tenant("acme".to_owned());
+        let query_id = query_manager.enqueue_job(query_request, None).unwrap();
+
+        assert_eq!(
+            query_manager.get_job("acme", &query_id),
+            Some(JobStatus::Pending)
+        );
+        assert_eq!(query_manager.get_job("globex", &query_id), None);
+        assert_eq!(query_manager.get_job("", &query_id), None);
+    }
+
+    #[test]
+    fn requests_are_not_coalesced_when_disabled() {
+        let query_manager = QueryManager::default();
+
+        for _ in 0..2 {
+            query_manager.enqueue_query(unencrypted_query_request(
+                r#"{"method": "get_network_status", "jsonrpc": "2.0", "id": 1}"#,
+            ));
+        }
+        assert_eq!(query_manager.get_pending_requests("instance", &[]).len(), 2);
+    }
+
+    #[test]
+    fn write_requests_are_not_coalesced() {
+        let query_manager =
+            QueryManager::new(true, DEFAULT_JOB_RETENTION, None, DEFAULT_INSTANCE_TIMEOUT);
+
+        for id in 1..=2 {
+            query_manager.enqueue_query(unencrypted_query_request(&format!(
+                r#"{{"method": "build_and_submit_transaction", "params": {{"account_id": "a"}}, "jsonrpc": "2.0", "id": {}}}"#,
+                id
+            )));
+        }
+        assert_eq!(query_manager.get_pending_requests("instance", &[]).len(), 2);
+    }
+
+    #[test]
+    fn encrypted_requests_are_not_coalesced() {
+        let query_manager =
+            QueryManager::new(true, DEFAULT_JOB_RETENTION, None, DEFAULT_INSTANCE_TIMEOUT);
+
+        for _ in 0..2 {
+            let mut encrypted_request = EncryptedRequest::new();
+            encrypted_request.set_payload(vec![1, 2, 3]);
+
+            let mut query_request = QueryRequest::new();
+            query_request.set_encrypted_request(encrypted_request);
+            query_manager.enqueue_query(query_request);
+        }
+        assert_eq!(query_manager.get_pending_requests("instance", &[]).len(), 2);
+    }
+
+    #[test]
+    fn session_requests_go_to_the_instance_that_established_the_session() {
+        let query_manager = QueryManager::default();
+        query_manager.get_pending_requests("a", &[]);
+        query_manager.get_pending_requests("b", &[]);
+
+        let mut query_request = QueryRequest::new();
+        query_request.set_session_handshake_request(SessionHandshakeRequest::new());
+        query_manager.enqueue_query(query_request);
+        let query_id = query_manager
+            .get_pending_requests("a", &[])
+            .keys()
+            .next()
+            .unwrap()
+            .clone();
+
+        let mut handshake_response = SessionHandshakeResponse::new();
+        handshake_response.set_session_id("s1".to_owned());
+        let mut response = QueryResponse::new();
+        response.set_session_handshake_response(handshake_response);
+        query_manager
+            .resolve_query("a", &[], &query_id, &response)
+            .unwrap();
+
+        let mut session_request = SessionRequest::new();
+        session_request.set_session_id("s1".to_owned());
+        let mut query_request = QueryRequest::new();
+        query_request.set_session_request(session_request);
+        query_manager.enqueue_query(query_request);
+
+        assert!(query_manager.get_pending_requests("b", &[]).is_empty());
+        assert_eq!(query_manager.get_pending_requests("a", &[]).len(), 1);
+    }
 }
@@ -0,0 +1,192 @@
+// Copyright (c) 2018-2022 MobileCoin Inc.
+
+//! Tenants allow a single public side to front several isolated wallets, each served by its own
+//! private side(s) registered with `--tenant`.
+//!
+//! A request is made for a tenant either by prefixing its path with `/t/<tenant>` (for example
+//! `/t/acme/encrypted-request`), or by passing an API key that belongs to the tenant in the
+//! `X-Api-Key` header. Tenants that have API keys require one of them to be passed, even when the
+//! path prefix is used.
+//!
+//! The config is a JSON file of the form:
+//! ```json
+//! {
+//!     "tenants": {
+//!         "acme": { "api_keys": ["<api key>"] },
+//!         "globex": {}
+//!     }
+//! }
+//! ```
+
+use rocket::{
+    request::{FromRequest, Outcome},
+    Request,
+};
+use serde_derive::Deserialize;
+use std::{collections::HashMap, fs};
+
+/// Header holding the API key of a request.
+pub const API_KEY_HEADER: &str = "X-Api-Key";
+
+#[derive(Deserialize)]
+struct TenantConfig {
+    #[serde(default)]
+    api_keys: Vec<String>,
+}
+
+#[derive(Deserialize)]
+struct TenantsConfig {
+    /// Map of tenant name -> tenant config.
+    tenants: HashMap<String, TenantConfig>,
+}
+
+/// The tenants requests can be made for.
+#[derive(Clone, Debug)]
+pub struct Tenants {
+    /// Map of tenant name -> whether the tenant requires an API key.
+    tenants: HashMap<String, bool>,
+
+    /// Map of API key -> tenant name.
+    api_keys: HashMap<String, String>,
+}
+
+impl Tenants {
+    /// Load the tenants config from a JSON file.
+    pub fn load(src: &str) -> Result<Self, String> {
+        let config_str = fs::read_to_string(src)
+            .map_err(|err| format!("failed reading tenants config {}: {:?}", src, err))?;
+        Self::from_json(&config_str)
+            .map_err(|err| format!("failed parsing tenants config {}: {}", src, err))
+    }
+
+    fn from_json(config_str: &str) -> Result<Self, String> {
+        let config: TenantsConfig =
+            serde_json::from_str(config_str).map_err(|err| err.to_string())?;
+
+        let mut tenants = HashMap::new();
+        let mut api_keys = HashMap::new();
+        for (tenant, tenant_config) in config.tenants {
+            if !is_valid_tenant_name(&tenant) {
+                return Err(format!("invalid tenant name \"{}\"", tenant));
+            }
+            for api_key in tenant_config.api_keys.iter() {
+                if api_keys.insert(api_key.clone(), tenant.clone()).is_some() {
+                    return Err(format!("API key of {} is used by another tenant", tenant));
+                }
+            }
+            tenants.insert(tenant, !tenant_config.api_keys.is_empty());
+        }
+
+        Ok(Self { tenants, api_keys })
+    }
+
+    /// Determine the tenant a request was made for, from the tenant in its path (if any) and its
+    /// API key (if any).
+    pub fn resolve(
+        &self,
+        path_tenant: Option<&str>,
+        api_key: Option<&str>,
+    ) -> Result<String, String> {
+        let key_tenant = match api_key {
+            Some(api_key) => Some(self.api_keys.get(api_key).ok_or("Unknown API key")?),
+            None => None,
+        };
+
+        match (path_tenant, key_tenant) {
+            (Some(path_tenant), key_tenant) => {
+                let requires_api_key = *self
+                    .tenants
+                    .get(path_tenant)
+                    .ok_or_else(|| format!("Unknown tenant {}", path_tenant))?;
+                match key_tenant {
+                    Some(key_tenant) if key_tenant != path_tenant => {
+                        Err(format!("API key does not belong to tenant {}", path_tenant))
+                    }
+                    None if requires_api_key => {
+                        Err(format!("Tenant {} requires an API key", path_tenant))
+                    }
+                    _ => Ok(path_tenant.to_owned()),
+                }
+            }
+            (None, Some(key_tenant)) => Ok(key_tenant.clone()),
+            (None, None) => Err("Requests must be made for a tenant".into()),
+        }
+    }
+}
+
+/// Determine the tenant a request was made for. Without a tenants config, every request is made
+/// without a tenant.
+pub fn resolve_tenant(
+    tenants: Option<&Tenants>,
+    path_tenant: Option<&str>,
+    api_key: Option<&str>,
+) -> Result<String, String> {
+    match (tenants, path_tenant) {
+        (Some(tenants), path_tenant) => tenants.resolve(path_tenant, api_key),
+        (None, None) => Ok(String::new()),
+        (None, Some(_)) => Err("Tenants are not enabled".into()),
+    }
+}
+
+fn is_valid_tenant_name(tenant: &str) -> bool {
+    !tenant.is_empty()
+        && tenant
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// The API key passed with a request, if any.
+pub struct ApiKey(pub Option<String>);
+
+impl<'a, 'r> FromRequest<'a, 'r> for ApiKey {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(ApiKey(
+            request.headers().get_one(API_KEY_HEADER).map(str::to_owned),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_tenants() -> Tenants {
+        Tenants::from_json(r#"{"tenants": {"acme": {"api_keys": ["acme-key"]}, "globex": {}}}"#)
+            .unwrap()
+    }
+
+    #[test]
+    fn tenants_are_resolved_from_path_and_api_key() {
+        let tenants = test_tenants();
+
+        assert_eq!(
+            tenants.resolve(Some("globex"), None),
+            Ok("globex".to_owned())
+        );
+        assert_eq!(
+            tenants.resolve(Some("acme"), Some("acme-key")),
+            Ok("acme".to_owned())
+        );
+        assert_eq!(
+            tenants.resolve(None, Some("acme-key")),
+            Ok("acme".to_owned())
+        );
+
+        assert!(tenants.resolve(Some("acme"), None).is_err());
+        assert!(tenants.resolve(Some("globex"), Some("acme-key")).is_err());
+        assert!(tenants.resolve(Some("initech"), None).is_err());
+        assert!(tenants.resolve(None, Some("other-key")).is_err());
+        assert!(tenants.resolve(None, None).is_err());
+    }
+
+    #[test]
+    fn invalid_configs_are_rejected() {
+        assert!(Tenants::from_json(r#"{"tenants": {"a/b": {}}}"#).is_err());
+        assert!(Tenants::from_json(
+            r#"{"tenants": {"a": {"api_keys": ["k"]}, "b": {"api_keys": ["k"]}}}"#
+        )
+        .is_err());
+    }
+}
@@ -3,6 +3,7 @@
 //! Misc utility methods.
 
 use mc_util_uri::ConnectionUri;
+use serde_json::Value;
 use x509_parser::{error::X509Error, parse_x509_der, pem::pem_to_der};
 
 /// Checks if an optionally-provided TLS certificate is self-signed. Returns false if no TLS is
@@ -39,3 +40,61 @@ pub fn is_tls_self_signed(uri: &impl ConnectionUri) -> Result<bool, String> {
         Err(err) => Err(format!("Error verifying certificate: {:?}", err)),
     }
 }
+
+/// Returns the method name of a JSON-RPC request along with a key that identifies the request by
+/// its method and params, ignoring the request id. Semantically identical requests map to the same
+/// key regardless of the order of their object keys.
+pub fn request_key(request: &Value) -> Option<(String, String)> {
+    let method = request.get("method")?.as_str()?;
+    let params = request.get("params").unwrap_or(&Value::Null);
+    Some((
+        method.to_owned(),
+        format!("{}:{}", method, normalize_json(params)),
+    ))
+}
+
+/// Replace the id of a JSON-RPC response with the id of the request it answers. This is needed
+/// when a response is shared between several requests. The response is returned as-is if either
+/// side cannot be parsed.
+pub fn with_request_id(json_request: &str, json_response: &str) -> String {
+    let request: Value = match serde_json::from_str(json_request) {
+        Ok(request) => request,
+        Err(_) => return json_response.to_owned(),
+    };
+    match serde_json::from_str::<Value>(json_response) {
+        Ok(Value::Object(mut response)) => {
+            response.insert(
+                "id".to_owned(),
+                request.get("id").cloned().unwrap_or(Value::Null),
+            );
+            Value::Object(response).to_string()
+        }
+        _ => json_response.to_owned(),
+    }
+}
+
+/// Serialize a JSON value with object keys in sorted order.
+fn normalize_json(value: &Value) -> String {
+    match value {
+        Value::Object(map) => {
+            let mut keys: Vec<&String> = map.keys().collect();
+            keys.sort();
+            let fields: Vec<String> = keys
+                .into_iter()
+                .map(|key| {
+                    format!(
+                        "{}:{}",
+                        Value::from(key.as_str()),
+                        normalize_json(&map[key])
+                    )
+                })
+                .collect();
+            format!("{{{}}}", fields.join(","))
+        }
+        Value::Array(values) => {
+            let values: Vec<String> = values.iter().map(normalize_json).collect();
+            format!("[{}]", values.join(","))
+        }
+        other => other.to_string(),
+    }
+}
@@ -0,0 +1,247 @@
+// Copyright (c) 2018-2022 MobileCoin Inc.
+
+//! Delivery of job results to client-supplied callback URLs.
+//! Each callback is a POST of the job status JSON (the same body `GET /jobs/<id>` returns, so
+//! encrypted responses stay encrypted), signed with HMAC-SHA256 over `<timestamp>.<body>` using a
+//! key shared with the receiver.
+//!
+//! Callbacks are queued and delivered by a fixed number of worker threads. Callbacks that do not
+//! fit in the queue are dropped.
+
+use crate::counters;
+use hmac::{Hmac, Mac, NewMac};
+use mc_common::logger::{log, Logger};
+use sha2::Sha256;
+use std::{
+    sync::{
+        mpsc::{sync_channel, Receiver, SyncSender, TrySendError},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Header holding the hex-encoded HMAC-SHA256 signature of a callback.
+pub const SIGNATURE_HEADER: &str = "X-Mirror-Signature";
+
+/// Header holding the Unix timestamp (seconds) covered by the signature.
+pub const TIMESTAMP_HEADER: &str = "X-Mirror-Timestamp";
+
+/// How many times delivering a callback is attempted before giving up.
+const MAX_ATTEMPTS: u32 = 5;
+
+/// How long to wait before retrying a failed callback. Doubles after every attempt.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+/// How long to wait for the callback receiver to reply.
+const CALLBACK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How many callbacks are delivered at the same time.
+const WORKER_COUNT: usize = 4;
+
+/// How many callbacks may wait for a worker.
+const MAX_QUEUED_CALLBACKS: usize = 1000;
+
+/// A key used for signing callbacks.
+#[derive(Clone, Debug)]
+pub struct HmacKey(Vec<u8>);
+
+impl HmacKey {
+    /// Load a key from a file holding the hex-encoded key.
+    pub fn load(src: &str) -> Result<Self, String> {
+        let key_hex = std::fs::read_to_string(src)
+            .map_err(|err| format!("failed reading callback key file {}: {:?}", src, err))?;
+        let key = hex::decode(key_hex.trim())
+            .map_err(|err| format!("failed decoding callback key file {}: {}", src, err))?;
+        if key.is_empty() {
+            return Err(format!("callback key file {} is empty", src));
+        }
+        Ok(Self(key))
+    }
+}
+
+/// A callback waiting to be delivered.
+struct Callback {
+    url: String,
+    body: String,
+}
+
+/// Sends signed callbacks to allowlisted URLs.
+#[derive(Clone)]
+pub struct WebhookSender {
+    /// URL prefixes callbacks may be sent to.
+    allowlist: Arc<Vec<String>>,
+
+    /// Queue of callbacks waiting for a worker.
+    queue: SyncSender<Callback>,
+
+    logger: Logger,
+}
+
+impl WebhookSender {
+    /// Create a sender, starting its worker threads. The workers stop once every clone of the
+    /// sender is dropped.
+    pub fn new(allowlist: Vec<String>, hmac_key: HmacKey, logger: Logger) -> Self {
+        let (queue, receiver) = sync_channel(MAX_QUEUED_CALLBACKS);
+        let receiver = Arc::new(Mutex::new(receiver));
+        for index in 0..WORKER_COUNT {
+            let receiver = receiver.clone();
+            let hmac_key = hmac_key.clone();
+            let logger = logger.clone();
+            thread::Builder::new()
+                .name(format!("webhook-{}", index))
+                .spawn(move || run_worker(&receiver, &hmac_key, &logger))
+                .expect("failed spawning webhook worker thread");
+        }
+
+        Self {
+            allowlist: Arc::new(allowlist),
+            queue,
+            logger,
+        }
+    }
+
+    /// Returns true if callbacks may be sent to the given URL. A URL is allowed if it equals an
+    /// allowlist entry, or extends it with a path or query (so that `https://example.com` does
+    /// not allow `https://example.com.evil.org`).
+    pub fn is_allowed(&self, url: &str) -> bool {
+        self.allowlist.iter().any(|entry| {
+            url == entry
+                || (url.starts_with(entry.as_str())
+                    && (entry.ends_with('/') || url[entry.len()..].starts_with(&['/', '?'][..])))
+        })
+    }
+
+    /// Queue a callback for delivery in the background, retrying with exponential backoff. The
+    /// callback is dropped if too many callbacks are already waiting.
+    pub fn send(&self, url: String, body: String) {
+        match self.queue.try_send(Callback { url, body }) {
+            Ok(()) => {}
+            Err(TrySendError::Full(callback)) => {
+                log::error!(
+                    self.logger,
+                    "Dropping callback to {}: too many callbacks are waiting",
+                    callback.url
+                );
+                counters::OP_COUNTERS.inc("webhook_dropped");
+            }
+            Err(TrySendError::Disconnected(callback)) => {
+                log::error!(
+                    self.logger,
+                    "Dropping callback to {}: no webhook workers",
+                    callback.url
+                );
+                counters::OP_COUNTERS.inc("webhook_dropped");
+            }
+        }
+    }
+}
+
+/// Deliver queued callbacks until the queue is closed.
+fn run_worker(receiver: &Mutex<Receiver<Callback>>, hmac_key: &HmacKey, logger: &Logger) {
+    loop {
+        // The lock is only held while waiting for a callback, not while delivering it.
+        let callback = receiver.lock().expect("mutex poisoned").recv();
+        match callback {
+            Ok(callback) => deliver_with_retries(&callback, hmac_key, logger),
+            Err(_) => return,
+        }
+    }
+}
+
+fn deliver_with_retries(callback: &Callback, hmac_key: &HmacKey, logger: &Logger) {
+    let mut backoff = INITIAL_BACKOFF;
+    for attempt in 1..=MAX_ATTEMPTS {
+        match deliver(&callback.url, &callback.body, hmac_key) {
+            Ok(()) => {
+                log::debug!(logger, "Delivered callback to {}", callback.url);
+                counters::OP_COUNTERS.inc("webhook_delivered");
+                return;
+            }
+            Err(err) => log::warn!(
+                logger,
+                "Delivering callback to {} failed (attempt {}/{}): {}",
+                callback.url,
+                attempt,
+                MAX_ATTEMPTS,
+                err
+            ),
+        }
+        if attempt < MAX_ATTEMPTS {
+            thread::sleep(backoff);
+            backoff *= 2;
+        }
+    }
+
+    log::error!(logger, "Giving up on callback to {}", callback.url);
+    counters::OP_COUNTERS.inc("webhook_failed");
+}
+
+fn deliver(url: &str, body: &str, hmac_key: &HmacKey) -> Result<(), String> {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0);
+    let signature = sign(&hmac_key.0, format!("{}.{}", timestamp, body).as_bytes());
+
+    let client = reqwest::blocking::Client::builder()
+        .timeout(CALLBACK_TIMEOUT)
+        .build()
+        .map_err(|e| e.to_string())?;
+    let res = client
+        .post(url)
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .header(TIMESTAMP_HEADER, timestamp.to_string())
+        .header(SIGNATURE_HEADER, signature)
+        .body(body.to_owned())
+        .send()
+        .map_err(|e| e.to_string())?;
+
+    if !res.status().is_success() {
+        return Err(format!("receiver returned {}", res.status()));
+    }
+    Ok(())
+}
+
+/// Hex-encoded HMAC-SHA256 of a message.
+fn sign(key: &[u8], message: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_varkey(key).expect("HMAC accepts keys of any length");
+    mac.update(message);
+    hex::encode(mac.finalize().into_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mc_common::logger::test_with_logger;
+
+    #[test]
+    fn sign_matches_rfc_4231() {
+        assert_eq!(
+            sign(b"Jefe", b"what do ya want for nothing?"),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test_with_logger]
+    fn allowlist_matches_whole_hosts_and_paths(logger: Logger) {
+        let sender = WebhookSender::new(
+            vec![
+                "https://example.com".to_owned(),
+                "https://hooks.example.org/mirror/".to_owned(),
+            ],
+            HmacKey(vec![1, 2, 3]),
+            logger,
+        );
+
+        assert!(sender.is_allowed("https://example.com"));
+        assert!(sender.is_allowed("https://example.com/callback"));
+        assert!(sender.is_allowed("https://example.com?x=1"));
+        assert!(sender.is_allowed("https://hooks.example.org/mirror/123"));
+
+        assert!(!sender.is_allowed("https://example.com.evil.org"));
+        assert!(!sender.is_allowed("https://example.com:8443/callback"));
+        assert!(!sender.is_allowed("http://example.com/callback"));
+        assert!(!sender.is_allowed("https://hooks.example.org/other"));
+    }
+}
@@ -0,0 +1,33 @@
+// Copyright (c) 2018-2022 MobileCoin Inc.
+
+//! Signatures by the private side of the mirror over the responses it returns, which let clients
+//! verify that a response was not forged or altered by the public side.
+//!
+//! The private side signs
+//! `wallet-service-mirror-response\n<query id>\n<request digest>\n<body>` with an Ed25519 key,
+//! where the request digest is the hex-encoded SHA-256 digest of the body of the request, and the
+//! body is that of the response. Bodies are the JSON request and response of unencrypted requests,
+//! or the encrypted payloads of encrypted requests. Covering the request keeps the public side
+//! from answering a request with a signed response to another one.
+//!
+//! Responses the public side serves from its response cache are not signed, nor are coalesced
+//! responses that were not made for the exact request. Clients that rely on signatures must
+//! reject responses that come without one.
+
+use sha2::{Digest, Sha256};
+
+/// Domain separator for response signatures.
+const RESPONSE_SIGNATURE_CONTEXT: &str = "wallet-service-mirror-response";
+
+/// The message the private side signs for the response to a request.
+pub fn response_message(query_id: &str, request: &[u8], body: &[u8]) -> Vec<u8> {
+    let mut message = format!(
+        "{}\n{}\n{}\n",
+        RESPONSE_SIGNATURE_CONTEXT,
+        query_id,
+        hex::encode(Sha256::digest(request))
+    )
+    .into_bytes();
+    message.extend_from_slice(body);
+    message
+}